@@ -1,19 +1,29 @@
 use crate::com::com_traits::RtcCommand;
 use crate::{net_traits::AppMetadata, Identity, RtcMessage, RtcPool, RtcTxn};
-use allotize_db::KvStore;
+use allotize_db::{
+    ArchiveSource, ContinuationToken, Eviction, ImportMode, KvStore, KvTxn, KvsError, Quota, Scan,
+    Segment, Tuple, WriteBatch,
+};
+use async_trait::async_trait;
 use futures::lock::Mutex;
+use std::cmp;
+use std::io;
 use std::ops::Bound;
 use std::path::PathBuf;
 use std::sync::Arc;
+use std::time::Duration;
 
 use js_sys::Object;
 use js_sys::Proxy;
 use wasm_bindgen::prelude::*;
-use web_sys::{CustomEvent, EventTarget, MessageEvent};
+use web_sys::{Blob, BlobPropertyBag, CustomEvent, EventTarget, MessageEvent};
 
 use crate::net_traits::{JsVal, VersionedComponent};
 use crdts::CvRDT;
 
+/// Number of bytes in each part of the `Blob` that `App::download` resolves to
+const BLOB_PART: usize = 64 * 1024;
+
 pub enum Status {
     NotFound,
     Success,
@@ -55,6 +65,36 @@ fn notify_js_about_local_change(
         .expect("Could not dispatch event");
 }
 
+/// Notifies subscribers of `key` that a peer removed it, with `null` as the new value
+fn notify_js_about_remote_removal(event_target: &EventTarget, key: &str) {
+    let key = format!("{}@remote", key);
+    let notify_event = CustomEvent::new(&key).unwrap();
+    notify_event.init_custom_event_with_can_bubble_and_cancelable_and_detail(
+        &key,
+        true,
+        true,
+        &JsValue::NULL,
+    );
+    event_target
+        .dispatch_event(&notify_event)
+        .expect("Could not dispatch event");
+}
+
+/// Notifies subscribers of `key` that it expired, with `null` as the new value
+fn notify_js_about_expiry(event_target: &EventTarget, key: &str) {
+    let key = format!("{}@local", key);
+    let notify_event = CustomEvent::new(&key).unwrap();
+    notify_event.init_custom_event_with_can_bubble_and_cancelable_and_detail(
+        &key,
+        true,
+        true,
+        &JsValue::NULL,
+    );
+    event_target
+        .dispatch_event(&notify_event)
+        .expect("Could not dispatch event");
+}
+
 #[wasm_bindgen]
 impl Tx {
     /// Shares a key/value pair with connected users
@@ -78,18 +118,27 @@ impl Tx {
     ///
     /// If no peer is connected, it postpones the message until
     /// atleast one peer is listening.
-    pub fn put(&self, key: String, value: JsValue) -> js_sys::Promise {
+    ///
+    /// If `ttl` is given, the pair expires after that many milliseconds,
+    /// see `App::sweepExpired`. Peers expire it at the same time.
+    pub fn put(&self, key: String, value: JsValue, ttl: Option<f64>) -> js_sys::Promise {
         let store = Arc::clone(&self.store);
         let pool = Arc::clone(&self.pool);
         let event_target = Arc::clone(&self.event_target);
         let future = async move {
-            store
-                .lock()
-                .await
-                .txn()
-                .set(key.clone(), &JsVal { v: value.clone() })
-                .await
-                .unwrap();
+            {
+                let mut store = store.lock().await;
+                let mut txn = store.txn();
+                let val = JsVal { v: value.clone() };
+                match ttl {
+                    Some(ttl) => txn
+                        .set_expiring(key.clone(), &val, Duration::from_millis(ttl as u64))
+                        .await
+                        .unwrap(),
+                    None => txn.set(key.clone(), &val).await.unwrap(),
+                }
+                txn.commit().await.unwrap();
+            }
 
             let component = value.into_serde().ok();
 
@@ -97,8 +146,14 @@ impl Tx {
                 notify_js_about_local_change(&event_target, &key, &component);
             }
 
+            let command = match ttl {
+                Some(ttl) => RtcCommand::PutExpiring {
+                    expires_at: js_sys::Date::now() as u64 + ttl as u64,
+                },
+                None => RtcCommand::Put,
+            };
             let message = RtcMessage {
-                command: RtcCommand::Put,
+                command,
                 key,
                 value: component,
             };
@@ -110,6 +165,107 @@ impl Tx {
         wasm_bindgen_futures::future_to_promise(future)
     }
 
+    /// Puts many key/value pairs in the store with a single write,
+    /// e.g. for a bulk import, and notifies connected peers about each of them.
+    ///
+    /// `entries` is an array of `[key, value]` pairs, like `Object.entries`.
+    #[wasm_bindgen(js_name = putBatch)]
+    pub fn put_batch(&self, entries: js_sys::Array) -> js_sys::Promise {
+        let store = Arc::clone(&self.store);
+        let pool = Arc::clone(&self.pool);
+        let event_target = Arc::clone(&self.event_target);
+        let future = async move {
+            let mut batch = WriteBatch::new();
+            let mut puts = Vec::with_capacity(entries.length() as usize);
+            for entry in entries.iter() {
+                let entry = js_sys::Array::from(&entry);
+                let key = entry
+                    .get(0)
+                    .as_string()
+                    .ok_or_else(|| JsValue::from_str("Keys must be strings"))?;
+                let value = entry.get(1);
+                batch
+                    .set(key.clone(), &JsVal { v: value.clone() })
+                    .map_err(|err| JsValue::from_str(&err.to_string()))?;
+                puts.push((key, value));
+            }
+            store
+                .lock()
+                .await
+                .write(batch)
+                .await
+                .map_err(|err| JsValue::from_str(&err.to_string()))?;
+
+            for (key, value) in puts {
+                broadcast_put(&pool, &event_target, key, value).await;
+            }
+            Ok(Status::Success.into())
+        };
+        wasm_bindgen_futures::future_to_promise(future)
+    }
+
+    /// Puts a key/value pair in the store if the key holds `expected`,
+    /// and notifies connected peers about the change, like `put`.
+    ///
+    /// Rejects with the error if the key holds another value, so that
+    /// an optimistic update can read the key again and retry.
+    #[wasm_bindgen(js_name = compareAndSwap)]
+    pub fn compare_and_swap(
+        &self,
+        key: String,
+        expected: JsValue,
+        value: JsValue,
+    ) -> js_sys::Promise {
+        let store = Arc::clone(&self.store);
+        let pool = Arc::clone(&self.pool);
+        let event_target = Arc::clone(&self.event_target);
+        let future = async move {
+            {
+                let mut store = store.lock().await;
+                let mut txn = store.txn();
+                txn.compare_and_swap(
+                    key.clone(),
+                    &JsVal { v: expected },
+                    &JsVal { v: value.clone() },
+                )
+                .await
+                .map_err(|err| JsValue::from_str(&err.to_string()))?;
+                txn.commit()
+                    .await
+                    .map_err(|err| JsValue::from_str(&err.to_string()))?;
+            }
+
+            broadcast_put(&pool, &event_target, key, value).await;
+            Ok(Status::Success.into())
+        };
+        wasm_bindgen_futures::future_to_promise(future)
+    }
+
+    /// Puts a key/value pair in the store if the key is absent,
+    /// see `compareAndSwap`.
+    #[wasm_bindgen(js_name = putIfAbsent)]
+    pub fn put_if_absent(&self, key: String, value: JsValue) -> js_sys::Promise {
+        let store = Arc::clone(&self.store);
+        let pool = Arc::clone(&self.pool);
+        let event_target = Arc::clone(&self.event_target);
+        let future = async move {
+            {
+                let mut store = store.lock().await;
+                let mut txn = store.txn();
+                txn.set_if_absent(key.clone(), &JsVal { v: value.clone() })
+                    .await
+                    .map_err(|err| JsValue::from_str(&err.to_string()))?;
+                txn.commit()
+                    .await
+                    .map_err(|err| JsValue::from_str(&err.to_string()))?;
+            }
+
+            broadcast_put(&pool, &event_target, key, value).await;
+            Ok(Status::Success.into())
+        };
+        wasm_bindgen_futures::future_to_promise(future)
+    }
+
     /// Puts a key/value pair in the store using CRDT. After
     /// applying the changes, the peers are notified.
     ///
@@ -143,13 +299,16 @@ impl Tx {
             component.apply(identity.username);
             component.data = Some(value.clone());
 
-            store
-                .lock()
-                .await
-                .txn()
-                .set(key.clone(), &component)
-                .await
-                .map_err(|e| JsValue::from_str(&e.to_string()))?;
+            {
+                let mut store = store.lock().await;
+                let mut txn = store.txn();
+                txn.set(key.clone(), &component)
+                    .await
+                    .map_err(|e| JsValue::from_str(&e.to_string()))?;
+                txn.commit()
+                    .await
+                    .map_err(|e| JsValue::from_str(&e.to_string()))?;
+            }
 
             notify_js_about_local_change(&event_target, &key, &component);
 
@@ -296,6 +455,77 @@ impl Tx {
         wasm_bindgen_futures::future_to_promise(future)
     }
 
+    /// Gets one page of key/value pairs from the store for a given `Range`.
+    ///
+    /// Resolves to `{ items, next }`, where `next` is a token that is passed
+    /// back as `token` to get the following page, or `null` on the last page.
+    #[wasm_bindgen(js_name = getRangePage)]
+    pub fn get_range_page(
+        &self,
+        start: String,
+        end: Option<String>,
+        limit: usize,
+        reverse: Option<bool>,
+        token: Option<String>,
+    ) -> js_sys::Promise {
+        let scan = Scan::range(
+            Bound::Included(start),
+            end.map(Bound::Excluded).unwrap_or(Bound::Unbounded),
+        );
+        self.scan(scan, limit, reverse, token)
+    }
+
+    /// Gets one page of key/value pairs whose keys begin with `prefix`,
+    /// see `getRangePage`.
+    #[wasm_bindgen(js_name = beginsWithPage)]
+    pub fn begins_with_page(
+        &self,
+        prefix: String,
+        limit: usize,
+        reverse: Option<bool>,
+        token: Option<String>,
+    ) -> js_sys::Promise {
+        self.scan(Scan::prefix(prefix), limit, reverse, token)
+    }
+
+    /// Packs an array of segments into a key that sorts segment by segment,
+    /// e.g. `["order", 10]` after `["order", 2]`.
+    ///
+    /// Segments are strings, integers, `Date`s or `Uint8Array`s.
+    #[wasm_bindgen(js_name = tupleKey)]
+    pub fn tuple_key(&self, segments: js_sys::Array) -> Result<String, JsValue> {
+        Ok(tuple_from_js(&segments)?.pack())
+    }
+
+    /// Unpacks a key packed by `tupleKey` into its array of segments.
+    #[wasm_bindgen(js_name = unpackKey)]
+    pub fn unpack_key(&self, key: String) -> Result<js_sys::Array, JsValue> {
+        let tuple = Tuple::unpack(&key).map_err(|err| JsValue::from_str(&err.to_string()))?;
+        Ok(tuple
+            .segments()
+            .iter()
+            .map(|segment| match segment {
+                Segment::Bytes(bytes) => js_sys::Uint8Array::from(bytes.as_slice()).into(),
+                Segment::String(string) => JsValue::from_str(string),
+                Segment::Int(int) => JsValue::from_f64(*int as f64),
+                Segment::Timestamp(millis) => {
+                    js_sys::Date::new(&JsValue::from_f64(*millis as f64)).into()
+                }
+            })
+            .collect())
+    }
+
+    /// Returns `[start, end]` of the keys packed from all tuples that begin
+    /// with `prefix`, which are passed on to `getRange` or `getRangePage`.
+    #[wasm_bindgen(js_name = tupleRange)]
+    pub fn tuple_range(&self, prefix: js_sys::Array) -> Result<js_sys::Array, JsValue> {
+        let range = match tuple_from_js(&prefix)?.range() {
+            (Bound::Included(start), Bound::Excluded(end)) => [start, end],
+            _ => unreachable!("a tuple range is half-open"),
+        };
+        Ok(range.iter().map(|key| JsValue::from_str(key)).collect())
+    }
+
     /// Gets a key/value pair from the store.
     ///
     /// If no value corresponds to the given key, an `JsValue`
@@ -310,14 +540,17 @@ impl Tx {
                 value: None,
             };
 
-            let res = store
-                .lock()
-                .await
-                .txn()
-                .remove(key)
-                .await
-                .map(|_| JsValue::from_bool(true))
-                .map_err(|_| JsValue::from_bool(false));
+            let res = {
+                let mut store = store.lock().await;
+                let mut txn = store.txn();
+                let removed = match txn.remove(key).await {
+                    Ok(()) => txn.commit().await,
+                    Err(e) => Err(e),
+                };
+                removed
+                    .map(|_| JsValue::from_bool(true))
+                    .map_err(|_| JsValue::from_bool(false))
+            };
 
             if res.is_ok() {
                 pool.lock().await.require_channels(1).await.unwrap();
@@ -329,6 +562,230 @@ impl Tx {
 
         wasm_bindgen_futures::future_to_promise(future)
     }
+
+    /// Removes the keys from `start` on, up to but excluding `end` if given,
+    /// like `getRange`, and notifies connected peers about it.
+    ///
+    /// The keys are removed with a single write, and peers are sent a single
+    /// message, however many keys there are.
+    #[wasm_bindgen(js_name = removeRange)]
+    pub fn remove_range(&self, start: String, end: Option<String>) -> js_sys::Promise {
+        let message = RtcMessage {
+            command: RtcCommand::RemoveRange { end: end.clone() },
+            key: start.clone(),
+            value: None,
+        };
+        self.remove_many(message, move |txn| {
+            txn.remove_range(
+                Bound::Included(start),
+                end.map(Bound::Excluded).unwrap_or(Bound::Unbounded),
+            )
+        })
+    }
+
+    /// Removes the keys that begin with `prefix`, see `removeRange`.
+    #[wasm_bindgen(js_name = removePrefix)]
+    pub fn remove_prefix(&self, prefix: String) -> js_sys::Promise {
+        let message = RtcMessage {
+            command: RtcCommand::RemovePrefix,
+            key: prefix.clone(),
+            value: None,
+        };
+        self.remove_many(message, move |txn| txn.remove_prefix(prefix))
+    }
+
+    /// Removes a key from the store if it holds `expected`,
+    /// and notifies connected peers about it, like `remove`.
+    ///
+    /// Rejects with the error if the key holds another value,
+    /// see `compareAndSwap`.
+    #[wasm_bindgen(js_name = removeIfEquals)]
+    pub fn remove_if_equals(&self, key: String, expected: JsValue) -> js_sys::Promise {
+        let store = Arc::clone(&self.store);
+        let pool = Arc::clone(&self.pool);
+        let future = async move {
+            {
+                let mut store = store.lock().await;
+                let mut txn = store.txn();
+                txn.remove_if_equals(key.clone(), &JsVal { v: expected })
+                    .await
+                    .map_err(|err| JsValue::from_str(&err.to_string()))?;
+                txn.commit()
+                    .await
+                    .map_err(|err| JsValue::from_str(&err.to_string()))?;
+            }
+
+            let message = RtcMessage {
+                command: RtcCommand::Remove,
+                key,
+                value: None,
+            };
+            pool.lock().await.require_channels(1).await.unwrap();
+            pool.lock().await.txn().broadcast(&message).await;
+            Ok(JsValue::from_bool(true))
+        };
+        wasm_bindgen_futures::future_to_promise(future)
+    }
+}
+
+/// Notifies local subscribers and connected peers about a value
+/// that was put in the store
+async fn broadcast_put(
+    pool: &Mutex<RtcPool>,
+    event_target: &EventTarget,
+    key: String,
+    value: JsValue,
+) {
+    let component = value.into_serde().ok();
+
+    if let Some(component) = &component {
+        notify_js_about_local_change(event_target, &key, component);
+    }
+
+    let message = RtcMessage {
+        command: RtcCommand::Put,
+        key,
+        value: component,
+    };
+
+    pool.lock().await.require_channels(1).await.unwrap();
+    pool.lock().await.txn().broadcast(&message).await;
+}
+
+/// Reads a `Tuple` from an array of segments, see `Tx::tuple_key`
+fn tuple_from_js(segments: &js_sys::Array) -> Result<Tuple, JsValue> {
+    segments
+        .iter()
+        .map(|segment| {
+            if let Some(string) = segment.as_string() {
+                Ok(Segment::String(string))
+            } else if let Some(number) = segment.as_f64() {
+                if number.fract() != 0.0 || number.abs() > i64::MAX as f64 {
+                    return Err(JsValue::from_str("Only integers can be tuple segments"));
+                }
+                Ok(Segment::Int(number as i64))
+            } else if segment.is_instance_of::<js_sys::Date>() {
+                let date = js_sys::Date::from(segment);
+                Ok(Segment::Timestamp(date.get_time() as i64))
+            } else if segment.is_instance_of::<js_sys::Uint8Array>() {
+                Ok(Segment::Bytes(js_sys::Uint8Array::from(segment).to_vec()))
+            } else {
+                Err(JsValue::from_str("Unsupported tuple segment"))
+            }
+        })
+        .collect()
+}
+
+/// Collects what is written to it into the parts of a `Blob`, see `App::download`.
+///
+/// Only the part that is being written is held in Rust.
+struct BlobParts {
+    parts: js_sys::Array,
+    part: Vec<u8>,
+}
+
+impl io::Write for BlobParts {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.part.extend_from_slice(buf);
+        if self.part.len() >= BLOB_PART {
+            self.flush()?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if !self.part.is_empty() {
+            self.parts.push(&js_sys::Uint8Array::from(&self.part[..]));
+            self.part.clear();
+        }
+        Ok(())
+    }
+}
+
+/// Reads an archive from slices of a `Blob`, see `App::upload`
+struct BlobSource(Blob);
+
+#[async_trait(?Send)]
+impl ArchiveSource for BlobSource {
+    async fn read_at(&mut self, pos: u64, buf: &mut [u8]) -> allotize_db::Result<usize> {
+        let end = cmp::min(pos + buf.len() as u64, self.0.size() as u64);
+        if pos >= end {
+            return Ok(0);
+        }
+        let slice = self
+            .0
+            .slice_with_f64_and_f64(pos as f64, end as f64)
+            .map_err(blob_error)?;
+        let bytes = wasm_bindgen_futures::JsFuture::from(slice.array_buffer())
+            .await
+            .map_err(blob_error)?;
+        let bytes = js_sys::Uint8Array::new(&bytes);
+        let len = bytes.length() as usize;
+        bytes.copy_to(&mut buf[..len]);
+        Ok(len)
+    }
+}
+
+/// Converts an error thrown while reading a `Blob` into a `KvsError`
+fn blob_error(err: JsValue) -> KvsError {
+    io::Error::other(format!("{:?}", err)).into()
+}
+
+impl Tx {
+    /// Commits the removal of many keys, and broadcasts `message` to the peers
+    fn remove_many(
+        &self,
+        message: RtcMessage,
+        remove: impl FnOnce(&mut KvTxn) + 'static,
+    ) -> js_sys::Promise {
+        let store = Arc::clone(&self.store);
+        let pool = Arc::clone(&self.pool);
+        let future = async move {
+            {
+                let mut store = store.lock().await;
+                let mut txn = store.txn();
+                remove(&mut txn);
+                txn.commit()
+                    .await
+                    .map_err(|err| JsValue::from_str(&err.to_string()))?;
+            }
+
+            pool.lock().await.require_channels(1).await.unwrap();
+            pool.lock().await.txn().broadcast(&message).await;
+            Ok(Status::Success.into())
+        };
+        wasm_bindgen_futures::future_to_promise(future)
+    }
+
+    /// Runs a paginated scan, resuming it after `token` if one is given
+    fn scan(
+        &self,
+        scan: Scan,
+        limit: usize,
+        reverse: Option<bool>,
+        token: Option<String>,
+    ) -> js_sys::Promise {
+        let store = self.store.clone();
+        let future = async move {
+            let mut scan = scan.reverse(reverse.unwrap_or(false)).limit(limit);
+            if let Some(token) = token {
+                let token: ContinuationToken = token
+                    .parse()
+                    .map_err(|err: KvsError| JsValue::from_str(&err.to_string()))?;
+                scan = scan.resume(&token);
+            }
+
+            let page = store
+                .lock()
+                .await
+                .txn()
+                .scan(scan)
+                .await
+                .map_err(|err| JsValue::from_str(&err.to_string()))?;
+            JsValue::from_serde(&page).map_err(|err| JsValue::from_str(&err.to_string()))
+        };
+        wasm_bindgen_futures::future_to_promise(future)
+    }
 }
 
 /// The `App` consists of a pool and a store.
@@ -368,12 +825,18 @@ impl App {
 
     pub fn metadata(&self) -> js_sys::Promise {
         let pool = Arc::clone(&self.pool);
+        let store = Arc::clone(&self.store);
         let token = self.token.clone();
 
         let future = async move {
             let metadata = AppMetadata {
                 token,
                 pool: pool.lock().await.metadata(),
+                store: store
+                    .lock()
+                    .await
+                    .stats()
+                    .map_err(|err| JsValue::from_str(&err.to_string()))?,
             };
 
             JsValue::from_serde(&metadata).map_err(|_| JsValue::from_str("Failed serialization"))
@@ -397,6 +860,123 @@ impl App {
         wasm_bindgen_futures::future_to_promise(future)
     }
 
+    /// Removes the keys whose time to live has passed, see `Tx::put`,
+    /// and notifies their subscribers.
+    ///
+    /// Resolves to the removed keys.
+    #[wasm_bindgen(js_name = sweepExpired)]
+    pub fn sweep_expired(&self) -> js_sys::Promise {
+        let store = Arc::clone(&self.store);
+        let event_target = Arc::clone(&self.event_target);
+        let future = async move {
+            let expired = store
+                .lock()
+                .await
+                .sweep_expired(None)
+                .await
+                .map_err(|err| JsValue::from_str(&err.to_string()))?;
+
+            for key in &expired {
+                notify_js_about_expiry(&event_target, key);
+            }
+            JsValue::from_serde(&expired).map_err(|err| JsValue::from_str(&err.to_string()))
+        };
+        wasm_bindgen_futures::future_to_promise(future)
+    }
+
+    /// Limits how many bytes the store takes up, see `KvStore::set_quota`.
+    ///
+    /// Once the store would grow past `maxBytes`, the least recently used keys
+    /// are evicted, or the keys that expire the soonest if `ttlFirst` is set.
+    /// Without `maxBytes` the store grows without limit.
+    #[wasm_bindgen(js_name = setQuota)]
+    pub fn set_quota(&self, max_bytes: Option<f64>, ttl_first: Option<bool>) -> js_sys::Promise {
+        let store = Arc::clone(&self.store);
+        let future = async move {
+            let eviction = if ttl_first.unwrap_or(false) {
+                Eviction::TtlFirst
+            } else {
+                Eviction::Lru
+            };
+            let quota = max_bytes.map(|max_bytes| Quota {
+                max_bytes: max_bytes as u64,
+                eviction,
+            });
+            store.lock().await.set_quota(quota);
+            Ok(JsValue::undefined())
+        };
+        wasm_bindgen_futures::future_to_promise(future)
+    }
+
+    /// Stores values of at least `bytes` bytes, e.g. images, in blobs of their own
+    /// instead of in the logs, see `KvStore::set_blob_threshold`.
+    /// Without `bytes` every value is stored in the logs.
+    #[wasm_bindgen(js_name = setBlobThreshold)]
+    pub fn set_blob_threshold(&self, bytes: Option<f64>) -> js_sys::Promise {
+        let store = Arc::clone(&self.store);
+        let future = async move {
+            store
+                .lock()
+                .await
+                .set_blob_threshold(bytes.map(|bytes| bytes as u64));
+            Ok(JsValue::undefined())
+        };
+        wasm_bindgen_futures::future_to_promise(future)
+    }
+
+    /// Exports every key of the store and of its substores, see `KvStore::export`.
+    ///
+    /// Resolves to a `Blob` of newline delimited json, which can be saved as a file
+    /// and handed to `upload` in another browser.
+    /// The archive is handed to the `Blob` in parts, as it is written.
+    pub fn download(&self) -> js_sys::Promise {
+        let store = Arc::clone(&self.store);
+        let future = async move {
+            let mut archive = BlobParts {
+                parts: js_sys::Array::new(),
+                part: Vec::with_capacity(BLOB_PART),
+            };
+            store
+                .lock()
+                .await
+                .export(&mut archive)
+                .await
+                .map_err(|err| JsValue::from_str(&err.to_string()))?;
+
+            let blob = Blob::new_with_u8_array_sequence_and_options(
+                &archive.parts,
+                BlobPropertyBag::new().type_("application/x-ndjson"),
+            )?;
+            Ok(blob.into())
+        };
+        wasm_bindgen_futures::future_to_promise(future)
+    }
+
+    /// Imports an archive made by `download`, see `KvStore::import`.
+    ///
+    /// Keys that are not in the archive are kept, unless `replace` is set.
+    /// Resolves to the number of imported keys.
+    /// The archive is read from slices of the `Blob`, as it is imported.
+    pub fn upload(&self, archive: Blob, replace: Option<bool>) -> js_sys::Promise {
+        let store = Arc::clone(&self.store);
+        let future = async move {
+            let mode = if replace.unwrap_or(false) {
+                ImportMode::Replace
+            } else {
+                ImportMode::Merge
+            };
+
+            let imported = store
+                .lock()
+                .await
+                .import(BlobSource(archive), mode)
+                .await
+                .map_err(|err| JsValue::from_str(&err.to_string()))?;
+            Ok(JsValue::from_f64(imported as f64))
+        };
+        wasm_bindgen_futures::future_to_promise(future)
+    }
+
     pub fn unsubscribe(&self, key: &str, callback: &js_sys::Function) {
         self.event_target
             .remove_event_listener_with_callback(&format!("{}@local", key), callback)
@@ -506,22 +1086,32 @@ impl App {
                         rtc_message.value.as_ref().unwrap(),
                     );
                 }
-                RtcCommand::Put => {
+                RtcCommand::Put | RtcCommand::PutExpiring { .. } => {
                     notify(
                         &cloned_event_target,
                         &rtc_message.key,
                         rtc_message.value.as_ref().unwrap(),
                     );
+                    let ttl = match rtc_message.command {
+                        RtcCommand::PutExpiring { expires_at } => Some(Duration::from_millis(
+                            expires_at.saturating_sub(js_sys::Date::now() as u64),
+                        )),
+                        _ => None,
+                    };
                     let cloned_store2 = Arc::clone(&cloned_store);
                     // Update the value
                     wasm_bindgen_futures::spawn_local(async move {
-                        cloned_store2
-                            .lock()
-                            .await
-                            .txn()
-                            .set(rtc_message.key, &rtc_message.value.unwrap())
-                            .await
-                            .unwrap();
+                        let mut store = cloned_store2.lock().await;
+                        let mut txn = store.txn();
+                        let value = rtc_message.value.unwrap();
+                        match ttl {
+                            Some(ttl) => txn
+                                .set_expiring(rtc_message.key, &value, ttl)
+                                .await
+                                .unwrap(),
+                            None => txn.set(rtc_message.key, &value).await.unwrap(),
+                        }
+                        txn.commit().await.unwrap();
                     });
                 }
                 RtcCommand::CrdtPut => {
@@ -556,13 +1146,12 @@ impl App {
                                     format!(" Remote: {:?}", remote_component)
                                 );
 
-                                cloned_store2
-                                    .lock()
+                                let mut store = cloned_store2.lock().await;
+                                let mut txn = store.txn();
+                                txn.set(rtc_message.key.clone(), &remote_component)
                                     .await
-                                    .txn()
-                                    .set(rtc_message.key.clone(), &remote_component)
-                                    .await
-                                    .unwrap()
+                                    .unwrap();
+                                txn.commit().await.unwrap();
                             }
                             Some(std::cmp::Ordering::Less) => {
                                 notify(
@@ -580,13 +1169,12 @@ impl App {
                                     ""
                                 );
 
-                                cloned_store2
-                                    .lock()
-                                    .await
-                                    .txn()
-                                    .set(rtc_message.key.clone(), &remote_component)
+                                let mut store = cloned_store2.lock().await;
+                                let mut txn = store.txn();
+                                txn.set(rtc_message.key.clone(), &remote_component)
                                     .await
                                     .unwrap();
+                                txn.commit().await.unwrap();
                             }
                             Some(std::cmp::Ordering::Greater) => {
                                 info!(
@@ -638,13 +1226,14 @@ impl App {
                                     rtc_message.value.as_ref().unwrap(),
                                 );
 
-                                cloned_store2
-                                    .lock()
-                                    .await
-                                    .txn()
-                                    .set(rtc_message.key.clone(), &local_component)
-                                    .await
-                                    .unwrap();
+                                {
+                                    let mut store = cloned_store2.lock().await;
+                                    let mut txn = store.txn();
+                                    txn.set(rtc_message.key.clone(), &local_component)
+                                        .await
+                                        .unwrap();
+                                    txn.commit().await.unwrap();
+                                }
 
                                 // Notify peers about the merge change
                                 let message = RtcMessage {
@@ -668,13 +1257,53 @@ impl App {
 
                     let cloned_store2 = Arc::clone(&cloned_store);
                     wasm_bindgen_futures::spawn_local(async move {
-                        cloned_store2
-                            .lock()
-                            .await
-                            .txn()
-                            .remove(rtc_message.key)
-                            .await
-                            .unwrap();
+                        let mut store = cloned_store2.lock().await;
+                        let mut txn = store.txn();
+                        txn.remove(rtc_message.key).await.unwrap();
+                        txn.commit().await.unwrap();
+                    })
+                }
+                RtcCommand::RemoveRange { end } => {
+                    let start = rtc_message.key;
+                    let cloned_store2 = Arc::clone(&cloned_store);
+                    let cloned_event_target2 = Arc::clone(&cloned_event_target);
+                    wasm_bindgen_futures::spawn_local(async move {
+                        let mut store = cloned_store2.lock().await;
+                        let removed: Vec<String> = store
+                            .snapshot()
+                            .keys(start.clone()..)
+                            .take_while(|key| end.as_ref().map_or(true, |end| *key < end))
+                            .cloned()
+                            .collect();
+                        let mut txn = store.txn();
+                        txn.remove_range(
+                            Bound::Included(start),
+                            end.map(Bound::Excluded).unwrap_or(Bound::Unbounded),
+                        );
+                        txn.commit().await.unwrap();
+                        for key in removed {
+                            notify_js_about_remote_removal(&cloned_event_target2, &key);
+                        }
+                    })
+                }
+                RtcCommand::RemovePrefix => {
+                    let prefix = rtc_message.key;
+                    let cloned_store2 = Arc::clone(&cloned_store);
+                    let cloned_event_target2 = Arc::clone(&cloned_event_target);
+                    wasm_bindgen_futures::spawn_local(async move {
+                        let mut store = cloned_store2.lock().await;
+                        let removed: Vec<String> = store
+                            .snapshot()
+                            .keys(prefix.clone()..)
+                            .take_while(|key| key.starts_with(&prefix))
+                            .cloned()
+                            .collect();
+                        let mut txn = store.txn();
+                        txn.remove_prefix(prefix);
+                        txn.commit().await.unwrap();
+                        for key in removed {
+                            notify_js_about_remote_removal(&cloned_event_target2, &key);
+                        }
                     })
                 }
                 RtcCommand::Done => todo!(),
@@ -702,13 +1331,12 @@ impl App {
         key: String,
         value: VersionedComponent,
     ) -> Result<JsValue, JsValue> {
-        self.store
-            .lock()
-            .await
-            .txn()
-            .set(key.clone(), &value)
-            .await
-            .unwrap();
+        {
+            let mut store = self.store.lock().await;
+            let mut txn = store.txn();
+            txn.set(key.clone(), &value).await.unwrap();
+            txn.commit().await.unwrap();
+        }
 
         // Notify peers about the change
         let message = RtcMessage {
@@ -752,13 +1380,12 @@ impl App {
             value
         };
 
-        self.store
-            .lock()
-            .await
-            .txn()
-            .set(key.clone(), &new_component)
-            .await
-            .unwrap();
+        {
+            let mut store = self.store.lock().await;
+            let mut txn = store.txn();
+            txn.set(key.clone(), &new_component).await.unwrap();
+            txn.commit().await.unwrap();
+        }
 
         // Notify peers about the change
         let message = RtcMessage {
@@ -23,8 +23,19 @@ pub struct PoolMetadata {
 pub enum RtcCommand {
     Share,
     Put,
+    /// Puts a value that expires at the given time,
+    /// in milliseconds since the Unix epoch
+    PutExpiring {
+        expires_at: u64,
+    },
     CrdtPut,
     Remove,
+    /// Removes the keys from `key` on, up to but excluding `end` if given
+    RemoveRange {
+        end: Option<String>,
+    },
+    /// Removes the keys that begin with `key`
+    RemovePrefix,
     Done,
 }
 
@@ -1,4 +1,5 @@
 use crate::com::com_traits::PoolMetadata;
+use allotize_db::Stats;
 use crdts::{CmRDT, VClock};
 use serde::{Deserialize, Serialize};
 use wasm_bindgen::prelude::*;
@@ -8,6 +9,8 @@ use wasm_bindgen::prelude::*;
 pub struct AppMetadata {
     pub(crate) token: Option<String>,
     pub pool: PoolMetadata,
+    /// The space taken up by the store and its substores
+    pub(crate) store: Stats,
 }
 
 #[wasm_bindgen]
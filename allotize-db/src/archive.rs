@@ -0,0 +1,392 @@
+//! Portable archives of a `KvStore`, see `KvStore::export`.
+//!
+//! An archive is newline delimited json. The first line is a header,
+//! every following line holds one key of the store or of a substore:
+//!
+//! ```text
+//! {"format":"allotize-db","version":2,"path":"tempstore","keys":3}
+//! {"key":"avatar","value":"iVBORw0KGgo=","encoding":"base64"}
+//! {"key":"user","value":"\"alice\""}
+//! {"substore":"component1","key":"draft","value":"{}","expires_at":1602940000000}
+//! ```
+
+use std::collections::BTreeMap;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::iter;
+use std::ops::Bound;
+use std::path::{Path, PathBuf};
+
+use async_trait::async_trait;
+
+use crate::{Backend, KvStore, KvsError, Result};
+
+/// Identifies an archive written by `KvStore::export`
+const ARCHIVE_FORMAT: &str = "allotize-db";
+
+/// Version of the archive layout written by `KvStore::export`.
+///
+/// Version 2 added binary values, `KvStore::import` reads version 1 as well.
+const ARCHIVE_VERSION: u32 = 2;
+
+/// Number of keys that `KvStore::import` commits at a time
+const IMPORT_CHUNK: usize = 512;
+
+/// Number of bytes that `KvStore::import` reads from the archive at a time
+const READ_CHUNK: usize = 64 * 1024;
+
+/// How `KvStore::import` treats the keys that are already in the store
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum ImportMode {
+    /// Keeps the keys that are not in the archive, and overwrites the ones that are
+    Merge,
+    /// Removes every key of the store and of its substores before importing
+    Replace,
+}
+
+/// The first line of an archive
+#[derive(Debug, Serialize, Deserialize)]
+struct Header {
+    format: String,
+    version: u32,
+    /// Path of the store that was exported
+    path: String,
+    /// Number of keys in the archive
+    keys: u64,
+}
+
+/// A key/value pair in an archive
+#[derive(Debug, Serialize, Deserialize)]
+struct Entry {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    substore: Option<PathBuf>,
+    key: String,
+    value: String,
+    /// How the value is encoded, `None` for a value that is text
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    encoding: Option<ValueEncoding>,
+    /// When the key expires, in milliseconds since the Unix epoch
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    expires_at: Option<u64>,
+}
+
+/// The encoding of a value in an archive that is not text
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum ValueEncoding {
+    /// A binary value, e.g. written through `KvStore::blob_writer`
+    Base64,
+}
+
+impl<B: Backend> KvStore<B> {
+    /// Writes every live key of the store and of its substores to `writer`,
+    /// as an archive that `KvStore::import` reads.
+    ///
+    /// The keys are read from a snapshot, so the archive is consistent even if
+    /// the store is written to while it is exported. Values that are not text,
+    /// see `blob_writer`, are written as base64. Returns the number of keys.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O errors during reading the logs or writing the archive.
+    pub async fn export<W: Write>(&mut self, mut writer: W) -> Result<u64> {
+        let snapshot = self.snapshot();
+        let scopes: Vec<Option<PathBuf>> = iter::once(None)
+            .chain(
+                snapshot
+                    .substores()
+                    .map(|sub_path| Some(sub_path.to_path_buf())),
+            )
+            .collect();
+
+        let mut keys = 0;
+        for scope in &scopes {
+            keys += snapshot.entries(scope.as_deref())?.count() as u64;
+        }
+        let header = Header {
+            format: ARCHIVE_FORMAT.to_owned(),
+            version: ARCHIVE_VERSION,
+            path: self.path().display().to_string(),
+            keys,
+        };
+        serde_json::to_writer(&mut writer, &header)?;
+        writer.write_all(b"\n")?;
+
+        for scope in &scopes {
+            let substore = scope.as_deref();
+            for (key, expires_at) in snapshot.entries(substore)? {
+                let (value, encoding) = match snapshot.get_scoped(self, key, substore).await {
+                    Ok(value) => (value.ok_or(KvsError::KeyNotFound)?, None),
+                    Err(KvsError::BinaryValue { .. }) => {
+                        let value = self.read_binary(key, substore).await?;
+                        (base64::encode(&value), Some(ValueEncoding::Base64))
+                    }
+                    Err(err) => return Err(err),
+                };
+                let entry = Entry {
+                    substore: scope.clone(),
+                    key: key.clone(),
+                    value,
+                    encoding,
+                    expires_at,
+                };
+                serde_json::to_writer(&mut writer, &entry)?;
+                writer.write_all(b"\n")?;
+            }
+        }
+        writer.flush()?;
+
+        Ok(keys)
+    }
+
+    /// Reads an archive written by `KvStore::export` into the store.
+    ///
+    /// The archive is read line by line and committed in chunks, so it never
+    /// has to be held in memory as a whole. Substores in the archive are added
+    /// to the store, and keys that have expired since the export are skipped.
+    /// Binary values are stored in blobs. Returns the number of imported keys.
+    ///
+    /// The archive is read twice, and the store is only written to once
+    /// every line of it has been checked.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::InvalidArchive`, without importing anything,
+    /// if the archive can not be read, or if it holds another number of keys
+    /// than its header says, e.g. because it was cut off.
+    pub async fn import<S: ArchiveSource>(
+        &mut self,
+        mut source: S,
+        mode: ImportMode,
+    ) -> Result<u64> {
+        let header = read_header(&mut source).await?;
+        let mut lines = Lines::new(&mut source);
+        let mut read = 0;
+        while let Some((line_number, line)) = lines.next().await? {
+            parse_entry(line_number, &line)?;
+            read += 1;
+        }
+        if read != header.keys {
+            return Err(invalid(
+                read + 2,
+                format!("expected {} keys, found {}", header.keys, read),
+            ));
+        }
+
+        if mode == ImportMode::Replace {
+            self.remove_all().await?;
+        }
+
+        let mut lines = Lines::new(&mut source);
+        let mut imported = 0;
+        let mut chunk = Vec::with_capacity(IMPORT_CHUNK);
+        while let Some((line_number, line)) = lines.next().await? {
+            chunk.push((line_number, parse_entry(line_number, &line)?));
+            if chunk.len() == IMPORT_CHUNK {
+                imported += self.import_chunk(&mut chunk).await?;
+            }
+        }
+        imported += self.import_chunk(&mut chunk).await?;
+        Ok(imported)
+    }
+
+    /// Commits the given entries, by their line numbers,
+    /// and returns how many of them had not expired
+    async fn import_chunk(&mut self, chunk: &mut Vec<(u64, Entry)>) -> Result<u64> {
+        for sub_path in chunk
+            .iter()
+            .filter_map(|(_, entry)| entry.substore.as_deref())
+        {
+            self.add_substore(sub_path).await?;
+        }
+
+        // a transaction writes to a single substore
+        let now = crate::engine::now();
+        let mut substores: BTreeMap<Option<PathBuf>, Vec<(u64, Entry)>> = BTreeMap::new();
+        for (line_number, entry) in chunk.drain(..) {
+            if entry.expires_at.is_some_and(|expires_at| expires_at <= now) {
+                continue;
+            }
+            substores
+                .entry(entry.substore.clone())
+                .or_default()
+                .push((line_number, entry));
+        }
+
+        let mut imported = 0;
+        let mut binary = Vec::new();
+        for (substore, entries) in substores {
+            let mut txn = self.txn();
+            for (line_number, entry) in entries {
+                imported += 1;
+                if entry.encoding.is_some() {
+                    binary.push((line_number, entry));
+                    continue;
+                }
+                txn.set_raw(
+                    substore.as_deref(),
+                    entry.key,
+                    entry.value,
+                    entry.expires_at,
+                );
+            }
+            txn.commit().await?;
+        }
+
+        // binary values can only be written to blobs
+        for (line_number, entry) in binary {
+            let value = decode(line_number, &entry)?;
+            let mut writer = self
+                .blob_writer(entry.key, entry.substore.as_deref())
+                .await?;
+            writer.write_all(&value)?;
+            self.commit_blob(writer).await?;
+        }
+        Ok(imported)
+    }
+
+    /// Reads the whole value of a key that is not text, see `read_blob`
+    async fn read_binary(&mut self, key: &str, substore: Option<&Path>) -> Result<Vec<u8>> {
+        let mut reader = self
+            .read_blob(key, substore)
+            .await?
+            .ok_or(KvsError::KeyNotFound)?;
+        let mut value = vec![0; reader.len() as usize];
+        let mut read = 0;
+        while read < value.len() {
+            read += reader.read(&mut value[read..]).await?;
+        }
+        Ok(value)
+    }
+
+    /// Removes every key of the store, and clears every substore
+    async fn remove_all(&mut self) -> Result<()> {
+        let sub_paths: Vec<PathBuf> = self.substores().map(Path::to_path_buf).collect();
+        for sub_path in sub_paths {
+            self.clear_substore(&sub_path).await?;
+        }
+
+        let mut txn = self.txn();
+        txn.remove_range(Bound::Unbounded, Bound::Unbounded);
+        txn.commit().await
+    }
+}
+
+/// An archive that `KvStore::import` reads from.
+///
+/// It is read in chunks, and from the start once more after it was checked,
+/// so it never has to be held in memory as a whole.
+#[async_trait(?Send)]
+pub trait ArchiveSource {
+    /// Reads the bytes at `pos` into `buf`, and returns how many were read,
+    /// which is 0 only at the end of the archive.
+    async fn read_at(&mut self, pos: u64, buf: &mut [u8]) -> Result<usize>;
+}
+
+#[async_trait(?Send)]
+impl<R: Read + Seek> ArchiveSource for R {
+    async fn read_at(&mut self, pos: u64, buf: &mut [u8]) -> Result<usize> {
+        self.seek(SeekFrom::Start(pos))?;
+        Ok(self.read(buf)?)
+    }
+}
+
+/// Reads the lines of an `ArchiveSource` after its header
+struct Lines<'a, S> {
+    source: &'a mut S,
+    // where the next chunk is read from
+    pos: u64,
+    // the bytes that were read, but are not returned yet
+    buf: Vec<u8>,
+    // number of the line that is returned next
+    line_number: u64,
+    done: bool,
+}
+
+impl<'a, S: ArchiveSource> Lines<'a, S> {
+    fn new(source: &'a mut S) -> Self {
+        Lines {
+            source,
+            pos: 0,
+            buf: Vec::new(),
+            line_number: 0,
+            done: false,
+        }
+    }
+
+    /// Returns the next line with text and its number, skipping the header
+    async fn next(&mut self) -> Result<Option<(u64, String)>> {
+        loop {
+            let line = match self.next_line().await? {
+                Some(line) => line,
+                None => return Ok(None),
+            };
+            if self.line_number > 1 && !line.trim().is_empty() {
+                return Ok(Some((self.line_number, line)));
+            }
+        }
+    }
+
+    /// Returns the next line, including the header
+    async fn next_line(&mut self) -> Result<Option<String>> {
+        loop {
+            let end = match self.buf.iter().position(|&byte| byte == b'\n') {
+                Some(newline) => newline + 1,
+                None if self.done && self.buf.is_empty() => return Ok(None),
+                None if self.done => self.buf.len(),
+                None => {
+                    let len = self.buf.len();
+                    self.buf.resize(len + READ_CHUNK, 0);
+                    let read = self.source.read_at(self.pos, &mut self.buf[len..]).await?;
+                    self.buf.truncate(len + read);
+                    self.pos += read as u64;
+                    self.done = read == 0;
+                    continue;
+                }
+            };
+            self.line_number += 1;
+            let line: Vec<u8> = self.buf.drain(..end).collect();
+            let line = String::from_utf8(line).map_err(|err| invalid(self.line_number, err))?;
+            return Ok(Some(line.trim_end_matches(&['\n', '\r'][..]).to_owned()));
+        }
+    }
+}
+
+/// Reads and checks the first line of an archive
+async fn read_header<S: ArchiveSource>(source: &mut S) -> Result<Header> {
+    let header: Header = match Lines::new(source).next_line().await? {
+        Some(line) => serde_json::from_str(&line).map_err(|err| invalid(1, err))?,
+        None => return Err(invalid(1, "the archive is empty")),
+    };
+    if header.format != ARCHIVE_FORMAT || !(1..=ARCHIVE_VERSION).contains(&header.version) {
+        return Err(invalid(
+            1,
+            format!(
+                "unsupported archive {} version {}",
+                header.format, header.version
+            ),
+        ));
+    }
+    Ok(header)
+}
+
+/// Parses the entry on the given line of an archive
+fn parse_entry(line_number: u64, line: &str) -> Result<Entry> {
+    let entry: Entry = serde_json::from_str(line).map_err(|err| invalid(line_number, err))?;
+    if entry.encoding.is_some() {
+        decode(line_number, &entry)?;
+    }
+    Ok(entry)
+}
+
+/// Returns the bytes of a binary value on the given line of an archive
+fn decode(line_number: u64, entry: &Entry) -> Result<Vec<u8>> {
+    base64::decode(&entry.value).map_err(|err| invalid(line_number, err))
+}
+
+/// Returns the error for an archive that can not be read at the given line
+fn invalid(line: u64, reason: impl ToString) -> KvsError {
+    KvsError::InvalidArchive {
+        line,
+        reason: reason.to_string(),
+    }
+}
@@ -0,0 +1,59 @@
+use async_trait::async_trait;
+
+use std::io::{self, Read, Seek, Write};
+use std::ops::Range;
+use std::path::Path;
+
+use crate::Result;
+
+/// A place where the `KvStore` keeps its log files.
+///
+/// The engine only needs a flat namespace of files addressed by path,
+/// so a backend can be anything from `IndexedDB` to a plain `HashMap`.
+#[async_trait(?Send)]
+pub trait Backend {
+    /// A handle to a single file in the backend.
+    type File: BackendFile;
+
+    /// Opens the file at `path`, creating an empty one if it does not exist.
+    ///
+    /// The returned handle is positioned at the end of the file.
+    async fn open_file(&mut self, path: &Path) -> Result<Self::File>;
+
+    /// Returns the names of all files stored in the backend.
+    async fn file_names(&self) -> Result<Vec<String>>;
+
+    /// Removes the file at `path`.
+    async fn remove_file(&mut self, path: &Path) -> Result<()>;
+}
+
+/// A handle to a single file in a `Backend`.
+///
+/// `Write::flush` hands everything written so far to the backend, and once
+/// `sync` resolves it must survive the store being reopened.
+#[async_trait(?Send)]
+pub trait BackendFile: Read + Write + Seek {
+    /// Truncates or extends the file to `len` bytes.
+    ///
+    /// Like writes, the new length is durable after the next flush.
+    fn set_len(&mut self, len: u64) -> io::Result<()>;
+
+    /// Makes sure that the bytes in `range` can be read.
+    ///
+    /// Backends that load files lazily must fetch the bytes here, since
+    /// `Read` can not wait for them. Others have nothing to do.
+    async fn fetch(&mut self, _range: Range<u64>) -> Result<()> {
+        Ok(())
+    }
+
+    /// Waits until everything that was flushed is stored durably.
+    ///
+    /// Backends whose `Write::flush` is durable by itself have nothing to do.
+    ///
+    /// # Errors
+    ///
+    /// It returns the error of any flush that failed to be stored.
+    async fn sync(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
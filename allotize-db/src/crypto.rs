@@ -0,0 +1,592 @@
+//! Encryption at rest, see `EncryptedBackend`.
+//!
+//! An encrypted file starts with a plaintext header that names the key it is
+//! encrypted with, and a random id of the file:
+//!
+//! ```text
+//! +---------------+-----------------+-----------------+------------------+
+//! | magic: "ADBE" | version: u32 BE | key id: 8 bytes | file id: 16 bytes |
+//! +---------------+-----------------+-----------------+------------------+
+//! ```
+//!
+//! Followed by chunks, each sealed with AES-256-GCM under a random nonce.
+//! The id of the file and the plaintext offset of a chunk are authenticated
+//! along with it, so chunks can not be reordered, moved between positions,
+//! or moved to another file:
+//!
+//! ```text
+//! +-------------+-----------------+-----------------------+---------------+
+//! | len: u32 BE | nonce: 12 bytes | ciphertext: len bytes | tag: 16 bytes |
+//! +-------------+-----------------+-----------------------+---------------+
+//! ```
+//!
+//! Chunks are only ever appended, one or more on every flush,
+//! so a torn write can only damage the last chunk.
+
+use async_trait::async_trait;
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM, NONCE_LEN};
+use ring::rand::{SecureRandom, SystemRandom};
+use ring::{digest, pbkdf2};
+
+use std::cmp;
+use std::fmt;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::num::NonZeroU32;
+use std::ops::Range;
+use std::path::Path;
+
+use crate::{Backend, BackendFile, IdbFolder, KvStore, KvsError, Result};
+
+/// Marks a file that is encrypted
+pub(crate) const MAGIC: &[u8; 4] = b"ADBE";
+
+/// Version of the layout of encrypted files.
+///
+/// Version 2 added the id of the file, which the chunks are bound to.
+const VERSION: u32 = 2;
+
+/// Number of bytes in a key
+const KEY_LEN: usize = 32;
+
+/// Number of bytes in the id of a key
+const KEY_ID_LEN: usize = 8;
+
+/// Number of bytes in the id of a file
+const FILE_ID_LEN: usize = 16;
+
+/// Number of bytes in front of the key id of a file
+const VERSION_HEADER_LEN: u64 = 8;
+
+/// Number of bytes in front of the ciphertext of a chunk
+const CHUNK_HEADER_LEN: u64 = 4 + NONCE_LEN as u64;
+
+/// Number of bytes after the ciphertext of a chunk
+const TAG_LEN: u64 = 16;
+
+/// Largest number of plaintext bytes in a chunk.
+///
+/// Reading a single record decrypts every chunk it overlaps,
+/// so large flushes are split up.
+const CHUNK_SIZE: usize = 4096;
+
+/// Number of PBKDF2 iterations in `EncryptionKey::from_passphrase`
+const PBKDF2_ITERATIONS: u32 = 100_000;
+
+/// A key that an `EncryptedBackend` encrypts files with
+#[derive(Clone)]
+pub struct EncryptionKey {
+    bytes: [u8; KEY_LEN],
+    id: [u8; KEY_ID_LEN],
+}
+
+impl EncryptionKey {
+    /// Uses the given bytes as a key, e.g. one that is supplied by the app
+    pub fn new(bytes: [u8; KEY_LEN]) -> Self {
+        let mut id = [0; KEY_ID_LEN];
+        let hash = digest::digest(
+            &digest::SHA256,
+            &[&b"allotize-db key id"[..], &bytes[..]].concat(),
+        );
+        id.copy_from_slice(&hash.as_ref()[..KEY_ID_LEN]);
+        EncryptionKey { bytes, id }
+    }
+
+    /// Derives a key from a passphrase with PBKDF2-HMAC-SHA256.
+    ///
+    /// The same passphrase and salt always derive the same key. The salt
+    /// should be unique to the store, e.g. its path and the id of the user.
+    pub fn from_passphrase(passphrase: &str, salt: &[u8]) -> Self {
+        let mut bytes = [0; KEY_LEN];
+        pbkdf2::derive(
+            pbkdf2::PBKDF2_HMAC_SHA256,
+            NonZeroU32::new(PBKDF2_ITERATIONS).expect("iterations are not zero"),
+            salt,
+            passphrase.as_bytes(),
+            &mut bytes,
+        );
+        EncryptionKey::new(bytes)
+    }
+
+    fn aead_key(&self) -> LessSafeKey {
+        LessSafeKey::new(
+            UnboundKey::new(&AES_256_GCM, &self.bytes).expect("AES-256 keys are 32 bytes"),
+        )
+    }
+}
+
+impl fmt::Debug for EncryptionKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("EncryptionKey")
+            .field("id", &self.id)
+            .finish()
+    }
+}
+
+/// A `Backend` that encrypts the files of another backend.
+///
+/// New files are encrypted with the current key. Files written before
+/// a key rotation stay readable as long as their key is kept as a previous key,
+/// until a compaction rewrites them with the current key.
+#[derive(Debug)]
+pub struct EncryptedBackend<B = IdbFolder> {
+    inner: B,
+    key: EncryptionKey,
+    previous: Vec<EncryptionKey>,
+}
+
+impl<B: Backend> EncryptedBackend<B> {
+    /// Encrypts the files of `inner` with `key`
+    pub fn new(inner: B, key: EncryptionKey) -> Self {
+        EncryptedBackend {
+            inner,
+            key,
+            previous: Vec::new(),
+        }
+    }
+
+    /// Keeps a key that files were encrypted with before a rotation readable
+    pub fn with_previous_key(mut self, key: EncryptionKey) -> Self {
+        self.previous.push(key);
+        self
+    }
+
+    /// Encrypts new files with `key`, keeping the current key to read older files
+    pub fn rotate_key(&mut self, key: EncryptionKey) {
+        let old = std::mem::replace(&mut self.key, key);
+        self.previous.push(old);
+    }
+
+    fn find_key(&self, id: &[u8]) -> Option<&EncryptionKey> {
+        std::iter::once(&self.key)
+            .chain(self.previous.iter())
+            .find(|key| key.id == id)
+    }
+}
+
+#[async_trait(?Send)]
+impl<B: Backend> Backend for EncryptedBackend<B> {
+    type File = EncryptedFile<B::File>;
+
+    async fn open_file(&mut self, path: &Path) -> Result<Self::File> {
+        let mut inner = self.inner.open_file(path).await?;
+        let inner_len = inner.seek(SeekFrom::End(0))?;
+        inner.fetch(0..inner_len).await?;
+
+        let mut version = VERSION;
+        if inner_len >= VERSION_HEADER_LEN {
+            let mut header = [0; VERSION_HEADER_LEN as usize];
+            inner.seek(SeekFrom::Start(0))?;
+            inner.read_exact(&mut header)?;
+            if &header[..4] != MAGIC {
+                return Err(KvsError::WrongKey {
+                    path: path.display().to_string(),
+                });
+            }
+            let mut bytes = [0; 4];
+            bytes.copy_from_slice(&header[4..8]);
+            version = u32::from_be_bytes(bytes);
+            if !(1..=VERSION).contains(&version) {
+                return Err(KvsError::UnsupportedFormat {
+                    path: path.display().to_string(),
+                    version,
+                });
+            }
+        }
+
+        let mut file_id = [0; FILE_ID_LEN];
+        let key = if inner_len < header_len(version) {
+            // a new file, or one whose header was torn
+            version = VERSION;
+            SystemRandom::new()
+                .fill(&mut file_id)
+                .map_err(|_| io::Error::other("could not generate a file id"))?;
+            &self.key
+        } else {
+            let mut key_id = [0; KEY_ID_LEN];
+            inner.read_exact(&mut key_id)?;
+            if version >= 2 {
+                inner.read_exact(&mut file_id)?;
+            }
+            self.find_key(&key_id).ok_or_else(|| KvsError::WrongKey {
+                path: path.display().to_string(),
+            })?
+        };
+
+        let mut file = EncryptedFile {
+            inner,
+            key: key.aead_key(),
+            key_id: key.id,
+            version,
+            file_id,
+            chunks: Vec::new(),
+            end: 0,
+            len: 0,
+            pending: Vec::new(),
+            pos: 0,
+            cache: None,
+        };
+        file.scan()?;
+        file.pos = file.len;
+        Ok(file)
+    }
+
+    async fn file_names(&self) -> Result<Vec<String>> {
+        self.inner.file_names().await
+    }
+
+    async fn remove_file(&mut self, path: &Path) -> Result<()> {
+        self.inner.remove_file(path).await
+    }
+}
+
+impl<B: Backend> KvStore<EncryptedBackend<B>> {
+    /// Encrypts the store with a new key.
+    ///
+    /// Compacts the store and every open substore, which rewrites the live keys
+    /// into new files that are encrypted with the new key, and rewrites the
+    /// blobs that they refer to. Logs pinned by a
+    /// snapshot are kept until the snapshot is dropped, and can still be read
+    /// since the old key is kept as a previous key.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O errors during the compaction.
+    pub async fn rotate_key(&mut self, key: EncryptionKey) -> Result<()> {
+        // a compaction in progress writes its log with the old key,
+        // so it is finished before the one that rewrites every log starts
+        self.finish_compactions().await?;
+        self.backend_mut().rotate_key(key);
+        self.compact().await?;
+        self.rewrite_blobs().await
+    }
+}
+
+/// Returns the number of bytes in front of the first chunk of a file
+fn header_len(version: u32) -> u64 {
+    let file_id_len = if version >= 2 { FILE_ID_LEN } else { 0 };
+    VERSION_HEADER_LEN + (KEY_ID_LEN + file_id_len) as u64
+}
+
+/// Where a chunk lies in the plaintext and in the encrypted file
+#[derive(Debug, Clone, Copy)]
+struct Chunk {
+    /// Plaintext offset of the first byte of the chunk
+    start: u64,
+    /// Offset of the chunk in the encrypted file
+    offset: u64,
+    /// Number of plaintext bytes in the chunk
+    len: u64,
+}
+
+impl Chunk {
+    fn end(&self) -> u64 {
+        self.start + self.len
+    }
+
+    fn encrypted_end(&self) -> u64 {
+        self.offset + CHUNK_HEADER_LEN + self.len + TAG_LEN
+    }
+}
+
+/// A file stored encrypted in an `EncryptedBackend`.
+///
+/// Reads and writes see the plaintext. Writes are only allowed at the end
+/// of the file, and are encrypted when they are flushed.
+pub struct EncryptedFile<F> {
+    inner: F,
+    key: LessSafeKey,
+    key_id: [u8; KEY_ID_LEN],
+    // version of the layout, files of version 1 have no id
+    version: u32,
+    file_id: [u8; FILE_ID_LEN],
+    chunks: Vec<Chunk>,
+    // end of the last complete chunk in the encrypted file
+    end: u64,
+    // number of plaintext bytes in the chunks
+    len: u64,
+    // written bytes that are encrypted on the next flush, they start at `len`
+    pending: Vec<u8>,
+    pos: u64,
+    // the last chunk that was decrypted, by its index
+    cache: Option<(usize, Vec<u8>)>,
+}
+
+impl<F> fmt::Debug for EncryptedFile<F> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("EncryptedFile")
+            .field("key_id", &self.key_id)
+            .field("len", &self.len)
+            .field("pending", &self.pending.len())
+            .field("pos", &self.pos)
+            .finish()
+    }
+}
+
+impl<F: BackendFile> EncryptedFile<F> {
+    /// Finds the chunks that were appended after the known ones,
+    /// e.g. by another handle to the same file
+    fn scan(&mut self) -> io::Result<()> {
+        let inner_len = self.inner.seek(SeekFrom::End(0))?;
+        if inner_len < self.end {
+            // the file was truncated by another handle
+            self.chunks.clear();
+            self.end = 0;
+            self.len = 0;
+            self.cache = None;
+        }
+        let header_len = header_len(self.version);
+        if inner_len < header_len {
+            return Ok(());
+        }
+
+        let mut offset = cmp::max(self.end, header_len);
+        let mut len = self.len;
+        while offset + CHUNK_HEADER_LEN <= inner_len {
+            let mut chunk_len = [0; 4];
+            self.inner.seek(SeekFrom::Start(offset))?;
+            self.inner.read_exact(&mut chunk_len)?;
+            let chunk = Chunk {
+                start: len,
+                offset,
+                len: u64::from(u32::from_be_bytes(chunk_len)),
+            };
+            // An empty chunk is never written, a zeroed header is garbage
+            if chunk.len == 0 || chunk.encrypted_end() > inner_len {
+                break;
+            }
+            if chunk.encrypted_end() == inner_len && self.decrypt(&chunk).is_err() {
+                // the last chunk was torn by a crash
+                break;
+            }
+            self.chunks.push(chunk);
+            offset = chunk.encrypted_end();
+            len = chunk.end();
+        }
+        self.end = offset;
+        self.len = len;
+        Ok(())
+    }
+
+    /// Reads and decrypts a chunk
+    fn decrypt(&mut self, chunk: &Chunk) -> io::Result<Vec<u8>> {
+        let mut nonce = [0; NONCE_LEN];
+        let mut sealed = vec![0; (chunk.len + TAG_LEN) as usize];
+        self.inner.seek(SeekFrom::Start(chunk.offset + 4))?;
+        self.inner.read_exact(&mut nonce)?;
+        self.inner.read_exact(&mut sealed)?;
+
+        let opened = self
+            .key
+            .open_in_place(
+                Nonce::assume_unique_for_key(nonce),
+                self.aad(chunk),
+                &mut sealed,
+            )
+            .map_err(|_| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("chunk at byte {} failed authentication", chunk.offset),
+                )
+            })?
+            .len();
+        sealed.truncate(opened);
+        Ok(sealed)
+    }
+
+    /// Encrypts the pending bytes and appends them as chunks
+    fn seal_pending(&mut self) -> io::Result<()> {
+        if self.pending.is_empty() {
+            return Ok(());
+        }
+
+        let inner_len = self.inner.seek(SeekFrom::End(0))?;
+        if inner_len != self.end {
+            // drop the garbage of a torn write before appending
+            self.inner.set_len(self.end)?;
+        }
+        let header_len = header_len(self.version);
+        if self.end < header_len {
+            let mut header = Vec::with_capacity(header_len as usize);
+            header.extend_from_slice(MAGIC);
+            header.extend_from_slice(&self.version.to_be_bytes());
+            header.extend_from_slice(&self.key_id);
+            if self.version >= 2 {
+                header.extend_from_slice(&self.file_id);
+            }
+            self.inner.seek(SeekFrom::Start(0))?;
+            self.inner.write_all(&header)?;
+            self.end = header_len;
+        }
+
+        let rng = SystemRandom::new();
+        let pending = std::mem::take(&mut self.pending);
+        self.inner.seek(SeekFrom::Start(self.end))?;
+        for plaintext in pending.chunks(CHUNK_SIZE) {
+            let chunk = Chunk {
+                start: self.len,
+                offset: self.end,
+                len: plaintext.len() as u64,
+            };
+            let mut nonce = [0; NONCE_LEN];
+            rng.fill(&mut nonce)
+                .map_err(|_| io::Error::other("could not generate a nonce"))?;
+            let mut sealed = plaintext.to_vec();
+            let tag = self
+                .key
+                .seal_in_place_separate_tag(
+                    Nonce::assume_unique_for_key(nonce),
+                    self.aad(&chunk),
+                    &mut sealed,
+                )
+                .map_err(|_| io::Error::other("could not encrypt a chunk"))?;
+
+            self.inner.write_all(&(chunk.len as u32).to_be_bytes())?;
+            self.inner.write_all(&nonce)?;
+            self.inner.write_all(&sealed)?;
+            self.inner.write_all(tag.as_ref())?;
+            self.chunks.push(chunk);
+            self.end = chunk.encrypted_end();
+            self.len = chunk.end();
+        }
+        Ok(())
+    }
+
+    /// Returns the data that a chunk is authenticated with, besides its ciphertext
+    fn aad(&self, chunk: &Chunk) -> Aad<Vec<u8>> {
+        let mut aad = Vec::with_capacity(FILE_ID_LEN + 8);
+        if self.version >= 2 {
+            aad.extend_from_slice(&self.file_id);
+        }
+        aad.extend_from_slice(&chunk.start.to_be_bytes());
+        Aad::from(aad)
+    }
+
+    /// Returns the index of the chunk that holds the plaintext byte at `pos`
+    fn chunk_at(&self, pos: u64) -> Option<usize> {
+        let index = self.chunks.partition_point(|chunk| chunk.end() <= pos);
+        self.chunks.get(index).map(|_| index)
+    }
+}
+
+#[async_trait(?Send)]
+impl<F: BackendFile> BackendFile for EncryptedFile<F> {
+    fn set_len(&mut self, len: u64) -> io::Result<()> {
+        if len >= self.len {
+            let pending = (len - self.len) as usize;
+            if pending > self.pending.len() {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "encrypted files can not be extended",
+                ));
+            }
+            self.pending.truncate(pending);
+            return Ok(());
+        }
+
+        self.pending.clear();
+        self.cache = None;
+        let index = self
+            .chunk_at(len)
+            .expect("len is below the end of the chunks");
+        let chunk = self.chunks[index];
+        // keep the part of the chunk before `len`, it is sealed again below
+        let mut kept = self.decrypt(&chunk)?;
+        kept.truncate((len - chunk.start) as usize);
+
+        self.chunks.truncate(index);
+        self.end = chunk.offset;
+        self.len = chunk.start;
+        self.inner.set_len(chunk.offset)?;
+        self.pending = kept;
+        self.seal_pending()
+    }
+
+    async fn fetch(&mut self, _range: Range<u64>) -> Result<()> {
+        // chunks appended by another handle have not been fetched yet
+        let inner_len = self.inner.seek(SeekFrom::End(0))?;
+        self.inner.fetch(self.end..inner_len).await?;
+        self.scan()?;
+        Ok(())
+    }
+
+    async fn sync(&mut self) -> Result<()> {
+        self.inner.sync().await
+    }
+}
+
+impl<F: BackendFile> Read for EncryptedFile<F> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos >= self.len {
+            if self.pending.is_empty() {
+                self.scan()?;
+            }
+            if self.pos >= self.len {
+                let start = cmp::min(self.pos - self.len, self.pending.len() as u64) as usize;
+                let n = Read::read(&mut &self.pending[start..], buf)?;
+                self.pos += n as u64;
+                return Ok(n);
+            }
+        }
+
+        let index = self
+            .chunk_at(self.pos)
+            .expect("pos is below the end of the chunks");
+        let chunk = self.chunks[index];
+        let plaintext = match self.cache.take() {
+            Some((cached, plaintext)) if cached == index => plaintext,
+            _ => self.decrypt(&chunk)?,
+        };
+        let start = (self.pos - chunk.start) as usize;
+        let n = Read::read(&mut &plaintext[start..], buf)?;
+        self.pos += n as u64;
+        self.cache = Some((index, plaintext));
+        Ok(n)
+    }
+}
+
+impl<F: BackendFile> Write for EncryptedFile<F> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.pos != self.len + self.pending.len() as u64 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "encrypted files can only be written at the end",
+            ));
+        }
+        self.pending.extend_from_slice(buf);
+        self.pos += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.seal_pending()?;
+        self.inner.flush()
+    }
+}
+
+impl<F: BackendFile> Seek for EncryptedFile<F> {
+    fn seek(&mut self, style: SeekFrom) -> io::Result<u64> {
+        let (base_pos, offset) = match style {
+            SeekFrom::Start(n) => {
+                self.pos = n;
+                return Ok(n);
+            }
+            SeekFrom::End(n) => (self.len + self.pending.len() as u64, n),
+            SeekFrom::Current(n) => (self.pos, n),
+        };
+        let new_pos = if offset >= 0 {
+            base_pos.checked_add(offset as u64)
+        } else {
+            base_pos.checked_sub(offset.wrapping_neg() as u64)
+        };
+        match new_pos {
+            Some(n) => {
+                self.pos = n;
+                Ok(self.pos)
+            }
+            None => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "invalid seek to a negative or overflowing position",
+            )),
+        }
+    }
+}
@@ -0,0 +1,366 @@
+//! Streaming range scans over a `KvStore`, see `Cursor`.
+
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::fmt;
+use std::ops::{Bound, RangeBounds};
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use crate::engine::{is_empty_range, BufferedMerge};
+use crate::{Backend, KvStore, KvsError, Result};
+
+/// The writes buffered in a `KvTxn`, keyed by substore and key
+pub(crate) type Writes = BTreeMap<(Option<PathBuf>, String), Option<String>>;
+
+/// The merges buffered in a `KvTxn`, of keys without a buffered write
+pub(crate) type Merges = BTreeMap<(Option<PathBuf>, String), Vec<BufferedMerge>>;
+
+/// Describes which keys a `Cursor` visits, and in which order.
+#[derive(Debug, Clone)]
+pub struct Scan {
+    start: Bound<String>,
+    end: Bound<String>,
+    reverse: bool,
+    limit: Option<usize>,
+    // set if a token was resumed that lies outside the range
+    foreign_token: bool,
+}
+
+impl Scan {
+    /// Scans the keys between `start` and `end`
+    pub fn range(start: Bound<String>, end: Bound<String>) -> Self {
+        Scan {
+            start,
+            end,
+            reverse: false,
+            limit: None,
+            foreign_token: false,
+        }
+    }
+
+    /// Scans all keys that begin with `prefix`
+    pub fn prefix(prefix: impl Into<String>) -> Self {
+        let prefix = prefix.into();
+        let end = prefix_end(&prefix);
+        Scan::range(Bound::Included(prefix), end)
+    }
+
+    /// Scans the keys from the last to the first
+    pub fn reverse(mut self, reverse: bool) -> Self {
+        self.reverse = reverse;
+        self
+    }
+
+    /// Visits at most `limit` keys
+    pub fn limit(mut self, limit: usize) -> Self {
+        self.limit = Some(limit);
+        self
+    }
+
+    /// Resumes a scan right after the key that the token was taken at.
+    ///
+    /// The direction of the scan is taken from the token. A token that was
+    /// taken on another scan, outside the range of this one, fails the cursor
+    /// with `KvsError::InvalidContinuationToken`.
+    pub fn resume(mut self, token: &ContinuationToken) -> Self {
+        let range = (self.start.as_ref(), self.end.as_ref());
+        self.foreign_token |= !range.contains(&token.key);
+        self.reverse = token.reverse;
+        let after = Bound::Excluded(token.key.clone());
+        if token.reverse {
+            self.end = tighter(self.end, after, true);
+        } else {
+            self.start = tighter(self.start, after, false);
+        }
+        self
+    }
+}
+
+/// Returns the bound that leaves out the most keys,
+/// of two lower bounds, or of two upper bounds if `upper` is set
+fn tighter(a: Bound<String>, b: Bound<String>, upper: bool) -> Bound<String> {
+    let key = |bound: &Bound<String>| match bound {
+        Bound::Included(key) | Bound::Excluded(key) => Some(key.clone()),
+        Bound::Unbounded => None,
+    };
+    match (key(&a), key(&b)) {
+        (None, _) => b,
+        (_, None) => a,
+        (Some(x), Some(y)) if x == y => match a {
+            Bound::Excluded(_) => a,
+            _ => b,
+        },
+        (Some(x), Some(y)) if (x < y) == upper => a,
+        _ => b,
+    }
+}
+
+/// Returns the smallest key that is greater than every key beginning with `prefix`
+pub(crate) fn prefix_end(prefix: &str) -> Bound<String> {
+    let mut end: Vec<char> = prefix.chars().collect();
+    while let Some(last) = end.pop() {
+        // skip the surrogates, which are not chars
+        let next = match last as u32 + 1 {
+            0xD800 => Some('\u{E000}'),
+            next => std::char::from_u32(next),
+        };
+        if let Some(next) = next {
+            end.push(next);
+            return Bound::Excluded(end.into_iter().collect());
+        }
+    }
+    Bound::Unbounded
+}
+
+/// Marks where a scan stopped, so that it can be resumed by `Scan::resume`.
+///
+/// It is passed around as an opaque string, e.g. to `JavaScript`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(into = "String", try_from = "String")]
+pub struct ContinuationToken {
+    key: String,
+    reverse: bool,
+}
+
+impl fmt::Display for ContinuationToken {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let direction = if self.reverse { 'r' } else { 'f' };
+        write!(f, "{}{}", direction, self.key)
+    }
+}
+
+impl FromStr for ContinuationToken {
+    type Err = KvsError;
+
+    fn from_str(token: &str) -> Result<Self> {
+        let reverse = match token.chars().next() {
+            Some('f') => false,
+            Some('r') => true,
+            _ => return Err(KvsError::InvalidContinuationToken),
+        };
+        Ok(ContinuationToken {
+            key: token[1..].to_owned(),
+            reverse,
+        })
+    }
+}
+
+impl From<ContinuationToken> for String {
+    fn from(token: ContinuationToken) -> String {
+        token.to_string()
+    }
+}
+
+impl TryFrom<String> for ContinuationToken {
+    type Error = KvsError;
+
+    fn try_from(token: String) -> Result<Self> {
+        token.parse()
+    }
+}
+
+/// One page of a paginated scan, see `KvTxn::scan`
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Page {
+    /// The key/value pairs on the page, in scan order
+    pub items: Vec<(String, String)>,
+    /// Resumes the scan on the next page, `None` on the last page
+    pub next: Option<ContinuationToken>,
+}
+
+/// Visits the key/value pairs of a range one at a time,
+/// instead of collecting all of them at once.
+///
+/// A cursor of a `KvTxn` sees the writes and merges buffered in the transaction.
+pub struct Cursor<'a, B: Backend> {
+    store: &'a mut KvStore<B>,
+    writes: Option<&'a Writes>,
+    merges: Option<&'a Merges>,
+    scan: Scan,
+    // the last key that was visited
+    last: Option<String>,
+    visited: usize,
+}
+
+/// Where the value of the next key comes from
+enum Source<'a> {
+    Stored,
+    Buffered(String),
+    // the stored value, with the buffered merges folded into it
+    Merged(&'a [BufferedMerge]),
+}
+
+impl<'a, B: Backend> Cursor<'a, B> {
+    pub(crate) fn new(
+        store: &'a mut KvStore<B>,
+        writes: Option<&'a Writes>,
+        merges: Option<&'a Merges>,
+        scan: Scan,
+    ) -> Self {
+        Cursor {
+            store,
+            writes,
+            merges,
+            scan,
+            last: None,
+            visited: 0,
+        }
+    }
+
+    /// Returns the next key/value pair, or `None` once the range,
+    /// or the limit of the scan, is exhausted.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::InvalidContinuationToken` if the scan was resumed
+    /// with a token of another scan, see `Scan::resume`.
+    pub async fn next(&mut self) -> Result<Option<(String, String)>> {
+        if self.scan.foreign_token {
+            return Err(KvsError::InvalidContinuationToken);
+        }
+        if self.scan.limit.is_some_and(|limit| self.visited >= limit) {
+            return Ok(None);
+        }
+
+        loop {
+            let (key, source) = match self.peek() {
+                Some(next) => next,
+                None => return Ok(None),
+            };
+            self.last = Some(key.clone());
+
+            let value = match source {
+                Source::Buffered(value) => value,
+                // the key expired since it was found
+                Source::Stored => match self.store.get(key.clone()).await? {
+                    Some(value) => value,
+                    None => continue,
+                },
+                Source::Merged(merges) => {
+                    let stored = self.store.get(key.clone()).await?;
+                    match self.store.fold_merges(&key, stored, merges)? {
+                        Some(value) => value,
+                        None => continue,
+                    }
+                }
+            };
+            self.visited += 1;
+            return Ok(Some((key, value)));
+        }
+    }
+
+    /// Collects the key/value pairs up to the limit of the scan into a `Page`.
+    ///
+    /// The page carries a token for the next page if the range holds more keys.
+    pub async fn page(mut self) -> Result<Page> {
+        let mut items = Vec::new();
+        while let Some(item) = self.next().await? {
+            items.push(item);
+        }
+        let next = if self.has_more() {
+            self.continuation()
+        } else {
+            None
+        };
+        Ok(Page { items, next })
+    }
+
+    /// Returns whether there are more keys in the range,
+    /// regardless of the limit of the scan
+    pub fn has_more(&self) -> bool {
+        self.peek().is_some()
+    }
+
+    /// Returns a token that resumes the scan after the last visited key
+    pub fn continuation(&self) -> Option<ContinuationToken> {
+        self.last.as_ref().map(|key| ContinuationToken {
+            key: key.clone(),
+            reverse: self.scan.reverse,
+        })
+    }
+
+    /// Finds the next key after the last visited one,
+    /// skipping keys that are removed in the transaction
+    fn peek(&self) -> Option<(String, Source<'a>)> {
+        let mut last = self.last.clone();
+        loop {
+            let (start, end) = self.remaining(&last);
+            // `BTreeMap::range` panics on an inverted range
+            if is_empty_range(&start, &end) {
+                return None;
+            }
+            let stored = {
+                let mut keys = self.store.keys((start.clone(), end.clone()));
+                if self.scan.reverse {
+                    keys.next_back()
+                } else {
+                    keys.next()
+                }
+            };
+            let buffered = (root_key(start, false), root_key(end, true));
+            let written = self.writes.and_then(|writes| {
+                let mut writes = writes.range(buffered.clone()).map(|((_, key), _)| key);
+                if self.scan.reverse {
+                    writes.next_back()
+                } else {
+                    writes.next()
+                }
+            });
+            let merged = self.merges.and_then(|merges| {
+                let mut merges = merges.range(buffered).map(|((_, key), _)| key);
+                if self.scan.reverse {
+                    merges.next_back()
+                } else {
+                    merges.next()
+                }
+            });
+
+            let candidates = stored.into_iter().chain(written).chain(merged);
+            let key = if self.scan.reverse {
+                candidates.max()
+            } else {
+                candidates.min()
+            }?
+            .clone();
+
+            // a buffered write hides what is stored under the same key,
+            // and buffered merges apply on top of it
+            let buffered = (None, key);
+            match self.writes.and_then(|writes| writes.get(&buffered)) {
+                Some(Some(value)) => return Some((buffered.1, Source::Buffered(value.clone()))),
+                Some(None) => last = Some(buffered.1),
+                None => {
+                    let source = match self.merges.and_then(|merges| merges.get(&buffered)) {
+                        Some(merges) => Source::Merged(merges),
+                        None => Source::Stored,
+                    };
+                    return Some((buffered.1, source));
+                }
+            }
+        }
+    }
+
+    /// Returns the part of the range that lies after `last`
+    fn remaining(&self, last: &Option<String>) -> (Bound<String>, Bound<String>) {
+        let (start, end) = (self.scan.start.clone(), self.scan.end.clone());
+        match last {
+            Some(last) if self.scan.reverse => {
+                (start, tighter(end, Bound::Excluded(last.clone()), true))
+            }
+            Some(last) => (tighter(start, Bound::Excluded(last.clone()), false), end),
+            None => (start, end),
+        }
+    }
+}
+
+/// Maps a bound on keys to a bound on the buffered writes to the root store
+fn root_key(bound: Bound<String>, upper: bool) -> Bound<(Option<PathBuf>, String)> {
+    match bound {
+        Bound::Included(key) => Bound::Included((None, key)),
+        Bound::Excluded(key) => Bound::Excluded((None, key)),
+        // writes to substores sort after all writes to the root store
+        Bound::Unbounded if upper => Bound::Excluded((Some(PathBuf::new()), String::new())),
+        Bound::Unbounded => Bound::Included((None, String::new())),
+    }
+}
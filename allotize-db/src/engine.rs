@@ -1,34 +1,195 @@
-use std::collections::{BTreeMap, HashMap};
+use std::borrow::Cow;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::ffi::OsStr;
 use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::iter;
 use std::path::{Path, PathBuf};
+use std::sync::{Arc, Weak};
+use std::time::Duration;
+#[cfg(not(target_arch = "wasm32"))]
+use std::time::{SystemTime, UNIX_EPOCH};
 
 use serde::Serialize;
 use serde_json::Deserializer;
-use std::collections::HashSet;
 use std::ops::Range;
 use std::ops::{Bound, Deref, RangeBounds};
 
-use crate::{IdbFile, IdbFolder, KvsError, Result};
+use crate::crypto;
+use crate::cursor::{prefix_end, Cursor, Merges, Page, Scan};
+use crate::feed::{Change, ChangeKind, Feed, Watch, Watcher};
+use crate::merge::{MergeOperator, MergeOperators};
+use crate::record::{self, Frame};
+use crate::{Backend, BackendFile, IdbFolder, KvsError, Result};
+
+#[cfg(not(target_arch = "wasm32"))]
+use crate::FsBackend;
+
+mod batch;
+mod blob;
+mod secondary;
+mod verify;
+
+use self::blob::{blob_path, open_blobs, read_blob_value};
+use self::secondary::SecondaryIndex;
+
+pub use self::batch::WriteBatch;
+pub use self::blob::{BlobReader, BlobWriter};
+pub use self::secondary::Lookup;
+pub use self::verify::{Problem, RepairReport, Report};
 
 const COMPACTION_THRESHOLD: u64 = 128 * 128;
 
-pub struct KvTxn<'a> {
-    /// Holds the request response for a request to `Indexeddb`
-    inner: &'a mut KvStore,
-    modified_stores: HashSet<Option<PathBuf>>, // TODO We can probably be smarter here
+/// Number of commands that a single step of a compaction copies
+const COMPACTION_STEP: usize = 128;
+
+/// Decides when the logs of a `KvStore` are compacted.
+///
+/// The logs of the store and of each substore are compacted separately,
+/// once both the `threshold` and the `ratio` are exceeded.
+/// A compaction copies the live commands in steps, so that writes
+/// are not held up until all of them are copied.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CompactionPolicy {
+    /// Number of stale bytes in the logs before they are compacted
+    pub threshold: u64,
+    /// Minimum ratio of stale bytes to live bytes before the logs are compacted
+    pub ratio: f64,
+    /// Whether writes start compactions and take steps of them. Otherwise,
+    /// compactions only run through `KvStore::compact` or `KvStore::compact_step`.
+    pub automatic: bool,
+}
+
+impl Default for CompactionPolicy {
+    fn default() -> Self {
+        CompactionPolicy {
+            threshold: COMPACTION_THRESHOLD,
+            ratio: 0.0,
+            automatic: true,
+        }
+    }
+}
+
+/// Limits how many bytes the logs of a `KvStore` take up, see `KvStore::set_quota`.
+///
+/// A write that would exceed the quota first compacts the logs. If that is not
+/// enough, keys are evicted until the logs take up three quarters of the quota.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Quota {
+    /// Largest number of bytes in the logs of the store and its substores
+    pub max_bytes: u64,
+    /// Which keys are evicted first
+    pub eviction: Eviction,
+}
+
+/// Which keys a `Quota` evicts first
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum Eviction {
+    /// The keys that were read or written the longest time ago.
+    ///
+    /// When the keys were read is stored by compactions, so after the store is
+    /// reopened, the reads since the last compaction count as if they were not made.
+    Lru,
+    /// The keys that expire the soonest, then the least recently used ones
+    TtlFirst,
+}
+
+/// Share of the quota that the logs take up after keys are evicted
+const EVICTION_TARGET: f64 = 0.75;
+
+/// The space taken up by a store, or by one of its substores, see `KvStore::stats`
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct LogStats {
+    /// Number of keys that have not expired
+    pub keys: u64,
+    /// Number of bytes of the commands that set these keys
+    pub live_bytes: u64,
+    /// Number of bytes that a compaction can get rid of
+    pub stale_bytes: u64,
+    /// Number of bytes in the logs
+    pub log_bytes: u64,
+    /// Number of logs
+    pub generations: u64,
+    /// When the logs were compacted last, in milliseconds since the Unix epoch
+    pub last_compaction: Option<u64>,
+}
+
+/// The space taken up by a store and its substores, see `KvStore::stats`
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Stats {
+    /// The keys that are not scoped to a substore
+    pub store: LogStats,
+    /// Every substore that was added, by its path
+    pub substores: BTreeMap<PathBuf, LogStats>,
+}
+
+impl Stats {
+    /// Adds up the store and its substores
+    pub fn total(&self) -> LogStats {
+        let mut total = self.store.clone();
+        for stats in self.substores.values() {
+            total.keys += stats.keys;
+            total.live_bytes += stats.live_bytes;
+            total.stale_bytes += stats.stale_bytes;
+            total.log_bytes += stats.log_bytes;
+            total.generations += stats.generations;
+            total.last_compaction = total.last_compaction.max(stats.last_compaction);
+        }
+        total
+    }
+}
+
+/// Extension of the hint files written by a compaction
+const HINT_EXTENSION: &str = "hint";
+
+// The framing of a `Command::Batch` in logs written before the binary encoding
+const JSON_BATCH_PREFIX: &[u8] = b"{\"Batch\":[";
+const JSON_BATCH_SEPARATOR: &[u8] = b",";
+
+/// The name of a merge operator, and an operand of it, buffered in a `KvTxn`
+pub(crate) type BufferedMerge = (String, String);
+
+/// A transaction against a `KvStore`.
+///
+/// Writes are buffered in the transaction and are visible to its own reads,
+/// but nothing reaches the log until `commit` is called. Dropping the
+/// transaction without committing discards the buffered writes.
+pub struct KvTxn<'a, B: Backend = IdbFolder> {
+    inner: &'a mut KvStore<B>,
+    // buffered sets and removes
+    batch: WriteBatch,
+    // buffered merges of keys without a buffered write
+    merges: Merges,
+    // ranges of the root store that are removed before the buffered writes are applied
+    ranges: Vec<(Bound<String>, Bound<String>)>,
 }
 
-impl<'a> KvTxn<'a> {
-    pub fn new(inner: &'a mut KvStore) -> Self {
+impl<'a, B: Backend> KvTxn<'a, B> {
+    pub fn new(inner: &'a mut KvStore<B>) -> Self {
         KvTxn {
             inner,
-            modified_stores: HashSet::new(),
+            batch: WriteBatch::new(),
+            merges: BTreeMap::new(),
+            ranges: Vec::new(),
         }
     }
 
     pub async fn set<T: ?Sized + Serialize>(&mut self, key: String, value: &T) -> Result<()> {
-        self.modified_stores.insert(None);
-        self.inner.set(key, serde_json::to_string(value)?).await
+        self.set_raw(None, key, serde_json::to_string(value)?, None);
+        Ok(())
+    }
+
+    /// Sets a value that expires once `ttl` has passed since the call.
+    ///
+    /// An expired key reads as if it was removed, see `KvStore::sweep_expired`.
+    pub async fn set_expiring<T: ?Sized + Serialize>(
+        &mut self,
+        key: String,
+        value: &T,
+        ttl: Duration,
+    ) -> Result<()> {
+        let expires_at = now().saturating_add(ttl.as_millis() as u64);
+        self.set_raw(None, key, serde_json::to_string(value)?, Some(expires_at));
+        Ok(())
     }
 
     pub async fn set_scoped(
@@ -37,12 +198,71 @@ impl<'a> KvTxn<'a> {
         value: String,
         substore: Option<&Path>,
     ) -> Result<()> {
-        substore.map(|path| self.modified_stores.insert(Some(path.to_path_buf())));
-        self.inner.set_scoped(key, value, substore).await
+        self.set_raw(substore, key, value, None);
+        Ok(())
+    }
+
+    /// Buffers an encoded value, which expires at the given time
+    /// in milliseconds since the Unix epoch, if any.
+    pub(crate) fn set_raw(
+        &mut self,
+        substore: Option<&Path>,
+        key: String,
+        value: String,
+        expires_at: Option<u64>,
+    ) {
+        self.merges
+            .remove(&(substore.map(Path::to_path_buf), key.clone()));
+        self.batch.set_raw(substore, key, value, expires_at);
+    }
+
+    /// Merges an operand into the value of `key`, with the merge operator
+    /// that applies to the key, see `KvStore::register_merge_operator`.
+    ///
+    /// Only the operand is written to the log, and it is folded into the value
+    /// whenever the key is read, until a compaction folds it for good.
+    /// A merge keeps the time to live of the value it applies to.
+    /// Buffered merges are seen by `get`, `get_range` and cursors.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::NoMergeOperator` if no operator applies to the key,
+    /// and `KvsError::MergeFailed`, without buffering anything, if the operand
+    /// can not be folded into the value the key holds.
+    pub async fn merge<T: ?Sized + Serialize>(&mut self, key: String, operand: &T) -> Result<()> {
+        self.merge_raw(None, key, serde_json::to_string(operand)?)
+            .await
+    }
+
+    /// Buffers an encoded operand, see `merge`
+    pub(crate) async fn merge_raw(
+        &mut self,
+        substore: Option<&Path>,
+        key: String,
+        operand: String,
+    ) -> Result<()> {
+        let operator = Arc::clone(self.inner.merge_operators.for_key(&key)?);
+        let buffered = (substore.map(Path::to_path_buf), key);
+        match self.batch.writes.get_mut(&buffered) {
+            // the value is written anyway, so the operand is folded right away
+            Some(value) => {
+                *value = Some(operator.merge(&buffered.1, value.as_deref(), &operand)?)
+            }
+            None => {
+                // an operand that can not be folded would fail every read of the key
+                let value = self.get_scoped(buffered.1.clone(), substore).await?;
+                operator.merge(&buffered.1, value.as_deref(), &operand)?;
+                self.merges
+                    .entry(buffered)
+                    .or_default()
+                    .push((operator.name().to_owned(), operand));
+            }
+        }
+        Ok(())
     }
 
     pub async fn get(&mut self, key: String) -> Result<Option<String>> {
-        self.inner.get(key).await
+        self.get_scoped(key, None).await
     }
 
     pub async fn get_range(
@@ -50,7 +270,55 @@ impl<'a> KvTxn<'a> {
         start: Bound<String>,
         end: Bound<String>,
     ) -> Result<Vec<(String, String)>> {
-        self.inner.get_range((start, end)).await
+        let range = (start, end);
+        let mut items: BTreeMap<_, _> = self
+            .inner
+            .get_range(range.clone())
+            .await?
+            .into_iter()
+            .collect();
+
+        for ((substore, key), value) in &self.batch.writes {
+            if substore.is_some() || !range.contains(key) {
+                continue;
+            }
+            match value {
+                Some(value) => items.insert(key.clone(), value.clone()),
+                None => items.remove(key),
+            };
+        }
+
+        let merged: Vec<String> = self
+            .merges
+            .keys()
+            .filter(|(substore, key)| substore.is_none() && range.contains(key))
+            .map(|(_, key)| key.clone())
+            .collect();
+        for key in merged {
+            match self.get(key.clone()).await? {
+                Some(value) => items.insert(key, value),
+                None => items.remove(&key),
+            };
+        }
+
+        Ok(items.into_iter().collect())
+    }
+
+    /// Returns a cursor over the keys of the root store, see `Cursor`.
+    ///
+    /// The cursor sees the writes buffered in this transaction.
+    pub fn cursor(&mut self, scan: Scan) -> Cursor<'_, B> {
+        Cursor::new(
+            self.inner,
+            Some(&self.batch.writes),
+            Some(&self.merges),
+            scan,
+        )
+    }
+
+    /// Returns the first page of `scan`, see `Cursor::page`
+    pub async fn scan(&mut self, scan: Scan) -> Result<Page> {
+        self.cursor(scan).page().await
     }
 
     pub async fn get_scoped(
@@ -58,46 +326,231 @@ impl<'a> KvTxn<'a> {
         key: String,
         substore: Option<&Path>,
     ) -> Result<Option<String>> {
-        self.inner.get_scoped(key, substore).await
+        let buffered = (substore.map(Path::to_path_buf), key);
+        let value = match self.batch.writes.get(&buffered) {
+            Some(value) => value.clone(),
+            None => self.inner.get_scoped(buffered.1.clone(), substore).await?,
+        };
+        match self.merges.get(&buffered) {
+            Some(merges) => self.inner.fold_merges(&buffered.1, value, merges),
+            None => Ok(value),
+        }
     }
 
+    /// Removes a given key.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::KeyNotFound` if the given key is not found.
     pub async fn remove(&mut self, key: String) -> Result<()> {
-        self.inner.remove(key).await
+        let buffered = (None, key);
+        let exists = match self.batch.writes.get(&buffered) {
+            Some(value) => value.is_some(),
+            None => {
+                self.merges.contains_key(&buffered) || self.inner.root.contains_key(&buffered.1)
+            }
+        };
+
+        if exists {
+            self.merges.remove(&buffered);
+            self.batch.remove(buffered.1);
+            Ok(())
+        } else {
+            Err(KvsError::KeyNotFound)
+        }
     }
-}
 
-impl<'a> std::ops::Drop for KvTxn<'a> {
-    fn drop(&mut self) {
-        for path in &self.modified_stores {
-            self.inner.flush(path.as_ref());
+    /// Removes every key of the root store between `start` and `end`.
+    ///
+    /// The removal is written as a single record, however many keys it covers,
+    /// and applies before the other writes of the transaction, so keys that are
+    /// set or merged into after the call are kept. Unlike `remove`, it is not
+    /// an error if no key is removed.
+    pub fn remove_range(&mut self, start: Bound<String>, end: Bound<String>) {
+        if is_empty_range(&start, &end) {
+            return;
+        }
+        let range = (start, end);
+
+        // the keys read as removed until the transaction is committed
+        let buffered = self.batch.writes.keys().chain(self.merges.keys());
+        let removed: Vec<String> = self
+            .inner
+            .root
+            .index
+            .range(range.clone())
+            .map(|(key, _)| key)
+            .chain(
+                buffered
+                    .filter(|(substore, key)| substore.is_none() && range.contains(key))
+                    .map(|(_, key)| key),
+            )
+            .cloned()
+            .collect();
+        for key in removed {
+            self.merges.remove(&(None, key.clone()));
+            self.batch.remove(key);
         }
+        self.ranges.push(range);
     }
+
+    /// Removes every key of the root store that begins with `prefix`, see `remove_range`
+    pub fn remove_prefix(&mut self, prefix: impl Into<String>) {
+        let prefix = prefix.into();
+        let end = prefix_end(&prefix);
+        self.remove_range(Bound::Included(prefix), end);
+    }
+
+    /// Sets `key` to `new` if it holds `expected`.
+    ///
+    /// The values are compared as json, and the buffered writes of the transaction
+    /// count. The store can not be written to while the transaction is open,
+    /// so the comparison still holds when the transaction is committed.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::PreconditionFailed`, without buffering anything,
+    /// if the key holds another value or is absent.
+    pub async fn compare_and_swap<T, U>(&mut self, key: String, expected: &T, new: &U) -> Result<()>
+    where
+        T: ?Sized + Serialize,
+        U: ?Sized + Serialize,
+    {
+        self.expect(&key, Some(serde_json::to_value(expected)?))
+            .await?;
+        self.set(key, new).await
+    }
+
+    /// Sets `key` to `value` if it is absent, see `compare_and_swap`.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::PreconditionFailed`, without buffering anything,
+    /// if the key holds a value.
+    pub async fn set_if_absent<T: ?Sized + Serialize>(
+        &mut self,
+        key: String,
+        value: &T,
+    ) -> Result<()> {
+        self.expect(&key, None).await?;
+        self.set(key, value).await
+    }
+
+    /// Removes `key` if it holds `expected`, see `compare_and_swap`.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::PreconditionFailed`, without buffering anything,
+    /// if the key holds another value or is absent.
+    pub async fn remove_if_equals<T: ?Sized + Serialize>(
+        &mut self,
+        key: String,
+        expected: &T,
+    ) -> Result<()> {
+        self.expect(&key, Some(serde_json::to_value(expected)?))
+            .await?;
+        self.remove(key).await
+    }
+
+    /// Checks that `key` holds the expected value, or is absent if `None` is expected
+    async fn expect(&mut self, key: &str, expected: Option<serde_json::Value>) -> Result<()> {
+        let actual = self.get(key.to_owned()).await?;
+        let holds = match (&actual, &expected) {
+            (Some(actual), Some(expected)) => {
+                // values that are not json, see `set_scoped`, are compared as strings
+                match serde_json::from_str::<serde_json::Value>(actual) {
+                    Ok(value) => &value == expected,
+                    Err(_) => expected.as_str() == Some(actual.as_str()),
+                }
+            }
+            (None, None) => true,
+            _ => false,
+        };
+        if holds {
+            Ok(())
+        } else {
+            Err(KvsError::PreconditionFailed {
+                key: key.to_owned(),
+                actual,
+            })
+        }
+    }
+
+    /// Writes all buffered changes to the log, and resolves once they are
+    /// stored durably by the backend.
+    ///
+    /// The writes are appended as a single batch record, so they are either
+    /// replayed together or not at all. A transaction writes to the store
+    /// or to a single substore, since each has logs of its own.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O or serialization errors during writing the log,
+    /// and errors of the backend, such as `KvsError::QuotaExceeded`,
+    /// in which case nothing is written.
+    /// It returns `KvsError::UnknownSubstore`, without writing anything,
+    /// if a write is scoped to a substore that was not added,
+    /// and `KvsError::CrossSubstoreCommit` if the writes span substores.
+    pub async fn commit(self) -> Result<()> {
+        let KvTxn {
+            inner,
+            batch,
+            merges,
+            ranges,
+        } = self;
+
+        // the keys removed by a range are not removed one by one
+        let mut batches = batch.into_commands(|substore, key| {
+            substore.is_some() || !ranges.iter().any(|range| range.contains(key))
+        });
+        if !ranges.is_empty() {
+            let removals = ranges
+                .into_iter()
+                .map(|(start, end)| Command::remove_range(start, end));
+            batches.entry(None).or_default().splice(0..0, removals);
+        }
+        for ((substore, key), merges) in merges {
+            let cmds = batches.entry(substore).or_default();
+            for (operator, operand) in merges {
+                cmds.push(Command::merge(key.clone(), operator, operand));
+            }
+        }
+
+        inner.write_batches(batches).await
+    }
+
+    /// Discards all buffered changes.
+    ///
+    /// This is the same as dropping the transaction without committing it.
+    pub fn rollback(self) {}
 }
 
 /// The `KvStore` stores string key/value pairs.
 ///
-/// Key/value pairs are persisted to `indexeddb` in log files. Log files are named after
-/// monotonically increasing generation numbers with a `log` extension name.
-/// A `BTreeMap` in memory stores the keys and the value locations for fast query.
-pub struct KvStore {
-    // sink for the data to be stored, here we use `IndexedDB`
-    sink: IdbFolder,
-    // directory for the log and other data
-    path: PathBuf,
-    // map generation number to the file reader
-    readers: HashMap<PathBuf, BufReaderWithPos<IdbFile>>,
-    // writer of the current log
-    writers: HashMap<PathBuf, BufWriterWithPos<IdbFile>>,
-
-    current_gen: u64,
-    index: BTreeMap<String, CommandPos>,
-    // the number of bytes representing "stale" commands that could be
-    // deleted during a compaction
-    uncompacted: u64,
+/// Key/value pairs are persisted to a `Backend` in log files, by default `indexeddb`.
+/// Log files are named after monotonically increasing generation numbers with a `log`
+/// extension name. A `BTreeMap` in memory stores the keys and the value locations for fast query.
+///
+/// Substores keep their own logs in a directory below the store, see `add_substore`.
+/// Their keys are independent of the keys in the store, and of other substores.
+pub struct KvStore<B: Backend = IdbFolder> {
+    // sink for the data to be stored, by default `IndexedDB`
+    sink: B,
+    // logs and index of the keys that are not scoped to a substore
+    root: Logs<B>,
+    // logs and index of every substore, keyed by the path it was added with
+    substores: BTreeMap<PathBuf, Logs<B>>,
+    compaction_policy: CompactionPolicy,
+    quota: Option<Quota>,
+    merge_operators: MergeOperators,
+    // the json pointer of every secondary index, by its name
+    indexes: BTreeMap<String, String>,
+    // values of at least this many bytes are stored in blobs
+    blob_threshold: Option<u64>,
 }
 
 impl KvStore {
-    /// Opens a `KvStore` with the given path.
+    /// Opens a `KvStore` with the given path, stored in `IndexedDB`.
     ///
     /// This will create a new directory if the given one does not exist.
     ///
@@ -106,11 +559,35 @@ impl KvStore {
     /// It propagates I/O or deserialization errors during the log replay.
     pub async fn open(path: impl Into<PathBuf>) -> Result<KvStore> {
         let path = path.into();
-        let mut sink = IdbFolder::open(&path).await.expect("Could not create sink");
+        let sink = IdbFolder::open(&path).await?;
+        KvStore::open_with_backend(sink, path).await
+    }
+}
 
-        let mut readers = HashMap::new();
-        let mut index = BTreeMap::new();
+#[cfg(not(target_arch = "wasm32"))]
+impl KvStore<FsBackend> {
+    /// Opens a `KvStore` in the given directory on the local filesystem.
+    ///
+    /// Logs written by an earlier process are replayed, so this is the
+    /// native counterpart of `KvStore::open`.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O or deserialization errors during the log replay.
+    pub async fn open_dir(path: impl Into<PathBuf>) -> Result<KvStore<FsBackend>> {
+        let path = path.into();
+        let sink = FsBackend::new(&path)?;
+        KvStore::open_with_backend(sink, path).await
+    }
+}
 
+impl<B: Backend> KvStore<B> {
+    /// Opens a `KvStore` with the given path, stored in the given `Backend`.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O or deserialization errors during the log replay.
+    pub async fn open_with_backend(mut sink: B, path: impl Into<PathBuf>) -> Result<KvStore<B>> {
         // let remote_gen_list = {
         //     let window = web_sys::window().unwrap();
         //     let mut request = web_sys::RequestInit::new();
@@ -125,345 +602,1230 @@ impl KvStore {
         //     let response = web_sys::Response::from(response);
         // };
 
-        let gen_list = sorted_gen_list(&path).await?;
-        let mut uncompacted = 0;
-
-        for &gen in &gen_list {
-            let log_path = log_path(&path, gen);
-            let file = sink.open_file(&log_path).await?;
-            let mut reader = BufReaderWithPos::new(file)?;
-            uncompacted += load(gen, &mut reader, &mut index)?;
-            readers.insert(log_path, reader);
-        }
-
-        let current_gen = gen_list.last().unwrap_or(&0) + 1;
-        let writer = new_log_file(&path, &mut sink, current_gen, &mut readers).await?;
-        let mut writers = HashMap::new();
-        writers.insert(path.clone(), writer);
+        let merge_operators = MergeOperators::default();
+        let root = Logs::open(path.into(), &mut sink, merge_operators.clone()).await?;
 
         Ok(KvStore {
             sink,
-            path,
-            readers,
-            writers,
-            current_gen,
-            index,
-            uncompacted,
+            root,
+            substores: BTreeMap::new(),
+            compaction_policy: CompactionPolicy::default(),
+            quota: None,
+            merge_operators,
+            indexes: BTreeMap::new(),
+            blob_threshold: None,
         })
     }
 
-    fn flush(&mut self, subpath: Option<&PathBuf>) {
-        let mut path = PathBuf::new();
-        path.push(&self.path);
-        if let Some(p) = subpath {
-            path.push(&p);
-        }
+    /// Returns the path the store was opened with
+    pub fn path(&self) -> &Path {
+        &self.root.path
+    }
 
-        let writer = self.writers.get_mut(&path).expect("Could not get writer");
-        writer.flush().expect("Could not flush");
+    /// Returns the backend that the logs are stored in
+    pub(crate) fn backend_mut(&mut self) -> &mut B {
+        &mut self.sink
     }
 
-    /// Returns a txn, that when dropped will flush all the transactions to idb
-    pub fn txn(&mut self) -> KvTxn {
+    /// Returns a new transaction, see `KvTxn`
+    pub fn txn(&mut self) -> KvTxn<'_, B> {
         KvTxn::new(self)
     }
 
     /// Adds a file to the KV-store, this makes it easy to scope
     /// different components to a separate file
+    ///
+    /// The logs of the substore are replayed into an index of its own,
+    /// and the secondary indexes are built for it.
+    /// Adding a substore that was already added does nothing.
     pub async fn add_substore(&mut self, sub_path: &Path) -> Result<()> {
-        let mut path = PathBuf::new();
-        path.push(&self.path);
-        path.push(&sub_path);
+        if self.substores.contains_key(sub_path) {
+            return Ok(());
+        }
 
-        let gen_list = sorted_gen_list(&path).await?;
+        let logs = Logs::open(
+            self.root.path.join(sub_path),
+            &mut self.sink,
+            self.merge_operators.clone(),
+        )
+        .await?;
+        self.substores.insert(sub_path.to_path_buf(), logs);
 
-        for &gen in &gen_list {
-            let log_path = log_path(&path, gen);
-            let mut reader = BufReaderWithPos::new(self.sink.open_file(&log_path).await?)?;
-            self.uncompacted += load(gen, &mut reader, &mut self.index)?;
-            self.readers.insert(log_path, reader);
-        }
+        self.build_indexes(sub_path).await
+    }
 
-        // TODO this should be + 1
-        // let current_gen = gen_list.last().unwrap_or(&0) + 0;
-        let current_gen = gen_list.last().unwrap_or(&0) + 1;
-        let writer = new_log_file(&path, &mut self.sink, current_gen, &mut self.readers).await?;
-        self.writers.insert(path.clone(), writer);
+    /// Returns the paths of the substores that were added, in order
+    pub fn substores(&self) -> impl Iterator<Item = &Path> {
+        self.substores.keys().map(PathBuf::as_path)
+    }
 
-        Ok(())
+    /// Removes a substore together with all of its logs.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::UnknownSubstore` if the substore was not added.
+    pub async fn drop_substore(&mut self, sub_path: &Path) -> Result<()> {
+        let logs = self
+            .substores
+            .remove(sub_path)
+            .ok_or_else(|| unknown_substore(sub_path))?;
+        logs.remove(&mut self.sink).await
     }
 
-    /// Sets the value of a string key to a string.
+    /// Removes every key of a substore, which stays added.
     ///
-    /// If the key already exists, the previous value will be overwritten.
+    /// # Errors
+    ///
+    /// It returns `KvsError::UnknownSubstore` if the substore was not added.
+    pub async fn clear_substore(&mut self, sub_path: &Path) -> Result<()> {
+        self.drop_substore(sub_path).await?;
+        self.add_substore(sub_path).await
+    }
+
+    /// Returns the logs of the given substore, or of the root store for `None`,
+    /// along with the sink that they are stored in.
+    fn logs_mut(&mut self, substore: Option<&Path>) -> Result<(&mut B, &mut Logs<B>)> {
+        let logs = match substore {
+            None => &mut self.root,
+            Some(sub_path) => self
+                .substores
+                .get_mut(sub_path)
+                .ok_or_else(|| unknown_substore(sub_path))?,
+        };
+        Ok((&mut self.sink, logs))
+    }
+
+    /// Appends the given commands to the log of a substore and updates its index.
+    ///
+    /// The commands are wrapped in one `Command::Sequenced` record, so that
+    /// the log replay applies all or none of them, and are sent to the watchers.
+    /// Values above the blob threshold are stored in blobs first.
     ///
     /// # Errors
     ///
     /// It propagates I/O or serialization errors during writing the log.
-    async fn set(&mut self, key: String, value: String) -> Result<()> {
-        let cmd = Command::set(key, value);
-        let mut writer = self
-            .writers
-            .get_mut(&self.path)
-            .expect("Could not get writer");
-        let pos = writer.pos;
-        serde_json::to_writer(&mut writer, &cmd)?;
+    async fn write_batch(&mut self, substore: Option<&Path>, cmds: Vec<Command>) -> Result<()> {
+        if let Some(quota) = self.quota.clone() {
+            let incoming = bincode::serialized_size(&cmds)? + record::HEADER_LEN;
+            self.enforce_quota(&quota, incoming).await?;
+        }
+        let policy = self.compaction_policy.clone();
+        let threshold = self.blob_threshold;
+        let (sink, logs) = self.logs_mut(substore)?;
+        let cmds = match threshold {
+            Some(threshold) => logs.store_blobs(sink, cmds, threshold).await?,
+            None => cmds,
+        };
+        logs.write_batch(sink, cmds, &policy).await
+    }
 
-        if let Command::Set { key, .. } = cmd {
-            if let Some(old_cmd) = self
-                .index
-                .insert(key, (self.current_gen, pos..writer.pos).into())
-            {
-                self.uncompacted += old_cmd.len;
-            }
+    /// Subscribes to the changes of a key, a prefix or a range, see `Watch`.
+    ///
+    /// Every write is numbered, and is sent to the watchers once it is stored.
+    /// A watcher that resumes from a sequence number first receives the
+    /// changes after it, which are replayed from the logs after a restart.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::ChangesUnavailable` if the changes to resume from
+    /// were compacted away, or were more than the store keeps in memory, and
+    /// `KvsError::UnknownSubstore` if the watch is scoped to a substore that was not added.
+    pub fn watch(&mut self, watch: Watch) -> Result<Watcher> {
+        let (_, logs) = self.logs_mut(watch.substore())?;
+        logs.feed.watch(watch)
+    }
+
+    /// Returns a read-only view of the store and its substores, see `Snapshot`.
+    ///
+    /// The logs that the snapshot reads from are kept by compactions until
+    /// the snapshot is dropped. While it lives, the first write to the store,
+    /// or to a substore, after a snapshot copies its index.
+    pub fn snapshot(&mut self) -> Snapshot {
+        Snapshot {
+            at: now(),
+            root: self.root.snapshot(),
+            substores: self
+                .substores
+                .iter_mut()
+                .map(|(sub_path, logs)| (sub_path.clone(), logs.snapshot()))
+                .collect(),
         }
+    }
+
+    /// Returns the policy that decides when logs are compacted
+    pub fn compaction_policy(&self) -> &CompactionPolicy {
+        &self.compaction_policy
+    }
 
-        if self.uncompacted > COMPACTION_THRESHOLD {
-            self.compact().await?;
+    /// Replaces the policy that decides when logs are compacted
+    pub fn set_compaction_policy(&mut self, policy: CompactionPolicy) {
+        self.compaction_policy = policy;
+    }
+
+    /// Applies a merge operator to the keys that begin with `prefix`, in the store
+    /// and in its substores, see `KvTxn::merge`.
+    ///
+    /// If several operators apply to a key, the one with the longest prefix is used.
+    /// Operators other than the built-in ones have to be registered again
+    /// whenever the store is opened, before the keys they merged into are read.
+    pub fn register_merge_operator(
+        &mut self,
+        prefix: impl Into<String>,
+        operator: impl MergeOperator + 'static,
+    ) {
+        self.merge_operators
+            .register(prefix.into(), Arc::new(operator));
+        for logs in iter::once(&mut self.root).chain(self.substores.values_mut()) {
+            logs.merge_operators = self.merge_operators.clone();
         }
+    }
 
-        Ok(())
+    /// Returns the quota of the store, `None` if it may grow without limit
+    pub fn quota(&self) -> Option<&Quota> {
+        self.quota.as_ref()
+    }
+
+    /// Limits how many bytes the logs of the store and its substores take up,
+    /// see `Quota`. It is enforced from the next write on.
+    pub fn set_quota(&mut self, quota: Option<Quota>) {
+        self.quota = quota;
     }
 
-    /// Sets the value of a string key to a string.
+    /// Returns how much space the store and each of its substores take up.
+    ///
+    /// # Errors
     ///
-    /// If the key already exists, the previous value will be overwritten.
+    /// It propagates I/O errors during measuring the logs.
+    pub fn stats(&mut self) -> Result<Stats> {
+        Ok(Stats {
+            store: self.root.stats()?,
+            substores: self
+                .substores
+                .iter_mut()
+                .map(|(sub_path, logs)| Ok((sub_path.clone(), logs.stats()?)))
+                .collect::<Result<_>>()?,
+        })
+    }
+
+    /// Makes room for a write of `incoming` bytes, by compacting the logs
+    /// and then evicting keys.
     ///
-    /// # Errorsset_scoped
+    /// # Errors
     ///
-    /// It propagates I/O or serialization errors during writing the log.
-    async fn set_scoped(
-        &mut self,
-        key: String,
-        value: String,
-        substore: Option<&Path>,
-    ) -> Result<()> {
-        let cmd = Command::set(key, value);
-        let mut path = PathBuf::new();
-        path.push(&self.path);
-        if let Some(p) = substore {
-            path.push(&p);
+    /// It returns `KvsError::QuotaExceeded` if there is still no room,
+    /// e.g. because snapshots keep compacted logs.
+    async fn enforce_quota(&mut self, quota: &Quota, incoming: u64) -> Result<()> {
+        if self.log_bytes()? + incoming <= quota.max_bytes {
+            return Ok(());
+        }
+        // the stale commands may be enough to make room
+        self.compact().await?;
+        if self.log_bytes()? + incoming <= quota.max_bytes {
+            return Ok(());
         }
 
-        let mut writer = self.writers.get_mut(&path).expect("Could not get writer");
-        let pos = writer.pos;
-        serde_json::to_writer(&mut writer, &cmd)?;
+        let mut candidates: Vec<_> = iter::once((None, &self.root))
+            .chain(
+                self.substores
+                    .iter()
+                    .map(|(sub_path, logs)| (Some(sub_path), logs)),
+            )
+            .flat_map(|(substore, logs)| {
+                logs.eviction_candidates(quota.eviction)
+                    .map(move |(rank, key, bytes)| (rank, substore.cloned(), key, bytes))
+            })
+            .collect();
+        candidates.sort();
 
-        if let Command::Set { key, .. } = cmd {
-            if let Some(old_cmd) = self
-                .index
-                .insert(key, (self.current_gen, pos..writer.pos).into())
-            {
-                self.uncompacted += old_cmd.len;
+        let target = (quota.max_bytes as f64 * EVICTION_TARGET) as u64;
+        let mut live: u64 = candidates.iter().map(|(.., bytes)| bytes).sum();
+        let mut evicted: BTreeMap<Option<PathBuf>, Vec<Command>> = BTreeMap::new();
+        for (_, substore, key, bytes) in candidates {
+            if live + incoming <= target {
+                break;
             }
+            live -= bytes;
+            evicted
+                .entry(substore)
+                .or_default()
+                .push(Command::remove(key));
         }
 
-        if self.uncompacted > COMPACTION_THRESHOLD {
-            self.compact().await?;
+        let policy = self.compaction_policy.clone();
+        for (substore, cmds) in evicted {
+            info!(
+                "Evicting keys",
+                format!("{} from {:?}", cmds.len(), substore)
+            );
+            let (sink, logs) = self.logs_mut(substore.as_deref())?;
+            logs.write_batch(sink, cmds, &policy).await?;
+        }
+        self.compact().await?;
+
+        if self.log_bytes()? + incoming <= quota.max_bytes {
+            Ok(())
+        } else {
+            Err(KvsError::QuotaExceeded)
+        }
+    }
+
+    /// Returns the number of bytes in the logs of the store and all substores
+    fn log_bytes(&mut self) -> Result<u64> {
+        let mut log_bytes = 0;
+        for logs in iter::once(&mut self.root).chain(self.substores.values_mut()) {
+            log_bytes += logs.log_bytes()?;
+        }
+        Ok(log_bytes)
+    }
+
+    /// Compacts the logs of the store and of every substore,
+    /// regardless of the compaction policy.
+    ///
+    /// Compactions in progress are finished.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O errors during copying the commands, and returns
+    /// `KvsError::MergeFailed` if the merges of a key can not be folded,
+    /// which leaves the logs as they were.
+    pub async fn compact(&mut self) -> Result<()> {
+        let sink = &mut self.sink;
+        for logs in iter::once(&mut self.root).chain(self.substores.values_mut()) {
+            logs.compact(sink).await?;
         }
+        Ok(())
+    }
 
+    /// Finishes the compactions in progress of the store and every substore,
+    /// without starting new ones.
+    pub(crate) async fn finish_compactions(&mut self) -> Result<()> {
+        let sink = &mut self.sink;
+        for logs in iter::once(&mut self.root).chain(self.substores.values_mut()) {
+            while logs.compact_step(sink, COMPACTION_STEP).await? {}
+        }
         Ok(())
     }
 
+    /// Takes a single step of compaction for the store and every substore,
+    /// starting compactions where the compaction policy asks for one.
+    ///
+    /// Returns whether a compaction is still in progress, so that the caller can
+    /// schedule the next step, for example on a later turn of the event loop.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O errors during copying the commands, and returns
+    /// `KvsError::MergeFailed` if the merges of a key can not be folded, see `compact`.
+    pub async fn compact_step(&mut self) -> Result<bool> {
+        let sink = &mut self.sink;
+        let mut in_progress = false;
+        for logs in iter::once(&mut self.root).chain(self.substores.values_mut()) {
+            if logs.needs_compaction(&self.compaction_policy) {
+                logs.start_compaction(sink).await?;
+            }
+            in_progress |= logs.compact_step(sink, COMPACTION_STEP).await?;
+        }
+        Ok(in_progress)
+    }
+
+    /// Returns a cursor over the keys of the root store, see `Cursor`
+    pub fn cursor(&mut self, scan: Scan) -> Cursor<'_, B> {
+        Cursor::new(self, None, None, scan)
+    }
+
+    /// Returns the first page of `scan`, see `Cursor::page`
+    pub async fn scan(&mut self, scan: Scan) -> Result<Page> {
+        self.cursor(scan).page().await
+    }
+
+    /// Returns the keys within `range`, in order
+    pub(crate) fn keys<R: RangeBounds<String>>(
+        &self,
+        range: R,
+    ) -> impl DoubleEndedIterator<Item = &String> {
+        let now = now();
+        self.root
+            .index
+            .range(range)
+            .filter(move |(_, cmd_pos)| !cmd_pos.is_expired(now))
+            .map(|(key, _)| key)
+    }
+
     /// Gets the string value of a given string key.
     ///
     /// Returns `None` if the given key does not exist.
-    async fn get(&mut self, key: String) -> Result<Option<String>> {
-        if let Some(cmd_pos) = self.index.get(&key) {
-            let log_path = log_path(&self.path, cmd_pos.gen);
-
-            let reader = self
-                .readers
-                .get_mut(&log_path)
-                .expect("Cannot find log reader in get");
-            reader.seek(SeekFrom::Start(cmd_pos.pos))?;
-            let cmd_reader = reader.take(cmd_pos.len);
-
-            if let Command::Set { value, .. } = serde_json::from_reader(cmd_reader)? {
-                Ok(Some(value))
-            } else {
-                Err(KvsError::UnexpectedCommandType)
-            }
-        } else {
-            Ok(None)
+    pub(crate) async fn get(&mut self, key: String) -> Result<Option<String>> {
+        self.root.get(&mut self.sink, &key).await
+    }
+
+    /// Folds the merges buffered in a `KvTxn` into the value of `key`, in order
+    pub(crate) fn fold_merges(
+        &self,
+        key: &str,
+        mut value: Option<String>,
+        merges: &[BufferedMerge],
+    ) -> Result<Option<String>> {
+        for (operator, operand) in merges {
+            value = Some(
+                self.merge_operators
+                    .merge(operator, key, value.as_deref(), operand)?,
+            );
         }
+        Ok(value)
     }
 
     /// Gets all values from the store
     pub async fn get_all(&mut self) -> Result<Vec<(String, String)>> {
-        let mut items = Vec::new();
-        for (key, cmd_pos) in &self.index {
-            let log_path = log_path(&self.path, cmd_pos.gen);
+        self.get_range(..).await
+    }
+
+    /// Gets all values from the store with a given prefix
+    pub async fn get_range<R: RangeBounds<String>>(
+        &mut self,
+        range: R,
+    ) -> Result<Vec<(String, String)>> {
+        let keys: Vec<_> = self.keys(range).cloned().collect();
+        let mut items = Vec::with_capacity(keys.len());
+        for key in keys {
+            // the key may have expired since it was listed
+            if let Some(value) = self.root.get(&mut self.sink, &key).await? {
+                items.push((key, value));
+            }
+        }
+        Ok(items)
+    }
 
-            let reader = self
-                .readers
-                .get_mut(&log_path)
-                .expect("Cannot find log reader in get");
-            reader.seek(SeekFrom::Start(cmd_pos.pos))?;
-            let cmd_reader = reader.take(cmd_pos.len);
+    /// Gets the string value of a given string key in a substore.
+    ///
+    /// Returns `None` if the given key does not exist.
+    async fn get_scoped(&mut self, key: String, substore: Option<&Path>) -> Result<Option<String>> {
+        let (sink, logs) = self.logs_mut(substore)?;
+        logs.get(sink, &key).await
+    }
+
+    /// Removes the keys of the store, or of a substore, whose time to live
+    /// has passed, and returns them.
+    ///
+    /// Expired keys are never read, this only makes their removal visible,
+    /// for example to notify subscribers about it.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O errors during writing the removals to the log, and
+    /// returns `KvsError::UnknownSubstore` if the substore was not added.
+    pub async fn sweep_expired(&mut self, substore: Option<&Path>) -> Result<Vec<String>> {
+        let policy = self.compaction_policy.clone();
+        let (sink, logs) = self.logs_mut(substore)?;
+        logs.sweep_expired(sink, &policy).await
+    }
+}
+
+/// A read-only view of a `KvStore` and its substores,
+/// pinned to the moment it was taken, see `KvStore::snapshot`.
+///
+/// Later writes and compactions of the store do not show in the snapshot.
+/// Values are read from the logs through the store that the snapshot was
+/// taken of, which is only borrowed for each read, so that a long scan does
+/// not have to hold on to the store throughout.
+pub struct Snapshot {
+    // the time the snapshot was taken at, which expiry is checked against
+    at: u64,
+    root: Arc<View>,
+    substores: BTreeMap<PathBuf, Arc<View>>,
+}
+
+/// An index pinned by a snapshot
+struct View {
+    index: Arc<BTreeMap<String, CommandPos>>,
+    operands: Arc<Operands>,
+    // generations of the logs that the index points into
+    gens: BTreeSet<u64>,
+}
 
-            if let Command::Set { value, .. } = serde_json::from_reader(cmd_reader)? {
+impl Snapshot {
+    /// Returns the keys of the store within `range`, in order
+    pub fn keys<R: RangeBounds<String>>(
+        &self,
+        range: R,
+    ) -> impl DoubleEndedIterator<Item = &String> {
+        let at = self.at;
+        self.root
+            .index
+            .range(range)
+            .filter(move |(_, cmd_pos)| !cmd_pos.is_expired(at))
+            .map(|(key, _)| key)
+    }
+
+    /// Returns the keys of the store, or of a substore, that had not expired
+    /// when the snapshot was taken, along with when they expire
+    pub(crate) fn entries(
+        &self,
+        substore: Option<&Path>,
+    ) -> Result<impl Iterator<Item = (&String, Option<u64>)>> {
+        let view = self.view(substore)?;
+        let at = self.at;
+        Ok(view
+            .index
+            .iter()
+            .filter(move |(_, cmd_pos)| !cmd_pos.is_expired(at))
+            .map(|(key, cmd_pos)| (key, cmd_pos.expires_at)))
+    }
+
+    /// Returns the pinned index of the store, or of a substore
+    fn view(&self, substore: Option<&Path>) -> Result<&View> {
+        match substore {
+            None => Ok(&self.root),
+            Some(sub_path) => self
+                .substores
+                .get(sub_path)
+                .map(Arc::as_ref)
+                .ok_or_else(|| unknown_substore(sub_path)),
+        }
+    }
+
+    /// Returns the paths of the substores when the snapshot was taken, in order
+    pub fn substores(&self) -> impl Iterator<Item = &Path> {
+        self.substores.keys().map(PathBuf::as_path)
+    }
+
+    /// Gets the string value of a given string key, as it was when the snapshot was taken.
+    ///
+    /// Returns `None` if the given key did not exist.
+    pub async fn get<B: Backend>(
+        &self,
+        store: &mut KvStore<B>,
+        key: &str,
+    ) -> Result<Option<String>> {
+        self.get_scoped(store, key, None).await
+    }
+
+    /// Gets the string value of a given string key in a substore,
+    /// as it was when the snapshot was taken.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::UnknownSubstore` if the substore did not exist when
+    /// the snapshot was taken, or was dropped since, and `KvsError::StaleSnapshot`
+    /// if it was cleared since.
+    pub async fn get_scoped<B: Backend>(
+        &self,
+        store: &mut KvStore<B>,
+        key: &str,
+        substore: Option<&Path>,
+    ) -> Result<Option<String>> {
+        let view = self.view(substore)?;
+        match view.index.get(key) {
+            Some(cmd_pos) if !cmd_pos.is_expired(self.at) => {
+                let operands = view.operands.get(key).map_or(&[][..], Vec::as_slice);
+                let (sink, logs) = store.logs_mut(substore)?;
+                logs.read_value(sink, cmd_pos, operands).await
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// Gets all values of the store within `range`, as they were when the snapshot was taken
+    pub async fn get_range<B: Backend, R: RangeBounds<String>>(
+        &self,
+        store: &mut KvStore<B>,
+        range: R,
+    ) -> Result<Vec<(String, String)>> {
+        let mut items = Vec::new();
+        for key in self.keys(range) {
+            if let Some(value) = self.get(store, key).await? {
                 items.push((key.clone(), value));
             }
         }
         Ok(items)
     }
+}
 
-    /// Gets all values from the store with a given prefix
-    pub async fn get_range<R: RangeBounds<String>>(
+/// Returns the error for a substore that was never added
+fn unknown_substore(sub_path: &Path) -> KvsError {
+    KvsError::UnknownSubstore {
+        path: sub_path.display().to_string(),
+    }
+}
+
+/// The logs of the root store, or of a substore, in a directory of their own.
+///
+/// Each has its own index and its own compaction, so keys never leak between them.
+struct Logs<B: Backend> {
+    // directory for the log and other data
+    path: PathBuf,
+    // map the path of a log to its reader
+    readers: HashMap<PathBuf, BufReaderWithPos<B::File>>,
+    // writer of the current log
+    writer: BufWriterWithPos<B::File>,
+
+    current_gen: u64,
+    // shared with the snapshots taken of it, and copied on write while they live
+    index: Arc<BTreeMap<String, CommandPos>>,
+    // the merged commands below the index, shared with the snapshots like the index
+    operands: Arc<Operands>,
+    // folds the operands when they are read or compacted
+    merge_operators: MergeOperators,
+    // the secondary indexes of the keys, by name
+    secondary: BTreeMap<String, SecondaryIndex>,
+    // the number of bytes representing "stale" commands that could be
+    // deleted during a compaction
+    uncompacted: u64,
+    compaction: Option<Compaction<B>>,
+    // the recent changes, and the watchers of them
+    feed: Feed,
+    // when each key was last read or written, in milliseconds since the Unix epoch
+    accessed: HashMap<String, u64>,
+    // when the logs were compacted last, in milliseconds since the Unix epoch
+    compacted_at: Option<u64>,
+    // snapshots that were taken of the index
+    snapshots: Vec<Weak<View>>,
+    // compacted logs that are kept until no snapshot reads from them
+    retired: Vec<u64>,
+    // the hashes of the blobs in the directory, see `KvStore::set_blob_threshold`
+    blobs: BTreeSet<String>,
+    // blobs that no command referred to when the last compaction finished,
+    // which are kept until no snapshot reads from the retired logs
+    garbage: BTreeSet<String>,
+    // number of blobs that were streamed, which numbers their part files
+    parts: u64,
+}
+
+/// A compaction in progress, which copies the live commands
+/// of older logs to the log of its own generation
+struct Compaction<B: Backend> {
+    gen: u64,
+    writer: BufWriterWithPos<B::File>,
+    // keys that are still to be copied, the next one last
+    pending: Vec<String>,
+    // the commands of each key in older logs, and their copies,
+    // which are a single command unless the merges could not be folded
+    copied: Vec<(String, Vec<CommandPos>, Vec<CommandPos>)>,
+    // commands that had expired, and were not copied
+    expired: Vec<(String, CommandPos)>,
+    // the number of stale bytes when the compaction started
+    stale: u64,
+    // sequence number of the last change when the compaction started
+    seq: u64,
+    // the number of copies, and of commands written since the compaction
+    // started, that refer to each blob
+    refs: HashMap<String, u64>,
+}
+
+impl<B: Backend> Logs<B> {
+    /// Replays the logs in `path`, and starts a new log to write to.
+    async fn open(path: PathBuf, sink: &mut B, merge_operators: MergeOperators) -> Result<Logs<B>> {
+        let mut readers = HashMap::new();
+        let mut index = BTreeMap::new();
+        let mut operands = BTreeMap::new();
+        let mut feed = Feed::default();
+        let mut accessed = HashMap::new();
+        let (current_gen, uncompacted, compacted_at) = open_logs(
+            &path,
+            sink,
+            &mut readers,
+            &mut index,
+            &mut operands,
+            &mut feed,
+            &mut accessed,
+        )
+        .await?;
+        let writer = new_log_file(&path, sink, current_gen, &mut readers).await?;
+        let blobs = open_blobs(&path, sink).await?;
+
+        Ok(Logs {
+            path,
+            readers,
+            writer,
+            current_gen,
+            index: Arc::new(index),
+            operands: Arc::new(operands),
+            merge_operators,
+            secondary: BTreeMap::new(),
+            uncompacted,
+            compaction: None,
+            feed,
+            accessed,
+            compacted_at,
+            snapshots: Vec::new(),
+            retired: Vec::new(),
+            blobs,
+            garbage: BTreeSet::new(),
+            parts: 0,
+        })
+    }
+
+    /// Appends the given commands to the current log and updates the index,
+    /// see `KvStore::write_batch`.
+    ///
+    /// Each write also takes a step of an automatic compaction.
+    async fn write_batch(
         &mut self,
-        range: R,
-    ) -> Result<Vec<(String, String)>> {
-        let path = &self.path;
-        let Self { readers, index, .. } = self;
-        index
-            .range(range)
-            .map(|(key, cmd_pos)| {
-                let log_path = log_path(path, cmd_pos.gen);
+        sink: &mut B,
+        cmds: Vec<Command>,
+        policy: &CompactionPolicy,
+    ) -> Result<()> {
+        if cmds.is_empty() {
+            return Ok(());
+        }
+        self.release_retired(sink).await;
+
+        let seq = self.feed.seq() + 1;
+        let changes = changes(seq, &cmds);
+        let cmd = Command::Sequenced { seq, cmds };
+        let payload = Encoding::Bincode.encode(&cmd)?;
+
+        let writer = &mut self.writer;
+        let record_pos = writer.pos;
+        let payload_pos = record_pos + record::HEADER_LEN;
+        let written = match record::write(writer, &payload).and_then(|()| writer.flush()) {
+            Ok(()) => writer.sync().await,
+            Err(err) => Err(err.into()),
+        };
+        if let Err(err) = written {
+            // The record must not be stored by a later flush, since it was
+            // never applied. Anything buffered ends up in memory only.
+            writer.seek(SeekFrom::Start(record_pos))?;
+            writer.writer.get_mut().set_len(record_pos)?;
+            return Err(err);
+        }
+
+        let range = payload_pos..payload_pos + payload.len() as u64;
+        self.uncompacted += replay(
+            cmd,
+            self.current_gen,
+            range,
+            Encoding::Bincode,
+            Arc::make_mut(&mut self.index),
+            Arc::make_mut(&mut self.operands),
+        )?;
+        self.update_indexes(sink, &changes).await?;
+        let now = now();
+        for change in &changes {
+            match &change.kind {
+                ChangeKind::Set(_) | ChangeKind::Merge(_) | ChangeKind::SetBlob { .. } => {
+                    self.accessed.insert(change.key.clone(), now);
+                }
+                ChangeKind::Remove => {
+                    self.accessed.remove(&change.key);
+                }
+                ChangeKind::RemoveRange { start, end } => {
+                    let range = (start.as_ref(), end.as_ref());
+                    self.accessed.retain(|key, _| !range.contains(key));
+                }
+            }
+        }
+        self.feed.publish(changes);
+
+        if policy.automatic {
+            if let Err(err) = self.auto_compact(sink, policy).await {
+                // The write itself is stored, the compaction is tried again later
+                info!("Could not compact", self.path.display(), err);
+                self.compaction = None;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Starts a compaction if the policy asks for one, and takes a step of it
+    async fn auto_compact(&mut self, sink: &mut B, policy: &CompactionPolicy) -> Result<()> {
+        if self.needs_compaction(policy) {
+            self.start_compaction(sink).await?;
+        }
+        self.compact_step(sink, COMPACTION_STEP).await?;
+        Ok(())
+    }
+
+    /// Gets the string value of a given string key.
+    ///
+    /// Returns `None` if the given key does not exist, or if it has expired.
+    async fn get(&mut self, sink: &mut B, key: &str) -> Result<Option<String>> {
+        let now = now();
+        match self.index.get(key) {
+            Some(&cmd_pos) if !cmd_pos.is_expired(now) => {
+                self.accessed.insert(key.to_owned(), now);
+                let operands = self.operands.get(key).cloned().unwrap_or_default();
+                self.read_value(sink, &cmd_pos, &operands).await
+            }
+            _ => Ok(None),
+        }
+    }
 
-                let reader = readers.get_mut(&log_path).expect("failed to find reader");
-                reader.seek(SeekFrom::Start(cmd_pos.pos))?;
-                let cmd_reader = reader.take(cmd_pos.len);
+    /// Returns how much space the logs take up
+    fn stats(&mut self) -> Result<LogStats> {
+        let now = now();
+        let live: Vec<_> = self
+            .index
+            .values()
+            .filter(|cmd_pos| !cmd_pos.is_expired(now))
+            .collect();
+        Ok(LogStats {
+            keys: live.len() as u64,
+            live_bytes: live.iter().map(|cmd_pos| cmd_pos.len).sum(),
+            stale_bytes: self.uncompacted,
+            log_bytes: self.log_bytes()?,
+            generations: self.readers.len() as u64,
+            last_compaction: self.compacted_at,
+        })
+    }
+
+    /// Returns the number of bytes in the logs
+    fn log_bytes(&mut self) -> Result<u64> {
+        let mut log_bytes = 0;
+        for reader in self.readers.values_mut() {
+            log_bytes += reader.seek(SeekFrom::End(0))?;
+        }
+        Ok(log_bytes)
+    }
+
+    /// Returns every key in the order that `eviction` evicts it, as a rank to sort by,
+    /// along with the number of bytes in the logs that evicting it frees
+    fn eviction_candidates(
+        &self,
+        eviction: Eviction,
+    ) -> impl Iterator<Item = ((bool, u64), String, u64)> + '_ {
+        self.index.iter().map(move |(key, cmd_pos)| {
+            let accessed = self.accessed.get(key).copied().unwrap_or(0);
+            let rank = match (eviction, cmd_pos.expires_at) {
+                (Eviction::TtlFirst, Some(expires_at)) => (false, expires_at),
+                _ => (true, accessed),
+            };
+            (rank, key.clone(), cmd_pos.len + record::HEADER_LEN)
+        })
+    }
+
+    /// Reads the value that was set by the command at the given position,
+    /// folding in the merges from `operands` on, see `apply`.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::StaleSnapshot` if the log of a command was removed,
+    /// and propagates the errors of the merge operators.
+    async fn read_value(
+        &mut self,
+        sink: &mut B,
+        cmd_pos: &CommandPos,
+        operands: &[CommandPos],
+    ) -> Result<Option<String>> {
+        fold(
+            &self.path,
+            sink,
+            &mut self.readers,
+            &self.blobs,
+            &self.merge_operators,
+            operands.iter().chain(iter::once(cmd_pos)),
+        )
+        .await
+    }
+
+    /// Pins the current index, see `KvStore::snapshot`
+    fn snapshot(&mut self) -> Arc<View> {
+        let view = Arc::new(View {
+            index: Arc::clone(&self.index),
+            operands: Arc::clone(&self.operands),
+            gens: self
+                .operands
+                .values()
+                .flatten()
+                .chain(self.index.values())
+                .map(|cmd_pos| cmd_pos.gen)
+                .collect(),
+        });
+        self.snapshots
+            .retain(|snapshot| snapshot.strong_count() > 0);
+        self.snapshots.push(Arc::downgrade(&view));
+        view
+    }
+
+    /// Returns the generations of the logs that live snapshots read from
+    fn pinned_gens(&mut self) -> BTreeSet<u64> {
+        self.snapshots
+            .retain(|snapshot| snapshot.strong_count() > 0);
+        self.snapshots
+            .iter()
+            .filter_map(Weak::upgrade)
+            .flat_map(|view| view.gens.iter().copied().collect::<Vec<_>>())
+            .collect()
+    }
+
+    /// Removes the retired logs that no snapshot reads from anymore,
+    /// and then the garbage blobs, which the retired logs may refer to
+    async fn release_retired(&mut self, sink: &mut B) {
+        if !self.retired.is_empty() {
+            let pinned = self.pinned_gens();
+            let (pinned, released) = self.retired.drain(..).partition(|gen| pinned.contains(gen));
+            self.retired = pinned;
+            for gen in released {
+                let stale_gen = log_path(&self.path, gen);
+                self.readers.remove(&stale_gen);
+                if sink.remove_file(&stale_gen).await.is_err() {
+                    info!("Could not remove stale gen {}", stale_gen.display());
+                };
+            }
+        }
+
+        if self.retired.is_empty() {
+            self.remove_garbage(sink).await;
+        }
+    }
+
+    /// Returns whether the key exists, and has not expired
+    fn contains_key(&self, key: &str) -> bool {
+        self.index
+            .get(key)
+            .is_some_and(|cmd_pos| !cmd_pos.is_expired(now()))
+    }
+
+    /// Removes the keys that have expired, and returns them.
+    ///
+    /// A removal is written for each of them, so they stay removed after a reopen.
+    async fn sweep_expired(
+        &mut self,
+        sink: &mut B,
+        policy: &CompactionPolicy,
+    ) -> Result<Vec<String>> {
+        let now = now();
+        let expired: Vec<String> = self
+            .index
+            .iter()
+            .filter(|(_, cmd_pos)| cmd_pos.is_expired(now))
+            .map(|(key, _)| key.clone())
+            .collect();
+
+        let cmds = expired.iter().cloned().map(Command::remove).collect();
+        self.write_batch(sink, cmds, policy).await?;
+        Ok(expired)
+    }
+
+    /// Returns whether the policy asks for a compaction of these logs
+    fn needs_compaction(&self, policy: &CompactionPolicy) -> bool {
+        if self.compaction.is_some() || self.uncompacted <= policy.threshold {
+            return false;
+        }
+        let live: u64 = self.index.values().map(|cmd_pos| cmd_pos.len).sum();
+        self.uncompacted as f64 >= live as f64 * policy.ratio
+    }
+
+    /// Starts a compaction, which copies the live commands to a new log
+    /// in steps, see `compact_step`.
+    ///
+    /// Writes go to a log after the compaction log from now on,
+    /// so they never wait for the compaction to finish.
+    async fn start_compaction(&mut self, sink: &mut B) -> Result<()> {
+        // increase current gen by 2. current_gen + 1 is for the compaction file
+        let gen = self.current_gen + 1;
+        let mut writer = new_log_file(&self.path, sink, gen, &mut self.readers).await?;
+        // the log replay picks up the numbering of changes from here,
+        // when the hint can not be used
+        let seq = self.feed.seq();
+        let marker = Command::Sequenced {
+            seq: seq + 1,
+            cmds: Vec::new(),
+        };
+        record::write(&mut writer, &Encoding::Bincode.encode(&marker)?)?;
+        self.current_gen += 2;
+        self.writer = new_log_file(&self.path, sink, self.current_gen, &mut self.readers).await?;
 
-                if let Command::Set { value, .. } = serde_json::from_reader(cmd_reader)? {
-                    Ok((key.clone(), value))
-                } else {
-                    Err(KvsError::UnexpectedCommandType)
-                }
-            })
-            .collect()
+        self.compaction = Some(Compaction {
+            gen,
+            writer,
+            pending: self.index.keys().rev().cloned().collect(),
+            copied: Vec::new(),
+            expired: Vec::new(),
+            stale: self.uncompacted,
+            seq,
+            refs: HashMap::new(),
+        });
+        Ok(())
     }
 
-    /// Gets the string value of a given string key.
+    /// Copies up to `limit` commands of the compaction in progress,
+    /// and finishes it once every command is copied.
     ///
-    /// Returns `None` if the given key does not exist.
-    async fn get_scoped(&mut self, key: String, substore: Option<&Path>) -> Result<Option<String>> {
-        if let Some(cmd_pos) = self.index.get(&key) {
-            let mut path = PathBuf::new();
-            path.push(&self.path);
-            if let Some(p) = substore {
-                path.push(&p);
-            }
-            let log_path = log_path(&path, cmd_pos.gen);
-
-            let reader = self
-                .readers
-                .get_mut(&log_path)
-                .expect("Cannot find log reader in get scoped");
-            reader.seek(SeekFrom::Start(cmd_pos.pos))?;
-            let cmd_reader = reader.take(cmd_pos.len);
-
-            if let Command::Set { value, .. } = serde_json::from_reader(cmd_reader)? {
-                Ok(Some(value))
+    /// Returns whether the compaction is still in progress.
+    async fn compact_step(&mut self, sink: &mut B, limit: usize) -> Result<bool> {
+        let compaction = match &mut self.compaction {
+            Some(compaction) => compaction,
+            None => return Ok(false),
+        };
+
+        for _ in 0..limit {
+            let key = match compaction.pending.pop() {
+                Some(key) => key,
+                None => break,
+            };
+            let cmd_pos = match self.index.get(&key) {
+                Some(&cmd_pos) => cmd_pos,
+                None => continue,
+            };
+            // commands that were written since the compaction started are in newer logs
+            let stack: Vec<CommandPos> = self
+                .operands
+                .get(&key)
+                .into_iter()
+                .flatten()
+                .copied()
+                .chain(iter::once(cmd_pos))
+                .collect();
+            let old: Vec<CommandPos> = stack
+                .into_iter()
+                .take_while(|cmd_pos| cmd_pos.gen < compaction.gen)
+                .collect();
+            let last = match old.last() {
+                Some(&last) => last,
+                None => continue,
+            };
+            // expired values are left behind in the old logs
+            if cmd_pos.is_expired(now()) {
+                compaction.expired.push((key, cmd_pos));
+                continue;
+            }
+
+            let folded = if old.len() > 1 {
+                let below = &old[..old.len() - 1];
+                fold(
+                    &self.path,
+                    sink,
+                    &mut self.readers,
+                    &self.blobs,
+                    &self.merge_operators,
+                    below.iter().chain(iter::once(&last)),
+                )
+                .await
             } else {
-                Err(KvsError::UnexpectedCommandType)
+                Ok(None)
+            };
+            let payloads = match folded {
+                Ok(Some(value)) => {
+                    let cmd = Command::set_expiring(key.clone(), value, last.expires_at);
+                    vec![(Encoding::Bincode.encode(&cmd)?, last.expires_at)]
+                }
+                // every command is copied as is, and its merges are folded once they are read,
+                // which waits for their operator to be registered
+                Ok(None) | Err(KvsError::UnknownMergeOperator { .. }) => {
+                    let mut payloads = Vec::with_capacity(old.len());
+                    for cmd_pos in &old {
+                        let log_path = log_path(&self.path, cmd_pos.gen);
+                        let reader = self
+                            .readers
+                            .get_mut(&log_path)
+                            .expect("Cannot find log reader");
+                        let payload = read_payload(reader, cmd_pos).await?;
+                        if !self.blobs.is_empty() {
+                            if let Command::SetBlob { blob, .. } =
+                                Encoding::Bincode.decode(&payload)?
+                            {
+                                *compaction.refs.entry(blob).or_default() += 1;
+                            }
+                        }
+                        payloads.push((payload, cmd_pos.expires_at));
+                    }
+                    payloads
+                }
+                // the key fails to read as well, it is reported rather than copied
+                Err(err) => {
+                    self.compaction = None;
+                    return Err(err);
+                }
+            };
+
+            // every command is framed as its own record in the new log
+            let writer = &mut compaction.writer;
+            let mut copies = Vec::with_capacity(payloads.len());
+            for (payload, expires_at) in payloads {
+                let new_pos = writer.pos + record::HEADER_LEN;
+                record::write(writer, &payload)?;
+                copies.push(CommandPos {
+                    expires_at,
+                    ..(compaction.gen, new_pos..writer.pos).into()
+                });
             }
-        } else {
-            Ok(None)
+            compaction.copied.push((key, old, copies));
         }
-    }
 
-    /// Removes a given key.
-    ///
-    /// # Errors
-    ///
-    /// It returns `KvsError::KeyNotFound` if the given key is not found.
-    ///
-    /// It propagates I/O or serialization errors during writing the log.
-    async fn remove(&mut self, key: String) -> Result<()> {
-        if self.index.contains_key(&key) {
-            let cmd = Command::remove(key);
-            let mut writer = self
-                .writers
-                .get_mut(&self.path)
-                .expect("Could not get writer");
-            serde_json::to_writer(&mut writer, &cmd)?;
-            writer.flush()?;
-            if let Command::Remove { key } = cmd {
-                let old_cmd = self.index.remove(&key).expect("key not found");
-                self.uncompacted += old_cmd.len;
-            }
-            Ok(())
+        if compaction.pending.is_empty() {
+            let compaction = self.compaction.take().expect("compaction is in progress");
+            self.finish_compaction(sink, compaction).await?;
+            Ok(false)
         } else {
-            Err(KvsError::KeyNotFound)
+            Ok(true)
         }
     }
 
-    /// Clears stale entries in the log.
-    async fn compact(&mut self) -> Result<()> {
-        // increase current gen by 2. current_gen + 1 is for the compaction file
-        let compaction_gen = self.current_gen + 1;
-        self.current_gen += 2;
-        let writer = self.new_log_file(self.current_gen).await?;
-        self.writers.insert(self.path.clone(), writer);
-
-        let mut compaction_writer = self.new_log_file(compaction_gen).await?;
-
-        let mut new_pos = 0; // pos in the new log file
-        for cmd_pos in &mut self.index.values_mut() {
-            let log_path = log_path(&self.path, cmd_pos.gen);
-            let reader = self
-                .readers
-                .get_mut(&log_path)
-                .expect("Cannot find log reader");
-            if reader.pos != cmd_pos.pos {
-                reader.seek(SeekFrom::Start(cmd_pos.pos))?;
+    /// Points the index at the compaction log, and removes the logs it replaces.
+    async fn finish_compaction(&mut self, sink: &mut B, compaction: Compaction<B>) -> Result<()> {
+        let Compaction {
+            gen: compaction_gen,
+            writer: mut compaction_writer,
+            copied,
+            expired,
+            stale,
+            seq,
+            refs,
+            ..
+        } = compaction;
+        compaction_writer.flush()?;
+        compaction_writer.sync().await?;
+
+        let index = Arc::make_mut(&mut self.index);
+        let operands = Arc::make_mut(&mut self.operands);
+        for (key, old, copies) in copied {
+            // the key may have been overwritten or removed since it was copied
+            let mut stack = match index.get(&key) {
+                Some(&cmd_pos) => {
+                    let mut stack = operands.remove(&key).unwrap_or_default();
+                    stack.push(cmd_pos);
+                    stack
+                }
+                None => continue,
+            };
+            // and it may have been merged into, on top of the copies
+            if stack.starts_with(&old) {
+                stack.splice(..old.len(), copies);
+            }
+            let cmd_pos = stack.pop().expect("a key has a command");
+            index.insert(key.clone(), cmd_pos);
+            if !stack.is_empty() {
+                operands.insert(key, stack);
+            }
+        }
+        for (key, old) in expired {
+            if index.get(&key) == Some(&old) {
+                index.remove(&key);
+                operands.remove(&key);
             }
+        }
 
-            let mut entry_reader = reader.take(cmd_pos.len);
-            let len = io::copy(&mut entry_reader, &mut compaction_writer)?;
-            *cmd_pos = (compaction_gen, new_pos..new_pos + len).into();
-            new_pos += len;
+        // only the commands in the compaction log, newer logs are replayed after the hint.
+        // Copies whose merges were not folded keep the commands below the last one.
+        let mut hinted = BTreeMap::new();
+        let mut hinted_operands = Operands::new();
+        for (key, &cmd_pos) in self.index.iter() {
+            let mut stack: Vec<CommandPos> = self
+                .operands
+                .get(key)
+                .into_iter()
+                .flatten()
+                .chain(iter::once(&cmd_pos))
+                .filter(|cmd_pos| cmd_pos.gen == compaction_gen)
+                .copied()
+                .collect();
+            if let Some(last) = stack.pop() {
+                hinted.insert(key.clone(), last);
+                if !stack.is_empty() {
+                    hinted_operands.insert(key.clone(), stack);
+                }
+            }
         }
-        compaction_writer.flush()?;
+        let hinted_accessed = self
+            .accessed
+            .iter()
+            .filter(|(key, _)| hinted.contains_key(*key))
+            .map(|(key, &accessed)| (key.clone(), accessed))
+            .collect();
+        let compacted_at = now();
+        let hint = Hint {
+            log_len: compaction_writer.pos,
+            seq,
+            compacted_at,
+            index: Cow::Owned(hinted),
+            operands: Cow::Owned(hinted_operands),
+            accessed: Cow::Owned(hinted_accessed),
+        };
+        self.compacted_at = Some(compacted_at);
+        if let Err(err) = write_hint(&self.path, sink, compaction_gen, &hint).await {
+            // The next open has to replay the whole log without it, nothing more
+            info!("Could not write hint", err);
+        }
+
+        // blobs that neither the copies nor newer commands refer to are garbage
+        self.garbage = self
+            .blobs
+            .iter()
+            .filter(|blob| !refs.contains_key(*blob))
+            .cloned()
+            .collect();
 
-        // remove stale log files
+        // remove stale log files, unless a snapshot still reads from them
         let stale_gens: Vec<_> = self
             .readers
             .keys()
-            .filter(|path| {
+            .map(|path| {
                 path.file_stem()
                     .unwrap()
                     .to_str()
                     .unwrap()
                     .parse::<u64>()
                     .unwrap()
-                    < compaction_gen
             })
-            .cloned()
+            .filter(|&gen| gen < compaction_gen && !self.retired.contains(&gen))
             .collect();
-
-        for stale_gen in stale_gens {
-            self.readers.remove(&stale_gen);
-            if self.sink.remove_file(&stale_gen).await.is_err() {
-                info!("Could not remove stale gen {}", stale_gen.display());
-            };
+        self.retired.extend(stale_gens);
+        self.release_retired(sink).await;
+
+        for gen in sorted_gen_list(sink, &self.path, Some(HINT_EXTENSION)).await? {
+            if gen < compaction_gen {
+                let stale_hint = hint_path(&self.path, gen);
+                if sink.remove_file(&stale_hint).await.is_err() {
+                    info!("Could not remove stale hint {}", stale_hint.display());
+                }
+            }
         }
 
-        self.uncompacted = 0;
+        // commands that went stale while the compaction was running are still
+        // in the compaction log, or in newer logs
+        self.uncompacted = self.uncompacted.saturating_sub(stale);
+
+        // expired keys were removed, and merges folded
+        self.rebuild_indexes(sink).await
+    }
 
+    /// Clears stale entries in the log, finishing a compaction in progress
+    /// or running a new one to completion.
+    async fn compact(&mut self, sink: &mut B) -> Result<()> {
+        if self.compaction.is_none() {
+            self.start_compaction(sink).await?;
+        }
+        while self.compact_step(sink, COMPACTION_STEP).await? {}
         Ok(())
     }
 
-    /// Create a new log file with given generation number and add the reader to the readers map.
-    ///
-    /// Returns the writer to the log.
-    async fn new_log_file(&mut self, gen: u64) -> Result<BufWriterWithPos<IdbFile>> {
-        new_log_file(&self.path, &mut self.sink, gen, &mut self.readers).await
+    /// Removes every log and hint file.
+    async fn remove(self, sink: &mut B) -> Result<()> {
+        let path = self.path;
+        // close all files before they are removed
+        drop(self.readers);
+        drop(self.writer);
+
+        for gen in sorted_gen_list(sink, &path, None).await? {
+            sink.remove_file(&log_path(&path, gen)).await?;
+        }
+        for gen in sorted_gen_list(sink, &path, Some(HINT_EXTENSION)).await? {
+            sink.remove_file(&hint_path(&path, gen)).await?;
+        }
+        for blob in &self.blobs {
+            sink.remove_file(&blob_path(&path, blob)).await?;
+        }
+        Ok(())
     }
 }
 
 /// Create a new log file with given generation number and add the reader to the readers map.
 ///
 /// Returns the writer to the log.
-async fn new_log_file(
+async fn new_log_file<B: Backend>(
     path: &Path,
-    sink: &mut IdbFolder,
+    sink: &mut B,
     gen: u64,
-    readers: &mut HashMap<PathBuf, BufReaderWithPos<IdbFile>>,
-) -> Result<BufWriterWithPos<IdbFile>> {
-    let path = log_path(&path, gen);
-    let writer = BufWriterWithPos::new(sink.open_file(&path).await?)?;
+    readers: &mut HashMap<PathBuf, BufReaderWithPos<B::File>>,
+) -> Result<BufWriterWithPos<B::File>> {
+    let path = log_path(path, gen);
+    let mut writer = BufWriterWithPos::new(sink.open_file(&path).await?)?;
+    if writer.pos == 0 {
+        record::write_header(&mut writer)?;
+        writer.flush()?;
+        writer.sync().await?;
+    }
     readers.insert(
         path.clone(),
         BufReaderWithPos::new(sink.open_file(&path).await?)?,
@@ -471,23 +1833,21 @@ async fn new_log_file(
     Ok(writer)
 }
 
-/// Returns sorted generation numbers in the given directory
-async fn sorted_gen_list(folder_path: &Path) -> Result<Vec<u64>> {
-    // TODO Get all items in an object store
-    let path: PathBuf = folder_path.into();
-
-    let idb_folder = IdbFolder::open(&path).await?;
-    let idb_files: Vec<String> = idb_folder
-        .get_file_names()
-        .await
-        .expect("Could not get file names")
-        .into_serde()
-        .expect("File names can not be converted to vector");
+/// Returns sorted generation numbers of the files in the given directory
+/// with the given extension, logs have none.
+async fn sorted_gen_list<B: Backend>(
+    sink: &B,
+    folder_path: &Path,
+    extension: Option<&str>,
+) -> Result<Vec<u64>> {
+    let file_names = sink.file_names().await?;
 
-    let filtered: Vec<_> = idb_files
+    let filtered: Vec<_> = file_names
         .iter()
-        .filter(|file_name| file_name.contains(folder_path.to_str().unwrap()))
-        .map(|file_name| Path::new(file_name))
+        .map(Path::new)
+        // files of substores are in directories below `folder_path`
+        .filter(|path| path.parent() == Some(folder_path))
+        .filter(|path| path.extension().and_then(OsStr::to_str) == extension)
         .collect();
 
     let mut gen_list: Vec<u64> = filtered
@@ -502,15 +1862,326 @@ async fn sorted_gen_list(folder_path: &Path) -> Result<Vec<u64>> {
     Ok(gen_list)
 }
 
+/// Replays the logs in `dir` into the index, and migrates logs that were
+/// written in an older format.
+///
+/// Logs up to the latest hint file that is still valid are not replayed,
+/// the index is loaded from the hint instead.
+///
+/// Returns the generation number for a new log, how many bytes can be
+/// saved after a compaction, and when the hinted log was compacted.
+async fn open_logs<B: Backend>(
+    dir: &Path,
+    sink: &mut B,
+    readers: &mut HashMap<PathBuf, BufReaderWithPos<B::File>>,
+    index: &mut BTreeMap<String, CommandPos>,
+    operands: &mut Operands,
+    feed: &mut Feed,
+    accessed: &mut HashMap<String, u64>,
+) -> Result<(u64, u64, Option<u64>)> {
+    let gen_list = sorted_gen_list(sink, dir, None).await?;
+    let mut uncompacted = 0;
+    let mut encodings = HashMap::new();
+
+    let mut compacted_at = None;
+    let hinted_gen = match latest_hint(dir, sink, &gen_list).await? {
+        Some((gen, hint)) => {
+            index.extend(hint.index.into_owned());
+            operands.extend(hint.operands.into_owned());
+            accessed.extend(hint.accessed.into_owned());
+            feed.skip_to(hint.seq);
+            compacted_at = Some(hint.compacted_at);
+            encodings.insert(gen, Encoding::Bincode);
+            let log_path = log_path(dir, gen);
+            let reader = BufReaderWithPos::new(sink.open_file(&log_path).await?)?;
+            readers.insert(log_path, reader);
+            Some(gen)
+        }
+        None => None,
+    };
+
+    for &gen in &gen_list {
+        let log_path = log_path(dir, gen);
+        match hinted_gen {
+            Some(hinted_gen) if gen == hinted_gen => continue,
+            Some(hinted_gen) if gen < hinted_gen => {
+                // A compaction was interrupted before it removed the logs
+                // that the hinted log replaces
+                sink.remove_file(&log_path).await?;
+                continue;
+            }
+            _ => {}
+        }
+
+        let mut reader = BufReaderWithPos::new(sink.open_file(&log_path).await?)?;
+        let (saved, encoding) = load(&log_path, gen, &mut reader, index, operands, feed).await?;
+        uncompacted += saved;
+        encodings.insert(gen, encoding);
+        readers.insert(log_path, reader);
+    }
+
+    // the keys written after the hint were accessed after the keys in it
+    if let (Some(hinted_gen), Some(compacted_at)) = (hinted_gen, compacted_at) {
+        for (key, cmd_pos) in index.iter() {
+            if cmd_pos.gen > hinted_gen {
+                accessed.insert(key.clone(), compacted_at);
+            }
+        }
+    }
+    accessed.retain(|key, _| index.contains_key(key));
+
+    let mut current_gen = gen_list.last().unwrap_or(&0) + 1;
+    if encodings
+        .values()
+        .any(|&encoding| encoding == Encoding::Json)
+    {
+        migrate(dir, sink, readers, index, operands, &encodings, current_gen).await?;
+        current_gen += 1;
+        uncompacted = 0;
+    }
+
+    Ok((current_gen, uncompacted, compacted_at))
+}
+
+/// Returns the latest hint file in `dir` that still describes its log.
+async fn latest_hint<B: Backend>(
+    dir: &Path,
+    sink: &mut B,
+    gen_list: &[u64],
+) -> Result<Option<(u64, Hint<'static>)>> {
+    let hint_list = sorted_gen_list(sink, dir, Some(HINT_EXTENSION)).await?;
+    for &gen in hint_list.iter().rev() {
+        if !gen_list.contains(&gen) {
+            continue;
+        }
+        match read_hint(dir, sink, gen).await? {
+            Some(hint) => return Ok(Some((gen, hint))),
+            None => info!("Ignoring stale hint", hint_path(dir, gen).display()),
+        }
+    }
+    Ok(None)
+}
+
+/// Reads the hint file of the given generation.
+///
+/// Returns `None` if the hint is damaged, or if it does not match its log.
+async fn read_hint<B: Backend>(
+    dir: &Path,
+    sink: &mut B,
+    gen: u64,
+) -> Result<Option<Hint<'static>>> {
+    let mut file = sink.open_file(&hint_path(dir, gen)).await?;
+    let len = file.seek(SeekFrom::End(0))?;
+    file.fetch(0..len).await?;
+    file.seek(SeekFrom::Start(0))?;
+    if len < record::FILE_HEADER_LEN
+        || record::read_header(&mut file)? != Some(record::FORMAT_VERSION)
+    {
+        return Ok(None);
+    }
+
+    let hint: Hint = match record::read(&mut file, record::FILE_HEADER_LEN, len)? {
+        Frame::Complete(payload) => match bincode::deserialize(&payload) {
+            Ok(hint) => hint,
+            Err(_) => return Ok(None),
+        },
+        _ => return Ok(None),
+    };
+
+    // A log is never written to after the compaction that wrote its hint
+    let log_len = sink
+        .open_file(&log_path(dir, gen))
+        .await?
+        .seek(SeekFrom::End(0))?;
+    if hint.log_len == log_len {
+        Ok(Some(hint))
+    } else {
+        Ok(None)
+    }
+}
+
+/// Writes a hint file for the log of the given generation,
+/// which holds every command in the index.
+async fn write_hint<B: Backend>(dir: &Path, sink: &mut B, gen: u64, hint: &Hint<'_>) -> Result<()> {
+    let payload = bincode::serialize(hint)?;
+
+    let mut file = sink.open_file(&hint_path(dir, gen)).await?;
+    file.set_len(0)?;
+    file.seek(SeekFrom::Start(0))?;
+    record::write_header(&mut file)?;
+    record::write(&mut file, &payload)?;
+    file.flush()?;
+    file.sync().await
+}
+
 /// Load the whole log file and store value locations in the index map.
 ///
+/// A record that was only partially written when the store was closed
+/// is truncated away, so that the log ends with the last complete record.
+///
+/// Returns how many bytes can be saved after a compaction,
+/// and how the commands of the log are encoded.
+///
+/// # Errors
+///
+/// It returns `KvsError::Corruption` if a record in the middle of the log is damaged,
+/// and `KvsError::UnsupportedFormat` if the log was written by a newer version.
+async fn load<F: BackendFile>(
+    log_path: &Path,
+    gen: u64,
+    reader: &mut BufReaderWithPos<F>,
+    index: &mut BTreeMap<String, CommandPos>,
+    operands: &mut Operands,
+    feed: &mut Feed,
+) -> Result<(u64, Encoding)> {
+    let log_len = reader.seek(SeekFrom::End(0))?;
+    // the records are fetched one at a time while they are replayed
+    reader.get_mut().fetch(0..record::FILE_HEADER_LEN).await?;
+    // To make sure we read from the beginning of the file
+    reader.seek(SeekFrom::Start(0))?;
+    if log_len < record::FILE_HEADER_LEN {
+        // Every record is longer than a header, so this is a new log
+        // whose header was torn, or that was never written to
+        if log_len > 0 {
+            truncate(log_path, reader, 0)?;
+            reader.get_mut().sync().await?;
+        }
+        return Ok((0, Encoding::Bincode));
+    }
+
+    let loaded = match record::read_header(reader)? {
+        // every version only adds commands, so older logs decode the same way
+        Some(version)
+            if (record::MIN_FORMAT_VERSION..=record::FORMAT_VERSION).contains(&version) =>
+        {
+            let uncompacted = load_framed(
+                log_path,
+                gen,
+                reader,
+                Encoding::Bincode,
+                index,
+                operands,
+                feed,
+            )
+            .await?;
+            (uncompacted, Encoding::Bincode)
+        }
+        Some(version) => {
+            return Err(KvsError::UnsupportedFormat {
+                path: log_path.display().to_string(),
+                version,
+            })
+        }
+        None => {
+            reader.seek(SeekFrom::Start(0))?;
+            let mut magic = [0; 4];
+            reader.read_exact(&mut magic)?;
+            if &magic == crypto::MAGIC {
+                // reading it as a legacy log would truncate it as garbage
+                return Err(KvsError::WrongKey {
+                    path: log_path.display().to_string(),
+                });
+            }
+            reader.seek(SeekFrom::Start(0))?;
+            let mut first = [0; 1];
+            reader.read_exact(&mut first)?;
+            reader.seek(SeekFrom::Start(0))?;
+            let uncompacted = if first[0] == b'{' {
+                reader.get_mut().fetch(0..log_len).await?;
+                load_unframed(gen, reader, index, operands)?
+            } else {
+                load_framed(log_path, gen, reader, Encoding::Json, index, operands, feed).await?
+            };
+            (uncompacted, Encoding::Json)
+        }
+    };
+
+    // make sure that a truncated torn write stays truncated
+    reader.get_mut().sync().await?;
+    Ok(loaded)
+}
+
+/// Load the framed records of a log, starting at the current position of `reader`.
+///
 /// Returns how many bytes can be saved after a compaction.
-fn load(
+async fn load_framed<F: BackendFile>(
+    log_path: &Path,
     gen: u64,
-    reader: &mut BufReaderWithPos<IdbFile>,
+    reader: &mut BufReaderWithPos<F>,
+    encoding: Encoding,
     index: &mut BTreeMap<String, CommandPos>,
+    operands: &mut Operands,
+    feed: &mut Feed,
+) -> Result<u64> {
+    let mut pos = reader.pos;
+    let log_len = reader.seek(SeekFrom::End(0))?;
+    reader.seek(SeekFrom::Start(pos))?;
+    let mut uncompacted = 0; // number of bytes that can be saved after a compaction
+    while pos < log_len {
+        let payload = match read_record(reader, pos, log_len).await? {
+            Frame::Complete(payload) => payload,
+            Frame::Corrupt | Frame::Torn => {
+                // Only a damaged record followed by an intact one is corruption,
+                // anything else is garbage left behind by a torn write
+                reader.get_mut().fetch(pos..log_len).await?;
+                if record::next_intact(reader, pos, log_len)?.is_some() {
+                    return Err(KvsError::Corruption {
+                        path: log_path.display().to_string(),
+                        pos,
+                    });
+                }
+                truncate(log_path, reader, pos)?;
+                break;
+            }
+        };
+
+        let payload_pos = pos + record::HEADER_LEN;
+        let cmd = encoding
+            .decode(&payload)
+            .map_err(|_| KvsError::Corruption {
+                path: log_path.display().to_string(),
+                pos,
+            })?;
+        if let Command::Sequenced { seq, cmds } = &cmd {
+            feed.skip_to(seq.saturating_sub(1));
+            feed.replay(changes(*seq, cmds));
+        }
+        let range = payload_pos..payload_pos + payload.len() as u64;
+        uncompacted += replay(cmd, gen, range, encoding, index, operands)?;
+        pos = payload_pos + payload.len() as u64;
+    }
+
+    Ok(uncompacted)
+}
+
+/// Reads the record at `pos` of a log that is `log_len` bytes long,
+/// fetching its header first and then its payload.
+///
+/// `reader` must be positioned at `pos`.
+async fn read_record<F: BackendFile>(
+    reader: &mut BufReaderWithPos<F>,
+    pos: u64,
+    log_len: u64,
+) -> Result<Frame> {
+    let header_end = (pos + record::HEADER_LEN).min(log_len);
+    reader.get_mut().fetch(pos..header_end).await?;
+    let (len, crc) = match record::read_record_header(reader, pos, log_len)? {
+        Some(header) => header,
+        None => return Ok(Frame::Torn),
+    };
+    reader.get_mut().fetch(header_end..header_end + len).await?;
+    Ok(record::read_payload(reader, len, crc)?)
+}
+
+/// Load a log written before records were framed, as plain concatenated json.
+///
+/// Returns how many bytes can be saved after a compaction.
+fn load_unframed<F: BackendFile>(
+    gen: u64,
+    reader: &mut BufReaderWithPos<F>,
+    index: &mut BTreeMap<String, CommandPos>,
+    operands: &mut Operands,
 ) -> Result<u64> {
-    // To make sure we read from the beginning of the file
     let mut pos = reader.seek(SeekFrom::Start(0))?;
     let mut stream = Deserializer::from_reader(reader).into_iter::<Command>();
     let mut uncompacted = 0; // number of bytes that can be saved after a compaction
@@ -518,20 +2189,10 @@ fn load(
         let new_pos = stream.byte_offset() as u64;
         // Check if the command is successfully read
         match cmd.ok() {
-            Some(Command::Set { key, .. }) => {
-                if let Some(old_cmd) = index.insert(key, (gen, pos..new_pos).into()) {
-                    uncompacted += old_cmd.len;
-                }
-            }
-            Some(Command::Remove { key }) => {
-                if let Some(old_cmd) = index.remove(&key) {
-                    uncompacted += old_cmd.len;
-                }
-                // the "remove" command itself can be deleted in the next compaction
-                // so we add its length to `uncompacted`
-                uncompacted += new_pos - pos;
+            Some(cmd) => {
+                uncompacted += replay(cmd, gen, pos..new_pos, Encoding::Json, index, operands)?;
             }
-            _ => {
+            None => {
                 // A false read has occured if we reach this.
                 // A false read occurs  if the database is closed
                 // before all bytes are flushed
@@ -544,15 +2205,317 @@ fn load(
     Ok(uncompacted)
 }
 
+/// Cuts off a torn write at the end of a log
+fn truncate<F: BackendFile>(
+    log_path: &Path,
+    reader: &mut BufReaderWithPos<F>,
+    pos: u64,
+) -> Result<()> {
+    info!(
+        "Truncating torn write",
+        format!("{} at byte {}", log_path.display(), pos)
+    );
+    let file = reader.get_mut();
+    file.set_len(pos)?;
+    file.flush()?;
+    reader.seek(SeekFrom::Start(pos))?;
+    Ok(())
+}
+
+/// Rewrites the live commands of logs in `dir` into a new log with the given
+/// generation number, in the current format, and removes the old logs.
+///
+/// This upgrades stores whose logs were written with an older encoding.
+async fn migrate<B: Backend>(
+    dir: &Path,
+    sink: &mut B,
+    readers: &mut HashMap<PathBuf, BufReaderWithPos<B::File>>,
+    index: &mut BTreeMap<String, CommandPos>,
+    operands: &mut Operands,
+    encodings: &HashMap<u64, Encoding>,
+    gen: u64,
+) -> Result<()> {
+    info!(
+        "Migrating logs",
+        format!("{} to gen {}", dir.display(), gen)
+    );
+    let mut writer = new_log_file(dir, sink, gen, readers).await?;
+
+    for cmd_pos in operands.values_mut().flatten().chain(index.values_mut()) {
+        let reader = readers
+            .get_mut(&log_path(dir, cmd_pos.gen))
+            .expect("Cannot find log reader");
+        let payload = match encodings[&cmd_pos.gen] {
+            Encoding::Bincode => read_payload(reader, cmd_pos).await?,
+            encoding => {
+                let cmd = encoding.decode(&read_payload(reader, cmd_pos).await?)?;
+                Encoding::Bincode.encode(&cmd)?
+            }
+        };
+        let new_pos = writer.pos + record::HEADER_LEN;
+        record::write(&mut writer, &payload)?;
+        *cmd_pos = (gen, new_pos..writer.pos).into();
+    }
+    writer.flush()?;
+    writer.sync().await?;
+
+    for old_gen in encodings.keys() {
+        let old_path = log_path(dir, *old_gen);
+        readers.remove(&old_path);
+        sink.remove_file(&old_path).await?;
+    }
+
+    Ok(())
+}
+
+/// Reads the encoded command at the given position of a log
+async fn read_payload<F: BackendFile>(
+    reader: &mut BufReaderWithPos<F>,
+    cmd_pos: &CommandPos,
+) -> Result<Vec<u8>> {
+    let range = cmd_pos.pos..cmd_pos.pos + cmd_pos.len;
+    reader.get_mut().fetch(range).await?;
+    if reader.pos != cmd_pos.pos {
+        reader.seek(SeekFrom::Start(cmd_pos.pos))?;
+    }
+    let mut payload = vec![0; cmd_pos.len as usize];
+    reader.read_exact(&mut payload)?;
+    Ok(payload)
+}
+
+/// Reads the value set by the first of the given commands,
+/// and folds the merges after it into the value.
+///
+/// # Errors
+///
+/// It returns `KvsError::StaleSnapshot` if the log of a command was removed,
+/// and propagates the errors of the merge operators.
+async fn fold<'a, B: Backend>(
+    dir: &Path,
+    sink: &mut B,
+    readers: &mut HashMap<PathBuf, BufReaderWithPos<B::File>>,
+    blobs: &BTreeSet<String>,
+    merge_operators: &MergeOperators,
+    cmds: impl Iterator<Item = &'a CommandPos>,
+) -> Result<Option<String>> {
+    let mut value = None;
+    for cmd_pos in cmds {
+        let reader = readers
+            .get_mut(&log_path(dir, cmd_pos.gen))
+            .ok_or(KvsError::StaleSnapshot)?;
+        value = match read_command(reader, cmd_pos).await? {
+            Command::Set { value, .. } | Command::SetExpiring { value, .. } => Some(value),
+            Command::SetBlob { key, blob, len, .. } => {
+                Some(read_blob_value(sink, dir, blobs, key, &blob, len).await?)
+            }
+            Command::Merge {
+                key,
+                operator,
+                operand,
+            } => Some(merge_operators.merge(&operator, &key, value.as_deref(), &operand)?),
+            _ => return Err(KvsError::UnexpectedCommandType),
+        };
+    }
+    Ok(value)
+}
+
+/// Reads the command at the given position of a log
+async fn read_command<F: BackendFile>(
+    reader: &mut BufReaderWithPos<F>,
+    cmd_pos: &CommandPos,
+) -> Result<Command> {
+    Encoding::Bincode.decode(&read_payload(reader, cmd_pos).await?)
+}
+
+/// Applies a command read from the given position of a log to the index,
+/// unpacking batches into the commands they hold.
+///
+/// Returns how many bytes can be saved after a compaction.
+fn replay(
+    cmd: Command,
+    gen: u64,
+    range: Range<u64>,
+    encoding: Encoding,
+    index: &mut BTreeMap<String, CommandPos>,
+    operands: &mut Operands,
+) -> Result<u64> {
+    let (cmds, empty) = match cmd {
+        Command::Batch(cmds) => (cmds, Command::Batch(Vec::new())),
+        Command::Sequenced { seq, cmds } => (
+            cmds,
+            Command::Sequenced {
+                seq,
+                cmds: Vec::new(),
+            },
+        ),
+        cmd => return Ok(apply(cmd, gen, range, index, operands)),
+    };
+
+    // The whole batch has been read, so it can be applied.
+    // The framing is dead weight that a compaction gets rid of.
+    let mut uncompacted = range.end - range.start;
+    let offsets = encoding.batch_offsets(&empty, &cmds)?;
+    for (cmd, offset) in cmds.into_iter().zip(offsets) {
+        uncompacted -= offset.end - offset.start;
+        let cmd_range = range.start + offset.start..range.start + offset.end;
+        uncompacted += apply(cmd, gen, cmd_range, index, operands);
+    }
+    Ok(uncompacted)
+}
+
+/// Applies a command read from, or written to, the given position of a log to the index.
+///
+/// The index points at the last merge of a key, and the commands below it,
+/// down to the one that set the key, are kept in `operands`.
+///
+/// Returns how many bytes can be saved after a compaction.
+fn apply(
+    cmd: Command,
+    gen: u64,
+    range: Range<u64>,
+    index: &mut BTreeMap<String, CommandPos>,
+    operands: &mut Operands,
+) -> u64 {
+    match cmd {
+        Command::Set { key, .. } => {
+            operands.remove(&key);
+            index
+                .insert(key, (gen, range).into())
+                .map_or(0, |old_cmd| old_cmd.len)
+        }
+        Command::SetExpiring {
+            key, expires_at, ..
+        } => {
+            operands.remove(&key);
+            let cmd_pos = CommandPos {
+                expires_at: Some(expires_at),
+                ..(gen, range).into()
+            };
+            index.insert(key, cmd_pos).map_or(0, |old_cmd| old_cmd.len)
+        }
+        Command::SetBlob {
+            key, expires_at, ..
+        } => {
+            operands.remove(&key);
+            let cmd_pos = CommandPos {
+                expires_at,
+                ..(gen, range).into()
+            };
+            index.insert(key, cmd_pos).map_or(0, |old_cmd| old_cmd.len)
+        }
+        Command::Remove { key } => {
+            operands.remove(&key);
+            // the "remove" command itself can be deleted in the next compaction
+            // so it counts as well
+            index.remove(&key).map_or(0, |old_cmd| old_cmd.len) + range.end - range.start
+        }
+        Command::Merge { key, .. } => {
+            // a merge keeps the time to live of the value it applies to
+            let below = index.get(&key).copied();
+            let cmd_pos = CommandPos {
+                expires_at: below.and_then(|below| below.expires_at),
+                ..(gen, range).into()
+            };
+            index.insert(key.clone(), cmd_pos);
+            match below {
+                // a compaction folds it into a single command
+                Some(below) => {
+                    operands.entry(key).or_default().push(below);
+                    below.len
+                }
+                None => 0,
+            }
+        }
+        Command::RemoveRange { start, end } => {
+            let keys: Vec<String> = if is_empty_range(&start, &end) {
+                Vec::new()
+            } else {
+                index
+                    .range((start, end))
+                    .map(|(key, _)| key.clone())
+                    .collect()
+            };
+            // like a "remove" command, the range itself counts as well
+            let mut stale = range.end - range.start;
+            for key in keys {
+                operands.remove(&key);
+                stale += index.remove(&key).map_or(0, |old_cmd| old_cmd.len);
+            }
+            stale
+        }
+        // batches are never nested
+        Command::Batch(_) | Command::Sequenced { .. } => 0,
+    }
+}
+
+/// Returns whether no key is between `start` and `end`,
+/// for which `BTreeMap::range` would panic
+pub(crate) fn is_empty_range<T: Ord>(start: &Bound<T>, end: &Bound<T>) -> bool {
+    match (start, end) {
+        (Bound::Included(start), Bound::Included(end)) => start > end,
+        (Bound::Included(start), Bound::Excluded(end))
+        | (Bound::Excluded(start), Bound::Included(end))
+        | (Bound::Excluded(start), Bound::Excluded(end)) => start >= end,
+        _ => false,
+    }
+}
+
 fn log_path(dir: &Path, gen: u64) -> PathBuf {
     dir.join(format!("{}", gen))
 }
 
+fn hint_path(dir: &Path, gen: u64) -> PathBuf {
+    dir.join(format!("{}.{}", gen, HINT_EXTENSION))
+}
+
 /// Struct representing a command
 #[derive(Serialize, Deserialize, Debug)]
 enum Command {
-    Set { key: String, value: String },
-    Remove { key: String },
+    Set {
+        key: String,
+        value: String,
+    },
+    Remove {
+        key: String,
+    },
+    /// Commands that are applied atomically, written by `KvTxn::commit`
+    Batch(Vec<Command>),
+    /// A `Set` that expires at a time in milliseconds since the Unix epoch,
+    /// written since format version 2
+    SetExpiring {
+        key: String,
+        value: String,
+        expires_at: u64,
+    },
+    /// Commands that are applied atomically, numbered from `seq` on,
+    /// written by `KvStore::write_batch` since format version 3.
+    ///
+    /// A compaction log starts with one without commands,
+    /// numbered after the last change before the compaction.
+    Sequenced {
+        seq: u64,
+        cmds: Vec<Command>,
+    },
+    /// An operand that the merge operator called `operator` folds into the value,
+    /// written since format version 4
+    Merge {
+        key: String,
+        operator: String,
+        operand: String,
+    },
+    /// Removes every key between `start` and `end`, written since format version 5
+    RemoveRange {
+        start: Bound<String>,
+        end: Bound<String>,
+    },
+    /// A `Set` whose value of `len` bytes is stored in the blob named after
+    /// its hash, written since format version 6
+    SetBlob {
+        key: String,
+        blob: String,
+        len: u64,
+        expires_at: Option<u64>,
+    },
 }
 
 impl Command {
@@ -560,17 +2523,149 @@ impl Command {
         Command::Set { key, value }
     }
 
+    fn set_expiring(key: String, value: String, expires_at: Option<u64>) -> Command {
+        match expires_at {
+            Some(expires_at) => Command::SetExpiring {
+                key,
+                value,
+                expires_at,
+            },
+            None => Command::set(key, value),
+        }
+    }
+
     fn remove(key: String) -> Command {
         Command::Remove { key }
     }
+
+    fn merge(key: String, operator: String, operand: String) -> Command {
+        Command::Merge {
+            key,
+            operator,
+            operand,
+        }
+    }
+
+    fn remove_range(start: Bound<String>, end: Bound<String>) -> Command {
+        Command::RemoveRange { start, end }
+    }
+}
+
+/// Returns the changes made by the given commands, numbered from `seq` on
+fn changes(seq: u64, cmds: &[Command]) -> Vec<Change> {
+    (seq..)
+        .zip(cmds)
+        .filter_map(|(seq, cmd)| {
+            let (key, kind) = match cmd {
+                Command::Set { key, value } | Command::SetExpiring { key, value, .. } => {
+                    (key.clone(), ChangeKind::Set(value.clone()))
+                }
+                Command::Remove { key } => (key.clone(), ChangeKind::Remove),
+                Command::Merge { key, operand, .. } => {
+                    (key.clone(), ChangeKind::Merge(operand.clone()))
+                }
+                Command::SetBlob { key, len, .. } => {
+                    (key.clone(), ChangeKind::SetBlob { len: *len })
+                }
+                Command::RemoveRange { start, end } => (
+                    String::new(),
+                    ChangeKind::RemoveRange {
+                        start: start.clone(),
+                        end: end.clone(),
+                    },
+                ),
+                // batches are never nested
+                Command::Batch(_) | Command::Sequenced { .. } => return None,
+            };
+            Some(Change { seq, key, kind })
+        })
+        .collect()
+}
+
+/// How the commands of a log are encoded
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Encoding {
+    /// Written before the log header was introduced
+    Json,
+    /// Written by the current `record::FORMAT_VERSION`
+    Bincode,
+}
+
+impl Encoding {
+    fn encode(self, cmd: &Command) -> Result<Vec<u8>> {
+        match self {
+            Encoding::Json => Ok(serde_json::to_vec(cmd)?),
+            Encoding::Bincode => Ok(bincode::serialize(cmd)?),
+        }
+    }
+
+    fn decode(self, payload: &[u8]) -> Result<Command> {
+        match self {
+            Encoding::Json => Ok(serde_json::from_slice(payload)?),
+            Encoding::Bincode => Ok(bincode::deserialize(payload)?),
+        }
+    }
+
+    /// Returns where each command of a batch is found in the encoded batch,
+    /// given the batch without any commands
+    fn batch_offsets(self, empty: &Command, cmds: &[Command]) -> Result<Vec<Range<u64>>> {
+        let (mut pos, separator) = match self {
+            Encoding::Json => (
+                JSON_BATCH_PREFIX.len() as u64,
+                JSON_BATCH_SEPARATOR.len() as u64,
+            ),
+            Encoding::Bincode => (bincode::serialized_size(empty)?, 0),
+        };
+
+        let mut offsets = Vec::with_capacity(cmds.len());
+        for cmd in cmds {
+            let len = match self {
+                Encoding::Json => serde_json::to_vec(cmd)?.len() as u64,
+                Encoding::Bincode => bincode::serialized_size(cmd)?,
+            };
+            offsets.push(pos..pos + len);
+            pos += len + separator;
+        }
+        Ok(offsets)
+    }
+}
+
+/// A snapshot of the index, written next to the log of a compaction,
+/// from which the index can be loaded without replaying the log
+#[derive(Serialize, Deserialize)]
+struct Hint<'a> {
+    /// Length of the log when the hint was written
+    log_len: u64,
+    /// Sequence number of the last change before the log was written
+    seq: u64,
+    /// When the log was written, in milliseconds since the Unix epoch
+    compacted_at: u64,
+    index: Cow<'a, BTreeMap<String, CommandPos>>,
+    /// The commands below the last one of keys whose merges were not folded
+    operands: Cow<'a, Operands>,
+    /// When the keys were last read or written, in milliseconds since the Unix epoch
+    accessed: Cow<'a, HashMap<String, u64>>,
 }
 
-/// Represents the position and length of a json-serialized command in the log
-#[derive(Debug, Clone, Copy)]
+/// The commands below the last merge of each key, see `apply`,
+/// from the one that set the key on
+type Operands = BTreeMap<String, Vec<CommandPos>>;
+
+/// Represents the position and length of an encoded command in the log
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
 struct CommandPos {
     gen: u64,
     pos: u64,
     len: u64,
+    // when the value expires, in milliseconds since the Unix epoch
+    expires_at: Option<u64>,
+}
+
+impl CommandPos {
+    /// Returns whether the value has expired at the time `now`
+    fn is_expired(&self, now: u64) -> bool {
+        self.expires_at.is_some_and(|expires_at| expires_at <= now)
+    }
 }
 
 impl From<(u64, Range<u64>)> for CommandPos {
@@ -579,10 +2674,25 @@ impl From<(u64, Range<u64>)> for CommandPos {
             gen,
             pos: range.start,
             len: range.end - range.start,
+            expires_at: None,
         }
     }
 }
 
+/// Returns the current time in milliseconds since the Unix epoch
+#[cfg(target_arch = "wasm32")]
+pub(crate) fn now() -> u64 {
+    js_sys::Date::now() as u64
+}
+
+/// Returns the current time in milliseconds since the Unix epoch
+#[cfg(not(target_arch = "wasm32"))]
+pub(crate) fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since_epoch| since_epoch.as_millis() as u64)
+}
+
 #[derive(Debug)]
 struct BufReaderWithPos<R: Read + Seek> {
     reader: BufReader<R>,
@@ -591,12 +2701,16 @@ struct BufReaderWithPos<R: Read + Seek> {
 
 impl<R: Read + Seek> BufReaderWithPos<R> {
     fn new(mut inner: R) -> Result<Self> {
-        let pos = inner.seek(SeekFrom::Current(0))?;
+        let pos = inner.stream_position()?;
         Ok(BufReaderWithPos {
             reader: BufReader::new(inner),
             pos,
         })
     }
+
+    fn get_mut(&mut self) -> &mut R {
+        self.reader.get_mut()
+    }
 }
 
 impl<R: Read + Seek> Read for BufReaderWithPos<R> {
@@ -621,7 +2735,7 @@ struct BufWriterWithPos<W: Write + Seek> {
 
 impl<W: Write + Seek> BufWriterWithPos<W> {
     fn new(mut inner: W) -> Result<Self> {
-        let pos = inner.seek(SeekFrom::Current(0))?;
+        let pos = inner.stream_position()?;
         Ok(BufWriterWithPos {
             writer: BufWriter::new(inner),
             pos,
@@ -629,6 +2743,13 @@ impl<W: Write + Seek> BufWriterWithPos<W> {
     }
 }
 
+impl<W: BackendFile> BufWriterWithPos<W> {
+    /// Waits until everything that was flushed is stored durably
+    async fn sync(&mut self) -> Result<()> {
+        self.writer.get_mut().sync().await
+    }
+}
+
 impl<W: Write + Seek> Write for BufWriterWithPos<W> {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
         let len = self.writer.write(buf)?;
@@ -0,0 +1,174 @@
+//! Blind writes of many keys at once, see `WriteBatch`.
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use serde::Serialize;
+
+use super::{now, Command, KvStore};
+use crate::{Backend, KvsError, Result};
+
+/// Collects sets and removes that `KvStore::write` applies at once.
+///
+/// Unlike a `KvTxn`, a batch holds no borrow of the store and reads nothing,
+/// so it can be filled up front, e.g. for a bulk load. The last write of a key wins.
+///
+/// Like a `KvTxn`, a batch writes to the store or to a single substore.
+/// A `KvTxn` buffers its sets and removes in a batch as well.
+#[derive(Debug, Clone, Default)]
+pub struct WriteBatch {
+    // writes keyed by substore and key, `None` marks a removal
+    pub(super) writes: BTreeMap<(Option<PathBuf>, String), Option<String>>,
+    // when the writes that have a time to live expire
+    pub(super) expiries: BTreeMap<(Option<PathBuf>, String), u64>,
+}
+
+impl WriteBatch {
+    /// Returns an empty batch
+    pub fn new() -> Self {
+        WriteBatch::default()
+    }
+
+    /// Sets a value, like `KvTxn::set`
+    pub fn set<T: ?Sized + Serialize>(&mut self, key: String, value: &T) -> Result<()> {
+        self.set_raw(None, key, serde_json::to_string(value)?, None);
+        Ok(())
+    }
+
+    /// Sets a value that expires once `ttl` has passed since the call, like `KvTxn::set_expiring`
+    pub fn set_expiring<T: ?Sized + Serialize>(
+        &mut self,
+        key: String,
+        value: &T,
+        ttl: Duration,
+    ) -> Result<()> {
+        let expires_at = now().saturating_add(ttl.as_millis() as u64);
+        self.set_raw(None, key, serde_json::to_string(value)?, Some(expires_at));
+        Ok(())
+    }
+
+    /// Sets an encoded value in a substore, or in the store for `None`, like `KvTxn::set_scoped`
+    pub fn set_scoped(&mut self, key: String, value: String, substore: Option<&Path>) {
+        self.set_raw(substore, key, value, None);
+    }
+
+    /// Removes a key, which is not an error if the key is absent
+    pub fn remove(&mut self, key: String) {
+        self.remove_scoped(key, None);
+    }
+
+    /// Removes a key of a substore, or of the store for `None`, see `remove`
+    pub fn remove_scoped(&mut self, key: String, substore: Option<&Path>) {
+        let key = (substore.map(Path::to_path_buf), key);
+        self.expiries.remove(&key);
+        self.writes.insert(key, None);
+    }
+
+    /// Returns the number of keys that are written
+    pub fn len(&self) -> usize {
+        self.writes.len()
+    }
+
+    /// Returns whether nothing is written
+    pub fn is_empty(&self) -> bool {
+        self.writes.is_empty()
+    }
+
+    /// Discards all writes, so the batch can be filled again
+    pub fn clear(&mut self) {
+        self.writes.clear();
+        self.expiries.clear();
+    }
+
+    pub(super) fn set_raw(
+        &mut self,
+        substore: Option<&Path>,
+        key: String,
+        value: String,
+        expires_at: Option<u64>,
+    ) {
+        let key = (substore.map(Path::to_path_buf), key);
+        match expires_at {
+            Some(expires_at) => self.expiries.insert(key.clone(), expires_at),
+            None => self.expiries.remove(&key),
+        };
+        self.writes.insert(key, Some(value));
+    }
+
+    /// Turns the writes into the commands for each substore, in the order of their keys.
+    ///
+    /// A removal is only written if `exists` returns true for its substore and key.
+    pub(super) fn into_commands(
+        self,
+        mut exists: impl FnMut(Option<&Path>, &String) -> bool,
+    ) -> BTreeMap<Option<PathBuf>, Vec<Command>> {
+        let WriteBatch {
+            writes,
+            mut expiries,
+        } = self;
+
+        let mut batches: BTreeMap<Option<PathBuf>, Vec<Command>> = BTreeMap::new();
+        for ((substore, key), value) in writes {
+            let cmd = match value {
+                Some(value) => {
+                    let expires_at = expiries.remove(&(substore.clone(), key.clone()));
+                    Command::set_expiring(key, value, expires_at)
+                }
+                None if exists(substore.as_deref(), &key) => Command::remove(key),
+                None => continue,
+            };
+            batches.entry(substore).or_default().push(cmd);
+        }
+        batches
+    }
+}
+
+impl<B: Backend> KvStore<B> {
+    /// Applies a batch of writes, and resolves once they are stored durably.
+    ///
+    /// The writes are appended as a single record, with one pass over the
+    /// index, one step of an automatic compaction and one flush, instead of
+    /// one for every key. Removes of absent keys are left out.
+    ///
+    /// # Errors
+    ///
+    /// It propagates the errors of `KvTxn::commit`, and like it writes nothing
+    /// if a write is scoped to a substore that was not added, or if the writes
+    /// span substores.
+    pub async fn write(&mut self, batch: WriteBatch) -> Result<()> {
+        for (substore, _) in batch.writes.keys() {
+            self.logs_mut(substore.as_deref())?;
+        }
+        let batches = batch.into_commands(|substore, key| {
+            self.logs_mut(substore)
+                .is_ok_and(|(_, logs)| logs.index.contains_key(key))
+        });
+        self.write_batches(batches).await
+    }
+
+    /// Writes the commands of a commit as a batch, see `write_batch`.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::UnknownSubstore` if the substore was not added, and
+    /// `KvsError::CrossSubstoreCommit` if the commands span substores,
+    /// without writing anything.
+    pub(super) async fn write_batches(
+        &mut self,
+        batches: BTreeMap<Option<PathBuf>, Vec<Command>>,
+    ) -> Result<()> {
+        for substore in batches.keys() {
+            self.logs_mut(substore.as_deref())?;
+        }
+        // only the record of a single log is replayed all or nothing
+        if batches.len() > 1 {
+            return Err(KvsError::CrossSubstoreCommit);
+        }
+
+        match batches.into_iter().next() {
+            Some((substore, cmds)) => self.write_batch(substore.as_deref(), cmds).await,
+            None => Ok(()),
+        }
+    }
+}
@@ -0,0 +1,529 @@
+//! Large values that are stored next to the logs instead of in them,
+//! see `KvStore::set_blob_threshold`.
+//!
+//! A blob is a file named after the SHA-256 hash of the value, so a value
+//! that is set under several keys, or set again, is stored once. The logs
+//! only hold a `Command::SetBlob` that refers to it. A compaction counts
+//! the references of the commands that it copies, and of the commands that
+//! are written while it runs, and the blobs without any are removed once
+//! no snapshot reads from the logs that the compaction retired.
+
+use std::collections::BTreeSet;
+use std::ffi::OsStr;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::iter;
+use std::mem;
+use std::path::{Path, PathBuf};
+
+use ring::digest::{self, Digest, SHA256};
+
+use super::{log_path, now, read_command, Command, KvStore, Logs};
+use crate::record;
+use crate::{Backend, BackendFile, IdbFolder, KvsError, Result};
+
+/// Extension of the files that hold blobs
+const BLOB_EXTENSION: &str = "blob";
+
+/// Extension of the files that values are streamed to, until they are hashed
+const PART_EXTENSION: &str = "part";
+
+/// Number of bytes that are copied from a part file to its blob at once
+const CHUNK_LEN: u64 = 64 * 1024;
+
+/// Reads a value in chunks, see `KvStore::read_blob`.
+///
+/// The reader holds a file of its own instead of a borrow of the store.
+/// A compaction removes the blob once no key refers to it anymore,
+/// so the value is meant to be read right away.
+pub struct BlobReader<B: Backend = IdbFolder> {
+    source: Source<B::File>,
+    pos: u64,
+    len: u64,
+}
+
+enum Source<F> {
+    // a blob, which is fetched chunk by chunk
+    Blob(F),
+    // a value that is stored in the logs, and was read at once
+    Inline(Vec<u8>),
+}
+
+impl<B: Backend> BlobReader<B> {
+    /// Returns the number of bytes of the value
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    /// Returns whether the value is empty
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Reads the next bytes of the value into `buf`, and returns how many
+    /// were read, which is 0 once the whole value was read.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O errors during reading the blob.
+    pub async fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        let read = (self.len - self.pos).min(buf.len() as u64);
+        let range = self.pos..self.pos + read;
+        match &mut self.source {
+            Source::Blob(file) => {
+                file.fetch(range.clone()).await?;
+                file.seek(SeekFrom::Start(range.start))?;
+                file.read_exact(&mut buf[..read as usize])?;
+            }
+            Source::Inline(value) => buf[..read as usize]
+                .copy_from_slice(&value[range.start as usize..range.end as usize]),
+        }
+        self.pos = range.end;
+        Ok(read as usize)
+    }
+}
+
+/// Streams a value to a blob, see `KvStore::blob_writer`.
+///
+/// The bytes are written to a part file of their own, and are hashed on the way.
+/// Dropping the writer without committing it leaves the part file behind,
+/// which is removed when the store is opened next.
+pub struct BlobWriter<B: Backend = IdbFolder> {
+    key: String,
+    substore: Option<PathBuf>,
+    path: PathBuf,
+    file: B::File,
+    digest: digest::Context,
+    len: u64,
+}
+
+impl<B: Backend> Write for BlobWriter<B> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.file.write(buf)?;
+        self.digest.update(&buf[..written]);
+        self.len += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+impl<B: Backend> KvStore<B> {
+    /// Returns the number of bytes from which on values are stored in blobs,
+    /// `None` if every value is stored in the logs
+    pub fn blob_threshold(&self) -> Option<u64> {
+        self.blob_threshold
+    }
+
+    /// Stores values of at least `threshold` bytes in blobs, instead of in the logs,
+    /// from the next write on. Values that were written before stay where they are.
+    ///
+    /// The logs only refer to the blob of a value, so compactions copy the
+    /// reference instead of the value, and remove the blobs that no key refers
+    /// to anymore. Equal values share a blob. A value that merges are folded
+    /// into is stored in the logs again by the compaction that folds them.
+    pub fn set_blob_threshold(&mut self, threshold: Option<u64>) {
+        self.blob_threshold = threshold;
+    }
+
+    /// Starts streaming the value of `key` to a blob, regardless of the blob threshold.
+    ///
+    /// The value is written through the returned writer, and is set once it is
+    /// committed, see `commit_blob`. It may be binary, e.g. an image or a file
+    /// attachment, in which case it can only be read with `read_blob`.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::UnknownSubstore` if the substore was not added,
+    /// and propagates I/O errors during creating the file the value is streamed to.
+    pub async fn blob_writer(
+        &mut self,
+        key: String,
+        substore: Option<&Path>,
+    ) -> Result<BlobWriter<B>> {
+        let (sink, logs) = self.logs_mut(substore)?;
+        logs.parts += 1;
+        let path = logs.path.join(format!("{}.{}", logs.parts, PART_EXTENSION));
+        let mut file = sink.open_file(&path).await?;
+        file.set_len(0)?;
+        file.seek(SeekFrom::Start(0))?;
+
+        Ok(BlobWriter {
+            key,
+            substore: substore.map(Path::to_path_buf),
+            path,
+            file,
+            digest: digest::Context::new(&SHA256),
+            len: 0,
+        })
+    }
+
+    /// Moves the value streamed to `writer` to its blob, and sets its key to it
+    /// in a write of its own, which is durable once this resolves.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::QuotaExceeded` if the reference to the blob does
+    /// not fit into the quota, and propagates I/O errors during writing the blob.
+    pub async fn commit_blob(&mut self, writer: BlobWriter<B>) -> Result<()> {
+        let BlobWriter {
+            key,
+            substore,
+            path,
+            mut file,
+            digest,
+            len,
+        } = writer;
+        file.flush()?;
+        let blob = hex(digest.finish());
+        let cmd = Command::SetBlob {
+            key,
+            blob: blob.clone(),
+            len,
+            expires_at: None,
+        };
+
+        // a compaction that makes room may remove the blob as garbage,
+        // so it is written afterwards
+        if let Some(quota) = self.quota.clone() {
+            let incoming = bincode::serialized_size(&cmd)? + record::HEADER_LEN;
+            self.enforce_quota(&quota, incoming).await?;
+        }
+        let policy = self.compaction_policy.clone();
+        let (sink, logs) = self.logs_mut(substore.as_deref())?;
+        if !logs.has_blob(sink, &blob, len).await? {
+            let mut target = create_blob(sink, &logs.path, &blob).await?;
+            copy(&mut file, &mut target, len).await?;
+            logs.blobs.insert(blob.clone());
+        }
+        drop(file);
+        sink.remove_file(&path).await?;
+        logs.refer(&blob);
+
+        logs.write_batch(sink, vec![cmd], &policy).await
+    }
+
+    /// Writes the blobs of the store and every substore anew, see `Logs::rewrite_blobs`
+    pub(crate) async fn rewrite_blobs(&mut self) -> Result<()> {
+        let sink = &mut self.sink;
+        for logs in iter::once(&mut self.root).chain(self.substores.values_mut()) {
+            logs.rewrite_blobs(sink).await?;
+        }
+        Ok(())
+    }
+
+    /// Returns a reader of the value of a key in a substore, or in the store
+    /// for `None`, which is `None` if the key does not exist or has expired.
+    ///
+    /// Values in blobs are read chunk by chunk, values in the logs at once.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::UnknownSubstore` if the substore was not added,
+    /// and `KvsError::MissingBlob` if the blob of the value was lost.
+    pub async fn read_blob(
+        &mut self,
+        key: &str,
+        substore: Option<&Path>,
+    ) -> Result<Option<BlobReader<B>>> {
+        let (sink, logs) = self.logs_mut(substore)?;
+        logs.read_blob(sink, key).await
+    }
+}
+
+impl<B: Backend> Logs<B> {
+    /// Moves the values of sets of at least `threshold` bytes to blobs,
+    /// and returns the commands with sets that refer to the blobs instead.
+    pub(super) async fn store_blobs(
+        &mut self,
+        sink: &mut B,
+        cmds: Vec<Command>,
+        threshold: u64,
+    ) -> Result<Vec<Command>> {
+        let mut stored = Vec::with_capacity(cmds.len());
+        for cmd in cmds {
+            let (key, value, expires_at) = match cmd {
+                Command::Set { key, value } if value.len() as u64 >= threshold => {
+                    (key, value, None)
+                }
+                Command::SetExpiring {
+                    key,
+                    value,
+                    expires_at,
+                } if value.len() as u64 >= threshold => (key, value, Some(expires_at)),
+                cmd => {
+                    stored.push(cmd);
+                    continue;
+                }
+            };
+
+            let blob = hex(digest::digest(&SHA256, value.as_bytes()));
+            let len = value.len() as u64;
+            if !self.has_blob(sink, &blob, len).await? {
+                let mut file = create_blob(sink, &self.path, &blob).await?;
+                file.write_all(value.as_bytes())?;
+                file.flush()?;
+                file.sync().await?;
+                self.blobs.insert(blob.clone());
+            }
+            self.refer(&blob);
+            stored.push(Command::SetBlob {
+                key,
+                blob,
+                len,
+                expires_at,
+            });
+        }
+        Ok(stored)
+    }
+
+    /// Returns whether the blob is stored with all of its bytes
+    async fn has_blob(&mut self, sink: &mut B, blob: &str, len: u64) -> Result<bool> {
+        if !self.blobs.contains(blob) {
+            return Ok(false);
+        }
+        // the write of the blob may have been interrupted
+        let mut file = sink.open_file(&blob_path(&self.path, blob)).await?;
+        Ok(file.seek(SeekFrom::End(0))? == len)
+    }
+
+    /// Keeps a blob that a new command refers to from being removed
+    fn refer(&mut self, blob: &str) {
+        self.garbage.remove(blob);
+        if let Some(compaction) = &mut self.compaction {
+            *compaction.refs.entry(blob.to_owned()).or_default() += 1;
+        }
+    }
+
+    /// Removes the blobs that no command referred to when the last compaction
+    /// finished, and that were not referred to again since
+    pub(super) async fn remove_garbage(&mut self, sink: &mut B) {
+        for blob in mem::take(&mut self.garbage) {
+            self.blobs.remove(&blob);
+            let path = blob_path(&self.path, &blob);
+            if sink.remove_file(&path).await.is_err() {
+                info!("Could not remove garbage blob {}", path.display());
+            }
+        }
+    }
+
+    /// Writes every blob that is not garbage anew, e.g. so that it is encrypted
+    /// with the current key of an `EncryptedBackend`.
+    ///
+    /// A blob can not be written in place, so it is copied to a part file named
+    /// after it first, from which `open_blobs` restores the blob if the rewrite
+    /// is interrupted.
+    pub(super) async fn rewrite_blobs(&mut self, sink: &mut B) -> Result<()> {
+        for blob in self.blobs.difference(&self.garbage) {
+            let path = blob_path(&self.path, blob);
+            let mut file = sink.open_file(&path).await?;
+            let len = file.seek(SeekFrom::End(0))?;
+            let part = rewrite_path(&self.path, blob);
+            let mut copied = sink.open_file(&part).await?;
+            copied.set_len(0)?;
+            copy(&mut file, &mut copied, len).await?;
+            drop(file);
+
+            sink.remove_file(&path).await?;
+            copy(
+                &mut copied,
+                &mut create_blob(sink, &self.path, blob).await?,
+                len,
+            )
+            .await?;
+            drop(copied);
+            sink.remove_file(&part).await?;
+        }
+        Ok(())
+    }
+
+    /// Returns a reader of the value of a key, see `KvStore::read_blob`
+    async fn read_blob(&mut self, sink: &mut B, key: &str) -> Result<Option<BlobReader<B>>> {
+        let now = now();
+        let cmd_pos = match self.index.get(key) {
+            Some(&cmd_pos) if !cmd_pos.is_expired(now) => cmd_pos,
+            _ => return Ok(None),
+        };
+
+        // merges are folded into the value, which is read at once
+        if self.operands.contains_key(key) {
+            let value = self.get(sink, key).await?;
+            return Ok(value.map(|value| BlobReader::inline(value.into_bytes())));
+        }
+
+        self.accessed.insert(key.to_owned(), now);
+        let reader = self
+            .readers
+            .get_mut(&log_path(&self.path, cmd_pos.gen))
+            .expect("Cannot find log reader");
+        match read_command(reader, &cmd_pos).await? {
+            Command::SetBlob { key, blob, len, .. } => {
+                let file = open_blob(sink, &self.path, &self.blobs, &key, &blob, len).await?;
+                Ok(Some(BlobReader {
+                    source: Source::Blob(file),
+                    pos: 0,
+                    len,
+                }))
+            }
+            Command::Set { value, .. } | Command::SetExpiring { value, .. } => {
+                Ok(Some(BlobReader::inline(value.into_bytes())))
+            }
+            _ => Err(KvsError::UnexpectedCommandType),
+        }
+    }
+}
+
+impl<B: Backend> BlobReader<B> {
+    /// Returns a reader of a value that was read from the logs
+    fn inline(value: Vec<u8>) -> Self {
+        BlobReader {
+            len: value.len() as u64,
+            source: Source::Inline(value),
+            pos: 0,
+        }
+    }
+}
+
+/// Returns the hashes of the blobs in `dir`, and removes the part files
+/// of values whose streaming was never committed.
+///
+/// A blob whose rewrite was interrupted is restored from its copy,
+/// which is complete once the blob is shorter, see `Logs::rewrite_blobs`.
+pub(super) async fn open_blobs<B: Backend>(dir: &Path, sink: &mut B) -> Result<BTreeSet<String>> {
+    let mut blobs = BTreeSet::new();
+    let mut rewritten = Vec::new();
+    for file_name in sink.file_names().await? {
+        let path = Path::new(&file_name);
+        // files of substores are in directories below `dir`
+        if path.parent() != Some(dir) {
+            continue;
+        }
+        let stem = match path.file_stem().and_then(OsStr::to_str) {
+            Some(stem) => stem,
+            None => continue,
+        };
+        match path.extension().and_then(OsStr::to_str) {
+            Some(BLOB_EXTENSION) => {
+                blobs.insert(stem.to_owned());
+            }
+            // streamed values are numbered, copies are named after their blob
+            Some(PART_EXTENSION) if stem.parse::<u64>().is_err() => {
+                rewritten.push(stem.to_owned());
+            }
+            Some(PART_EXTENSION) => sink.remove_file(path).await?,
+            _ => {}
+        }
+    }
+
+    for blob in rewritten {
+        let part = rewrite_path(dir, &blob);
+        let mut copied = sink.open_file(&part).await?;
+        let len = copied.seek(SeekFrom::End(0))?;
+        let blob_len = if blobs.contains(&blob) {
+            let path = blob_path(dir, &blob);
+            let blob_len = sink.open_file(&path).await?.seek(SeekFrom::End(0))?;
+            if blob_len < len {
+                sink.remove_file(&path).await?;
+            }
+            blob_len
+        } else {
+            0
+        };
+        if blob_len < len {
+            copy(&mut copied, &mut create_blob(sink, dir, &blob).await?, len).await?;
+            blobs.insert(blob);
+        }
+        drop(copied);
+        sink.remove_file(&part).await?;
+    }
+    Ok(blobs)
+}
+
+/// Reads the value of a key from its blob.
+///
+/// # Errors
+///
+/// It returns `KvsError::MissingBlob` if the blob was lost,
+/// and `KvsError::BinaryValue` if the value is not UTF-8.
+pub(super) async fn read_blob_value<B: Backend>(
+    sink: &mut B,
+    dir: &Path,
+    blobs: &BTreeSet<String>,
+    key: String,
+    blob: &str,
+    len: u64,
+) -> Result<String> {
+    let mut file = open_blob(sink, dir, blobs, &key, blob, len).await?;
+    file.fetch(0..len).await?;
+    file.seek(SeekFrom::Start(0))?;
+    let mut value = vec![0; len as usize];
+    file.read_exact(&mut value)?;
+    String::from_utf8(value).map_err(|_| KvsError::BinaryValue { key })
+}
+
+/// Opens the blob of a key, which has to hold `len` bytes
+async fn open_blob<B: Backend>(
+    sink: &mut B,
+    dir: &Path,
+    blobs: &BTreeSet<String>,
+    key: &str,
+    blob: &str,
+    len: u64,
+) -> Result<B::File> {
+    let missing = || KvsError::MissingBlob {
+        key: key.to_owned(),
+        blob: blob.to_owned(),
+    };
+    if !blobs.contains(blob) {
+        return Err(missing());
+    }
+    let mut file = sink.open_file(&blob_path(dir, blob)).await?;
+    if file.seek(SeekFrom::End(0))? != len {
+        return Err(missing());
+    }
+    Ok(file)
+}
+
+/// Opens the blob with the given hash, emptied to be written from the start
+async fn create_blob<B: Backend>(sink: &mut B, dir: &Path, blob: &str) -> Result<B::File> {
+    let mut file = sink.open_file(&blob_path(dir, blob)).await?;
+    file.set_len(0)?;
+    file.seek(SeekFrom::Start(0))?;
+    Ok(file)
+}
+
+/// Copies the first `len` bytes of `source` to `target` chunk by chunk,
+/// and syncs `target`
+async fn copy<F: BackendFile>(source: &mut F, target: &mut F, len: u64) -> Result<()> {
+    let mut chunk = vec![0; CHUNK_LEN.min(len) as usize];
+    let mut pos = 0;
+    while pos < len {
+        let copied = CHUNK_LEN.min(len - pos);
+        source.fetch(pos..pos + copied).await?;
+        source.seek(SeekFrom::Start(pos))?;
+        source.read_exact(&mut chunk[..copied as usize])?;
+        target.write_all(&chunk[..copied as usize])?;
+        pos += copied;
+    }
+    target.flush()?;
+    target.sync().await
+}
+
+pub(super) fn blob_path(dir: &Path, blob: &str) -> PathBuf {
+    dir.join(format!("{}.{}", blob, BLOB_EXTENSION))
+}
+
+/// Returns the path of the copy of a blob that is being rewritten
+fn rewrite_path(dir: &Path, blob: &str) -> PathBuf {
+    dir.join(format!("{}.{}", blob, PART_EXTENSION))
+}
+
+/// Returns the lowercase hex digits of a hash, which name its blob
+fn hex(digest: Digest) -> String {
+    digest
+        .as_ref()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
@@ -0,0 +1,330 @@
+//! Secondary indexes over the json values of a `KvStore`, see `KvStore::define_index`.
+
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::iter;
+use std::ops::{Bound, RangeBounds};
+use std::path::{Path, PathBuf};
+
+use serde_json::Value;
+
+use super::{is_empty_range, unknown_substore, KvStore, Logs};
+use crate::feed::{Change, ChangeKind};
+use crate::{Backend, KvsError, Result};
+
+/// Describes which keys `KvStore::lookup` finds through a secondary index
+#[derive(Debug, Clone)]
+pub struct Lookup {
+    index: String,
+    start: Bound<Value>,
+    end: Bound<Value>,
+    substore: Option<PathBuf>,
+}
+
+impl Lookup {
+    /// Finds the keys whose indexed value equals `value`
+    pub fn eq(index: impl Into<String>, value: impl Into<Value>) -> Self {
+        let value = value.into();
+        Lookup::range(
+            index,
+            Bound::Included(value.clone()),
+            Bound::Included(value),
+        )
+    }
+
+    /// Finds the keys whose indexed value is between `start` and `end`.
+    ///
+    /// Values of different types are ordered `null`, booleans, numbers, strings.
+    pub fn range(index: impl Into<String>, start: Bound<Value>, end: Bound<Value>) -> Self {
+        Lookup {
+            index: index.into(),
+            start,
+            end,
+            substore: None,
+        }
+    }
+
+    /// Finds the keys of a substore instead of the keys of the store
+    pub fn scoped(mut self, substore: impl Into<PathBuf>) -> Self {
+        self.substore = Some(substore.into());
+        self
+    }
+}
+
+/// A json value that can be indexed, which is any value but an array or an object
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+enum IndexValue {
+    Null,
+    Bool(bool),
+    Number(Number),
+    String(String),
+}
+
+impl IndexValue {
+    fn from_json(value: &Value) -> Option<IndexValue> {
+        match value {
+            Value::Null => Some(IndexValue::Null),
+            Value::Bool(value) => Some(IndexValue::Bool(*value)),
+            Value::Number(value) => value
+                .as_f64()
+                .map(|value| IndexValue::Number(Number(value))),
+            Value::String(value) => Some(IndexValue::String(value.clone())),
+            Value::Array(_) | Value::Object(_) => None,
+        }
+    }
+}
+
+/// A json number, which are all compared as floats
+#[derive(Debug, Clone, Copy)]
+struct Number(f64);
+
+impl PartialEq for Number {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Number {}
+
+impl PartialOrd for Number {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Number {
+    fn cmp(&self, other: &Self) -> Ordering {
+        // json has no NaN, and -0 is the same number as 0
+        (self.0 + 0.0).total_cmp(&(other.0 + 0.0))
+    }
+}
+
+/// The keys of a store, or of a substore, by the value at a json pointer
+pub(super) struct SecondaryIndex {
+    pointer: String,
+    // the indexed value of every key that has one
+    values: HashMap<String, IndexValue>,
+    keys: BTreeMap<IndexValue, BTreeSet<String>>,
+}
+
+impl SecondaryIndex {
+    fn new(pointer: String) -> Self {
+        SecondaryIndex {
+            pointer,
+            values: HashMap::new(),
+            keys: BTreeMap::new(),
+        }
+    }
+
+    /// Indexes the new value of `key`, `None` if it was removed
+    pub(super) fn update(&mut self, key: &str, value: Option<&str>) {
+        if let Some(old) = self.values.remove(key) {
+            if let Some(keys) = self.keys.get_mut(&old) {
+                keys.remove(key);
+                if keys.is_empty() {
+                    self.keys.remove(&old);
+                }
+            }
+        }
+
+        // values that are not json, or lack the pointer, are not indexed
+        let indexed = value
+            .and_then(|value| serde_json::from_str::<Value>(value).ok())
+            .and_then(|value| value.pointer(&self.pointer).and_then(IndexValue::from_json));
+        if let Some(indexed) = indexed {
+            self.keys
+                .entry(indexed.clone())
+                .or_default()
+                .insert(key.to_owned());
+            self.values.insert(key.to_owned(), indexed);
+        }
+    }
+
+    /// Returns the keys whose value is within the bounds, ordered by value and key
+    fn range(&self, start: Bound<IndexValue>, end: Bound<IndexValue>) -> Vec<String> {
+        if is_empty_range(&start, &end) {
+            return Vec::new();
+        }
+        self.keys
+            .range((start, end))
+            .flat_map(|(_, keys)| keys.iter().cloned())
+            .collect()
+    }
+}
+
+impl<B: Backend> KvStore<B> {
+    /// Defines a secondary index called `name` over the store and its substores,
+    /// which indexes the value at the json pointer `pointer` within each value,
+    /// e.g. `/status`, see `lookup`.
+    ///
+    /// Values that are not json, that lack the pointer, or that hold an array
+    /// or an object at it, are not indexed. Indexes are not stored, they are
+    /// defined again whenever the store is opened, and built from the values.
+    /// Defining an index that exists replaces it.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O errors during reading the values.
+    pub async fn define_index(
+        &mut self,
+        name: impl Into<String>,
+        pointer: impl Into<String>,
+    ) -> Result<()> {
+        let name = name.into();
+        let pointer = pointer.into();
+        let sink = &mut self.sink;
+        for logs in iter::once(&mut self.root).chain(self.substores.values_mut()) {
+            logs.build_index(sink, name.clone(), pointer.clone())
+                .await?;
+        }
+        self.indexes.insert(name, pointer);
+        Ok(())
+    }
+
+    /// Removes a secondary index, see `define_index`
+    pub fn drop_index(&mut self, name: &str) {
+        self.indexes.remove(name);
+        for logs in iter::once(&mut self.root).chain(self.substores.values_mut()) {
+            logs.secondary.remove(name);
+        }
+    }
+
+    /// Returns the key/value pairs that a secondary index finds,
+    /// ordered by their indexed value and then by key.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::UnknownIndex` if the index was not defined, and
+    /// `KvsError::UnknownSubstore` if the lookup is scoped to a substore that was not added.
+    pub async fn lookup(&mut self, lookup: Lookup) -> Result<Vec<(String, String)>> {
+        let (sink, logs) = self.logs_mut(lookup.substore.as_deref())?;
+        let index = logs
+            .secondary
+            .get(&lookup.index)
+            .ok_or_else(|| KvsError::UnknownIndex {
+                name: lookup.index.clone(),
+            })?;
+
+        // bounds that can not be indexed match nothing
+        let keys = match (bound(&lookup.start), bound(&lookup.end)) {
+            (Some(start), Some(end)) => index.range(start, end),
+            _ => Vec::new(),
+        };
+        let mut items = Vec::with_capacity(keys.len());
+        for key in keys {
+            // the key may have expired since it was indexed
+            if let Some(value) = logs.get(sink, &key).await? {
+                items.push((key, value));
+            }
+        }
+        Ok(items)
+    }
+
+    /// Builds the secondary indexes of a substore that was just added
+    pub(super) async fn build_indexes(&mut self, sub_path: &Path) -> Result<()> {
+        let logs = self
+            .substores
+            .get_mut(sub_path)
+            .ok_or_else(|| unknown_substore(sub_path))?;
+        for (name, pointer) in &self.indexes {
+            logs.build_index(&mut self.sink, name.clone(), pointer.clone())
+                .await?;
+        }
+        Ok(())
+    }
+}
+
+impl<B: Backend> Logs<B> {
+    /// Builds the secondary index called `name` from every value
+    async fn build_index(&mut self, sink: &mut B, name: String, pointer: String) -> Result<()> {
+        let mut index = SecondaryIndex::new(pointer);
+        let keys: Vec<String> = self.index.keys().cloned().collect();
+        for key in keys {
+            index.update(&key, self.indexed_value(sink, &key).await?.as_deref());
+        }
+        self.secondary.insert(name, index);
+        Ok(())
+    }
+
+    /// Builds every secondary index again, e.g. after a compaction
+    pub(super) async fn rebuild_indexes(&mut self, sink: &mut B) -> Result<()> {
+        let indexes: Vec<(String, String)> = self
+            .secondary
+            .iter()
+            .map(|(name, index)| (name.clone(), index.pointer.clone()))
+            .collect();
+        for (name, pointer) in indexes {
+            self.build_index(sink, name, pointer).await?;
+        }
+        Ok(())
+    }
+
+    /// Indexes the values that were just written
+    pub(super) async fn update_indexes(&mut self, sink: &mut B, changes: &[Change]) -> Result<()> {
+        let mut read_back = BTreeSet::new();
+        for change in changes {
+            let value = match &change.kind {
+                ChangeKind::Set(value) => Some(value.as_str()),
+                ChangeKind::Remove => None,
+                ChangeKind::Merge(_) | ChangeKind::SetBlob { .. } => {
+                    read_back.insert(&change.key);
+                    continue;
+                }
+                ChangeKind::RemoveRange { start, end } => {
+                    let range = (start.as_ref(), end.as_ref());
+                    for index in self.secondary.values_mut() {
+                        let removed: Vec<String> = index
+                            .values
+                            .keys()
+                            .filter(|key| range.contains(*key))
+                            .cloned()
+                            .collect();
+                        for key in removed {
+                            index.update(&key, None);
+                        }
+                    }
+                    continue;
+                }
+            };
+            for index in self.secondary.values_mut() {
+                index.update(&change.key, value);
+            }
+        }
+
+        // a merge only writes its operand, and a blob is not in the log,
+        // so the value is read back
+        for key in read_back {
+            let value = self.indexed_value(sink, key).await?;
+            for index in self.secondary.values_mut() {
+                index.update(key, value.as_deref());
+            }
+        }
+        Ok(())
+    }
+
+    /// Reads the value of a key to index it, expired or not.
+    ///
+    /// A value whose merges can not be folded, or that is binary, is not indexed.
+    async fn indexed_value(&mut self, sink: &mut B, key: &str) -> Result<Option<String>> {
+        let cmd_pos = match self.index.get(key) {
+            Some(&cmd_pos) => cmd_pos,
+            None => return Ok(None),
+        };
+        let operands = self.operands.get(key).cloned().unwrap_or_default();
+        match self.read_value(sink, &cmd_pos, &operands).await {
+            Err(KvsError::MergeFailed { .. })
+            | Err(KvsError::UnknownMergeOperator { .. })
+            | Err(KvsError::BinaryValue { .. }) => Ok(None),
+            value => value,
+        }
+    }
+}
+
+/// Returns the bound of an index lookup, `None` if the value can not be indexed
+fn bound(bound: &Bound<Value>) -> Option<Bound<IndexValue>> {
+    match bound {
+        Bound::Included(value) => IndexValue::from_json(value).map(Bound::Included),
+        Bound::Excluded(value) => IndexValue::from_json(value).map(Bound::Excluded),
+        Bound::Unbounded => Some(Bound::Unbounded),
+    }
+}
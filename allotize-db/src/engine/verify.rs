@@ -0,0 +1,506 @@
+//! Integrity checks of a `KvStore`, see `KvStore::verify` and `KvStore::repair`.
+
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::io::{Seek, SeekFrom, Write};
+use std::iter;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use super::blob::open_blobs;
+use super::{
+    hint_path, latest_hint, log_path, new_log_file, read_command, read_record, replay,
+    sorted_gen_list, BufReaderWithPos, Command, CommandPos, CompactionPolicy, Encoding, KvStore,
+    Logs, Operands, HINT_EXTENSION,
+};
+use crate::feed::Feed;
+use crate::merge::MergeOperators;
+use crate::record::{self, Frame};
+use crate::{Backend, BackendFile, Result};
+
+/// Something wrong with a store, found by `KvStore::verify`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum Problem {
+    /// An index entry that does not point at a value of its key
+    BadEntry {
+        /// The substore of the key, `None` for the store itself
+        substore: Option<PathBuf>,
+        key: String,
+        /// What the entry points at instead
+        reason: String,
+    },
+    /// A record of a log that can not be read
+    UnreadableRecord {
+        /// Path of the log
+        path: PathBuf,
+        /// Position of the record in the log
+        pos: u64,
+        /// Why the record can not be read
+        reason: String,
+    },
+    /// A log or hint file that none of the logs of the store refer to
+    OrphanedFile {
+        /// Path of the file
+        path: PathBuf,
+    },
+}
+
+/// The outcome of `KvStore::verify`
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Report {
+    /// Every problem that was found, empty for a healthy store
+    pub problems: Vec<Problem>,
+}
+
+impl Report {
+    /// Returns whether no problems were found
+    pub fn is_ok(&self) -> bool {
+        self.problems.is_empty()
+    }
+}
+
+/// What `KvStore::repair` could not recover
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct RepairReport {
+    /// Keys that were lost, with their substore
+    pub lost: Vec<(Option<PathBuf>, String)>,
+    /// Keys whose value is now an older one, with their substore,
+    /// since the newer value could not be read.
+    /// This includes keys whose removal could not be read.
+    /// Always empty for a store that was not open before, see `KvStore::open_repaired`.
+    pub reverted: Vec<(Option<PathBuf>, String)>,
+    /// Number of records that could not be read, and were dropped
+    pub unreadable_records: u64,
+    /// Log and hint files that none of the logs referred to, and were removed
+    pub removed_files: Vec<PathBuf>,
+}
+
+impl<B: Backend> KvStore<B> {
+    /// Checks the store and every substore that was added.
+    ///
+    /// Every index entry must point at a readable command that sets its key,
+    /// every record of every log must pass its checksum, and every log and hint
+    /// file must belong to the logs. Nothing is changed, see `repair`.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O errors of the backend, problems with the store
+    /// itself are reported instead.
+    pub async fn verify(&mut self) -> Result<Report> {
+        let mut report = Report::default();
+        let sink = &self.sink;
+        for (substore, logs) in iter::once((None, &mut self.root)).chain(
+            self.substores
+                .iter_mut()
+                .map(|(sub_path, logs)| (Some(sub_path.as_path()), logs)),
+        ) {
+            logs.verify(sink, substore, &mut report).await?;
+        }
+        Ok(report)
+    }
+
+    /// Rebuilds the index of the store, and of every substore that was added,
+    /// from the records that can still be read.
+    ///
+    /// The recovered values are compacted into new logs, and the damaged logs
+    /// and orphaned files are removed. Returns what could not be recovered.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O errors of the backend.
+    pub async fn repair(&mut self) -> Result<RepairReport> {
+        let mut report = RepairReport::default();
+        let sink = &mut self.sink;
+        for (substore, logs) in iter::once((None, &mut self.root)).chain(
+            self.substores
+                .iter_mut()
+                .map(|(sub_path, logs)| (Some(sub_path.as_path()), logs)),
+        ) {
+            logs.repair(sink, substore, &mut report).await?;
+        }
+        Ok(report)
+    }
+
+    /// Opens a store that `open_with_backend` refuses to open, e.g. because
+    /// of a damaged record in the middle of a log, and repairs it, see `repair`.
+    ///
+    /// Since the index can not be replayed, lost keys are only known if a hint
+    /// file of an earlier compaction can still be read. Logs written before
+    /// records were framed can not be repaired, `open_with_backend` migrates them.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O errors of the backend.
+    pub async fn open_repaired(
+        mut sink: B,
+        path: impl Into<PathBuf>,
+    ) -> Result<(KvStore<B>, RepairReport)> {
+        let mut report = RepairReport::default();
+        let merge_operators = MergeOperators::default();
+        let root = Logs::open_repaired(
+            path.into(),
+            &mut sink,
+            merge_operators.clone(),
+            None,
+            &mut report,
+        )
+        .await?;
+        report.reverted.clear();
+        let store = KvStore {
+            sink,
+            root,
+            substores: BTreeMap::new(),
+            compaction_policy: CompactionPolicy::default(),
+            quota: None,
+            merge_operators,
+            indexes: BTreeMap::new(),
+            blob_threshold: None,
+        };
+        Ok((store, report))
+    }
+
+    /// Adds a substore that `add_substore` refuses to add, and repairs it,
+    /// see `open_repaired`. A substore that was already added is repaired as well.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O errors of the backend.
+    pub async fn add_substore_repaired(&mut self, sub_path: &Path) -> Result<RepairReport> {
+        let mut report = RepairReport::default();
+        match self.substores.get_mut(sub_path) {
+            Some(logs) => {
+                logs.repair(&mut self.sink, Some(sub_path), &mut report)
+                    .await?
+            }
+            None => {
+                let logs = Logs::open_repaired(
+                    self.root.path.join(sub_path),
+                    &mut self.sink,
+                    self.merge_operators.clone(),
+                    Some(sub_path),
+                    &mut report,
+                )
+                .await?;
+                self.substores.insert(sub_path.to_path_buf(), logs);
+                self.build_indexes(sub_path).await?;
+                report.reverted.clear();
+            }
+        }
+        Ok(report)
+    }
+}
+
+impl<B: Backend> Logs<B> {
+    /// Opens every log in `path` without replaying them, and repairs them
+    async fn open_repaired(
+        path: PathBuf,
+        sink: &mut B,
+        merge_operators: MergeOperators,
+        substore: Option<&Path>,
+        report: &mut RepairReport,
+    ) -> Result<Logs<B>> {
+        let gen_list = sorted_gen_list(sink, &path, None).await?;
+        let mut readers = HashMap::new();
+        for &gen in &gen_list {
+            let log_path = log_path(&path, gen);
+            let reader = BufReaderWithPos::new(sink.open_file(&log_path).await?)?;
+            readers.insert(log_path, reader);
+        }
+
+        // the index of the latest hint tells which keys were lost
+        let mut feed = Feed::default();
+        let index = match latest_hint(&path, sink, &gen_list).await? {
+            Some((_, hint)) => {
+                feed.skip_to(hint.seq);
+                hint.index.into_owned()
+            }
+            None => BTreeMap::new(),
+        };
+        let current_gen = gen_list.last().unwrap_or(&0) + 1;
+        let writer = new_log_file(&path, sink, current_gen, &mut readers).await?;
+        let blobs = open_blobs(&path, sink).await?;
+
+        let mut logs = Logs {
+            path,
+            readers,
+            writer,
+            current_gen,
+            index: Arc::new(index),
+            operands: Arc::new(BTreeMap::new()),
+            merge_operators,
+            secondary: BTreeMap::new(),
+            uncompacted: 0,
+            compaction: None,
+            feed,
+            accessed: HashMap::new(),
+            compacted_at: None,
+            snapshots: Vec::new(),
+            retired: Vec::new(),
+            blobs,
+            garbage: BTreeSet::new(),
+            parts: 0,
+        };
+        logs.repair(sink, substore, report).await?;
+        Ok(logs)
+    }
+}
+
+impl<B: Backend> Logs<B> {
+    async fn verify(
+        &mut self,
+        sink: &B,
+        substore: Option<&Path>,
+        report: &mut Report,
+    ) -> Result<()> {
+        // the reader sees everything that was copied so far
+        if let Some(compaction) = &mut self.compaction {
+            compaction.writer.flush()?;
+        }
+
+        // the bytes of the records that can not be read, by generation
+        let mut damaged: HashMap<u64, Vec<Range<u64>>> = HashMap::new();
+        for gen in self.gens() {
+            let path = log_path(&self.path, gen);
+            let reader = self.readers.get_mut(&path).expect("gen has a reader");
+            let mut index = BTreeMap::new();
+            let mut operands = BTreeMap::new();
+            let mut feed = Feed::default();
+            read_log(
+                gen,
+                reader,
+                &mut index,
+                &mut operands,
+                &mut feed,
+                |range, reason| {
+                    report.problems.push(Problem::UnreadableRecord {
+                        path: path.clone(),
+                        pos: range.start,
+                        reason,
+                    });
+                    damaged.entry(gen).or_default().push(range);
+                },
+            )
+            .await?;
+        }
+
+        // the commands below the merges of a key are checked as well
+        let entries: Vec<(String, CommandPos)> = self
+            .operands
+            .iter()
+            .flat_map(|(key, operands)| operands.iter().map(move |&cmd_pos| (key, cmd_pos)))
+            .chain(self.index.iter().map(|(key, &cmd_pos)| (key, cmd_pos)))
+            .map(|(key, cmd_pos)| (key.clone(), cmd_pos))
+            .collect();
+        for (key, cmd_pos) in entries {
+            let in_damaged_record = damaged
+                .get(&cmd_pos.gen)
+                .is_some_and(|ranges| ranges.iter().any(|range| range.contains(&cmd_pos.pos)));
+            let reason = match self.readers.get_mut(&log_path(&self.path, cmd_pos.gen)) {
+                None => Some(format!("log {} is missing", cmd_pos.gen)),
+                // the command may decode, but its value can not be trusted
+                Some(_) if in_damaged_record => Some("a damaged record".to_owned()),
+                Some(reader) => match read_command(reader, &cmd_pos).await {
+                    Ok(Command::SetBlob {
+                        key: found, blob, ..
+                    }) if found == key && !self.blobs.contains(&blob) => {
+                        Some(format!("blob {}, which is missing", blob))
+                    }
+                    Ok(Command::Set { key: found, .. })
+                    | Ok(Command::SetBlob { key: found, .. })
+                    | Ok(Command::SetExpiring { key: found, .. })
+                    | Ok(Command::Merge { key: found, .. }) => {
+                        if found == key {
+                            None
+                        } else {
+                            Some(format!("the value of {}", found))
+                        }
+                    }
+                    Ok(_) => Some("a command that sets no value".to_owned()),
+                    Err(err) => Some(format!("an unreadable command: {}", err)),
+                },
+            };
+            if let Some(reason) = reason {
+                report.problems.push(Problem::BadEntry {
+                    substore: substore.map(Path::to_path_buf),
+                    key,
+                    reason,
+                });
+            }
+        }
+
+        for path in self.orphaned_files(sink).await? {
+            report.problems.push(Problem::OrphanedFile { path });
+        }
+        Ok(())
+    }
+
+    async fn repair(
+        &mut self,
+        sink: &mut B,
+        substore: Option<&Path>,
+        report: &mut RepairReport,
+    ) -> Result<()> {
+        // an abandoned compaction log holds valid copies, which are replayed too
+        if let Some(mut compaction) = self.compaction.take() {
+            compaction.writer.flush()?;
+        }
+
+        let mut index = BTreeMap::new();
+        let mut operands = BTreeMap::new();
+        let mut uncompacted = 0;
+        for gen in self.gens() {
+            let path = log_path(&self.path, gen);
+            let reader = self.readers.get_mut(&path).expect("gen has a reader");
+            uncompacted += read_log(
+                gen,
+                reader,
+                &mut index,
+                &mut operands,
+                &mut self.feed,
+                |range, reason| {
+                    info!(
+                        "Dropping unreadable record",
+                        format!("{} at byte {}: {}", path.display(), range.start, reason)
+                    );
+                    report.unreadable_records += 1;
+                },
+            )
+            .await?;
+        }
+
+        for (key, cmd_pos) in self.index.iter() {
+            match index.get(key) {
+                None => report
+                    .lost
+                    .push((substore.map(Path::to_path_buf), key.clone())),
+                Some(recovered) if recovered != cmd_pos => report
+                    .reverted
+                    .push((substore.map(Path::to_path_buf), key.clone())),
+                Some(_) => {}
+            }
+        }
+        for key in index.keys().filter(|key| !self.index.contains_key(*key)) {
+            report
+                .reverted
+                .push((substore.map(Path::to_path_buf), key.clone()));
+        }
+
+        self.index = Arc::new(index);
+        self.operands = Arc::new(operands);
+        self.uncompacted = uncompacted;
+        let index = &self.index;
+        self.accessed.retain(|key, _| index.contains_key(key));
+
+        for path in self.orphaned_files(sink).await? {
+            sink.remove_file(&path).await?;
+            report.removed_files.push(path);
+        }
+        // rewrites the recovered values, and retires the damaged logs
+        self.compact(sink).await
+    }
+
+    /// Returns the generations of the logs, in order
+    fn gens(&self) -> Vec<u64> {
+        let mut gens: Vec<u64> = self
+            .readers
+            .keys()
+            .filter_map(|path| path.file_name()?.to_str()?.parse().ok())
+            .collect();
+        gens.sort_unstable();
+        gens
+    }
+
+    /// Returns the log and hint files in the directory of the logs
+    /// that have no reader
+    async fn orphaned_files(&self, sink: &B) -> Result<Vec<PathBuf>> {
+        let mut orphaned = Vec::new();
+        for gen in sorted_gen_list(sink, &self.path, None).await? {
+            let path = log_path(&self.path, gen);
+            if !self.readers.contains_key(&path) {
+                orphaned.push(path);
+            }
+        }
+        for gen in sorted_gen_list(sink, &self.path, Some(HINT_EXTENSION)).await? {
+            if !self.readers.contains_key(&log_path(&self.path, gen)) {
+                orphaned.push(hint_path(&self.path, gen));
+            }
+        }
+        Ok(orphaned)
+    }
+}
+
+/// Replays every record of a log that can be read into `index`,
+/// skipping over the ones that can not, whose bytes are passed to `unreadable`.
+///
+/// Returns how many bytes can be saved after a compaction.
+async fn read_log<F: BackendFile>(
+    gen: u64,
+    reader: &mut BufReaderWithPos<F>,
+    index: &mut BTreeMap<String, CommandPos>,
+    operands: &mut Operands,
+    feed: &mut Feed,
+    mut unreadable: impl FnMut(Range<u64>, String),
+) -> Result<u64> {
+    let log_len = reader.seek(SeekFrom::End(0))?;
+    if log_len < record::FILE_HEADER_LEN {
+        return Ok(0);
+    }
+    reader.get_mut().fetch(0..record::FILE_HEADER_LEN).await?;
+    reader.seek(SeekFrom::Start(0))?;
+    match record::read_header(reader)? {
+        Some(version)
+            if (record::MIN_FORMAT_VERSION..=record::FORMAT_VERSION).contains(&version) => {}
+        version => {
+            unreadable(0..log_len, format!("unsupported format {:?}", version));
+            return Ok(0);
+        }
+    }
+
+    let mut uncompacted = 0;
+    let mut pos = record::FILE_HEADER_LEN;
+    while pos < log_len {
+        reader.seek(SeekFrom::Start(pos))?;
+        let payload = match read_record(reader, pos, log_len).await? {
+            Frame::Complete(payload) => payload,
+            frame => {
+                let reason = match frame {
+                    Frame::Torn => "record ends past the end of the log",
+                    _ => "checksum mismatch",
+                };
+                // the length may be damaged as well, so reading goes on
+                // from the next intact record
+                reader.get_mut().fetch(pos..log_len).await?;
+                match record::next_intact(reader, pos, log_len)? {
+                    Some(next) => {
+                        unreadable(pos..next, reason.to_owned());
+                        pos = next;
+                        continue;
+                    }
+                    None => {
+                        unreadable(pos..log_len, reason.to_owned());
+                        break;
+                    }
+                }
+            }
+        };
+
+        let record_pos = pos;
+        let payload_pos = pos + record::HEADER_LEN;
+        let range = payload_pos..payload_pos + payload.len() as u64;
+        pos = range.end;
+        match Encoding::Bincode.decode(&payload) {
+            Ok(cmd) => {
+                // changes can not be resumed from, but are never numbered twice
+                if let Command::Sequenced { seq, cmds } = &cmd {
+                    feed.skip_to((seq + cmds.len() as u64).saturating_sub(1));
+                }
+                uncompacted += replay(cmd, gen, range, Encoding::Bincode, index, operands)?
+            }
+            Err(err) => unreadable(
+                record_pos..range.end,
+                format!("undecodable command: {}", err),
+            ),
+        }
+    }
+    Ok(uncompacted)
+}
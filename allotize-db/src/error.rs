@@ -1,3 +1,6 @@
+// the impls that `failure` derives are nested in a constant
+#![allow(non_local_definitions)]
+
 use failure::Fail;
 use std::io;
 
@@ -10,6 +13,9 @@ pub enum KvsError {
     /// Serialization or deserialization error
     #[fail(display = "{}", _0)]
     Serde(#[cause] serde_json::Error),
+    /// Binary encoding or decoding error
+    #[fail(display = "{}", _0)]
+    Bincode(#[cause] bincode::Error),
     /// Removing non-existent key error
     #[fail(display = "Key not found")]
     KeyNotFound,
@@ -17,6 +23,141 @@ pub enum KvsError {
     /// It indicated a corrupted log or a program bug.
     #[fail(display = "Unexpected command type")]
     UnexpectedCommandType,
+    /// A record in the middle of a log failed its checksum.
+    /// Unlike a torn write at the end of a log, this can not be repaired
+    /// by truncating the log, since later records would be lost.
+    #[fail(display = "Corrupted record in log {} at byte {}", path, pos)]
+    Corruption {
+        /// Path of the corrupted log
+        path: String,
+        /// Position of the corrupted record in the log
+        pos: u64,
+    },
+    /// `IndexedDB` ran out of storage quota, or a write did not fit into
+    /// the `Quota` of the store, so it was not stored.
+    #[fail(display = "Storage quota exceeded")]
+    QuotaExceeded,
+    /// An `IndexedDB` request or transaction failed.
+    #[fail(display = "IndexedDB error {}: {}", name, message)]
+    Idb {
+        /// Name of the `DOMException`, e.g. `AbortError`
+        name: String,
+        /// Message of the `DOMException`
+        message: String,
+    },
+    /// A log was written in a format that is newer than this version understands.
+    #[fail(display = "Log {} has unsupported format version {}", path, version)]
+    UnsupportedFormat {
+        /// Path of the log
+        path: String,
+        /// Format version found in the header of the log
+        version: u32,
+    },
+    /// A key was scoped to a substore that was not added to the store.
+    #[fail(display = "Unknown substore {}", path)]
+    UnknownSubstore {
+        /// Path the substore was scoped with
+        path: String,
+    },
+    /// A transaction or a `WriteBatch` wrote to more than one substore, counting the store
+    /// itself as one. The logs of each are replayed on their own, so they can not
+    /// be committed as a whole.
+    #[fail(display = "A commit can only write to a single substore")]
+    CrossSubstoreCommit,
+    /// A snapshot read from a log that was removed since the snapshot was taken,
+    /// because its substore was cleared.
+    #[fail(display = "Snapshot refers to a removed log")]
+    StaleSnapshot,
+    /// An archive handed to `KvStore::import` could not be read.
+    #[fail(display = "Invalid archive at line {}: {}", line, reason)]
+    InvalidArchive {
+        /// Line of the archive that could not be read, starting at 1
+        line: u64,
+        /// What is wrong with the line
+        reason: String,
+    },
+    /// A continuation token handed to `Scan::resume` could not be parsed,
+    /// or was taken on another scan.
+    #[fail(display = "Invalid continuation token")]
+    InvalidContinuationToken,
+    /// A log is encrypted with a key that the store was not opened with,
+    /// or it is not encrypted while the store is, or the other way around.
+    #[fail(display = "Log {} can not be decrypted with the given keys", path)]
+    WrongKey {
+        /// Path of the log
+        path: String,
+    },
+    /// A watcher resumed from a change that is no longer known,
+    /// e.g. because it was compacted away.
+    #[fail(
+        display = "Changes after {} are not available, the oldest to resume from is {}",
+        seq, oldest
+    )]
+    ChangesUnavailable {
+        /// Sequence number that the watcher resumed from
+        seq: u64,
+        /// Oldest sequence number that can be resumed from
+        oldest: u64,
+    },
+    /// A conditional write of a `KvTxn` was not made, since the key
+    /// did not hold the expected value.
+    #[fail(display = "Precondition failed for key {}", key)]
+    PreconditionFailed {
+        /// The key that was written to
+        key: String,
+        /// The encoded value that the key holds instead, `None` if it is absent
+        actual: Option<String>,
+    },
+    /// `KvTxn::merge` was called for a key that no merge operator applies to.
+    #[fail(display = "No merge operator applies to key {}", key)]
+    NoMergeOperator {
+        /// The key that was merged into
+        key: String,
+    },
+    /// A log holds an operand of a merge operator that is not registered.
+    #[fail(display = "Unknown merge operator {}", name)]
+    UnknownMergeOperator {
+        /// Name of the operator, see `MergeOperator::name`
+        name: String,
+    },
+    /// A merge operator could not fold an operand into the value of a key.
+    #[fail(display = "Could not merge into key {}: {}", key, reason)]
+    MergeFailed {
+        /// The key that was merged into
+        key: String,
+        /// Why the operand could not be folded
+        reason: String,
+    },
+    /// A lookup used a secondary index that was not defined.
+    #[fail(display = "Unknown index {}", name)]
+    UnknownIndex {
+        /// Name the index was looked up by
+        name: String,
+    },
+    /// A key could not be unpacked into a `Tuple`.
+    #[fail(display = "Invalid tuple key {:?}: {}", key, reason)]
+    InvalidTuple {
+        /// The key that was unpacked
+        key: String,
+        /// What is wrong with it
+        reason: String,
+    },
+    /// The blob that holds the value of a key is missing, or is shorter or
+    /// longer than the value.
+    #[fail(display = "Blob {} of key {} is missing", blob, key)]
+    MissingBlob {
+        /// The key whose value is stored in the blob
+        key: String,
+        /// Hash of the value, which names the blob
+        blob: String,
+    },
+    /// The value of a key is not UTF-8, e.g. a file written by `KvStore::blob_writer`,
+    /// so it can only be read with `KvStore::read_blob`.
+    #[fail(display = "Value of key {} is binary", key)]
+    BinaryValue {
+        /// The key that was read
+        key: String,
+    },
 }
 
 impl From<io::Error> for KvsError {
@@ -31,5 +172,11 @@ impl From<serde_json::Error> for KvsError {
     }
 }
 
+impl From<bincode::Error> for KvsError {
+    fn from(err: bincode::Error) -> KvsError {
+        KvsError::Bincode(err)
+    }
+}
+
 /// Result type for kvs
 pub type Result<T> = std::result::Result<T, KvsError>;
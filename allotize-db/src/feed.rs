@@ -0,0 +1,287 @@
+//! A feed of the changes made to a `KvStore`, see `KvStore::watch`.
+
+use std::collections::VecDeque;
+use std::future;
+use std::ops::{Bound, RangeBounds};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, Weak};
+use std::task::{Poll, Waker};
+
+use crate::cursor::prefix_end;
+use crate::{KvsError, Result};
+
+/// Number of recent changes that a store, or a substore, keeps in memory
+/// to resume watchers from
+const FEED_CAPACITY: usize = 1024;
+
+/// A change of a single key
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Change {
+    /// Orders the changes of a store, or of a substore.
+    /// The first change is number 1, and every change is one after the previous one.
+    pub seq: u64,
+    /// The key that was changed, empty for `ChangeKind::RemoveRange`
+    pub key: String,
+    /// What happened to the key
+    pub kind: ChangeKind,
+}
+
+/// What happened to a key in a `Change`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum ChangeKind {
+    /// The key was set to the value
+    Set(String),
+    /// The key was removed
+    Remove,
+    /// The operand was merged into the key, see `KvTxn::merge`
+    Merge(String),
+    /// The key was set to a value of `len` bytes that is stored in a blob,
+    /// see `KvStore::read_blob`
+    SetBlob {
+        /// Number of bytes of the value
+        len: u64,
+    },
+    /// The keys between `start` and `end` were removed, see `KvTxn::remove_range`
+    RemoveRange {
+        /// The first key that was removed
+        start: Bound<String>,
+        /// The last key that was removed
+        end: Bound<String>,
+    },
+}
+
+/// Describes which changes a `Watcher` receives.
+#[derive(Debug, Clone)]
+pub struct Watch {
+    start: Bound<String>,
+    end: Bound<String>,
+    substore: Option<PathBuf>,
+    after: Option<u64>,
+}
+
+impl Watch {
+    /// Watches every key
+    pub fn all() -> Self {
+        Watch::range(Bound::Unbounded, Bound::Unbounded)
+    }
+
+    /// Watches a single key
+    pub fn key(key: impl Into<String>) -> Self {
+        let key = key.into();
+        Watch::range(Bound::Included(key.clone()), Bound::Included(key))
+    }
+
+    /// Watches all keys that begin with `prefix`
+    pub fn prefix(prefix: impl Into<String>) -> Self {
+        let prefix = prefix.into();
+        let end = prefix_end(&prefix);
+        Watch::range(Bound::Included(prefix), end)
+    }
+
+    /// Watches the keys between `start` and `end`
+    pub fn range(start: Bound<String>, end: Bound<String>) -> Self {
+        Watch {
+            start,
+            end,
+            substore: None,
+            after: None,
+        }
+    }
+
+    /// Watches the keys of a substore instead of the keys of the store
+    pub fn scoped(mut self, substore: impl Into<PathBuf>) -> Self {
+        self.substore = Some(substore.into());
+        self
+    }
+
+    /// Receives the changes after the one with sequence number `seq` first,
+    /// e.g. the last change that was seen before a restart
+    pub fn resume(mut self, seq: u64) -> Self {
+        self.after = Some(seq);
+        self
+    }
+
+    pub(crate) fn substore(&self) -> Option<&Path> {
+        self.substore.as_deref()
+    }
+
+    fn matches(&self, change: &Change) -> bool {
+        match &change.kind {
+            // the removed range overlaps the watched one
+            ChangeKind::RemoveRange { start, end } => {
+                !ends_before(end.as_ref(), self.start.as_ref())
+                    && !ends_before(self.end.as_ref(), start.as_ref())
+            }
+            _ => (self.start.as_ref(), self.end.as_ref()).contains(&change.key),
+        }
+    }
+}
+
+/// The changes that were sent to a `Watcher`, but not received yet
+struct Channel {
+    watch: Watch,
+    queue: VecDeque<Change>,
+    waker: Option<Waker>,
+    // set once the store, or the substore, is closed
+    closed: bool,
+}
+
+/// Receives the changes made to a range of keys, in order.
+///
+/// A watcher does not borrow the store, so it can wait for changes
+/// while the store is written to.
+pub struct Watcher {
+    channel: Arc<Mutex<Channel>>,
+}
+
+impl Watcher {
+    /// Returns the next change, if one was made.
+    pub fn try_next(&mut self) -> Option<Change> {
+        self.channel
+            .lock()
+            .expect("Could not lock the channel")
+            .queue
+            .pop_front()
+    }
+
+    /// Waits for the next change.
+    ///
+    /// Returns `None` once the store is dropped, or the substore is removed,
+    /// and every change before that was received.
+    pub async fn next(&mut self) -> Option<Change> {
+        future::poll_fn(|cx| {
+            let mut channel = self.channel.lock().expect("Could not lock the channel");
+            match channel.queue.pop_front() {
+                Some(change) => Poll::Ready(Some(change)),
+                None if channel.closed => Poll::Ready(None),
+                None => {
+                    channel.waker = Some(cx.waker().clone());
+                    Poll::Pending
+                }
+            }
+        })
+        .await
+    }
+}
+
+/// The recent changes of a store, or of a substore, and its watchers
+#[derive(Default)]
+pub(crate) struct Feed {
+    // sequence number of the last change
+    seq: u64,
+    // the most recent changes, ending with the last one
+    recent: VecDeque<Change>,
+    watchers: Vec<Weak<Mutex<Channel>>>,
+}
+
+impl Feed {
+    /// Returns the sequence number of the last change
+    pub(crate) fn seq(&self) -> u64 {
+        self.seq
+    }
+
+    /// Remembers that changes up to `seq` were made,
+    /// even though they are no longer known
+    pub(crate) fn skip_to(&mut self, seq: u64) {
+        if seq > self.seq {
+            self.seq = seq;
+            self.recent.clear();
+        }
+    }
+
+    /// Remembers changes that were read from a log
+    pub(crate) fn replay(&mut self, changes: Vec<Change>) {
+        for change in changes {
+            if change.seq != self.seq + 1 {
+                // changes in between were compacted away
+                self.recent.clear();
+            }
+            self.seq = change.seq;
+            self.remember(change);
+        }
+    }
+
+    /// Sends changes that were just written to the watchers
+    pub(crate) fn publish(&mut self, changes: Vec<Change>) {
+        self.watchers.retain(|watcher| watcher.strong_count() > 0);
+        for change in changes {
+            for watcher in self.watchers.iter().filter_map(Weak::upgrade) {
+                let mut channel = watcher.lock().expect("Could not lock the channel");
+                if channel.watch.matches(&change) {
+                    channel.queue.push_back(change.clone());
+                    if let Some(waker) = channel.waker.take() {
+                        waker.wake();
+                    }
+                }
+            }
+            self.seq = change.seq;
+            self.remember(change);
+        }
+    }
+
+    fn remember(&mut self, change: Change) {
+        if self.recent.len() == FEED_CAPACITY {
+            self.recent.pop_front();
+        }
+        self.recent.push_back(change);
+    }
+
+    /// Adds a watcher, which first receives the recent changes
+    /// that it resumes from.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::ChangesUnavailable` if the changes
+    /// to resume from are no longer known.
+    pub(crate) fn watch(&mut self, watch: Watch) -> Result<Watcher> {
+        let mut queue = VecDeque::new();
+        if let Some(after) = watch.after {
+            // the oldest sequence number that can be resumed from
+            let oldest = self
+                .recent
+                .front()
+                .map_or(self.seq, |change| change.seq - 1);
+            if after < oldest || after > self.seq {
+                return Err(KvsError::ChangesUnavailable { seq: after, oldest });
+            }
+            queue.extend(
+                self.recent
+                    .iter()
+                    .filter(|change| change.seq > after && watch.matches(change))
+                    .cloned(),
+            );
+        }
+
+        let channel = Arc::new(Mutex::new(Channel {
+            watch,
+            queue,
+            waker: None,
+            closed: false,
+        }));
+        self.watchers.push(Arc::downgrade(&channel));
+        Ok(Watcher { channel })
+    }
+}
+
+impl Drop for Feed {
+    fn drop(&mut self) {
+        for watcher in self.watchers.iter().filter_map(Weak::upgrade) {
+            let mut channel = watcher.lock().expect("Could not lock the channel");
+            channel.closed = true;
+            if let Some(waker) = channel.waker.take() {
+                waker.wake();
+            }
+        }
+    }
+}
+
+/// Returns whether every key up to `end` is before `start`
+fn ends_before(end: Bound<&String>, start: Bound<&String>) -> bool {
+    match (end, start) {
+        (Bound::Unbounded, _) | (_, Bound::Unbounded) => false,
+        (Bound::Included(end), Bound::Included(start)) => end < start,
+        (Bound::Included(end), Bound::Excluded(start))
+        | (Bound::Excluded(end), Bound::Included(start))
+        | (Bound::Excluded(end), Bound::Excluded(start)) => end <= start,
+    }
+}
@@ -0,0 +1,136 @@
+use async_trait::async_trait;
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use crate::{Backend, BackendFile, Result};
+
+/// A `Backend` that stores log files on the local filesystem.
+///
+/// Paths handed to the backend are used as-is, so a store opened at
+/// `dir` keeps its generations at `dir/1`, `dir/2`, ... on disk.
+/// Only files below `root` are reported by `file_names`.
+#[derive(Clone, Debug)]
+pub struct FsBackend {
+    root: PathBuf,
+}
+
+impl FsBackend {
+    /// Creates a backend rooted at the given directory,
+    /// creating the directory if it does not exist.
+    pub fn new(root: impl Into<PathBuf>) -> Result<Self> {
+        let root = root.into();
+        fs::create_dir_all(&root)?;
+        Ok(FsBackend { root })
+    }
+}
+
+#[async_trait(?Send)]
+impl Backend for FsBackend {
+    type File = FsFile;
+
+    async fn open_file(&mut self, path: &Path) -> Result<FsFile> {
+        let dir = path.parent().unwrap_or_else(|| Path::new("."));
+        fs::create_dir_all(dir)?;
+
+        let existed = path.exists();
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)?;
+        file.seek(SeekFrom::End(0))?;
+
+        // Make the new directory entry durable as well, otherwise a crash
+        // could lose the file even though its contents were synced
+        if !existed {
+            sync_dir(dir)?;
+        }
+
+        Ok(FsFile { inner: file })
+    }
+
+    async fn file_names(&self) -> Result<Vec<String>> {
+        let mut names = Vec::new();
+        collect_file_names(&self.root, &mut names)?;
+        Ok(names)
+    }
+
+    async fn remove_file(&mut self, path: &Path) -> Result<()> {
+        fs::remove_file(path)?;
+        if let Some(dir) = path.parent() {
+            sync_dir(dir)?;
+        }
+        Ok(())
+    }
+}
+
+/// Recursively collects the paths of all files below `dir`
+fn collect_file_names(dir: &Path, names: &mut Vec<String>) -> io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            collect_file_names(&path, names)?;
+        } else if let Some(name) = path.to_str() {
+            names.push(name.to_owned());
+        }
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+fn sync_dir(dir: &Path) -> io::Result<()> {
+    File::open(dir)?.sync_all()
+}
+
+#[cfg(not(unix))]
+fn sync_dir(_dir: &Path) -> io::Result<()> {
+    // Directories can not be opened as files on this platform
+    Ok(())
+}
+
+/// A log file stored in a `FsBackend`
+///
+/// Flushing the file calls `fsync`, so flushed data survives a crash.
+#[derive(Debug)]
+pub struct FsFile {
+    inner: File,
+}
+
+impl FsFile {
+    /// Returns the size of a file in bytes
+    pub fn size(&self) -> io::Result<u64> {
+        Ok(self.inner.metadata()?.len())
+    }
+}
+
+impl BackendFile for FsFile {
+    fn set_len(&mut self, len: u64) -> io::Result<()> {
+        self.inner.set_len(len)
+    }
+}
+
+impl Read for FsFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.inner.read(buf)
+    }
+}
+
+impl Write for FsFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.inner.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()?;
+        self.inner.sync_data()
+    }
+}
+
+impl Seek for FsFile {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.inner.seek(pos)
+    }
+}
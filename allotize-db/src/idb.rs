@@ -1,27 +1,234 @@
-use std::sync::{Arc, RwLock};
+use std::cell::RefCell;
+use std::rc::Rc;
 
 use std::task::{Context, Poll};
 use wasm_bindgen::prelude::*;
 use wasm_bindgen::JsCast;
-use web_sys::{self, RequestInit};
 
 use std::cmp;
-use std::collections::HashMap;
+use std::collections::{BTreeSet, HashMap};
 
 use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::ops::Range;
 use std::path::Path;
 use std::pin::Pin;
 
+use async_trait::async_trait;
+
+use crate::{Backend, BackendFile, KvsError};
+
+/// Number of bytes in a page of an `IdbFile`
+const PAGE_SIZE: u64 = 16 * 1024;
+
+/// Separates the name of a file from the number of a page in the key of the page
+const PAGE_SEPARATOR: char = '#';
+
+/// Number of clean pages of a file that are kept loaded, besides the ones
+/// that are being fetched. The least recently used ones are unloaded first.
+const MAX_CLEAN_PAGES: usize = 256;
+
+/// A file as it was stored before files were split into pages
 #[derive(Default, Debug, Serialize, Deserialize)]
 struct RawFile {
     pub inner: Vec<u8>,
 }
 
+/// The record that is stored under the name of a file
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(untagged)]
+enum StoredFile {
+    /// The pages of the file are stored under `page_key`
+    Paged { len: u64 },
+    /// The whole file is stored in this record
+    Raw(RawFile),
+}
+
+/// The contents of a file, of which only some pages may be loaded
+#[derive(Default, Debug)]
+struct PagedFile {
+    len: u64,
+    // loaded pages by page number, only the last page can be shorter than `PAGE_SIZE`
+    pages: HashMap<u64, Vec<u8>>,
+    // the time each loaded page was last used, as counted by `clock`
+    used: HashMap<u64, u64>,
+    clock: u64,
+    // pages that have changed since the last flush
+    dirty: BTreeSet<u64>,
+    // the length of the file in `IndexedDB`
+    stored_len: u64,
+    // flushes that were started, but whose outcome has not been applied yet
+    flushes: Vec<Flush>,
+    // the id of the next flush
+    next_flush: u64,
+}
+
+/// A transaction that stores some pages of a file, and its length
+#[derive(Clone)]
+struct Flush {
+    id: u64,
+    commit: IdbCommit,
+    pages: BTreeSet<u64>,
+    len: u64,
+}
+
+impl std::fmt::Debug for Flush {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("Flush")
+            .field("id", &self.id)
+            .field("pages", &self.pages)
+            .field("len", &self.len)
+            .finish()
+    }
+}
+
+impl PagedFile {
+    fn from_bytes(bytes: &[u8]) -> Self {
+        let pages: HashMap<_, _> = bytes
+            .chunks(PAGE_SIZE as usize)
+            .enumerate()
+            .map(|(n, page)| (n as u64, page.to_vec()))
+            .collect();
+        PagedFile {
+            len: bytes.len() as u64,
+            dirty: pages.keys().copied().collect(),
+            pages,
+            ..PagedFile::default()
+        }
+    }
+
+    /// Applies the outcome of a flush, unless it was applied already.
+    ///
+    /// The pages of a failed flush are dirty again, so that the next flush
+    /// writes them, and the length it stored is only known once it succeeded.
+    fn finish_flush(&mut self, id: u64, succeeded: bool) {
+        let flush = match self.flushes.iter().position(|flush| flush.id == id) {
+            Some(index) => self.flushes.remove(index),
+            None => return,
+        };
+        if succeeded {
+            self.stored_len = flush.len;
+            // the pages it stored can be fetched again
+            self.evict(0..0);
+        } else {
+            let page_count = page_count(self.len);
+            self.dirty
+                .extend(flush.pages.into_iter().filter(|&n| n < page_count));
+        }
+    }
+
+    fn page(&mut self, n: u64) -> io::Result<&Vec<u8>> {
+        self.touch(n);
+        self.pages.get(&n).ok_or_else(|| page_not_loaded(n))
+    }
+
+    /// Returns a page to write to, pages past the end of the file are created
+    fn page_mut(&mut self, n: u64) -> io::Result<&mut Vec<u8>> {
+        if n * PAGE_SIZE >= self.len {
+            self.pages.entry(n).or_default();
+        }
+        self.touch(n);
+        self.dirty.insert(n);
+        self.pages.get_mut(&n).ok_or_else(|| page_not_loaded(n))
+    }
+
+    /// Marks page `n` as the most recently used one
+    fn touch(&mut self, n: u64) {
+        self.clock += 1;
+        self.used.insert(n, self.clock);
+    }
+
+    /// Unloads the least recently used clean pages, until at most
+    /// `MAX_CLEAN_PAGES` of them are left besides the ones in `keep`.
+    ///
+    /// Pages that are dirty or being flushed can not be fetched again, and
+    /// the last page is kept, since it is appended to.
+    fn evict(&mut self, keep: Range<u64>) {
+        let flushing: BTreeSet<u64> = self
+            .flushes
+            .iter()
+            .flat_map(|flush| flush.pages.iter().copied())
+            .collect();
+        let last_page = self.len / PAGE_SIZE;
+        let mut clean: Vec<(u64, u64)> = self
+            .pages
+            .keys()
+            .filter(|n| !keep.contains(n) && **n != last_page)
+            .filter(|n| !self.dirty.contains(n) && !flushing.contains(n))
+            .map(|&n| (self.used.get(&n).copied().unwrap_or(0), n))
+            .collect();
+        if clean.len() <= MAX_CLEAN_PAGES {
+            return;
+        }
+
+        clean.sort_unstable();
+        for (_, n) in &clean[..clean.len() - MAX_CLEAN_PAGES] {
+            self.pages.remove(n);
+            self.used.remove(n);
+        }
+    }
+
+    fn write_at(&mut self, mut pos: u64, mut buf: &[u8]) -> io::Result<()> {
+        if pos > self.len {
+            // fill the gap with zeros, like a sparse file
+            let gap = vec![0; (pos - self.len) as usize];
+            self.write_at(self.len, &gap)?;
+        }
+
+        while !buf.is_empty() {
+            let offset = (pos % PAGE_SIZE) as usize;
+            let (left, right) = buf.split_at(cmp::min(buf.len(), PAGE_SIZE as usize - offset));
+            let page = self.page_mut(pos / PAGE_SIZE)?;
+            if page.len() < offset + left.len() {
+                page.resize(offset + left.len(), 0);
+            }
+            page[offset..offset + left.len()].copy_from_slice(left);
+
+            pos += left.len() as u64;
+            buf = right;
+        }
+        self.len = cmp::max(self.len, pos);
+
+        Ok(())
+    }
+
+    fn set_len(&mut self, len: u64) -> io::Result<()> {
+        if len > self.len {
+            return self.write_at(len, &[]);
+        }
+
+        let page_count = page_count(len);
+        self.pages.retain(|&n, _| n < page_count);
+        self.used.retain(|&n, _| n < page_count);
+        self.dirty.retain(|&n| n < page_count);
+        if !len.is_multiple_of(PAGE_SIZE) {
+            let last_page = self.page_mut(len / PAGE_SIZE)?;
+            last_page.truncate((len % PAGE_SIZE) as usize);
+        }
+        self.len = len;
+
+        Ok(())
+    }
+}
+
+/// Returns the number of pages needed to store `len` bytes
+fn page_count(len: u64) -> u64 {
+    len.div_ceil(PAGE_SIZE)
+}
+
+/// Returns the key that page `n` of a file is stored under
+fn page_key(name: &str, n: u64) -> String {
+    format!("{}{}{}", name, PAGE_SEPARATOR, n)
+}
+
+fn page_not_loaded(n: u64) -> io::Error {
+    io::Error::other(format!("Page {} is read before it was fetched", n))
+}
+
 /// Emulates a Folder that is stored in `IndexedDB`
 pub struct IdbFolder {
-    _name: Arc<String>,
-    idb_handle: Arc<IdbHandle>,
-    raw_files: HashMap<String, Arc<RwLock<RawFile>>>,
+    _name: Rc<String>,
+    idb_handle: Rc<IdbHandle>,
+    raw_files: HashMap<String, Rc<RefCell<PagedFile>>>,
 }
 
 impl IdbFolder {
@@ -35,8 +242,8 @@ impl IdbFolder {
         let name = path.to_str().expect("Could not transform path to str");
 
         Ok(IdbFolder {
-            _name: Arc::new(name.into()),
-            idb_handle: Arc::new(idb_handle),
+            _name: Rc::new(name.into()),
+            idb_handle: Rc::new(idb_handle),
             raw_files: HashMap::new(),
         })
     }
@@ -46,74 +253,274 @@ impl IdbFolder {
         self.idb_handle.get_all_keys().await
     }
 
-    /// Removes a file for idb
-    pub async fn remove_file(&self, path: &Path) -> Result<JsValue, JsValue> {
+    /// Removes a file, and all of its pages, from idb
+    pub async fn remove_file(&self, path: &Path) -> crate::Result<()> {
         let name = path.to_str().expect("Could not transform path to str");
         let handle = &self.idb_handle;
-        handle.remove(name).await
+        // every page key starts with the name and the separator
+        let pages = web_sys::IdbKeyRange::bound_with_lower_open_and_upper_open(
+            &JsValue::from_str(&format!("{}{}", name, PAGE_SEPARATOR)),
+            &JsValue::from_str(&format!("{}{}", name, (PAGE_SEPARATOR as u8 + 1) as char)),
+            false,
+            true,
+        )
+        .map_err(idb_error)?;
+        handle
+            .update(&[], &[JsValue::from_str(name), pages.into()])
+            .wait()
+            .await
     }
 
     /// Opens a new file in idb
-    pub async fn open_file(&mut self, path: &Path) -> io::Result<IdbFile> {
+    ///
+    /// Only the last page of the file is loaded, so that it can be appended to.
+    /// The other pages are loaded by `BackendFile::fetch` when they are needed.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::Idb` if the file could not be read from idb,
+    /// and a serialization error if the stored record is not a file.
+    pub async fn open_file(&mut self, path: &Path) -> crate::Result<IdbFile> {
         let name = path.to_str().expect("Could not transform path to str");
-        let file_as_jsv: Result<JsValue, JsValue> = self.idb_handle.get(name).await;
-
-        let raw_file: RawFile = match file_as_jsv {
-            Ok(file) => {
-                if file.is_undefined() {
-                    RawFile::default()
-                } else {
-                    file.into_serde().expect("Could not serialize file")
-                }
-            }
-            Err(_err) => RawFile::default(),
-        };
-
-        let pos = raw_file.inner.len() as u64;
 
         let file = match self.raw_files.get(name) {
-            Some(file) => Arc::clone(&file),
+            Some(file) => Rc::clone(file),
             _ => {
-                let file = Arc::new(RwLock::new(raw_file));
-                self.raw_files.insert(name.into(), Arc::clone(&file));
+                let file = self.idb_handle.get(name).await.map_err(idb_error)?;
+                let paged_file = if file.is_undefined() {
+                    PagedFile::default()
+                } else {
+                    match from_js(&file)? {
+                        StoredFile::Paged { len } => PagedFile {
+                            len,
+                            stored_len: len,
+                            ..PagedFile::default()
+                        },
+                        // written before files were paged, it is
+                        // stored as pages by the next flush
+                        StoredFile::Raw(raw_file) => PagedFile::from_bytes(&raw_file.inner),
+                    }
+                };
+
+                let file = Rc::new(RefCell::new(paged_file));
+                self.raw_files.insert(name.into(), Rc::clone(&file));
                 file
             }
         };
 
-        Ok(IdbFile {
-            pos,
+        let mut file = IdbFile {
+            pos: 0,
             name: name.into(),
-            idb_handle: Arc::clone(&self.idb_handle),
+            idb_handle: Rc::clone(&self.idb_handle),
             inner: file,
-        })
+        };
+        let len = file.size() as u64;
+        file.fetch(len - len % PAGE_SIZE..len).await?;
+        file.pos = len;
+
+        Ok(file)
+    }
+}
+
+#[async_trait(?Send)]
+impl Backend for IdbFolder {
+    type File = IdbFile;
+
+    async fn open_file(&mut self, path: &Path) -> crate::Result<IdbFile> {
+        IdbFolder::open_file(self, path).await
+    }
+
+    async fn file_names(&self) -> crate::Result<Vec<String>> {
+        let names = self.get_file_names().await.map_err(idb_error)?;
+        let names: Vec<String> = from_js(&names)?;
+        Ok(names
+            .into_iter()
+            .filter(|name| !name.contains(PAGE_SEPARATOR))
+            .collect())
+    }
+
+    async fn remove_file(&mut self, path: &Path) -> crate::Result<()> {
+        IdbFolder::remove_file(self, path).await?;
+        if let Some(name) = path.to_str() {
+            self.raw_files.remove(name);
+        }
+        Ok(())
+    }
+}
+
+/// Converts a value into the `JsValue` of its json
+fn to_js<T: serde::Serialize + ?Sized>(value: &T) -> crate::Result<JsValue> {
+    let json = serde_json::to_string(value)?;
+    js_sys::JSON::parse(&json).map_err(idb_error)
+}
+
+/// Converts a `JsValue` into the value of its json, see `to_js`
+fn from_js<T: serde::de::DeserializeOwned>(value: &JsValue) -> crate::Result<T> {
+    let json = js_sys::JSON::stringify(value).map_err(idb_error)?;
+    // `undefined` has no json
+    let json = json.as_string().unwrap_or_else(|| "null".to_owned());
+    Ok(serde_json::from_str(&json)?)
+}
+
+/// Converts an error thrown by `IndexedDB` into a `KvsError`
+fn idb_error(err: JsValue) -> KvsError {
+    match err.dyn_ref::<web_sys::DomException>() {
+        Some(err) if err.name() == "QuotaExceededError" => KvsError::QuotaExceeded,
+        Some(err) => KvsError::Idb {
+            name: err.name(),
+            message: err.message(),
+        },
+        None => KvsError::Idb {
+            name: "Error".into(),
+            message: format!("{:?}", err),
+        },
     }
 }
 
 /// Emulates a File that is stored in `IndexedDB`
+///
+/// The file is split into pages of `PAGE_SIZE` bytes, each stored as a
+/// separate record, so that a flush only writes the pages that changed.
 pub struct IdbFile {
     pos: u64,
     name: String,
-    idb_handle: Arc<IdbHandle>,
-    inner: Arc<RwLock<RawFile>>,
+    idb_handle: Rc<IdbHandle>,
+    inner: Rc<RefCell<PagedFile>>,
 }
 
 impl IdbFile {
     /// Returns the size of a file in bytes
     pub fn size(&self) -> usize {
-        self.inner.read().unwrap().inner.len()
+        self.inner.borrow().len as usize
+    }
+
+    /// Saves a file to idb, and waits until it is stored
+    pub async fn save(&mut self) -> crate::Result<()> {
+        self.flush()?;
+        self.sync().await
     }
 
-    /// Saves a file to idb
-    pub async fn save(&mut self) {
-        let raw_file: &RawFile = &*(self.inner.read().unwrap());
-        self.idb_handle.put(&self.name, raw_file);
+    /// Starts to write the dirty pages and the length of the file to idb,
+    /// in a single transaction.
+    ///
+    /// The pages are no longer dirty while the transaction is in progress,
+    /// `IdbFile::sync` makes them dirty again if it fails.
+    fn persist(&self) {
+        let mut file = self.inner.borrow_mut();
+        // the length stored once the flushes in progress are done
+        let flushed_len = file
+            .flushes
+            .last()
+            .map_or(file.stored_len, |flush| flush.len);
+        if file.dirty.is_empty() && file.len == flushed_len {
+            return;
+        }
+
+        let stored_len = file
+            .flushes
+            .iter()
+            .fold(file.stored_len, |len, flush| cmp::max(len, flush.len));
+        let stale_pages: Vec<JsValue> = (page_count(file.len)..page_count(stored_len))
+            .map(|n| JsValue::from_str(&page_key(&self.name, n)))
+            .collect();
+        let mut puts: Vec<(JsValue, JsValue)> = file
+            .dirty
+            .iter()
+            .filter_map(|n| file.pages.get(n).map(|page| (n, page)))
+            .map(|(n, page)| {
+                let key = JsValue::from_str(&page_key(&self.name, *n));
+                (key, js_sys::Uint8Array::from(&page[..]).into())
+            })
+            .collect();
+        let meta = StoredFile::Paged { len: file.len };
+        puts.push((
+            JsValue::from_str(&self.name),
+            to_js(&meta).expect("Unable to serialize to JsValue"),
+        ));
+
+        let flush = Flush {
+            id: file.next_flush,
+            commit: self.idb_handle.update(&puts, &stale_pages),
+            pages: std::mem::take(&mut file.dirty),
+            len: file.len,
+        };
+        file.next_flush += 1;
+        file.flushes.push(flush);
+    }
+}
+
+#[async_trait(?Send)]
+impl BackendFile for IdbFile {
+    fn set_len(&mut self, len: u64) -> io::Result<()> {
+        self.inner.borrow_mut().set_len(len)
+    }
+
+    async fn fetch(&mut self, range: Range<u64>) -> crate::Result<()> {
+        let (pages, missing) = {
+            let file = self.inner.borrow();
+            let end = cmp::min(range.end, file.len);
+            if range.start >= end {
+                return Ok(());
+            }
+            let pages = range.start / PAGE_SIZE..(end - 1) / PAGE_SIZE + 1;
+            let missing: Vec<u64> = pages
+                .clone()
+                .filter(|n| !file.pages.contains_key(n))
+                .collect();
+            (pages, missing)
+        };
+        if missing.is_empty() {
+            return Ok(());
+        }
+
+        for n in missing {
+            let page = self
+                .idb_handle
+                .get(&page_key(&self.name, n))
+                .await
+                .map_err(idb_error)?;
+            if page.is_undefined() {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Page {} of {} is missing", n, self.name),
+                )
+                .into());
+            }
+            let page = js_sys::Uint8Array::new(&page).to_vec();
+            let mut file = self.inner.borrow_mut();
+            file.pages.entry(n).or_insert(page);
+            file.touch(n);
+        }
+
+        self.inner.borrow_mut().evict(pages);
+        Ok(())
+    }
+
+    /// Waits for every flush of the file to be stored, including the ones
+    /// started through other handles to it.
+    async fn sync(&mut self) -> crate::Result<()> {
+        self.persist();
+        let flushes = self.inner.borrow().flushes.clone();
+        for flush in flushes {
+            let result = flush.commit.wait().await;
+            self.inner
+                .borrow_mut()
+                .finish_flush(flush.id, result.is_ok());
+            result?;
+        }
+        Ok(())
     }
 }
 
 impl Read for IdbFile {
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
-        let amt = cmp::min(self.pos, self.inner.read().unwrap().inner.len() as u64);
-        let mut fill_buff = &self.inner.read().unwrap().inner[(amt as usize)..];
+        let mut file = self.inner.borrow_mut();
+        if self.pos >= file.len {
+            return Ok(0);
+        }
+
+        let page = file.page(self.pos / PAGE_SIZE)?;
+        let mut fill_buff = &page[(self.pos % PAGE_SIZE) as usize..];
         let n = Read::read(&mut fill_buff, buf)?;
         self.pos += n as u64;
 
@@ -123,77 +530,30 @@ impl Read for IdbFile {
 
 impl Write for IdbFile {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
-        let pos: usize = self.pos as usize;
-        let len: usize = self
-            .inner
-            .read()
-            .expect("Could not get read lock on raw file")
-            .inner
-            .len();
-        // Make sure the internal buffer is as least as big as where we
-        // currently are
-        if len < pos {
-            // use `resize` so that the zero filling is as efficient as possible
-            self.inner
-                .write()
-                .expect("Could not get write lock on raw file")
-                .inner
-                .resize(pos, 0);
-        }
-
-        // Figure out what bytes will be used to overwrite what's currently
-        // there (left), and what will be appended on the end (right)
-        {
-            let space = self
-                .inner
-                .read()
-                .expect("Could not get read lock on raw file")
-                .inner
-                .len()
-                - pos;
-            let (left, right) = buf.split_at(cmp::min(space, buf.len()));
-            self.inner
-                .write()
-                .expect("Could not get write lock on raw file")
-                .inner[pos..pos + left.len()]
-                .copy_from_slice(left);
-            self.inner
-                .write()
-                .expect("Could not get write lock on raw file")
-                .inner
-                .extend_from_slice(right);
-        }
+        self.inner.borrow_mut().write_at(self.pos, buf)?;
 
         // Bump us forward
-        self.pos = (pos + buf.len()) as u64;
+        self.pos += buf.len() as u64;
 
         Ok(buf.len())
     }
 
+    /// Starts to save the dirty pages to indexed db,
+    /// `BackendFile::sync` waits until they are stored.
     fn flush(&mut self) -> io::Result<()> {
-        // Save to indexed db
-        let file = Arc::clone(&self.inner);
-        let idb = Arc::clone(&self.idb_handle);
-        let name = self.name.clone();
-
-        let future = async move {
-            let raw_file: &RawFile = &*(file.read().expect("Could not get read lock on raw file"));
-            idb.put(&name, raw_file);
-
-            // // Send backup to KV store
-            // let window = web_sys::window().unwrap();
-            // let mut request =  RequestInit::new();
-            // request
-            //     .body(Some(&JsValue::from_serde(&raw_file.inner).unwrap()))
-            //     .method("POST");
-            // let promise = window.fetch_with_str_and_init(
-            //     &format!("http://127.0.0.1:8787/upload/{}", name.replace("/", "%2F")),
-            //     &request
-            // );
-            // wasm_bindgen_futures::JsFuture::from(promise).await;
-        };
-
-        wasm_bindgen_futures::spawn_local(future);
+        self.persist();
+
+        // // Send backup to KV store
+        // let window = web_sys::window().unwrap();
+        // let mut request =  RequestInit::new();
+        // request
+        //     .body(Some(&JsValue::from_serde(&raw_file.inner).unwrap()))
+        //     .method("POST");
+        // let promise = window.fetch_with_str_and_init(
+        //     &format!("http://127.0.0.1:8787/upload/{}", name.replace("/", "%2F")),
+        //     &request
+        // );
+        // wasm_bindgen_futures::JsFuture::from(promise).await;
 
         Ok(())
     }
@@ -206,7 +566,7 @@ impl Seek for IdbFile {
                 self.pos = n;
                 return Ok(n);
             }
-            SeekFrom::End(n) => (self.inner.read().unwrap().inner.len() as u64, n),
+            SeekFrom::End(n) => (self.inner.borrow().len, n),
             SeekFrom::Current(n) => (self.pos, n),
         };
         let new_pos = if offset >= 0 {
@@ -219,21 +579,21 @@ impl Seek for IdbFile {
                 self.pos = n;
                 Ok(self.pos)
             }
-            None => Err(std::io::Error::new(std::io::ErrorKind::Other, "Uh oh")),
+            None => Err(std::io::Error::other("Uh oh")),
         }
     }
 }
 
 pub struct IdbTxn {
     /// Holds the request response for a request to `Indexeddb`
-    pub inner: Arc<web_sys::IdbRequest>,
+    pub inner: Rc<web_sys::IdbRequest>,
     callback: Option<Closure<dyn FnMut()>>,
 }
 
 impl IdbTxn {
     pub fn new(request: web_sys::IdbRequest) -> IdbTxn {
         IdbTxn {
-            inner: Arc::new(request),
+            inner: Rc::new(request),
             callback: None,
         }
     }
@@ -268,6 +628,48 @@ impl std::future::Future for IdbTxn {
     }
 }
 
+/// Resolves once an `IndexedDB` transaction has completed, and its
+/// changes are stored, or once it has failed.
+///
+/// Clones wait for the same transaction.
+#[derive(Clone)]
+pub struct IdbCommit {
+    transaction: web_sys::IdbTransaction,
+    done: js_sys::Promise,
+}
+
+impl IdbCommit {
+    /// Must be created before control returns to the event loop,
+    /// so that the completion of the transaction is not missed.
+    fn new(transaction: web_sys::IdbTransaction) -> IdbCommit {
+        let promise = js_sys::Promise::new(&mut |resolve, reject| {
+            transaction.set_oncomplete(Some(&resolve));
+            transaction.set_onerror(Some(&reject));
+            transaction.set_onabort(Some(&reject));
+        });
+
+        IdbCommit {
+            transaction,
+            done: promise,
+        }
+    }
+
+    /// Waits for the transaction to complete.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::QuotaExceeded` if the transaction ran out of quota,
+    /// and `KvsError::Idb` if it failed for any other reason.
+    pub async fn wait(self) -> crate::Result<()> {
+        match wasm_bindgen_futures::JsFuture::from(self.done).await {
+            Ok(_) => Ok(()),
+            Err(event) => Err(idb_error(
+                self.transaction.error().map_or(event, JsValue::from),
+            )),
+        }
+    }
+}
+
 /// A handle to acces an `Indexeddb`
 #[derive(Debug)]
 pub struct IdbHandle {
@@ -310,9 +712,8 @@ impl IdbHandle {
     where
         T: serde::ser::Serialize + ?Sized,
     {
-        let key_as_jsv =
-            JsValue::from_serde(&key.to_string()).expect("Unable to serialize to JsValue");
-        let value_as_jsv = JsValue::from_serde(&value).expect("Unable to serialize to JsValue");
+        let key_as_jsv = JsValue::from_str(key);
+        let value_as_jsv = to_js(value).expect("Unable to serialize to JsValue");
 
         IdbTxn::new(
             self.inner
@@ -328,12 +729,39 @@ impl IdbHandle {
         )
     }
 
+    /// Deletes and then puts the given keys in a single transaction,
+    /// so that either all or none of the changes are applied.
+    ///
+    /// A key to delete can also be an `IdbKeyRange`.
+    pub fn update(&self, puts: &[(JsValue, JsValue)], deletes: &[JsValue]) -> IdbCommit {
+        let transaction = self
+            .inner
+            .transaction_with_str_and_mode(
+                &self.active_store,
+                web_sys::IdbTransactionMode::Readwrite,
+            )
+            .expect("Could not create transaction");
+        let store = transaction
+            .object_store(&self.active_store)
+            .expect("Could not get hold of object store");
+
+        for key in deletes {
+            store.delete(key).expect("Could not remove the given key");
+        }
+        for (key, value) in puts {
+            store
+                .put_with_key(value, key)
+                .expect("Could not put the given key");
+        }
+
+        IdbCommit::new(transaction)
+    }
+
     /// Gets the string value of a given string key.
     ///
     /// Returns `None` if the given key does not exist.
     pub fn get(&self, key: &str) -> IdbTxn {
-        let key_as_jsv =
-            JsValue::from_serde(&key.to_string()).expect("Unable to serialize to JsValue");
+        let key_as_jsv = JsValue::from_str(key);
 
         let store = self
             .inner
@@ -356,8 +784,7 @@ impl IdbHandle {
     ///
     /// It propagates I/O or serialization errors during writing the log.
     pub fn remove(&self, key: &str) -> IdbTxn {
-        let key_as_jsv =
-            JsValue::from_serde(&key.to_string()).expect("Unable to serialize to JsValue");
+        let key_as_jsv = JsValue::from_str(key);
         IdbTxn::new(
             self.inner
                 .transaction_with_str_and_mode(
@@ -375,7 +802,7 @@ impl IdbHandle {
 
 /// Holds an indexed database
 pub struct IdbOpenDbRequest {
-    inner: Arc<web_sys::IdbOpenDbRequest>,
+    inner: Rc<web_sys::IdbOpenDbRequest>,
     callback: Option<Closure<dyn FnMut()>>,
 }
 
@@ -389,14 +816,14 @@ impl IdbOpenDbRequest {
             .expect("TypeError is not possible with Rust");
 
         IdbOpenDbRequest {
-            inner: Arc::new(open_request),
+            inner: Rc::new(open_request),
             callback: None,
         }
     }
 
     /// Opens a new database
     pub fn open_with_store(self, name: &str) -> IdbOpenDbRequest {
-        let _db_request_aclone = Arc::clone(&self.inner);
+        let _db_request_aclone = Rc::clone(&self.inner);
 
         let name_clone: String = name.into();
         let upgradeneeded_cb = Closure::once(move |e: web_sys::Event| {
@@ -42,6 +42,7 @@ macro_rules! info {
     }};
 }
 
+#[cfg(target_arch = "wasm32")]
 #[wasm_bindgen]
 extern "C" {
     /// Extern JS Binding for `console.log()`
@@ -49,15 +50,46 @@ extern "C" {
     fn log(s: &str);
 }
 
+/// Native stand-in for `console.log()`, used outside of the browser
+#[cfg(not(target_arch = "wasm32"))]
+fn log(s: &str) {
+    eprintln!("{}", s);
+}
+
+mod archive;
+mod backend;
+mod crypto;
+mod cursor;
 mod engine;
 mod error;
+mod feed;
+#[cfg(not(target_arch = "wasm32"))]
+mod fs;
 mod idb;
+mod memory;
+mod merge;
+mod record;
+mod tuple;
 // mod worker;
 // mod thread_pool;
 // mod engines;
 
-pub use engine::KvStore;
+pub use archive::{ArchiveSource, ImportMode};
+pub use backend::{Backend, BackendFile};
+pub use crypto::{EncryptedBackend, EncryptedFile, EncryptionKey};
+pub use cursor::{ContinuationToken, Cursor, Page, Scan};
+pub use engine::{
+    BlobReader, BlobWriter, CompactionPolicy, Eviction, KvStore, KvTxn, LogStats, Lookup, Problem,
+    Quota, RepairReport, Report, Snapshot, Stats, WriteBatch,
+};
 pub use error::{KvsError, Result};
-pub use idb::{IdbFile, IdbFolder, IdbHandle, IdbOpenDbRequest};
+pub use feed::{Change, ChangeKind, Watch, Watcher};
+#[cfg(not(target_arch = "wasm32"))]
+pub use fs::{FsBackend, FsFile};
+pub use idb::{IdbCommit, IdbFile, IdbFolder, IdbHandle, IdbOpenDbRequest};
+pub use memory::{MemoryBackend, MemoryFile};
+pub use merge::{JsonMergePatch, ListAppend, MergeOperator, NumericAdd};
+pub use tuple::{Segment, Tuple};
 
+#[cfg(target_arch = "wasm32")]
 use wasm_bindgen::prelude::*;
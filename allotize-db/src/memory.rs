@@ -0,0 +1,149 @@
+use async_trait::async_trait;
+
+use std::cmp;
+use std::collections::HashMap;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::sync::{Arc, RwLock};
+
+use crate::{Backend, BackendFile, Result};
+
+type SharedBuffer = Arc<RwLock<Vec<u8>>>;
+
+/// A `Backend` that keeps all files in memory.
+///
+/// Clones share the same files, so a store can be dropped and reopened
+/// from a clone to emulate persistence.
+#[derive(Clone, Default, Debug)]
+pub struct MemoryBackend {
+    files: Arc<RwLock<HashMap<String, SharedBuffer>>>,
+}
+
+impl MemoryBackend {
+    /// Creates an empty in-memory backend
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+#[async_trait(?Send)]
+impl Backend for MemoryBackend {
+    type File = MemoryFile;
+
+    async fn open_file(&mut self, path: &Path) -> Result<MemoryFile> {
+        let name = path.to_str().expect("Could not transform path to str");
+        let inner = Arc::clone(
+            self.files
+                .write()
+                .expect("Could not get write lock on files")
+                .entry(name.into())
+                .or_default(),
+        );
+        let pos = inner.read().expect("Could not get read lock on file").len() as u64;
+
+        Ok(MemoryFile { pos, inner })
+    }
+
+    async fn file_names(&self) -> Result<Vec<String>> {
+        Ok(self
+            .files
+            .read()
+            .expect("Could not get read lock on files")
+            .keys()
+            .cloned()
+            .collect())
+    }
+
+    async fn remove_file(&mut self, path: &Path) -> Result<()> {
+        let name = path.to_str().expect("Could not transform path to str");
+        self.files
+            .write()
+            .expect("Could not get write lock on files")
+            .remove(name);
+        Ok(())
+    }
+}
+
+/// A file stored in a `MemoryBackend`
+#[derive(Debug)]
+pub struct MemoryFile {
+    pos: u64,
+    inner: SharedBuffer,
+}
+
+impl MemoryFile {
+    /// Returns the size of a file in bytes
+    pub fn size(&self) -> usize {
+        self.inner.read().unwrap().len()
+    }
+}
+
+impl BackendFile for MemoryFile {
+    fn set_len(&mut self, len: u64) -> io::Result<()> {
+        self.inner
+            .write()
+            .expect("Could not get write lock on file")
+            .resize(len as usize, 0);
+        Ok(())
+    }
+}
+
+impl Read for MemoryFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let inner = self.inner.read().expect("Could not get read lock on file");
+        let start = cmp::min(self.pos, inner.len() as u64) as usize;
+        let n = Read::read(&mut &inner[start..], buf)?;
+        self.pos += n as u64;
+
+        Ok(n)
+    }
+}
+
+impl Write for MemoryFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut inner = self
+            .inner
+            .write()
+            .expect("Could not get write lock on file");
+        let pos = self.pos as usize;
+        if inner.len() < pos + buf.len() {
+            inner.resize(pos + buf.len(), 0);
+        }
+        inner[pos..pos + buf.len()].copy_from_slice(buf);
+        self.pos += buf.len() as u64;
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Seek for MemoryFile {
+    fn seek(&mut self, style: SeekFrom) -> io::Result<u64> {
+        let (base_pos, offset) = match style {
+            SeekFrom::Start(n) => {
+                self.pos = n;
+                return Ok(n);
+            }
+            SeekFrom::End(n) => (self.size() as u64, n),
+            SeekFrom::Current(n) => (self.pos, n),
+        };
+        let new_pos = if offset >= 0 {
+            base_pos.checked_add(offset as u64)
+        } else {
+            base_pos.checked_sub(offset.wrapping_neg() as u64)
+        };
+        match new_pos {
+            Some(n) => {
+                self.pos = n;
+                Ok(self.pos)
+            }
+            None => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "invalid seek to a negative or overflowing position",
+            )),
+        }
+    }
+}
@@ -0,0 +1,199 @@
+//! Merge operators, which fold small updates into a value, see `KvTxn::merge`.
+//!
+//! A merge only appends its operand to the log. The operands of a key are
+//! folded into its value when it is read, and for good during a compaction.
+
+use std::sync::Arc;
+
+use serde_json::{Map, Value};
+
+use crate::{KvsError, Result};
+
+/// Folds an operand into the value of a key.
+///
+/// Values and operands are json, like the values set by `KvTxn::set`.
+/// The operator is written to the log by its name, so it has to be registered
+/// under the same name whenever the store is opened, see `KvStore::register_merge_operator`.
+pub trait MergeOperator: Send + Sync {
+    /// Identifies the operator in the log
+    fn name(&self) -> &str;
+
+    /// Returns the value of `key` after the operand is folded into `existing`,
+    /// which is `None` if the key was absent.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::MergeFailed` if the operand can not be folded into the value.
+    fn merge(&self, key: &str, existing: Option<&str>, operand: &str) -> Result<String>;
+}
+
+/// Adds numbers to a number, an absent key counts as 0.
+///
+/// The sum stays an integer as long as both numbers are.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NumericAdd;
+
+impl MergeOperator for NumericAdd {
+    fn name(&self) -> &str {
+        "add"
+    }
+
+    fn merge(&self, key: &str, existing: Option<&str>, operand: &str) -> Result<String> {
+        let existing = match existing {
+            Some(existing) => parse(key, existing)?,
+            None => Value::from(0),
+        };
+        let operand = parse(key, operand)?;
+        let sum = match (existing.as_i64(), operand.as_i64()) {
+            (Some(existing), Some(operand)) => existing.checked_add(operand).map(Value::from),
+            _ => match (existing.as_f64(), operand.as_f64()) {
+                (Some(existing), Some(operand)) => Some(Value::from(existing + operand)),
+                _ => return Err(failed(key, "only numbers can be added")),
+            },
+        };
+        let sum = sum.ok_or_else(|| failed(key, "the sum overflows"))?;
+        Ok(sum.to_string())
+    }
+}
+
+/// Applies json merge patches to an object, as described in RFC 7396.
+///
+/// Members that are `null` in the patch are removed, objects are patched
+/// recursively and everything else replaces the member.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct JsonMergePatch;
+
+impl MergeOperator for JsonMergePatch {
+    fn name(&self) -> &str {
+        "merge_patch"
+    }
+
+    fn merge(&self, key: &str, existing: Option<&str>, operand: &str) -> Result<String> {
+        let mut target = match existing {
+            Some(existing) => parse(key, existing)?,
+            None => Value::Null,
+        };
+        merge_patch(&mut target, parse(key, operand)?);
+        Ok(target.to_string())
+    }
+}
+
+/// Applies `patch` to `target`, see `JsonMergePatch`
+fn merge_patch(target: &mut Value, patch: Value) {
+    let patch = match patch {
+        Value::Object(patch) => patch,
+        patch => {
+            *target = patch;
+            return;
+        }
+    };
+    if !target.is_object() {
+        *target = Value::Object(Map::new());
+    }
+    let target = target.as_object_mut().expect("target is an object");
+    for (name, value) in patch {
+        if value.is_null() {
+            target.remove(&name);
+        } else {
+            merge_patch(target.entry(name).or_insert(Value::Null), value);
+        }
+    }
+}
+
+/// Appends each operand as one element to a list, an absent key counts as `[]`
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ListAppend;
+
+impl MergeOperator for ListAppend {
+    fn name(&self) -> &str {
+        "append"
+    }
+
+    fn merge(&self, key: &str, existing: Option<&str>, operand: &str) -> Result<String> {
+        let mut list = match existing {
+            Some(existing) => match parse(key, existing)? {
+                Value::Array(list) => list,
+                _ => return Err(failed(key, "only lists can be appended to")),
+            },
+            None => Vec::new(),
+        };
+        list.push(parse(key, operand)?);
+        Ok(Value::Array(list).to_string())
+    }
+}
+
+/// The merge operators that are registered on a store, by the keys they apply to
+#[derive(Clone, Default)]
+pub(crate) struct MergeOperators {
+    // the key prefix of each operator, the latest registration first
+    by_prefix: Vec<(String, Arc<dyn MergeOperator>)>,
+}
+
+impl MergeOperators {
+    /// Applies `operator` to the keys that begin with `prefix`
+    pub(crate) fn register(&mut self, prefix: String, operator: Arc<dyn MergeOperator>) {
+        self.by_prefix.insert(0, (prefix, operator));
+    }
+
+    /// Returns the operator that applies to `key`, the one with the longest prefix
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::NoMergeOperator` if no operator applies to the key.
+    pub(crate) fn for_key(&self, key: &str) -> Result<&Arc<dyn MergeOperator>> {
+        self.by_prefix
+            .iter()
+            .filter(|(prefix, _)| key.starts_with(prefix.as_str()))
+            .max_by_key(|(prefix, _)| prefix.len())
+            .map(|(_, operator)| operator)
+            .ok_or_else(|| KvsError::NoMergeOperator {
+                key: key.to_owned(),
+            })
+    }
+
+    /// Folds an operand that was written by the operator called `name`.
+    ///
+    /// The built-in operators are known by their names even if they are not registered.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::UnknownMergeOperator` if no operator of that name is registered.
+    pub(crate) fn merge(
+        &self,
+        name: &str,
+        key: &str,
+        existing: Option<&str>,
+        operand: &str,
+    ) -> Result<String> {
+        let registered = self
+            .by_prefix
+            .iter()
+            .map(|(_, operator)| operator.as_ref())
+            .find(|operator| operator.name() == name);
+        let builtins: [&dyn MergeOperator; 3] = [&NumericAdd, &JsonMergePatch, &ListAppend];
+        let operator = registered
+            .or_else(|| {
+                builtins
+                    .iter()
+                    .copied()
+                    .find(|operator| operator.name() == name)
+            })
+            .ok_or_else(|| KvsError::UnknownMergeOperator {
+                name: name.to_owned(),
+            })?;
+        operator.merge(key, existing, operand)
+    }
+}
+
+/// Parses a value or an operand of `key`
+fn parse(key: &str, json: &str) -> Result<Value> {
+    serde_json::from_str(json).map_err(|err| failed(key, err))
+}
+
+/// Returns the error for an operand that can not be folded into the value of `key`
+fn failed(key: &str, reason: impl ToString) -> KvsError {
+    KvsError::MergeFailed {
+        key: key.to_owned(),
+        reason: reason.to_string(),
+    }
+}
@@ -0,0 +1,181 @@
+//! Layout of a log file.
+//!
+//! A log starts with a header that carries the version of the record encoding:
+//!
+//! ```text
+//! +---------------+-----------------+
+//! | magic: "ADBL" | version: u32 BE |
+//! +---------------+-----------------+
+//! ```
+//!
+//! Followed by records, each prefixed by the length of its payload and a CRC32 of it:
+//!
+//! ```text
+//! +-------------+-------------+--------------------+
+//! | len: u32 BE | crc: u32 BE | payload: len bytes |
+//! +-------------+-------------+--------------------+
+//! ```
+//!
+//! Logs written before the header was introduced hold json payloads.
+//! The length is big endian, so a framed log never starts with `{`.
+//! This is how logs written before the framing was introduced,
+//! as plain concatenated json, are told apart.
+
+use std::io::{self, Read, Seek, SeekFrom, Write};
+
+/// Marks a log that starts with a header
+const MAGIC: &[u8; 4] = b"ADBL";
+
+/// Version of the record encoding written to new logs.
+///
+/// Version 2 added commands that set a key with an expiry.
+/// Version 3 added sequence numbers to the commands.
+/// Version 4 added the operands of merge operators.
+/// Version 5 added commands that remove a range of keys.
+/// Version 6 added commands that set a value stored in a blob.
+pub(crate) const FORMAT_VERSION: u32 = 6;
+
+/// Oldest version of the record encoding that can still be read
+pub(crate) const MIN_FORMAT_VERSION: u32 = 1;
+
+/// Number of bytes in front of the first record of a log
+pub(crate) const FILE_HEADER_LEN: u64 = 8;
+
+/// Number of bytes in front of every payload
+pub(crate) const HEADER_LEN: u64 = 8;
+
+/// The outcome of reading a single record
+pub(crate) enum Frame {
+    /// A record whose checksum matches its payload
+    Complete(Vec<u8>),
+    /// A record that ends past the end of the log, it was only partially written
+    Torn,
+    /// A record whose checksum does not match its payload
+    Corrupt,
+}
+
+/// Writes the header of a new log, with the current `FORMAT_VERSION`.
+pub(crate) fn write_header<W: Write>(writer: &mut W) -> io::Result<()> {
+    writer.write_all(MAGIC)?;
+    writer.write_all(&FORMAT_VERSION.to_be_bytes())
+}
+
+/// Reads the header of a log that is at least `FILE_HEADER_LEN` bytes long.
+///
+/// Returns the version of the log, or `None` if it was written before
+/// the header was introduced. `reader` must be positioned at the start of the log.
+pub(crate) fn read_header<R: Read>(reader: &mut R) -> io::Result<Option<u32>> {
+    let mut header = [0; FILE_HEADER_LEN as usize];
+    reader.read_exact(&mut header)?;
+    if &header[..4] != MAGIC {
+        return Ok(None);
+    }
+
+    let mut version = [0; 4];
+    version.copy_from_slice(&header[4..]);
+    Ok(Some(u32::from_be_bytes(version)))
+}
+
+/// Writes `payload` as a single record.
+///
+/// The payload starts `HEADER_LEN` bytes after the current position of `writer`.
+pub(crate) fn write<W: Write>(writer: &mut W, payload: &[u8]) -> io::Result<()> {
+    let len = payload.len() as u32;
+    if len as usize != payload.len() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "record is too large to be framed",
+        ));
+    }
+
+    let mut header = [0; HEADER_LEN as usize];
+    header[..4].copy_from_slice(&len.to_be_bytes());
+    header[4..].copy_from_slice(&crc32fast::hash(payload).to_be_bytes());
+    writer.write_all(&header)?;
+    writer.write_all(payload)
+}
+
+/// Reads the record at `pos` of a log that is `log_len` bytes long.
+///
+/// `reader` must be positioned at `pos`.
+pub(crate) fn read<R: Read>(reader: &mut R, pos: u64, log_len: u64) -> io::Result<Frame> {
+    match read_record_header(reader, pos, log_len)? {
+        Some((len, crc)) => read_payload(reader, len, crc),
+        None => Ok(Frame::Torn),
+    }
+}
+
+/// Reads the header of the record at `pos` of a log that is `log_len` bytes long.
+///
+/// Returns the length and the checksum of the payload, or `None` if the record
+/// ends past the end of the log. `reader` must be positioned at `pos`.
+pub(crate) fn read_record_header<R: Read>(
+    reader: &mut R,
+    pos: u64,
+    log_len: u64,
+) -> io::Result<Option<(u64, u32)>> {
+    if pos + HEADER_LEN > log_len {
+        return Ok(None);
+    }
+
+    let mut header = [0; HEADER_LEN as usize];
+    reader.read_exact(&mut header)?;
+    let (len, crc) = split_header(&header);
+    if pos + HEADER_LEN + len > log_len {
+        return Ok(None);
+    }
+    Ok(Some((len, crc)))
+}
+
+/// Reads the payload of a record, whose header was read by `read_record_header`.
+pub(crate) fn read_payload<R: Read>(reader: &mut R, len: u64, crc: u32) -> io::Result<Frame> {
+    let mut payload = vec![0; len as usize];
+    reader.read_exact(&mut payload)?;
+    // An empty payload is never written, a zeroed header is garbage
+    if len == 0 || crc32fast::hash(&payload) != crc {
+        return Ok(Frame::Corrupt);
+    }
+
+    Ok(Frame::Complete(payload))
+}
+
+/// Returns the position of the first intact record after the damaged one at `pos`,
+/// of a log that is `log_len` bytes long, if there is one.
+///
+/// The length of a damaged record can not be trusted, so every position after it
+/// is tried. Since a log is only appended to, a damaged record that is followed
+/// by an intact one is corruption, rather than what a torn write left behind.
+pub(crate) fn next_intact<R: Read + Seek>(
+    reader: &mut R,
+    pos: u64,
+    log_len: u64,
+) -> io::Result<Option<u64>> {
+    reader.seek(SeekFrom::Start(pos + 1))?;
+    let mut rest = Vec::new();
+    reader
+        .take(log_len.saturating_sub(pos + 1))
+        .read_to_end(&mut rest)?;
+
+    let header_len = HEADER_LEN as usize;
+    for start in 0..rest.len().saturating_sub(header_len) {
+        let (len, crc) = split_header(&rest[start..start + header_len]);
+        let payload_start = start + header_len;
+        let payload = match rest.get(payload_start..payload_start + len as usize) {
+            Some(payload) => payload,
+            None => continue,
+        };
+        if len > 0 && crc32fast::hash(payload) == crc {
+            return Ok(Some(pos + 1 + start as u64));
+        }
+    }
+    Ok(None)
+}
+
+/// Splits the header of a record into the length and the checksum of its payload
+fn split_header(header: &[u8]) -> (u64, u32) {
+    let mut len = [0; 4];
+    len.copy_from_slice(&header[..4]);
+    let mut crc = [0; 4];
+    crc.copy_from_slice(&header[4..HEADER_LEN as usize]);
+    (u64::from(u32::from_be_bytes(len)), u32::from_be_bytes(crc))
+}
@@ -0,0 +1,277 @@
+//! Composite keys of typed segments, see `Tuple`.
+//!
+//! A tuple is packed into a string key whose order is the order of the
+//! tuples, segment by segment, so numbers and timestamps sort by their
+//! value instead of by their digits. Each segment starts with a tag:
+//!
+//! * bytes are hex digits, ended by `\0`
+//! * strings are ended by `\0`, and a `\0` within them is escaped as `\0\u{ff}`
+//! * integers and timestamps are 16 hex digits, with the sign bit flipped
+//!
+//! Since every segment is ended or has a fixed length, the packed key of a
+//! tuple is a prefix of the packed keys of all tuples that begin with it,
+//! where it is followed by a tag. Strings ending in `\0` are followed by
+//! the escape instead, which is why `Tuple::range` is not a plain prefix scan.
+
+use std::fmt;
+use std::iter::FromIterator;
+use std::ops::Bound;
+use std::str::Chars;
+
+use crate::cursor::Scan;
+use crate::{KvsError, Result};
+
+const BYTES: char = '\u{1}';
+const STRING: char = '\u{2}';
+const INT: char = '\u{3}';
+const TIMESTAMP: char = '\u{4}';
+
+const END: char = '\0';
+const ESCAPE: char = '\u{ff}';
+
+/// A segment of a `Tuple`.
+///
+/// Segments of different types are ordered bytes, strings, integers, timestamps.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Segment {
+    /// Ordered byte by byte
+    Bytes(Vec<u8>),
+    /// Ordered char by char, like the keys of a store
+    String(String),
+    /// Ordered by value, negative ones first
+    Int(i64),
+    /// Milliseconds since the Unix epoch, ordered by time
+    Timestamp(i64),
+}
+
+impl From<&str> for Segment {
+    fn from(segment: &str) -> Self {
+        Segment::String(segment.to_owned())
+    }
+}
+
+impl From<String> for Segment {
+    fn from(segment: String) -> Self {
+        Segment::String(segment)
+    }
+}
+
+impl From<i64> for Segment {
+    fn from(segment: i64) -> Self {
+        Segment::Int(segment)
+    }
+}
+
+impl From<Vec<u8>> for Segment {
+    fn from(segment: Vec<u8>) -> Self {
+        Segment::Bytes(segment)
+    }
+}
+
+impl From<&[u8]> for Segment {
+    fn from(segment: &[u8]) -> Self {
+        Segment::Bytes(segment.to_vec())
+    }
+}
+
+/// A composite key, e.g. `("user", 42)`, that is packed into a string key
+/// which sorts like the tuple, see `pack`.
+///
+/// E.g. `("order", 2)` sorts before `("order", 10)`, unlike `"order/2"` and `"order/10"`,
+/// and all orders are scanned by the prefix `("order",)`, see `scan`.
+#[derive(Debug, Clone, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Tuple {
+    segments: Vec<Segment>,
+}
+
+impl Tuple {
+    /// Returns the empty tuple, which is a prefix of every tuple
+    pub fn new() -> Self {
+        Tuple::default()
+    }
+
+    /// Appends a segment
+    pub fn with(mut self, segment: impl Into<Segment>) -> Self {
+        self.segments.push(segment.into());
+        self
+    }
+
+    /// Returns the segments, in order
+    pub fn segments(&self) -> &[Segment] {
+        &self.segments
+    }
+
+    /// Returns whether the tuple begins with the segments of `prefix`
+    pub fn starts_with(&self, prefix: &Tuple) -> bool {
+        self.segments.starts_with(&prefix.segments)
+    }
+
+    /// Returns the key of the tuple, which sorts before the keys of
+    /// all greater tuples, and is a prefix of the keys of longer ones.
+    pub fn pack(&self) -> String {
+        let mut key = String::new();
+        for segment in &self.segments {
+            match segment {
+                Segment::Bytes(bytes) => {
+                    key.push(BYTES);
+                    for byte in bytes {
+                        key.push_str(&format!("{:02x}", byte));
+                    }
+                    key.push(END);
+                }
+                Segment::String(string) => {
+                    key.push(STRING);
+                    for c in string.chars() {
+                        key.push(c);
+                        if c == END {
+                            key.push(ESCAPE);
+                        }
+                    }
+                    key.push(END);
+                }
+                Segment::Int(int) => {
+                    key.push(INT);
+                    key.push_str(&pack_int(*int));
+                }
+                Segment::Timestamp(millis) => {
+                    key.push(TIMESTAMP);
+                    key.push_str(&pack_int(*millis));
+                }
+            }
+        }
+        key
+    }
+
+    /// Reads a tuple from its key, see `pack`.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::InvalidTuple` if the key was not packed from a tuple.
+    pub fn unpack(key: &str) -> Result<Tuple> {
+        let invalid = |reason: &str| KvsError::InvalidTuple {
+            key: key.to_owned(),
+            reason: reason.to_owned(),
+        };
+
+        let mut segments = Vec::new();
+        let mut chars = key.chars();
+        while let Some(tag) = chars.next() {
+            let segment = match tag {
+                BYTES => {
+                    let hex = until_end(&mut chars).ok_or_else(|| invalid("unterminated bytes"))?;
+                    Segment::Bytes(unpack_hex(&hex).ok_or_else(|| invalid("invalid bytes"))?)
+                }
+                STRING => {
+                    let mut string = String::new();
+                    loop {
+                        match chars.next() {
+                            Some(END) if chars.as_str().starts_with(ESCAPE) => {
+                                chars.next();
+                                string.push(END);
+                            }
+                            Some(END) => break,
+                            Some(c) => string.push(c),
+                            None => return Err(invalid("unterminated string")),
+                        }
+                    }
+                    Segment::String(string)
+                }
+                INT | TIMESTAMP => {
+                    let hex: String = chars.by_ref().take(16).collect();
+                    let int = unpack_int(&hex).ok_or_else(|| invalid("invalid integer"))?;
+                    if tag == INT {
+                        Segment::Int(int)
+                    } else {
+                        Segment::Timestamp(int)
+                    }
+                }
+                _ => return Err(invalid("unknown segment type")),
+            };
+            segments.push(segment);
+        }
+        Ok(Tuple { segments })
+    }
+
+    /// Returns the bounds of the keys of all tuples that begin with this one,
+    /// including itself, e.g. for `KvTxn::get_range`
+    pub fn range(&self) -> (Bound<String>, Bound<String>) {
+        let prefix = self.pack();
+        // the tags of the following segments are all below the escape
+        let mut end = prefix.clone();
+        end.push(ESCAPE);
+        (Bound::Included(prefix), Bound::Excluded(end))
+    }
+
+    /// Scans the keys of all tuples that begin with this one, including itself
+    pub fn scan(&self) -> Scan {
+        let (start, end) = self.range();
+        Scan::range(start, end)
+    }
+}
+
+impl<S: Into<Segment>> FromIterator<S> for Tuple {
+    fn from_iter<I: IntoIterator<Item = S>>(iter: I) -> Self {
+        Tuple {
+            segments: iter.into_iter().map(Into::into).collect(),
+        }
+    }
+}
+
+impl fmt::Display for Tuple {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "(")?;
+        for (i, segment) in self.segments.iter().enumerate() {
+            if i > 0 {
+                write!(f, ", ")?;
+            }
+            match segment {
+                Segment::Bytes(bytes) => write!(f, "{:?}", bytes)?,
+                Segment::String(string) => write!(f, "{:?}", string)?,
+                Segment::Int(int) => write!(f, "{}", int)?,
+                Segment::Timestamp(millis) => write!(f, "@{}", millis)?,
+            }
+        }
+        write!(f, ")")
+    }
+}
+
+/// Returns the hex digits of an integer, which sort like the integers
+fn pack_int(int: i64) -> String {
+    format!("{:016x}", (int as u64) ^ (1 << 63))
+}
+
+/// Reads an integer from its hex digits, see `pack_int`
+fn unpack_int(hex: &str) -> Option<i64> {
+    if hex.len() != 16 || !is_hex(hex) {
+        return None;
+    }
+    let int = u64::from_str_radix(hex, 16).ok()?;
+    Some((int ^ (1 << 63)) as i64)
+}
+
+/// Reads bytes from pairs of hex digits
+fn unpack_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) || !is_hex(hex) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).ok())
+        .collect()
+}
+
+/// Returns whether `hex` only holds the hex digits that `pack` writes
+fn is_hex(hex: &str) -> bool {
+    hex.chars().all(|c| matches!(c, '0'..='9' | 'a'..='f'))
+}
+
+/// Returns the chars up to the next `END`, `None` if there is none
+fn until_end(chars: &mut Chars<'_>) -> Option<String> {
+    let mut segment = String::new();
+    loop {
+        match chars.next()? {
+            END => return Some(segment),
+            c => segment.push(c),
+        }
+    }
+}
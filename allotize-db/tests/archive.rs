@@ -0,0 +1,151 @@
+//! Test suite for exporting and importing archives, running natively on top of `MemoryBackend`.
+
+#![cfg(not(target_arch = "wasm32"))]
+
+mod common;
+
+use allotize_db::{ImportMode, KvsError, MemoryBackend};
+use common::{all_keys, numbered, open};
+use futures::executor::block_on;
+use std::io::Cursor;
+use std::path::Path;
+use std::time::Duration;
+
+// An exported archive is imported into another store, merging with
+// or replacing what is there
+#[test]
+fn export_import() {
+    block_on(async {
+        let component = Path::new("component");
+        let mut source = open(MemoryBackend::new(), "/tmp/export").await;
+        source.add_substore(component).await.unwrap();
+        let mut txn = source.txn();
+        txn.set("key1".to_owned(), "value1").await.unwrap();
+        txn.set_expiring("key2".to_owned(), "value2", Duration::from_secs(3600))
+            .await
+            .unwrap();
+        txn.commit().await.unwrap();
+        let mut txn = source.txn();
+        txn.set_scoped("key1".to_owned(), "scoped".to_owned(), Some(component))
+            .await
+            .unwrap();
+        txn.commit().await.unwrap();
+
+        let mut archive = Vec::new();
+        assert_eq!(source.export(&mut archive).await.unwrap(), 3);
+        let header = String::from_utf8(archive.clone()).unwrap();
+        assert!(header
+            .starts_with(r#"{"format":"allotize-db","version":2,"path":"/tmp/export","keys":3}"#));
+
+        let mut target = open(MemoryBackend::new(), "/tmp/import").await;
+        let mut txn = target.txn();
+        txn.set("key1".to_owned(), "old").await.unwrap();
+        txn.set("other".to_owned(), "other").await.unwrap();
+        txn.commit().await.unwrap();
+
+        assert_eq!(
+            target
+                .import(Cursor::new(&archive), ImportMode::Merge)
+                .await
+                .unwrap(),
+            3
+        );
+        assert_eq!(
+            target.get_all().await.unwrap(),
+            [
+                ("key1".to_owned(), "\"value1\"".to_owned()),
+                ("key2".to_owned(), "\"value2\"".to_owned()),
+                ("other".to_owned(), "\"other\"".to_owned()),
+            ]
+        );
+        assert_eq!(
+            target
+                .txn()
+                .get_scoped("key1".to_owned(), Some(component))
+                .await
+                .unwrap(),
+            Some("scoped".to_owned())
+        );
+
+        target
+            .import(Cursor::new(&archive), ImportMode::Replace)
+            .await
+            .unwrap();
+        let keys: Vec<_> = all_keys(&mut target).await;
+        assert_eq!(keys, ["key1", "key2"]);
+
+        // the expiry is carried over
+        let mut reexported = Vec::new();
+        target.export(&mut reexported).await.unwrap();
+        assert!(String::from_utf8(reexported)
+            .unwrap()
+            .contains(r#""key":"key2","value":"\"value2\"","expires_at":"#));
+    });
+}
+
+// A cut off archive is reported, without importing anything
+#[test]
+fn truncated_archive() {
+    block_on(async {
+        let mut source = open(MemoryBackend::new(), "/tmp/truncated").await;
+        numbered(&mut source).await;
+        let mut archive = Vec::new();
+        source.export(&mut archive).await.unwrap();
+        let archive = String::from_utf8(archive).unwrap();
+        let lines: Vec<_> = archive.lines().take(6).collect();
+        let truncated = lines.join("\n");
+
+        let mut target = open(MemoryBackend::new(), "/tmp/truncated").await;
+        match target
+            .import(Cursor::new(truncated), ImportMode::Merge)
+            .await
+        {
+            Err(KvsError::InvalidArchive { line, .. }) => assert_eq!(line, 7),
+            _ => panic!("truncated archive was not detected"),
+        }
+        assert!(target.get_all().await.unwrap().is_empty());
+
+        assert!(target
+            .import(
+                Cursor::new(&b"{\"not\":\"an archive\"}\n"[..]),
+                ImportMode::Merge
+            )
+            .await
+            .is_err());
+    });
+}
+
+// An archive that is broken after its header leaves the store as it was,
+// even if it was to replace what is there
+#[test]
+fn invalid_archive() {
+    block_on(async {
+        let mut source = open(MemoryBackend::new(), "/tmp/invalid_source").await;
+        numbered(&mut source).await;
+        let mut archive = Vec::new();
+        source.export(&mut archive).await.unwrap();
+        let header = archive.split(|&byte| byte == b'\n').next().unwrap();
+        let mut broken = header.to_vec();
+        broken.extend_from_slice(b"\nGARBAGE\n");
+
+        let mut target = open(MemoryBackend::new(), "/tmp/invalid_archive").await;
+        numbered(&mut target).await;
+        match target
+            .import(Cursor::new(broken), ImportMode::Replace)
+            .await
+        {
+            Err(KvsError::InvalidArchive { line, .. }) => assert_eq!(line, 2),
+            _ => panic!("invalid archive was not detected"),
+        }
+        assert_eq!(target.get_all().await.unwrap().len(), 10);
+
+        assert_eq!(
+            target
+                .import(Cursor::new(&archive), ImportMode::Replace)
+                .await
+                .unwrap(),
+            10
+        );
+        assert_eq!(target.get_all().await.unwrap().len(), 10);
+    });
+}
@@ -0,0 +1,92 @@
+//! Test suite for write batches, running natively on top of `MemoryBackend`.
+
+#![cfg(not(target_arch = "wasm32"))]
+
+mod common;
+
+use allotize_db::{KvsError, Watch, WriteBatch};
+use common::{open, FullBackend};
+use futures::executor::block_on;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::Ordering;
+
+// A write batch stores many keys with a single record,
+// so a batch that can not be stored leaves nothing behind
+#[test]
+fn write_batch() {
+    block_on(async {
+        let backend = FullBackend::default();
+        let test_path = PathBuf::from("/tmp/write_batch");
+        let mut store = open(backend.clone(), &test_path).await;
+        let substore = Path::new("component");
+        store.add_substore(substore).await.unwrap();
+        let mut txn = store.txn();
+        txn.set("stale".to_owned(), "value").await.unwrap();
+        txn.commit().await.unwrap();
+        let mut watcher = store.watch(Watch::all()).unwrap();
+
+        let mut batch = WriteBatch::new();
+        for i in 0..100 {
+            batch.set(format!("key{:03}", i), &i).unwrap();
+        }
+        batch.set("key000".to_owned(), "overwritten").unwrap();
+        batch.remove("stale".to_owned());
+        batch.remove("absent".to_owned());
+        assert_eq!(batch.len(), 102);
+
+        backend.full.store(true, Ordering::SeqCst);
+        assert!(matches!(
+            store.write(batch.clone()).await,
+            Err(KvsError::QuotaExceeded)
+        ));
+        backend.full.store(false, Ordering::SeqCst);
+        assert_eq!(store.get_all().await.unwrap().len(), 1);
+        assert!(watcher.try_next().is_none());
+
+        let mut unknown = batch.clone();
+        unknown.remove_scoped("key".to_owned(), Some(Path::new("unknown")));
+        assert!(matches!(
+            store.write(unknown).await,
+            Err(KvsError::UnknownSubstore { .. })
+        ));
+        assert_eq!(store.get_all().await.unwrap().len(), 1);
+
+        // a batch writes to a single substore
+        let mut spanning = batch.clone();
+        spanning.set_scoped("draft".to_owned(), "text".to_owned(), Some(substore));
+        assert!(matches!(
+            store.write(spanning).await,
+            Err(KvsError::CrossSubstoreCommit)
+        ));
+        assert_eq!(store.get_all().await.unwrap().len(), 1);
+
+        store.write(batch).await.unwrap();
+        // the removal of an absent key is left out
+        let changes: Vec<_> = std::iter::from_fn(|| watcher.try_next()).collect();
+        assert_eq!(changes.len(), 101);
+        assert_eq!(changes[0].seq, 2);
+        assert!(!changes.iter().any(|change| change.key == "absent"));
+        let mut batch = WriteBatch::new();
+        batch.set_scoped("draft".to_owned(), "text".to_owned(), Some(substore));
+        store.write(batch).await.unwrap();
+        drop(store);
+
+        let mut store = open(backend, &test_path).await;
+        store.add_substore(substore).await.unwrap();
+        let items = store.get_all().await.unwrap();
+        assert_eq!(items.len(), 100);
+        assert_eq!(
+            items[0],
+            ("key000".to_owned(), "\"overwritten\"".to_owned())
+        );
+        assert_eq!(items[99], ("key099".to_owned(), "99".to_owned()));
+        let mut txn = store.txn();
+        assert_eq!(
+            txn.get_scoped("draft".to_owned(), Some(substore))
+                .await
+                .unwrap(),
+            Some("text".to_owned())
+        );
+        txn.rollback();
+    });
+}
@@ -5,7 +5,7 @@ extern crate wasm_bindgen_test;
 use wasm_bindgen::prelude::*;
 use wasm_bindgen_test::*;
 
-use allotize_db::{IdbOpenDbRequest, KvStore};
+use allotize_db::{IdbOpenDbRequest, KvStore, WriteBatch};
 use std::path::PathBuf;
 
 use web_sys::console;
@@ -31,6 +31,7 @@ async fn store_1000() {
             txn.set(format!("key{}", i), format!("value{}", random[i]))
                 .await
                 .unwrap();
+            txn.commit().await.unwrap();
         }
     }
     console::time_end_with_label("store_1000_setters");
@@ -49,6 +50,30 @@ async fn store_1000() {
     console::time_end_with_label("store_1000");
 }
 
+// Bulk loads with one write instead of one transaction per key
+#[wasm_bindgen_test]
+async fn store_1000_batch() {
+    let test_path = PathBuf::from("/tmp/bench_batch");
+    let mut store = KvStore::open(&test_path).await.unwrap();
+
+    let n = 1000;
+    let mut random = vec![1; n];
+    getrandom::getrandom(&mut random).unwrap();
+
+    console::time_with_label("store_1000_batch");
+    {
+        // Set 1000
+        let mut batch = WriteBatch::new();
+        for i in 0..n {
+            batch
+                .set(format!("key{}", i), &format!("value{}", random[i]))
+                .unwrap();
+        }
+        store.write(batch).await.unwrap();
+    }
+    console::time_end_with_label("store_1000_batch");
+}
+
 // #[wasm_bindgen_test]
 // async fn idb_1000() {
 //     let idb = IdbOpenDbRequest::new("allotize-db")
@@ -0,0 +1,196 @@
+//! Test suite for values stored as blobs, running natively on top of `MemoryBackend`.
+
+#![cfg(not(target_arch = "wasm32"))]
+
+mod common;
+
+use allotize_db::{Backend, ImportMode, KvStore, KvsError, MemoryBackend};
+use common::{all_keys, open};
+use futures::executor::block_on;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+// Returns the blobs that the backend holds
+async fn blob_names(backend: &MemoryBackend) -> Vec<String> {
+    let mut names: Vec<String> = backend
+        .file_names()
+        .await
+        .unwrap()
+        .into_iter()
+        .filter(|name| name.ends_with(".blob"))
+        .collect();
+    names.sort();
+    names
+}
+
+// Reads a value through `KvStore::read_blob` in small chunks
+async fn read_chunked<B: Backend>(store: &mut KvStore<B>, key: &str) -> Option<Vec<u8>> {
+    let mut reader = store.read_blob(key, None).await.unwrap()?;
+    let mut value = Vec::new();
+    let mut chunk = [0; 7];
+    loop {
+        match reader.read(&mut chunk).await.unwrap() {
+            0 => break,
+            read => value.extend_from_slice(&chunk[..read]),
+        }
+    }
+    assert_eq!(value.len() as u64, reader.len());
+    Some(value)
+}
+
+// Values above the threshold are stored once in a blob of their own,
+// which compactions keep while a key or a snapshot refers to it
+#[test]
+fn blobs() {
+    block_on(async {
+        let backend = MemoryBackend::new();
+        let test_path = PathBuf::from("/tmp/blobs");
+        let mut store = open(backend.clone(), &test_path).await;
+        store.set_blob_threshold(Some(64));
+        let large = "x".repeat(100);
+        let mut txn = store.txn();
+        txn.set("a".to_owned(), &large).await.unwrap();
+        txn.set("b".to_owned(), &large).await.unwrap();
+        txn.set("small".to_owned(), "value").await.unwrap();
+        txn.commit().await.unwrap();
+        // equal values share a blob
+        assert_eq!(blob_names(&backend).await.len(), 1);
+        let encoded = serde_json::to_string(&large).unwrap();
+        assert_eq!(
+            store.txn().get("a".to_owned()).await.unwrap(),
+            Some(encoded.clone())
+        );
+        assert_eq!(
+            read_chunked(&mut store, "b").await,
+            Some(encoded.clone().into_bytes())
+        );
+        assert_eq!(
+            read_chunked(&mut store, "small").await,
+            Some(b"\"value\"".to_vec())
+        );
+        assert!(store.read_blob("c", None).await.unwrap().is_none());
+
+        // binary values are streamed, and can only be read as a stream
+        let image: Vec<u8> = (0..=255).cycle().take(100_000).collect();
+        let mut writer = store.blob_writer("image".to_owned(), None).await.unwrap();
+        for chunk in image.chunks(1000) {
+            writer.write_all(chunk).unwrap();
+        }
+        store.commit_blob(writer).await.unwrap();
+        assert_eq!(blob_names(&backend).await.len(), 2);
+        assert_eq!(read_chunked(&mut store, "image").await, Some(image.clone()));
+        assert!(matches!(
+            store.txn().get("image".to_owned()).await,
+            Err(KvsError::BinaryValue { .. })
+        ));
+        assert!(store.verify().await.unwrap().is_ok());
+        drop(store);
+
+        let mut store = open(backend.clone(), &test_path).await;
+        assert_eq!(read_chunked(&mut store, "image").await, Some(image.clone()));
+        let mut txn = store.txn();
+        txn.set("a".to_owned(), "small").await.unwrap();
+        txn.remove("image".to_owned()).await.unwrap();
+        txn.commit().await.unwrap();
+        store.compact().await.unwrap();
+        // "b" still refers to the large value
+        assert_eq!(blob_names(&backend).await.len(), 1);
+        assert_eq!(
+            store.txn().get("b".to_owned()).await.unwrap(),
+            Some(encoded.clone())
+        );
+
+        // a snapshot keeps the blobs of the values it reads
+        let snapshot = store.snapshot();
+        let mut txn = store.txn();
+        txn.remove("b".to_owned()).await.unwrap();
+        txn.commit().await.unwrap();
+        store.compact().await.unwrap();
+        assert_eq!(blob_names(&backend).await.len(), 1);
+        assert_eq!(snapshot.get(&mut store, "b").await.unwrap(), Some(encoded));
+        drop(snapshot);
+        store.compact().await.unwrap();
+        assert!(blob_names(&backend).await.is_empty());
+        drop(store);
+
+        let mut store = open(backend, &test_path).await;
+        let keys: Vec<String> = all_keys(&mut store).await;
+        assert_eq!(keys, ["a", "small"]);
+    });
+}
+
+// A blob whose rewrite was interrupted after it was removed
+// is restored from its copy when the store is opened
+#[test]
+fn interrupted_blob_rewrite() {
+    block_on(async {
+        let mut backend = MemoryBackend::new();
+        let test_path = PathBuf::from("/tmp/blob_rewrite");
+        let mut store = open(backend.clone(), &test_path).await;
+        store.set_blob_threshold(Some(64));
+        let mut txn = store.txn();
+        txn.set("large".to_owned(), &"x".repeat(100)).await.unwrap();
+        txn.commit().await.unwrap();
+        drop(store);
+
+        let blob = blob_names(&backend).await.remove(0);
+        let mut contents = Vec::new();
+        let mut file = backend.open_file(Path::new(&blob)).await.unwrap();
+        file.seek(SeekFrom::Start(0)).unwrap();
+        file.read_to_end(&mut contents).unwrap();
+        let part = blob.replace(".blob", ".part");
+        let mut copy = backend.open_file(Path::new(&part)).await.unwrap();
+        copy.write_all(&contents).unwrap();
+        backend.remove_file(Path::new(&blob)).await.unwrap();
+
+        let mut store = open(backend.clone(), &test_path).await;
+        assert_eq!(blob_names(&backend).await, [blob]);
+        assert!(!backend.file_names().await.unwrap().contains(&part));
+        assert_eq!(
+            store
+                .txn()
+                .get("large".to_owned())
+                .await
+                .unwrap()
+                .map(|value| value.len()),
+            Some(102)
+        );
+    });
+}
+
+// Binary values are exported as base64, and imported into blobs again
+#[test]
+fn binary_archive() {
+    block_on(async {
+        let mut source = open(MemoryBackend::new(), "/tmp/binary_export").await;
+        let image: Vec<u8> = (0..=255).cycle().take(10_000).collect();
+        let mut writer = source.blob_writer("img".to_owned(), None).await.unwrap();
+        writer.write_all(&image).unwrap();
+        source.commit_blob(writer).await.unwrap();
+        let mut txn = source.txn();
+        txn.set("text".to_owned(), "value").await.unwrap();
+        txn.commit().await.unwrap();
+
+        let mut archive = Vec::new();
+        assert_eq!(source.export(&mut archive).await.unwrap(), 2);
+        assert!(String::from_utf8(archive.clone())
+            .unwrap()
+            .contains(r#""key":"img","value":"AAECAwQF"#));
+
+        let backend = MemoryBackend::new();
+        let mut target = open(backend.clone(), "/tmp/binary_import").await;
+        assert_eq!(
+            target
+                .import(io::Cursor::new(&archive), ImportMode::Replace)
+                .await
+                .unwrap(),
+            2
+        );
+        assert_eq!(read_chunked(&mut target, "img").await, Some(image));
+        assert_eq!(
+            target.txn().get("text".to_owned()).await.unwrap(),
+            Some("\"value\"".to_owned())
+        );
+        assert_eq!(blob_names(&backend).await.len(), 1);
+    });
+}
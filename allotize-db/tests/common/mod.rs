@@ -0,0 +1,148 @@
+//! Scaffolding shared by the test suites that run natively on top of `MemoryBackend`
+
+#![allow(dead_code)]
+
+use allotize_db::{Backend, BackendFile, KvStore, KvsError, MemoryBackend, MemoryFile, Result};
+use async_trait::async_trait;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+
+/// A `MemoryBackend` whose files fail to sync while `full` is set,
+/// like `IndexedDB` when it runs out of quota.
+///
+/// The length of the longest range fetched from its files is kept in `fetched`.
+#[derive(Clone, Default)]
+pub struct FullBackend {
+    inner: MemoryBackend,
+    pub full: Arc<AtomicBool>,
+    pub fetched: Arc<AtomicU64>,
+}
+
+pub struct FullFile {
+    inner: MemoryFile,
+    full: Arc<AtomicBool>,
+    fetched: Arc<AtomicU64>,
+}
+
+#[async_trait(?Send)]
+impl Backend for FullBackend {
+    type File = FullFile;
+
+    async fn open_file(&mut self, path: &Path) -> Result<FullFile> {
+        Ok(FullFile {
+            inner: self.inner.open_file(path).await?,
+            full: Arc::clone(&self.full),
+            fetched: Arc::clone(&self.fetched),
+        })
+    }
+
+    async fn file_names(&self) -> Result<Vec<String>> {
+        self.inner.file_names().await
+    }
+
+    async fn remove_file(&mut self, path: &Path) -> Result<()> {
+        self.inner.remove_file(path).await
+    }
+}
+
+#[async_trait(?Send)]
+impl BackendFile for FullFile {
+    fn set_len(&mut self, len: u64) -> io::Result<()> {
+        self.inner.set_len(len)
+    }
+
+    async fn fetch(&mut self, range: Range<u64>) -> Result<()> {
+        let len = range.end.saturating_sub(range.start);
+        self.fetched.fetch_max(len, Ordering::SeqCst);
+        Ok(())
+    }
+
+    async fn sync(&mut self) -> Result<()> {
+        if self.full.load(Ordering::SeqCst) {
+            Err(KvsError::QuotaExceeded)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+impl Read for FullFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.inner.read(buf)
+    }
+}
+
+impl Write for FullFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.inner.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl Seek for FullFile {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.inner.seek(pos)
+    }
+}
+
+/// Overwrites the first occurrence of `needle` in a file with `replacement`
+pub async fn overwrite(
+    backend: &mut MemoryBackend,
+    path: &Path,
+    needle: &[u8],
+    replacement: &[u8],
+) {
+    let mut file = backend.open_file(path).await.unwrap();
+    let mut contents = Vec::new();
+    file.seek(SeekFrom::Start(0)).unwrap();
+    file.read_to_end(&mut contents).unwrap();
+    let pos = contents
+        .windows(needle.len())
+        .position(|window| window == needle)
+        .expect("the file contains the needle");
+    file.seek(SeekFrom::Start(pos as u64)).unwrap();
+    file.write_all(replacement).unwrap();
+}
+
+/// Overwrites the same keys until a compaction has happened
+pub async fn fill<B: Backend>(store: &mut KvStore<B>) {
+    for iter in 0..128 {
+        for key_id in 0..128 {
+            let key = format!("key{}", key_id);
+            let mut txn = store.txn();
+            txn.set(key, &iter.to_string()).await.unwrap();
+            txn.commit().await.unwrap();
+        }
+    }
+}
+
+/// Commits `key0` to `key9`, each holding its own number
+pub async fn numbered<B: Backend>(store: &mut KvStore<B>) {
+    let mut txn = store.txn();
+    for key_id in 0..10 {
+        txn.set(format!("key{}", key_id), &key_id).await.unwrap();
+    }
+    txn.commit().await.unwrap();
+}
+
+/// Opens the store at `path`, failing the test if it can not be opened
+pub async fn open<B: Backend>(backend: B, path: impl Into<PathBuf>) -> KvStore<B> {
+    KvStore::open_with_backend(backend, path).await.unwrap()
+}
+
+/// Returns every key of the store, in order
+pub async fn all_keys<B: Backend>(store: &mut KvStore<B>) -> Vec<String> {
+    store
+        .get_all()
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|(key, _)| key)
+        .collect()
+}
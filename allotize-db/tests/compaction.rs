@@ -0,0 +1,224 @@
+//! Test suite for compactions and hint files, running natively on top of `MemoryBackend`.
+
+#![cfg(not(target_arch = "wasm32"))]
+
+mod common;
+
+use allotize_db::{Backend, CompactionPolicy, MemoryBackend};
+use common::{fill, open};
+use futures::executor::block_on;
+use std::io::{Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+// Overwriting the same keys should eventually trigger a compaction,
+// which must leave the latest values readable after a reopen.
+#[test]
+fn compaction() {
+    block_on(async {
+        let backend = MemoryBackend::new();
+        let test_path = PathBuf::from("/tmp/compaction");
+        let mut store = open(backend.clone(), &test_path).await;
+
+        fill(&mut store).await;
+        drop(store);
+
+        let mut store = open(backend, &test_path).await;
+        for key_id in 0..128 {
+            let key = format!("key{}", key_id);
+            assert_eq!(
+                store.txn().get(key).await.unwrap(),
+                Some("\"127\"".to_owned())
+            );
+        }
+    });
+}
+
+// A compaction writes a hint file, from which the next open loads the index
+#[test]
+fn hint_file() {
+    block_on(async {
+        let mut backend = MemoryBackend::new();
+        let test_path = PathBuf::from("/tmp/hint");
+        let mut store = open(backend.clone(), &test_path).await;
+        fill(&mut store).await;
+        drop(store);
+
+        let file_names = backend.file_names().await.unwrap();
+        let hints: Vec<_> = file_names
+            .iter()
+            .filter(|name| name.ends_with(".hint"))
+            .collect();
+        assert_eq!(hints.len(), 1);
+        assert!(!file_names.contains(&"/tmp/hint/1".to_owned()));
+
+        // A log that the hinted log replaces, as if a compaction was interrupted.
+        // It is only ignored if the hint is used.
+        let mut log = backend.open_file(&test_path.join("1")).await.unwrap();
+        log.write_all(br#"{"Set":{"key":"stale","value":"\"stale\""}}"#)
+            .unwrap();
+
+        let mut store = open(backend.clone(), &test_path).await;
+        assert_eq!(store.txn().get("stale".to_owned()).await.unwrap(), None);
+        for key_id in 0..128 {
+            let key = format!("key{}", key_id);
+            assert_eq!(
+                store.txn().get(key).await.unwrap(),
+                Some("\"127\"".to_owned())
+            );
+        }
+        assert!(!backend
+            .file_names()
+            .await
+            .unwrap()
+            .contains(&"/tmp/hint/1".to_owned()));
+    });
+}
+
+// A damaged hint file is ignored, and the logs are replayed instead
+#[test]
+fn damaged_hint_file() {
+    block_on(async {
+        let mut backend = MemoryBackend::new();
+        let test_path = PathBuf::from("/tmp/damaged_hint");
+        let mut store = open(backend.clone(), &test_path).await;
+        fill(&mut store).await;
+        drop(store);
+
+        let file_names = backend.file_names().await.unwrap();
+        let hint = file_names
+            .iter()
+            .find(|name| name.ends_with(".hint"))
+            .unwrap();
+        let mut hint = backend.open_file(Path::new(hint)).await.unwrap();
+        hint.seek(SeekFrom::Start(20)).unwrap();
+        hint.write_all(b"garbage").unwrap();
+
+        let mut store = open(backend, &test_path).await;
+        for key_id in 0..128 {
+            let key = format!("key{}", key_id);
+            assert_eq!(
+                store.txn().get(key).await.unwrap(),
+                Some("\"127\"".to_owned())
+            );
+        }
+    });
+}
+
+// Returns the number of logs, ignoring hint files
+async fn log_count(backend: &MemoryBackend, dir: &str) -> usize {
+    backend
+        .file_names()
+        .await
+        .unwrap()
+        .iter()
+        .filter(|name| Path::new(name).parent() == Some(Path::new(dir)))
+        .filter(|name| !name.ends_with(".hint"))
+        .count()
+}
+
+// Without automatic compaction, logs are only compacted on request,
+// and that covers every substore
+#[test]
+fn manual_compaction() {
+    block_on(async {
+        let backend = MemoryBackend::new();
+        let test_path = PathBuf::from("/tmp/manual_compaction");
+        let component = Path::new("component");
+        let mut store = open(backend.clone(), &test_path).await;
+        store.add_substore(component).await.unwrap();
+        store.set_compaction_policy(CompactionPolicy {
+            automatic: false,
+            ..CompactionPolicy::default()
+        });
+
+        fill(&mut store).await;
+        let mut txn = store.txn();
+        txn.set_scoped("key".to_owned(), "1".to_owned(), Some(component))
+            .await
+            .unwrap();
+        txn.commit().await.unwrap();
+        let mut txn = store.txn();
+        txn.set_scoped("key".to_owned(), "2".to_owned(), Some(component))
+            .await
+            .unwrap();
+        txn.commit().await.unwrap();
+        assert_eq!(log_count(&backend, "/tmp/manual_compaction").await, 1);
+
+        store.compact().await.unwrap();
+        assert_eq!(log_count(&backend, "/tmp/manual_compaction").await, 2);
+        assert_eq!(
+            log_count(&backend, "/tmp/manual_compaction/component").await,
+            2
+        );
+        drop(store);
+
+        let mut store = open(backend, &test_path).await;
+        store.add_substore(component).await.unwrap();
+        let mut txn = store.txn();
+        assert_eq!(
+            txn.get("key127".to_owned()).await.unwrap(),
+            Some("\"127\"".to_owned())
+        );
+        assert_eq!(
+            txn.get_scoped("key".to_owned(), Some(component))
+                .await
+                .unwrap(),
+            Some("2".to_owned())
+        );
+    });
+}
+
+// A compaction runs in steps, and writes in between are not lost
+#[test]
+fn incremental_compaction() {
+    block_on(async {
+        let backend = MemoryBackend::new();
+        let test_path = PathBuf::from("/tmp/incremental_compaction");
+        let mut store = open(backend.clone(), &test_path).await;
+        store.set_compaction_policy(CompactionPolicy {
+            threshold: 0,
+            automatic: false,
+            ..CompactionPolicy::default()
+        });
+
+        let mut txn = store.txn();
+        for key_id in 0..300 {
+            txn.set(format!("key{}", key_id), "old").await.unwrap();
+        }
+        txn.commit().await.unwrap();
+        let mut txn = store.txn();
+        txn.set("key0".to_owned(), "stale").await.unwrap();
+        txn.commit().await.unwrap();
+
+        // the first step copies a part of the keys
+        assert!(store.compact_step().await.unwrap());
+        let mut txn = store.txn();
+        txn.set("key1".to_owned(), "new").await.unwrap();
+        txn.set("key299".to_owned(), "new").await.unwrap();
+        txn.remove("key2".to_owned()).await.unwrap();
+        txn.remove("key298".to_owned()).await.unwrap();
+        txn.commit().await.unwrap();
+        while store.compact_step().await.unwrap() {}
+        drop(store);
+
+        let mut store = open(backend, &test_path).await;
+        let mut txn = store.txn();
+        assert_eq!(
+            txn.get("key0".to_owned()).await.unwrap(),
+            Some("\"stale\"".to_owned())
+        );
+        for key in &["key1", "key299"] {
+            assert_eq!(
+                txn.get(key.to_string()).await.unwrap(),
+                Some("\"new\"".to_owned())
+            );
+        }
+        for key in &["key2", "key298"] {
+            assert_eq!(txn.get(key.to_string()).await.unwrap(), None);
+        }
+        assert_eq!(
+            txn.get("key100".to_owned()).await.unwrap(),
+            Some("\"old\"".to_owned())
+        );
+    });
+}
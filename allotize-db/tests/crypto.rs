@@ -0,0 +1,251 @@
+//! Test suite for encrypted stores, running natively on top of `MemoryBackend`.
+
+#![cfg(not(target_arch = "wasm32"))]
+
+mod common;
+
+use allotize_db::{
+    Backend, BackendFile, CompactionPolicy, EncryptedBackend, EncryptionKey, KvStore, KvsError,
+    MemoryBackend,
+};
+use common::{fill, numbered, open};
+use futures::executor::block_on;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+/// Returns whether any file of the backend contains `needle`
+async fn contains_bytes(backend: &MemoryBackend, needle: &[u8]) -> bool {
+    let mut backend = backend.clone();
+    for name in backend.file_names().await.unwrap() {
+        let mut file = backend.open_file(Path::new(&name)).await.unwrap();
+        let mut contents = Vec::new();
+        file.seek(SeekFrom::Start(0)).unwrap();
+        file.read_to_end(&mut contents).unwrap();
+        if contents
+            .windows(needle.len())
+            .any(|window| window == needle)
+        {
+            return true;
+        }
+    }
+    false
+}
+
+// Logs are stored encrypted, and can only be opened with the right key
+#[test]
+fn encryption() {
+    block_on(async {
+        let backend = MemoryBackend::new();
+        let key = EncryptionKey::from_passphrase("correct horse", b"/tmp/encrypted");
+        {
+            let encrypted = EncryptedBackend::new(backend.clone(), key.clone());
+            let mut store = open(encrypted, "/tmp/encrypted").await;
+            let mut txn = store.txn();
+            txn.set("secret".to_owned(), &"plaintext value")
+                .await
+                .unwrap();
+            // spans several chunks
+            txn.set("large".to_owned(), &"x".repeat(10_000))
+                .await
+                .unwrap();
+            txn.commit().await.unwrap();
+            fill(&mut store).await;
+            store.compact().await.unwrap();
+        }
+        assert!(!contains_bytes(&backend, b"plaintext value").await);
+        assert!(!contains_bytes(&backend, b"secret").await);
+
+        let same_key = EncryptionKey::from_passphrase("correct horse", b"/tmp/encrypted");
+        let encrypted = EncryptedBackend::new(backend.clone(), same_key);
+        let mut store = open(encrypted, "/tmp/encrypted").await;
+        let mut txn = store.txn();
+        assert_eq!(
+            txn.get("secret".to_owned()).await.unwrap(),
+            Some("\"plaintext value\"".to_owned())
+        );
+        assert_eq!(
+            txn.get("large".to_owned())
+                .await
+                .unwrap()
+                .map(|value| value.len()),
+            Some(10_002)
+        );
+        drop(store);
+
+        let wrong_key = EncryptionKey::from_passphrase("battery staple", b"/tmp/encrypted");
+        let encrypted = EncryptedBackend::new(backend.clone(), wrong_key);
+        match KvStore::open_with_backend(encrypted, "/tmp/encrypted").await {
+            Err(KvsError::WrongKey { .. }) => {}
+            _ => panic!("the wrong key was not detected"),
+        }
+
+        // without a key the logs are left alone instead of being read as garbage
+        match KvStore::open_with_backend(backend.clone(), "/tmp/encrypted").await {
+            Err(KvsError::WrongKey { .. }) => {}
+            _ => panic!("an encrypted log was read without a key"),
+        }
+        let encrypted = EncryptedBackend::new(backend, key);
+        let mut store = open(encrypted, "/tmp/encrypted").await;
+        assert!(store
+            .txn()
+            .get("secret".to_owned())
+            .await
+            .unwrap()
+            .is_some());
+    });
+}
+
+// Rotating the key re-encrypts the logs, so the old key is no longer needed
+#[test]
+fn key_rotation() {
+    block_on(async {
+        let backend = MemoryBackend::new();
+        let old_key = EncryptionKey::new([1; 32]);
+        let new_key = EncryptionKey::new([2; 32]);
+        {
+            let encrypted = EncryptedBackend::new(backend.clone(), old_key.clone());
+            let mut store = open(encrypted, "/tmp/rotation").await;
+            numbered(&mut store).await;
+            store.set_blob_threshold(Some(64));
+            let mut txn = store.txn();
+            txn.set("large".to_owned(), &"x".repeat(100)).await.unwrap();
+            txn.commit().await.unwrap();
+
+            store.rotate_key(new_key.clone()).await.unwrap();
+            let mut txn = store.txn();
+            txn.set("key10".to_owned(), &10).await.unwrap();
+            txn.commit().await.unwrap();
+            assert_eq!(store.get_all().await.unwrap().len(), 12);
+        }
+
+        let encrypted = EncryptedBackend::new(backend.clone(), old_key);
+        assert!(KvStore::open_with_backend(encrypted, "/tmp/rotation")
+            .await
+            .is_err());
+
+        let encrypted = EncryptedBackend::new(backend, new_key);
+        let mut store = open(encrypted, "/tmp/rotation").await;
+        let mut txn = store.txn();
+        assert_eq!(
+            txn.get("key3".to_owned()).await.unwrap(),
+            Some("3".to_owned())
+        );
+        assert_eq!(
+            txn.get("key10".to_owned()).await.unwrap(),
+            Some("10".to_owned())
+        );
+        // the blob of the value was encrypted with the new key as well
+        assert_eq!(
+            txn.get("large".to_owned())
+                .await
+                .unwrap()
+                .map(|value| value.len()),
+            Some(102)
+        );
+    });
+}
+
+// A compaction that was started with the old key is finished
+// before the logs are rewritten with the new one
+#[test]
+fn key_rotation_during_compaction() {
+    block_on(async {
+        let backend = MemoryBackend::new();
+        let old_key = EncryptionKey::new([3; 32]);
+        let new_key = EncryptionKey::new([4; 32]);
+        {
+            let encrypted = EncryptedBackend::new(backend.clone(), old_key);
+            let mut store = open(encrypted, "/tmp/rotation_compaction").await;
+            store.set_compaction_policy(CompactionPolicy {
+                threshold: 0,
+                automatic: false,
+                ..CompactionPolicy::default()
+            });
+            let mut txn = store.txn();
+            for key_id in 0..300 {
+                txn.set(format!("key{}", key_id), &key_id).await.unwrap();
+            }
+            txn.commit().await.unwrap();
+
+            assert!(store.compact_step().await.unwrap());
+            store.rotate_key(new_key.clone()).await.unwrap();
+            let mut txn = store.txn();
+            txn.set("key300".to_owned(), &300).await.unwrap();
+            txn.commit().await.unwrap();
+        }
+
+        let encrypted = EncryptedBackend::new(backend, new_key);
+        let mut store = open(encrypted, "/tmp/rotation_compaction").await;
+        assert_eq!(store.get_all().await.unwrap().len(), 301);
+    });
+}
+
+// A chunk torn by a crash is dropped, along with the records in it
+#[test]
+fn encrypted_torn_tail() {
+    block_on(async {
+        let backend = MemoryBackend::new();
+        let key = EncryptionKey::new([7; 32]);
+        {
+            let encrypted = EncryptedBackend::new(backend.clone(), key.clone());
+            let mut store = open(encrypted, "/tmp/encrypted_torn").await;
+            numbered(&mut store).await;
+            let mut txn = store.txn();
+            txn.set("torn".to_owned(), &"value").await.unwrap();
+            txn.commit().await.unwrap();
+        }
+
+        let mut raw = backend.clone();
+        let mut log = raw
+            .open_file(Path::new("/tmp/encrypted_torn/1"))
+            .await
+            .unwrap();
+        let len = log.seek(SeekFrom::End(0)).unwrap();
+        log.set_len(len - 3).unwrap();
+
+        let encrypted = EncryptedBackend::new(backend, key);
+        let mut store = open(encrypted, "/tmp/encrypted_torn").await;
+        assert_eq!(store.get_all().await.unwrap().len(), 10);
+        assert_eq!(store.txn().get("torn".to_owned()).await.unwrap(), None);
+
+        let mut txn = store.txn();
+        txn.set("after".to_owned(), &"crash").await.unwrap();
+        txn.commit().await.unwrap();
+        assert_eq!(store.get_all().await.unwrap().len(), 11);
+    });
+}
+
+// A chunk can not be moved to another file, not even to the same position
+#[test]
+fn transplanted_chunk() {
+    block_on(async {
+        let mut raw = MemoryBackend::new();
+        let mut encrypted = EncryptedBackend::new(raw.clone(), EncryptionKey::new([5; 32]));
+        let (target, source) = (
+            Path::new("/tmp/transplant/a"),
+            Path::new("/tmp/transplant/b"),
+        );
+        for &(path, byte) in &[(target, b'a'), (source, b'b')] {
+            let mut file = encrypted.open_file(path).await.unwrap();
+            file.write_all(&[byte; 5000]).unwrap();
+            file.flush().unwrap();
+        }
+
+        // both files hold a full chunk and a partial one, after a header
+        let mut contents = Vec::new();
+        let mut file = raw.open_file(source).await.unwrap();
+        file.seek(SeekFrom::Start(0)).unwrap();
+        file.read_to_end(&mut contents).unwrap();
+        let header_len = contents.len() - 5000 - 2 * (4 + 12 + 16);
+        let first_chunk = header_len..header_len + 4 + 12 + 4096 + 16;
+        let mut file = raw.open_file(target).await.unwrap();
+        file.seek(SeekFrom::Start(first_chunk.start as u64))
+            .unwrap();
+        file.write_all(&contents[first_chunk]).unwrap();
+
+        let mut file = encrypted.open_file(target).await.unwrap();
+        file.seek(SeekFrom::Start(0)).unwrap();
+        let mut read = Vec::new();
+        assert!(file.read_to_end(&mut read).is_err());
+    });
+}
@@ -0,0 +1,178 @@
+//! Test suite for cursors and paginated scans, running natively on top of `MemoryBackend`.
+
+#![cfg(not(target_arch = "wasm32"))]
+
+mod common;
+
+use allotize_db::{ContinuationToken, KvsError, MemoryBackend, NumericAdd, Scan};
+use common::{numbered, open};
+use futures::executor::block_on;
+use std::ops::Bound;
+
+// A cursor visits the keys of a range one by one, in either direction
+#[test]
+fn cursor() {
+    block_on(async {
+        let mut store = open(MemoryBackend::new(), "/tmp/cursor").await;
+        numbered(&mut store).await;
+
+        let scan = Scan::range(
+            Bound::Included("key2".to_owned()),
+            Bound::Excluded("key5".to_owned()),
+        );
+        let mut cursor = store.cursor(scan.clone());
+        let mut keys = Vec::new();
+        while let Some((key, _)) = cursor.next().await.unwrap() {
+            keys.push(key);
+        }
+        assert_eq!(keys, ["key2", "key3", "key4"]);
+
+        let mut cursor = store.cursor(scan.reverse(true).limit(2));
+        assert_eq!(
+            cursor.next().await.unwrap(),
+            Some(("key4".to_owned(), "4".to_owned()))
+        );
+        assert_eq!(
+            cursor.next().await.unwrap(),
+            Some(("key3".to_owned(), "3".to_owned()))
+        );
+        assert_eq!(cursor.next().await.unwrap(), None);
+        assert!(cursor.has_more());
+    });
+}
+
+// Continuation tokens resume a paginated scan where the last page ended
+#[test]
+fn pagination() {
+    block_on(async {
+        let mut store = open(MemoryBackend::new(), "/tmp/pagination").await;
+        numbered(&mut store).await;
+
+        for &reverse in &[false, true] {
+            let mut keys = Vec::new();
+            let mut token: Option<ContinuationToken> = None;
+            loop {
+                let mut scan = Scan::prefix("key").reverse(reverse).limit(4);
+                if let Some(token) = &token {
+                    // tokens are handed out as strings
+                    scan = scan.resume(&token.to_string().parse().unwrap());
+                }
+                let page = store.scan(scan).await.unwrap();
+                keys.extend(page.items.into_iter().map(|(key, _)| key));
+                token = page.next;
+                if token.is_none() {
+                    break;
+                }
+            }
+
+            let mut expected: Vec<_> = (0..10).map(|key_id| format!("key{}", key_id)).collect();
+            if reverse {
+                expected.reverse();
+            }
+            assert_eq!(keys, expected);
+        }
+
+        assert!("xkey".parse::<ContinuationToken>().is_err());
+    });
+}
+
+// An inverted range scans no keys, and a token can only resume the scan it was taken on
+#[test]
+fn invalid_scans() {
+    block_on(async {
+        let mut store = open(MemoryBackend::new(), "/tmp/invalid_scans").await;
+        numbered(&mut store).await;
+
+        let inverted = Scan::range(
+            Bound::Included("key5".to_owned()),
+            Bound::Excluded("key2".to_owned()),
+        );
+        let page = store.scan(inverted.clone()).await.unwrap();
+        assert!(page.items.is_empty());
+        assert!(page.next.is_none());
+        let mut txn = store.txn();
+        txn.set("key3".to_owned(), "new").await.unwrap();
+        let page = txn.scan(inverted.reverse(true)).await.unwrap();
+        assert!(page.items.is_empty());
+        txn.rollback();
+
+        let page = store.scan(Scan::prefix("key").limit(4)).await.unwrap();
+        let token = page.next.unwrap();
+        assert!(matches!(
+            store.scan(Scan::prefix("ab").resume(&token)).await,
+            Err(KvsError::InvalidContinuationToken)
+        ));
+        let mut cursor = store.cursor(Scan::prefix("key").resume(&token).limit(1));
+        assert_eq!(
+            cursor.next().await.unwrap(),
+            Some(("key4".to_owned(), "4".to_owned()))
+        );
+    });
+}
+
+// The cursor of a transaction sees its buffered writes and removals
+#[test]
+fn txn_cursor() {
+    block_on(async {
+        let mut store = open(MemoryBackend::new(), "/tmp/txn_cursor").await;
+        numbered(&mut store).await;
+
+        let mut txn = store.txn();
+        txn.remove("key1".to_owned()).await.unwrap();
+        txn.remove("key2".to_owned()).await.unwrap();
+        txn.set("key3".to_owned(), "three").await.unwrap();
+        txn.set("key35".to_owned(), "new").await.unwrap();
+        txn.set("other".to_owned(), "other").await.unwrap();
+
+        let page = txn.scan(Scan::prefix("key").limit(4)).await.unwrap();
+        assert_eq!(
+            page.items,
+            [
+                ("key0".to_owned(), "0".to_owned()),
+                ("key3".to_owned(), "\"three\"".to_owned()),
+                ("key35".to_owned(), "\"new\"".to_owned()),
+                ("key4".to_owned(), "4".to_owned()),
+            ]
+        );
+
+        let page = txn
+            .scan(Scan::prefix("key").resume(&page.next.unwrap()))
+            .await
+            .unwrap();
+        let keys: Vec<_> = page.items.into_iter().map(|(key, _)| key).collect();
+        assert_eq!(keys, ["key5", "key6", "key7", "key8", "key9"]);
+        assert!(page.next.is_none());
+    });
+}
+
+// The cursor of a transaction folds its buffered merges into the values,
+// including those of keys that are only merged into
+#[test]
+fn txn_cursor_merges() {
+    block_on(async {
+        let mut store = open(MemoryBackend::new(), "/tmp/txn_cursor_merges").await;
+        store.register_merge_operator("count/", NumericAdd);
+        let mut txn = store.txn();
+        txn.set("count/a".to_owned(), &1).await.unwrap();
+        txn.set("count/b".to_owned(), &10).await.unwrap();
+        txn.commit().await.unwrap();
+
+        let mut txn = store.txn();
+        txn.merge("count/a".to_owned(), &2).await.unwrap();
+        txn.merge("count/new".to_owned(), &5).await.unwrap();
+        let expected = vec![
+            ("count/a".to_owned(), "3".to_owned()),
+            ("count/b".to_owned(), "10".to_owned()),
+            ("count/new".to_owned(), "5".to_owned()),
+        ];
+        let page = txn.scan(Scan::prefix("count/")).await.unwrap();
+        assert_eq!(page.items, expected);
+
+        let page = txn
+            .scan(Scan::prefix("count/").reverse(true).limit(2))
+            .await
+            .unwrap();
+        assert_eq!(page.items, [expected[2].clone(), expected[1].clone()]);
+        txn.rollback();
+    });
+}
@@ -0,0 +1,83 @@
+//! Test suite for keys with a time to live, running natively on top of `MemoryBackend`.
+
+#![cfg(not(target_arch = "wasm32"))]
+
+mod common;
+
+use allotize_db::MemoryBackend;
+use common::{all_keys, open};
+use futures::executor::block_on;
+use std::path::PathBuf;
+use std::thread;
+use std::time::Duration;
+
+// A key with a time to live reads as removed once it expires,
+// and stays removed after it is swept and the store is reopened
+#[test]
+fn expiring_keys() {
+    block_on(async {
+        let backend = MemoryBackend::new();
+        let test_path = PathBuf::from("/tmp/expiring_keys");
+        let mut store = open(backend.clone(), &test_path).await;
+        let mut txn = store.txn();
+        txn.set_expiring("short".to_owned(), "gone", Duration::from_millis(1))
+            .await
+            .unwrap();
+        txn.set_expiring("long".to_owned(), "kept", Duration::from_secs(3600))
+            .await
+            .unwrap();
+        txn.set("forever".to_owned(), "kept").await.unwrap();
+        txn.commit().await.unwrap();
+        thread::sleep(Duration::from_millis(5));
+
+        let mut txn = store.txn();
+        assert_eq!(txn.get("short".to_owned()).await.unwrap(), None);
+        assert!(txn.remove("short".to_owned()).await.is_err());
+        let keys: Vec<_> = all_keys(&mut store).await;
+        assert_eq!(keys, ["forever", "long"]);
+
+        assert_eq!(store.sweep_expired(None).await.unwrap(), ["short"]);
+        assert!(store.sweep_expired(None).await.unwrap().is_empty());
+        drop(store);
+
+        let mut store = open(backend, &test_path).await;
+        assert!(store.sweep_expired(None).await.unwrap().is_empty());
+        assert_eq!(
+            store.txn().get("long".to_owned()).await.unwrap(),
+            Some("\"kept\"".to_owned())
+        );
+    });
+}
+
+// Expired keys are purged by a compaction, while the expiry of live keys is kept
+#[test]
+fn expired_keys_are_compacted() {
+    block_on(async {
+        let backend = MemoryBackend::new();
+        let test_path = PathBuf::from("/tmp/expired_compaction");
+        let mut store = open(backend.clone(), &test_path).await;
+        let mut txn = store.txn();
+        txn.set_expiring("short".to_owned(), "gone", Duration::from_millis(1))
+            .await
+            .unwrap();
+        txn.set_expiring("long".to_owned(), "kept", Duration::from_millis(200))
+            .await
+            .unwrap();
+        txn.commit().await.unwrap();
+        thread::sleep(Duration::from_millis(5));
+
+        store.compact().await.unwrap();
+        drop(store);
+
+        let mut store = open(backend, &test_path).await;
+        assert!(store.sweep_expired(None).await.unwrap().is_empty());
+        assert_eq!(
+            store.txn().get("long".to_owned()).await.unwrap(),
+            Some("\"kept\"".to_owned())
+        );
+
+        thread::sleep(Duration::from_millis(250));
+        assert_eq!(store.txn().get("long".to_owned()).await.unwrap(), None);
+        assert_eq!(store.sweep_expired(None).await.unwrap(), ["long"]);
+    });
+}
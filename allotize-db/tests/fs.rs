@@ -0,0 +1,54 @@
+//! Test suite for the engine on top of the local filesystem.
+
+#![cfg(not(target_arch = "wasm32"))]
+
+use allotize_db::{FsBackend, KvStore};
+use futures::executor::block_on;
+use tempfile::TempDir;
+
+// Logs written by one store should be replayed by the next one
+// opened on the same directory
+#[test]
+fn reopen_replays_logs() {
+    block_on(async {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+
+        let mut store = KvStore::open_dir(temp_dir.path()).await.unwrap();
+        let mut txn = store.txn();
+        txn.set("key1".to_owned(), "value1").await.unwrap();
+        txn.set("key2".to_owned(), "value2").await.unwrap();
+        txn.commit().await.unwrap();
+        let mut txn = store.txn();
+        txn.remove("key1".to_owned()).await.unwrap();
+        txn.commit().await.unwrap();
+        drop(store);
+
+        let mut store = KvStore::open_dir(temp_dir.path()).await.unwrap();
+        assert_eq!(store.txn().get("key1".to_owned()).await.unwrap(), None);
+        assert_eq!(
+            store.txn().get("key2".to_owned()).await.unwrap(),
+            Some("\"value2\"".to_owned())
+        );
+    });
+}
+
+// Generations should be plain files named after their number
+#[test]
+fn log_files_on_disk() {
+    block_on(async {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+
+        let backend = FsBackend::new(temp_dir.path()).unwrap();
+        let mut store = KvStore::open_with_backend(backend, temp_dir.path())
+            .await
+            .unwrap();
+        let mut txn = store.txn();
+        txn.set("key1".to_owned(), "value1").await.unwrap();
+        txn.commit().await.unwrap();
+        drop(store);
+
+        let log = temp_dir.path().join("1");
+        assert!(log.is_file());
+        assert!(std::fs::metadata(&log).unwrap().len() > 0);
+    });
+}
@@ -124,3 +124,34 @@ async fn file() {
     file.read(&mut buffer).unwrap();
     assert_eq!(std::str::from_utf8(&buffer).unwrap(), "hello");
 }
+
+// A file spanning several pages should be readable after a reopen,
+// once the pages that are read have been fetched
+#[wasm_bindgen_test]
+async fn paged_file() {
+    use allotize_db::BackendFile;
+    use std::io::Read;
+    use std::io::Write;
+    use std::io::{Seek, SeekFrom};
+
+    let path = Path::new("/tmp/idb_paged_file");
+    let bytes: Vec<u8> = (0..100_000).map(|i| i as u8).collect();
+    {
+        let mut folder = IdbFolder::open(&path).await.unwrap();
+        let mut file = folder.open_file(&path).await.unwrap();
+        file.set_len(0).unwrap();
+        file.seek(SeekFrom::Start(0)).unwrap();
+        file.write_all(&bytes).unwrap();
+        file.save().await.unwrap();
+    }
+
+    let mut folder = IdbFolder::open(&path).await.unwrap();
+    let mut file = folder.open_file(&path).await.unwrap();
+    assert_eq!(file.size(), bytes.len());
+
+    let mut buffer = vec![0; 40_000];
+    file.fetch(50_000..90_000).await.unwrap();
+    file.seek(SeekFrom::Start(50_000)).unwrap();
+    file.read_exact(&mut buffer).unwrap();
+    assert_eq!(&buffer[..], &bytes[50_000..90_000]);
+}
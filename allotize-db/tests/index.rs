@@ -0,0 +1,113 @@
+//! Test suite for secondary indexes, running natively on top of `MemoryBackend`.
+
+#![cfg(not(target_arch = "wasm32"))]
+
+mod common;
+
+use allotize_db::{JsonMergePatch, KvsError, Lookup, MemoryBackend};
+use common::open;
+use futures::executor::block_on;
+use std::ops::Bound;
+use std::path::{Path, PathBuf};
+
+// Secondary indexes follow the writes, a compaction and a reopen,
+// and find keys by an exact value or a range of values
+#[test]
+fn secondary_indexes() {
+    block_on(async {
+        let backend = MemoryBackend::new();
+        let test_path = PathBuf::from("/tmp/secondary_indexes");
+        let mut store = open(backend.clone(), &test_path).await;
+        store.register_merge_operator("order/", JsonMergePatch);
+        let mut txn = store.txn();
+        for (id, status, total) in [(1, "open", 10), (2, "paid", 25), (3, "open", 40)] {
+            txn.set(
+                format!("order/{}", id),
+                &serde_json::json!({"status": status, "total": total}),
+            )
+            .await
+            .unwrap();
+        }
+        txn.set("note".to_owned(), "not an order").await.unwrap();
+        txn.commit().await.unwrap();
+
+        store.define_index("status", "/status").await.unwrap();
+        store.define_index("total", "/total").await.unwrap();
+        let keys = |items: Vec<(String, String)>| -> Vec<String> {
+            items.into_iter().map(|(key, _)| key).collect()
+        };
+        let open_orders = store.lookup(Lookup::eq("status", "open")).await.unwrap();
+        assert_eq!(keys(open_orders), ["order/1", "order/3"]);
+        let totals = Lookup::range("total", Bound::Excluded(10.into()), Bound::Unbounded);
+        let items = store.lookup(totals.clone()).await.unwrap();
+        assert_eq!(items[0].1, r#"{"status":"paid","total":25}"#);
+        assert_eq!(keys(items), ["order/2", "order/3"]);
+        assert!(matches!(
+            store.lookup(Lookup::eq("missing", 1)).await,
+            Err(KvsError::UnknownIndex { name }) if name == "missing"
+        ));
+
+        // sets, removals and merges update the indexes
+        let mut txn = store.txn();
+        txn.merge("order/1".to_owned(), &serde_json::json!({"status": "paid"}))
+            .await
+            .unwrap();
+        txn.commit().await.unwrap();
+        let mut txn = store.txn();
+        txn.remove("order/2".to_owned()).await.unwrap();
+        txn.set(
+            "order/4".to_owned(),
+            &serde_json::json!({"status": "paid", "total": 5}),
+        )
+        .await
+        .unwrap();
+        txn.merge("order/3".to_owned(), &serde_json::json!({"total": 1}))
+            .await
+            .unwrap();
+        txn.commit().await.unwrap();
+        let paid = store.lookup(Lookup::eq("status", "paid")).await.unwrap();
+        assert_eq!(keys(paid), ["order/1", "order/4"]);
+        let open_orders = store.lookup(Lookup::eq("status", "open")).await.unwrap();
+        assert_eq!(keys(open_orders), ["order/3"]);
+        // ordered by the indexed value
+        let all = Lookup::range("total", Bound::Unbounded, Bound::Unbounded);
+        assert_eq!(
+            keys(store.lookup(all.clone()).await.unwrap()),
+            ["order/3", "order/4", "order/1"]
+        );
+
+        store.compact().await.unwrap();
+        assert_eq!(
+            keys(store.lookup(all.clone()).await.unwrap()),
+            ["order/3", "order/4", "order/1"]
+        );
+        store.drop_index("total");
+        assert!(store.lookup(all.clone()).await.is_err());
+        drop(store);
+
+        // indexes are defined again on open, and cover the substores
+        let mut store = open(backend, &test_path).await;
+        store.define_index("status", "/status").await.unwrap();
+        let paid = store.lookup(Lookup::eq("status", "paid")).await.unwrap();
+        assert_eq!(keys(paid), ["order/1", "order/4"]);
+        let substore = Path::new("archive");
+        store.add_substore(substore).await.unwrap();
+        let mut txn = store.txn();
+        txn.set_scoped(
+            "order/0".to_owned(),
+            r#"{"status":"paid"}"#.to_owned(),
+            Some(substore),
+        )
+        .await
+        .unwrap();
+        txn.commit().await.unwrap();
+        let archived = Lookup::eq("status", "paid").scoped(substore);
+        assert_eq!(keys(store.lookup(archived).await.unwrap()), ["order/0"]);
+        assert!(matches!(
+            store
+                .lookup(Lookup::eq("status", "paid").scoped("other"))
+                .await,
+            Err(KvsError::UnknownSubstore { .. })
+        ));
+    });
+}
@@ -0,0 +1,315 @@
+//! Test suite for the log format and its recovery from crashes, running natively on top of `MemoryBackend`.
+
+#![cfg(not(target_arch = "wasm32"))]
+
+mod common;
+
+use allotize_db::{Backend, KvStore, KvsError, MemoryBackend};
+use common::{open, FullBackend};
+use futures::executor::block_on;
+use std::io::{Seek, SeekFrom, Write};
+use std::path::PathBuf;
+use std::sync::atomic::Ordering;
+
+// Reopening the store from the same backend should replay the old values
+#[test]
+fn persistance() {
+    block_on(async {
+        let backend = MemoryBackend::new();
+        let test_path = PathBuf::from("/tmp/2");
+        let mut store = open(backend.clone(), &test_path).await;
+        let mut txn = store.txn();
+        txn.set("key1".to_owned(), "value1").await.unwrap();
+        txn.set("key2".to_owned(), "value2").await.unwrap();
+        txn.commit().await.unwrap();
+        let mut txn = store.txn();
+        txn.remove("key2".to_owned()).await.unwrap();
+        txn.commit().await.unwrap();
+        drop(store);
+
+        let mut store = open(backend, &test_path).await;
+        assert_eq!(
+            store.txn().get("key1".to_owned()).await.unwrap(),
+            Some("\"value1\"".to_owned())
+        );
+        assert_eq!(store.txn().get("key2".to_owned()).await.unwrap(), None);
+    });
+}
+
+// Replaying a log fetches one record at a time, rather than the whole log
+#[test]
+fn replay_fetches_records() {
+    block_on(async {
+        let backend = FullBackend::default();
+        let test_path = PathBuf::from("/tmp/replay_fetch");
+        let mut store = open(backend.clone(), &test_path).await;
+        for key_id in 0..100 {
+            let mut txn = store.txn();
+            txn.set(format!("key{}", key_id), &key_id).await.unwrap();
+            txn.commit().await.unwrap();
+        }
+        let log_bytes = store.stats().unwrap().store.log_bytes;
+        drop(store);
+
+        backend.fetched.store(0, Ordering::SeqCst);
+        let mut store = open(backend.clone(), &test_path).await;
+        assert_eq!(store.get_all().await.unwrap().len(), 100);
+        let fetched = backend.fetched.load(Ordering::SeqCst);
+        assert!(fetched > 0);
+        assert!(
+            fetched * 10 < log_bytes,
+            "fetched {} bytes at once",
+            fetched
+        );
+    });
+}
+
+// A batch that was only partially written should not be replayed at all
+#[test]
+fn torn_batch_is_not_replayed() {
+    block_on(async {
+        let mut backend = MemoryBackend::new();
+        let test_path = PathBuf::from("/tmp/6");
+        let mut store = open(backend.clone(), &test_path).await;
+        let mut txn = store.txn();
+        txn.set("key1".to_owned(), "value1").await.unwrap();
+        txn.commit().await.unwrap();
+        let mut txn = store.txn();
+        txn.set("key1".to_owned(), "value2").await.unwrap();
+        txn.set("key2".to_owned(), "value2").await.unwrap();
+        txn.commit().await.unwrap();
+        drop(store);
+
+        // Emulate a crash in the middle of writing the batch
+        let mut log = backend.open_file(&test_path.join("1")).await.unwrap();
+        let len = log.seek(SeekFrom::End(0)).unwrap();
+        log.seek(SeekFrom::Start(len - 5)).unwrap();
+        log.write_all(b"\0\0\0\0\0").unwrap();
+
+        let mut store = open(backend, &test_path).await;
+        assert_eq!(
+            store.txn().get("key1".to_owned()).await.unwrap(),
+            Some("\"value1\"".to_owned())
+        );
+        assert_eq!(store.txn().get("key2".to_owned()).await.unwrap(), None);
+    });
+}
+
+// A torn record at the end of a log is cut off, and the log stays writable
+#[test]
+fn torn_tail_is_truncated() {
+    block_on(async {
+        let mut backend = MemoryBackend::new();
+        let test_path = PathBuf::from("/tmp/7");
+        let mut store = open(backend.clone(), &test_path).await;
+        let mut txn = store.txn();
+        txn.set("key1".to_owned(), "value1").await.unwrap();
+        txn.commit().await.unwrap();
+        drop(store);
+
+        // Emulate a crash after only part of a record was written
+        let mut log = backend.open_file(&test_path.join("1")).await.unwrap();
+        let len = log.seek(SeekFrom::End(0)).unwrap();
+        log.write_all(&[0, 0, 0, 42, 1, 2]).unwrap();
+
+        let mut store = open(backend.clone(), &test_path).await;
+        assert_eq!(log.seek(SeekFrom::End(0)).unwrap(), len);
+        let mut txn = store.txn();
+        txn.set("key2".to_owned(), "value2").await.unwrap();
+        txn.commit().await.unwrap();
+        drop(store);
+
+        let mut store = open(backend, &test_path).await;
+        assert_eq!(
+            store.txn().get("key1".to_owned()).await.unwrap(),
+            Some("\"value1\"".to_owned())
+        );
+        assert_eq!(
+            store.txn().get("key2".to_owned()).await.unwrap(),
+            Some("\"value2\"".to_owned())
+        );
+    });
+}
+
+// A damaged record followed by intact ones can not be a torn write
+#[test]
+fn corruption_is_reported() {
+    block_on(async {
+        let mut backend = MemoryBackend::new();
+        let test_path = PathBuf::from("/tmp/8");
+        let mut store = open(backend.clone(), &test_path).await;
+        for key in &["key1", "key2"] {
+            let mut txn = store.txn();
+            txn.set((*key).to_owned(), "value").await.unwrap();
+            txn.commit().await.unwrap();
+        }
+        drop(store);
+
+        // Flip a byte in the payload of the first record,
+        // which follows the log header and the record header
+        let mut log = backend.open_file(&test_path.join("1")).await.unwrap();
+        log.seek(SeekFrom::Start(18)).unwrap();
+        log.write_all(b"X").unwrap();
+
+        match KvStore::open_with_backend(backend, &test_path).await {
+            Err(KvsError::Corruption { pos, .. }) => assert_eq!(pos, 8),
+            _ => panic!("corruption was not detected"),
+        }
+    });
+}
+
+// A damaged length is not mistaken for a torn write, which would cut off
+// the records after it, and they are recovered by a repair
+#[test]
+fn damaged_length_is_reported() {
+    block_on(async {
+        // the length of the first record runs past the end of the log, or falls short
+        for &(path, offset, byte) in &[
+            ("/tmp/damaged_length_long", 8, 0xff),
+            ("/tmp/damaged_length_short", 11, 0x01),
+        ] {
+            let mut backend = MemoryBackend::new();
+            let test_path = PathBuf::from(path);
+            let mut store = open(backend.clone(), &test_path).await;
+            for key in &["key1", "key2"] {
+                let mut txn = store.txn();
+                txn.set((*key).to_owned(), "value").await.unwrap();
+                txn.commit().await.unwrap();
+            }
+            drop(store);
+
+            let mut log = backend.open_file(&test_path.join("1")).await.unwrap();
+            log.seek(SeekFrom::Start(offset)).unwrap();
+            log.write_all(&[byte]).unwrap();
+
+            match KvStore::open_with_backend(backend.clone(), &test_path).await {
+                Err(KvsError::Corruption { pos, .. }) => assert_eq!(pos, 8),
+                _ => panic!("corruption was not detected"),
+            }
+            let (mut store, report) = KvStore::open_repaired(backend, &test_path).await.unwrap();
+            assert_eq!(report.unreadable_records, 1);
+            assert_eq!(store.txn().get("key1".to_owned()).await.unwrap(), None);
+            assert_eq!(
+                store.txn().get("key2".to_owned()).await.unwrap(),
+                Some("\"value\"".to_owned())
+            );
+        }
+    });
+}
+
+// Logs written before records were framed are still readable
+#[test]
+fn unframed_log() {
+    block_on(async {
+        let mut backend = MemoryBackend::new();
+        let test_path = PathBuf::from("/tmp/9");
+        let mut log = backend.open_file(&test_path.join("1")).await.unwrap();
+        log.write_all(br#"{"Set":{"key":"key1","value":"\"value1\""}}"#)
+            .unwrap();
+        log.write_all(br#"{"Set":{"key":"key2","value":"\"value2\""}}"#)
+            .unwrap();
+        log.write_all(br#"{"Remove":{"key":"key2"}}"#).unwrap();
+
+        let mut store = open(backend, &test_path).await;
+        assert_eq!(
+            store.txn().get("key1".to_owned()).await.unwrap(),
+            Some("\"value1\"".to_owned())
+        );
+        assert_eq!(store.txn().get("key2".to_owned()).await.unwrap(), None);
+    });
+}
+
+// Logs holding framed json, written before the binary encoding,
+// are migrated into a single log in the current format
+#[test]
+fn json_logs_are_migrated() {
+    block_on(async {
+        let mut backend = MemoryBackend::new();
+        let test_path = PathBuf::from("/tmp/10");
+        let mut log = backend.open_file(&test_path.join("1")).await.unwrap();
+        log.write_all(br#"{"Set":{"key":"key1","value":"\"value1\""}}"#)
+            .unwrap();
+        let mut log = backend.open_file(&test_path.join("2")).await.unwrap();
+        for payload in &[
+            &br#"{"Set":{"key":"key2","value":"\"value2\""}}"#[..],
+            &br#"{"Batch":[{"Remove":{"key":"key1"}},{"Set":{"key":"key3","value":"\"value3\""}}]}"#[..],
+        ] {
+            log.write_all(&(payload.len() as u32).to_be_bytes()).unwrap();
+            log.write_all(&crc32fast::hash(payload).to_be_bytes()).unwrap();
+            log.write_all(payload).unwrap();
+        }
+
+        let mut store = open(backend.clone(), &test_path).await;
+        let mut file_names = backend.file_names().await.unwrap();
+        file_names.sort();
+        assert_eq!(file_names, vec!["/tmp/10/3", "/tmp/10/4"]);
+        let mut txn = store.txn();
+        txn.set("key4".to_owned(), "value4").await.unwrap();
+        txn.commit().await.unwrap();
+        drop(store);
+
+        let mut store = open(backend, &test_path).await;
+        assert_eq!(store.txn().get("key1".to_owned()).await.unwrap(), None);
+        for key in &["key2", "key3", "key4"] {
+            assert_eq!(
+                store.txn().get((*key).to_owned()).await.unwrap(),
+                Some(format!("\"value{}\"", &key[3..]))
+            );
+        }
+    });
+}
+
+// Logs from a newer version of the format are refused instead of misread
+#[test]
+fn unsupported_format() {
+    block_on(async {
+        let mut backend = MemoryBackend::new();
+        let test_path = PathBuf::from("/tmp/11");
+        let mut log = backend.open_file(&test_path.join("1")).await.unwrap();
+        log.write_all(b"ADBL\0\0\0\x63").unwrap();
+
+        match KvStore::open_with_backend(backend, &test_path).await {
+            Err(KvsError::UnsupportedFormat { version, .. }) => assert_eq!(version, 99),
+            _ => panic!("unsupported format was not detected"),
+        }
+    });
+}
+
+// A commit that could not be stored fails, and leaves nothing behind
+#[test]
+fn failed_sync() {
+    block_on(async {
+        let backend = FullBackend::default();
+        let test_path = PathBuf::from("/tmp/12");
+        let mut store = open(backend.clone(), &test_path).await;
+        let mut txn = store.txn();
+        txn.set("key1".to_owned(), "value1").await.unwrap();
+        txn.commit().await.unwrap();
+
+        backend.full.store(true, Ordering::SeqCst);
+        let mut txn = store.txn();
+        txn.set("key2".to_owned(), "value2").await.unwrap();
+        match txn.commit().await {
+            Err(KvsError::QuotaExceeded) => {}
+            _ => panic!("failed sync was not reported"),
+        }
+        assert_eq!(store.txn().get("key2".to_owned()).await.unwrap(), None);
+
+        backend.full.store(false, Ordering::SeqCst);
+        let mut txn = store.txn();
+        txn.set("key3".to_owned(), "value3").await.unwrap();
+        txn.commit().await.unwrap();
+        drop(store);
+
+        let mut store = open(backend, &test_path).await;
+        assert_eq!(
+            store.txn().get("key1".to_owned()).await.unwrap(),
+            Some("\"value1\"".to_owned())
+        );
+        assert_eq!(store.txn().get("key2".to_owned()).await.unwrap(), None);
+        assert_eq!(
+            store.txn().get("key3".to_owned()).await.unwrap(),
+            Some("\"value3\"".to_owned())
+        );
+    });
+}
@@ -0,0 +1,286 @@
+//! Test suite for merge operators, running natively on top of `MemoryBackend`.
+
+#![cfg(not(target_arch = "wasm32"))]
+
+mod common;
+
+use allotize_db::{
+    CompactionPolicy, JsonMergePatch, KvsError, ListAppend, MemoryBackend, MergeOperator,
+    NumericAdd, Result,
+};
+use common::open;
+use futures::executor::block_on;
+use std::path::PathBuf;
+
+/// Keeps the largest number that was merged
+struct Max;
+
+impl MergeOperator for Max {
+    fn name(&self) -> &str {
+        "max"
+    }
+
+    fn merge(&self, _key: &str, existing: Option<&str>, operand: &str) -> Result<String> {
+        let existing: i64 = existing.map_or(Ok(i64::MIN), serde_json::from_str)?;
+        let operand: i64 = serde_json::from_str(operand)?;
+        Ok(existing.max(operand).to_string())
+    }
+}
+
+// Merges are folded into the value when it is read, and by a compaction
+#[test]
+fn merge_operators() {
+    block_on(async {
+        let backend = MemoryBackend::new();
+        let test_path = PathBuf::from("/tmp/merge");
+        let mut store = open(backend.clone(), &test_path).await;
+        store.register_merge_operator("counter/", NumericAdd);
+        store.register_merge_operator("log/", ListAppend);
+        store.register_merge_operator("doc/", JsonMergePatch);
+
+        for _ in 0..3 {
+            let mut txn = store.txn();
+            txn.merge("counter/visits".to_owned(), &2).await.unwrap();
+            txn.commit().await.unwrap();
+        }
+        let mut txn = store.txn();
+        txn.merge("log/events".to_owned(), "opened").await.unwrap();
+        txn.merge("log/events".to_owned(), "closed").await.unwrap();
+        txn.set(
+            "doc/user".to_owned(),
+            &serde_json::json!({"name": "alice", "age": 30}),
+        )
+        .await
+        .unwrap();
+        txn.merge(
+            "doc/user".to_owned(),
+            &serde_json::json!({"age": null, "city": "Lund"}),
+        )
+        .await
+        .unwrap();
+        // the merges of a transaction are seen by its reads
+        assert_eq!(
+            txn.get("log/events".to_owned()).await.unwrap(),
+            Some(r#"["opened","closed"]"#.to_owned())
+        );
+        assert!(matches!(
+            txn.merge("other".to_owned(), &1).await,
+            Err(KvsError::NoMergeOperator { .. })
+        ));
+        txn.commit().await.unwrap();
+
+        let expected = vec![
+            ("counter/visits".to_owned(), "6".to_owned()),
+            (
+                "doc/user".to_owned(),
+                r#"{"city":"Lund","name":"alice"}"#.to_owned(),
+            ),
+            ("log/events".to_owned(), r#"["opened","closed"]"#.to_owned()),
+        ];
+        assert_eq!(store.get_all().await.unwrap(), expected);
+
+        store.compact().await.unwrap();
+        assert_eq!(store.get_all().await.unwrap(), expected);
+        drop(store);
+
+        // the built-in operators are known by name without registering them
+        let mut store = open(backend, &test_path).await;
+        assert_eq!(store.get_all().await.unwrap(), expected);
+        store.register_merge_operator("counter/", NumericAdd);
+        let mut txn = store.txn();
+        txn.merge("counter/visits".to_owned(), &-1).await.unwrap();
+        txn.commit().await.unwrap();
+        let mut txn = store.txn();
+        assert_eq!(
+            txn.get("counter/visits".to_owned()).await.unwrap(),
+            Some("5".to_owned())
+        );
+        txn.remove("counter/visits".to_owned()).await.unwrap();
+        txn.merge("counter/visits".to_owned(), &1.5).await.unwrap();
+        txn.commit().await.unwrap();
+        let mut txn = store.txn();
+        assert_eq!(
+            txn.get("counter/visits".to_owned()).await.unwrap(),
+            Some("1.5".to_owned())
+        );
+        txn.rollback();
+    });
+}
+
+// Keys merged into while a compaction is in progress keep every operand
+#[test]
+fn merge_during_compaction() {
+    block_on(async {
+        let backend = MemoryBackend::new();
+        let test_path = PathBuf::from("/tmp/merge_compaction");
+        let mut store = open(backend.clone(), &test_path).await;
+        store.set_compaction_policy(CompactionPolicy {
+            threshold: 0,
+            automatic: false,
+            ..CompactionPolicy::default()
+        });
+        store.register_merge_operator("", NumericAdd);
+
+        for _ in 0..2 {
+            let mut txn = store.txn();
+            for key_id in 0..300 {
+                txn.merge(format!("key{:03}", key_id), &1).await.unwrap();
+            }
+            txn.commit().await.unwrap();
+        }
+
+        // the first step folds a part of the keys
+        assert!(store.compact_step().await.unwrap());
+        let snapshot = store.snapshot();
+        let mut txn = store.txn();
+        txn.merge("key000".to_owned(), &10).await.unwrap();
+        txn.merge("key299".to_owned(), &10).await.unwrap();
+        txn.commit().await.unwrap();
+        while store.compact_step().await.unwrap() {}
+
+        assert_eq!(
+            snapshot.get(&mut store, "key299").await.unwrap(),
+            Some("2".to_owned())
+        );
+        drop(snapshot);
+        let mut txn = store.txn();
+        txn.merge("key000".to_owned(), &100).await.unwrap();
+        txn.commit().await.unwrap();
+        assert!(store.verify().await.unwrap().is_ok());
+        drop(store);
+
+        let mut store = open(backend, &test_path).await;
+        let mut txn = store.txn();
+        assert_eq!(
+            txn.get("key000".to_owned()).await.unwrap(),
+            Some("112".to_owned())
+        );
+        assert_eq!(
+            txn.get("key299".to_owned()).await.unwrap(),
+            Some("12".to_owned())
+        );
+        assert_eq!(
+            txn.get("key150".to_owned()).await.unwrap(),
+            Some("2".to_owned())
+        );
+        txn.rollback();
+    });
+}
+
+// Operators other than the built-in ones are registered on every open,
+// and operands of operators that are not registered do not hold up compactions
+#[test]
+fn custom_merge_operator() {
+    block_on(async {
+        let backend = MemoryBackend::new();
+        let test_path = PathBuf::from("/tmp/custom_merge");
+        let mut store = open(backend.clone(), &test_path).await;
+        store.register_merge_operator("max/", Max);
+        let mut txn = store.txn();
+        txn.merge("max/score".to_owned(), &3).await.unwrap();
+        txn.merge("max/score".to_owned(), &7).await.unwrap();
+        txn.merge("max/score".to_owned(), &5).await.unwrap();
+        txn.commit().await.unwrap();
+        drop(store);
+
+        let mut store = open(backend.clone(), &test_path).await;
+        let mut txn = store.txn();
+        assert!(matches!(
+            txn.get("max/score".to_owned()).await,
+            Err(KvsError::UnknownMergeOperator { name }) if name == "max"
+        ));
+        txn.rollback();
+        store.compact().await.unwrap();
+
+        store.register_merge_operator("max/", Max);
+        let mut txn = store.txn();
+        assert_eq!(
+            txn.get("max/score".to_owned()).await.unwrap(),
+            Some("7".to_owned())
+        );
+        txn.rollback();
+        drop(store);
+
+        // the operands that were copied as is are loaded from the hint
+        let mut store = open(backend, &test_path).await;
+        store.register_merge_operator("max/", Max);
+        let mut txn = store.txn();
+        assert_eq!(
+            txn.get("max/score".to_owned()).await.unwrap(),
+            Some("7".to_owned())
+        );
+        txn.rollback();
+        assert!(store.verify().await.unwrap().is_ok());
+    });
+}
+
+/// Fails to fold anything, in place of `Max`
+struct Broken;
+
+impl MergeOperator for Broken {
+    fn name(&self) -> &str {
+        "max"
+    }
+
+    fn merge(&self, key: &str, _existing: Option<&str>, _operand: &str) -> Result<String> {
+        Err(KvsError::MergeFailed {
+            key: key.to_owned(),
+            reason: "broken".to_owned(),
+        })
+    }
+}
+
+// Operands that can not be folded into the stored value are rejected,
+// and a compaction that can not fold them reports it
+#[test]
+fn merge_failures() {
+    block_on(async {
+        let backend = MemoryBackend::new();
+        let test_path = PathBuf::from("/tmp/merge_failures");
+        let mut store = open(backend.clone(), &test_path).await;
+        store.register_merge_operator("n/", NumericAdd);
+        store.register_merge_operator("max/", Max);
+        let mut txn = store.txn();
+        txn.set("n/x".to_owned(), "hello").await.unwrap();
+        txn.merge("max/score".to_owned(), &3).await.unwrap();
+        txn.merge("max/score".to_owned(), &7).await.unwrap();
+        txn.commit().await.unwrap();
+
+        let mut txn = store.txn();
+        assert!(matches!(
+            txn.merge("n/x".to_owned(), &1).await,
+            Err(KvsError::MergeFailed { key, .. }) if key == "n/x"
+        ));
+        txn.merge("n/y".to_owned(), &1).await.unwrap();
+        txn.commit().await.unwrap();
+        assert_eq!(
+            store.get_all().await.unwrap(),
+            vec![
+                ("max/score".to_owned(), "7".to_owned()),
+                ("n/x".to_owned(), "\"hello\"".to_owned()),
+                ("n/y".to_owned(), "1".to_owned()),
+            ]
+        );
+        drop(store);
+
+        let mut store = open(backend.clone(), &test_path).await;
+        store.register_merge_operator("max/", Broken);
+        assert!(matches!(
+            store.compact().await,
+            Err(KvsError::MergeFailed { key, .. }) if key == "max/score"
+        ));
+        drop(store);
+
+        // the logs are left as they were
+        let mut store = open(backend, &test_path).await;
+        store.register_merge_operator("max/", Max);
+        let mut txn = store.txn();
+        assert_eq!(
+            txn.get("max/score".to_owned()).await.unwrap(),
+            Some("7".to_owned())
+        );
+        txn.rollback();
+        store.compact().await.unwrap();
+        assert!(store.verify().await.unwrap().is_ok());
+    });
+}
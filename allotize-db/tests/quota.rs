@@ -0,0 +1,181 @@
+//! Test suite for stats and quotas, running natively on top of `MemoryBackend`.
+
+#![cfg(not(target_arch = "wasm32"))]
+
+mod common;
+
+use allotize_db::{Eviction, KvsError, MemoryBackend, Quota};
+use common::{all_keys, numbered, open};
+use futures::executor::block_on;
+use std::path::Path;
+use std::thread;
+use std::time::Duration;
+
+// Stats break the space down by substore, and remember the last compaction
+#[test]
+fn stats() {
+    block_on(async {
+        let backend = MemoryBackend::new();
+        let component = Path::new("component1");
+        {
+            let mut store = open(backend.clone(), "/tmp/stats").await;
+            store.add_substore(component).await.unwrap();
+            numbered(&mut store).await;
+            let mut txn = store.txn();
+            txn.set("key0".to_owned(), &"overwritten").await.unwrap();
+            txn.commit().await.unwrap();
+            let mut txn = store.txn();
+            txn.set_scoped("draft".to_owned(), "{}".to_owned(), Some(component))
+                .await
+                .unwrap();
+            txn.commit().await.unwrap();
+
+            let stats = store.stats().unwrap();
+            assert_eq!(stats.store.keys, 10);
+            assert!(stats.store.live_bytes > 0);
+            assert!(stats.store.stale_bytes > 0);
+            assert!(stats.store.log_bytes > stats.store.live_bytes);
+            assert_eq!(stats.store.generations, 1);
+            assert_eq!(stats.store.last_compaction, None);
+            assert_eq!(stats.substores[component].keys, 1);
+            assert_eq!(stats.total().keys, 11);
+
+            store.compact().await.unwrap();
+        }
+
+        let mut store = open(backend, "/tmp/stats").await;
+        let stats = store.stats().unwrap();
+        assert_eq!(stats.store.keys, 10);
+        assert_eq!(stats.store.stale_bytes, 0);
+        assert!(stats.store.last_compaction.is_some());
+        // the substore was not added since the store was reopened
+        assert!(stats.substores.is_empty());
+    });
+}
+
+// Over the quota, the least recently used keys are evicted
+#[test]
+fn lru_eviction() {
+    block_on(async {
+        let mut store = open(MemoryBackend::new(), "/tmp/lru").await;
+        numbered(&mut store).await;
+        thread::sleep(Duration::from_millis(2));
+        let mut txn = store.txn();
+        for key in &["key0", "key1", "key2"] {
+            txn.get((*key).to_owned()).await.unwrap();
+        }
+        txn.rollback();
+        let log_bytes = store.stats().unwrap().total().log_bytes;
+
+        store.set_quota(Some(Quota {
+            max_bytes: log_bytes,
+            eviction: Eviction::Lru,
+        }));
+        let mut txn = store.txn();
+        txn.set("key10".to_owned(), &"x".repeat(64)).await.unwrap();
+        txn.commit().await.unwrap();
+
+        let stats = store.stats().unwrap();
+        assert!(stats.total().log_bytes <= log_bytes);
+        let keys: Vec<_> = all_keys(&mut store).await;
+        assert!(keys.len() < 11);
+        for key in &["key0", "key1", "key10", "key2"] {
+            assert!(keys.contains(&(*key).to_owned()), "{} was evicted", key);
+        }
+
+        // a value that can never fit is refused
+        let mut txn = store.txn();
+        txn.set("huge".to_owned(), &"x".repeat(log_bytes as usize))
+            .await
+            .unwrap();
+        match txn.commit().await {
+            Err(KvsError::QuotaExceeded) => {}
+            _ => panic!("the quota was not enforced"),
+        }
+    });
+}
+
+// The order in which keys were used is kept by a compaction, and the keys
+// written after it count as used more recently, once the store is reopened
+#[test]
+fn lru_eviction_after_reopen() {
+    block_on(async {
+        let backend = MemoryBackend::new();
+        {
+            let mut store = open(backend.clone(), "/tmp/lru_reopen").await;
+            numbered(&mut store).await;
+            thread::sleep(Duration::from_millis(2));
+            let mut txn = store.txn();
+            for key in &["key0", "key1", "key2"] {
+                txn.get((*key).to_owned()).await.unwrap();
+            }
+            txn.rollback();
+            thread::sleep(Duration::from_millis(2));
+            store.compact().await.unwrap();
+            let mut txn = store.txn();
+            txn.set("key3".to_owned(), &3).await.unwrap();
+            txn.commit().await.unwrap();
+        }
+
+        let mut store = open(backend, "/tmp/lru_reopen").await;
+        let log_bytes = store.stats().unwrap().total().log_bytes;
+        store.set_quota(Some(Quota {
+            max_bytes: log_bytes,
+            eviction: Eviction::Lru,
+        }));
+        let mut txn = store.txn();
+        txn.set("key10".to_owned(), &"x".repeat(64)).await.unwrap();
+        txn.commit().await.unwrap();
+
+        let keys: Vec<_> = all_keys(&mut store).await;
+        assert!(keys.len() < 11);
+        for key in &["key0", "key1", "key10", "key2", "key3"] {
+            assert!(keys.contains(&(*key).to_owned()), "{} was evicted", key);
+        }
+    });
+}
+
+// The keys that expire the soonest are evicted first
+#[test]
+fn ttl_first_eviction() {
+    block_on(async {
+        let mut store = open(MemoryBackend::new(), "/tmp/ttl_first").await;
+        let mut txn = store.txn();
+        txn.set_expiring(
+            "session".to_owned(),
+            &"x".repeat(256),
+            Duration::from_secs(60),
+        )
+        .await
+        .unwrap();
+        txn.set_expiring(
+            "cache".to_owned(),
+            &"x".repeat(256),
+            Duration::from_secs(3600),
+        )
+        .await
+        .unwrap();
+        txn.commit().await.unwrap();
+        numbered(&mut store).await;
+        let log_bytes = store.stats().unwrap().total().log_bytes;
+
+        store.set_quota(Some(Quota {
+            max_bytes: log_bytes,
+            eviction: Eviction::TtlFirst,
+        }));
+        let mut txn = store.txn();
+        txn.set("key10".to_owned(), &10).await.unwrap();
+        txn.commit().await.unwrap();
+
+        let mut txn = store.txn();
+        assert_eq!(txn.get("session".to_owned()).await.unwrap(), None);
+        assert_eq!(
+            txn.get("key0".to_owned()).await.unwrap(),
+            Some("0".to_owned())
+        );
+        assert_eq!(
+            txn.get("key10".to_owned()).await.unwrap(),
+            Some("10".to_owned())
+        );
+    });
+}
@@ -0,0 +1,100 @@
+//! Test suite for snapshots, running natively on top of `MemoryBackend`.
+
+#![cfg(not(target_arch = "wasm32"))]
+
+mod common;
+
+use allotize_db::{Backend, MemoryBackend};
+use common::{numbered, open};
+use futures::executor::block_on;
+use std::path::{Path, PathBuf};
+
+// A snapshot keeps reading the values from when it was taken,
+// also after the logs it reads from are compacted
+#[test]
+fn snapshot() {
+    block_on(async {
+        let backend = MemoryBackend::new();
+        let test_path = PathBuf::from("/tmp/snapshot");
+        let mut store = open(backend.clone(), &test_path).await;
+        numbered(&mut store).await;
+
+        let snapshot = store.snapshot();
+        let mut txn = store.txn();
+        txn.set("key1".to_owned(), "new").await.unwrap();
+        txn.set("key10".to_owned(), "new").await.unwrap();
+        txn.remove("key2".to_owned()).await.unwrap();
+        txn.commit().await.unwrap();
+        store.compact().await.unwrap();
+        assert!(backend
+            .file_names()
+            .await
+            .unwrap()
+            .iter()
+            .any(|name| name == "/tmp/snapshot/1"));
+
+        assert_eq!(
+            snapshot.get(&mut store, "key1").await.unwrap(),
+            Some("1".to_owned())
+        );
+        assert_eq!(
+            snapshot.get(&mut store, "key2").await.unwrap(),
+            Some("2".to_owned())
+        );
+        assert_eq!(snapshot.get(&mut store, "key10").await.unwrap(), None);
+        assert_eq!(snapshot.get_range(&mut store, ..).await.unwrap().len(), 10);
+        assert_eq!(
+            store.txn().get("key1".to_owned()).await.unwrap(),
+            Some("\"new\"".to_owned())
+        );
+
+        // the compacted log is removed once the snapshot is gone
+        drop(snapshot);
+        let mut txn = store.txn();
+        txn.set("key3".to_owned(), "new").await.unwrap();
+        txn.commit().await.unwrap();
+        assert!(backend
+            .file_names()
+            .await
+            .unwrap()
+            .iter()
+            .all(|name| name != "/tmp/snapshot/1"));
+    });
+}
+
+// A snapshot covers the substores
+#[test]
+fn substore_snapshot() {
+    block_on(async {
+        let mut store = open(MemoryBackend::new(), "/tmp/substore_snapshot").await;
+        let component = Path::new("component");
+        store.add_substore(component).await.unwrap();
+        let mut txn = store.txn();
+        txn.set_scoped("key".to_owned(), "old".to_owned(), Some(component))
+            .await
+            .unwrap();
+        txn.commit().await.unwrap();
+
+        let snapshot = store.snapshot();
+        let mut txn = store.txn();
+        txn.set_scoped("key".to_owned(), "new".to_owned(), Some(component))
+            .await
+            .unwrap();
+        txn.commit().await.unwrap();
+
+        assert_eq!(snapshot.substores().collect::<Vec<_>>(), [component]);
+        assert_eq!(
+            snapshot
+                .get_scoped(&mut store, "key", Some(component))
+                .await
+                .unwrap(),
+            Some("old".to_owned())
+        );
+
+        store.clear_substore(component).await.unwrap();
+        assert!(snapshot
+            .get_scoped(&mut store, "key", Some(component))
+            .await
+            .is_err());
+    });
+}
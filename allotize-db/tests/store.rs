@@ -1,6 +1,6 @@
 //! Test suite for the Web and headless browsers.
 
-#![feature(async_closure)]
+#![cfg(target_arch = "wasm32")]
 
 extern crate wasm_bindgen_test;
 use wasm_bindgen_test::*;
@@ -16,12 +16,16 @@ async fn overwrite_value() {
     let test_path = PathBuf::from("/tmp/1");
     let mut store = KvStore::open(&test_path).await.unwrap();
 
-    store.txn().set("key13".to_owned(), "value1").await.unwrap();
+    let mut txn = store.txn();
+    txn.set("key13".to_owned(), "value1").await.unwrap();
+    txn.commit().await.unwrap();
     assert_eq!(
         store.txn().get("key13".to_owned()).await.unwrap(),
         Some("value1".to_owned())
     );
-    store.txn().set("key13".to_owned(), "value2").await.unwrap();
+    let mut txn = store.txn();
+    txn.set("key13".to_owned(), "value2").await.unwrap();
+    txn.commit().await.unwrap();
     assert_eq!(
         store.txn().get("key13".to_owned()).await.unwrap(),
         Some("value2".to_owned())
@@ -33,18 +37,20 @@ async fn read_old_values_with_active_substore() {
     let test_path = PathBuf::from("/tmp/2");
     let mut store = KvStore::open(&test_path).await.unwrap();
 
-    store.txn().set("key1".to_owned(), "value1").await.unwrap();
+    let mut txn = store.txn();
+    txn.set("key1".to_owned(), "value1").await.unwrap();
+    txn.commit().await.unwrap();
     store.add_substore(Path::new("/component1")).await.unwrap();
 
-    store
-        .txn()
-        .set_scoped(
-            "key1".to_owned(),
-            "value2".to_owned(),
-            Some(Path::new("/component1")),
-        )
-        .await
-        .unwrap();
+    let mut txn = store.txn();
+    txn.set_scoped(
+        "key1".to_owned(),
+        "value2".to_owned(),
+        Some(Path::new("/component1")),
+    )
+    .await
+    .unwrap();
+    txn.commit().await.unwrap();
 
     assert_eq!(
         store.txn().get("key1".to_owned()).await.unwrap(),
@@ -68,7 +74,9 @@ async fn persistance() {
     let test_path = PathBuf::from("/tmp/3");
     let mut store = KvStore::open(&test_path).await.unwrap();
 
-    store.txn().set("key4".to_owned(), "value1").await.unwrap();
+    let mut txn = store.txn();
+    txn.set("key4".to_owned(), "value1").await.unwrap();
+    txn.commit().await.unwrap();
 
     drop(store);
 
@@ -85,7 +93,9 @@ async fn get_non_existent_value() {
     let test_path = PathBuf::from("/tmp/4");
     let mut store = KvStore::open(&test_path).await.unwrap();
 
-    store.txn().set("key1".to_owned(), "value1").await.unwrap();
+    let mut txn = store.txn();
+    txn.set("key1".to_owned(), "value1").await.unwrap();
+    txn.commit().await.unwrap();
     assert_eq!(store.txn().get("key2".to_owned()).await.unwrap(), None);
 
     // Open from disk again and check persistent data
@@ -105,7 +115,9 @@ async fn remove_non_existent_key() {
 async fn remove_key() {
     let test_path = PathBuf::from("/tmp/6");
     let mut store = KvStore::open(&test_path).await.unwrap();
-    store.txn().set("key1".to_owned(), "value1").await.unwrap();
+    let mut txn = store.txn();
+    txn.set("key1".to_owned(), "value1").await.unwrap();
+    txn.commit().await.unwrap();
     assert_eq!(
         store.txn().get("key1".to_owned()).await.unwrap(),
         Some("value1".into())
@@ -143,7 +155,9 @@ async fn compaction() {
         for key_id in 0..128 {
             let key = format!("key{}", key_id);
             let value = format!("{}", iter);
-            store.txn().set(key, &value).await.unwrap();
+            let mut txn = store.txn();
+            txn.set(key, &value).await.unwrap();
+            txn.commit().await.unwrap();
         }
         let new_size = dir_size().await;
         if new_size > current_size {
@@ -0,0 +1,156 @@
+//! Test suite for substores, running natively on top of `MemoryBackend`.
+
+#![cfg(not(target_arch = "wasm32"))]
+
+mod common;
+
+use allotize_db::{Backend, MemoryBackend};
+use common::open;
+use futures::executor::block_on;
+use std::path::{Path, PathBuf};
+
+// Keys of a substore are isolated from the keys of the store, also after a reopen
+#[test]
+fn substore_isolation() {
+    block_on(async {
+        let backend = MemoryBackend::new();
+        let test_path = PathBuf::from("/tmp/substores");
+        let component = Path::new("component1");
+        let mut store = open(backend.clone(), &test_path).await;
+        store.add_substore(component).await.unwrap();
+
+        let mut txn = store.txn();
+        txn.set("key1".to_owned(), "root").await.unwrap();
+        txn.commit().await.unwrap();
+        let mut txn = store.txn();
+        txn.set_scoped("key1".to_owned(), "scoped".to_owned(), Some(component))
+            .await
+            .unwrap();
+        txn.set_scoped("key2".to_owned(), "scoped".to_owned(), Some(component))
+            .await
+            .unwrap();
+        txn.commit().await.unwrap();
+        drop(store);
+
+        let mut store = open(backend, &test_path).await;
+        assert_eq!(
+            store.get_all().await.unwrap(),
+            [("key1".to_owned(), "\"root\"".to_owned())]
+        );
+        assert!(store
+            .txn()
+            .get_scoped("key1".to_owned(), Some(component))
+            .await
+            .is_err());
+
+        store.add_substore(component).await.unwrap();
+        let mut txn = store.txn();
+        assert_eq!(
+            txn.get_scoped("key1".to_owned(), Some(component))
+                .await
+                .unwrap(),
+            Some("scoped".to_owned())
+        );
+        assert_eq!(txn.get("key2".to_owned()).await.unwrap(), None);
+    });
+}
+
+// Substores can be listed, cleared and dropped
+#[test]
+fn substore_lifecycle() {
+    block_on(async {
+        let backend = MemoryBackend::new();
+        let test_path = PathBuf::from("/tmp/substore_lifecycle");
+        let (first, second) = (Path::new("first"), Path::new("second"));
+        let mut store = open(backend.clone(), &test_path).await;
+        store.add_substore(second).await.unwrap();
+        store.add_substore(first).await.unwrap();
+        assert_eq!(store.substores().collect::<Vec<_>>(), [first, second]);
+
+        let mut txn = store.txn();
+        txn.set_scoped("key".to_owned(), "first".to_owned(), Some(first))
+            .await
+            .unwrap();
+        txn.commit().await.unwrap();
+        let mut txn = store.txn();
+        txn.set_scoped("key".to_owned(), "second".to_owned(), Some(second))
+            .await
+            .unwrap();
+        txn.commit().await.unwrap();
+
+        store.clear_substore(first).await.unwrap();
+        let mut txn = store.txn();
+        assert_eq!(
+            txn.get_scoped("key".to_owned(), Some(first)).await.unwrap(),
+            None
+        );
+        assert_eq!(
+            txn.get_scoped("key".to_owned(), Some(second))
+                .await
+                .unwrap(),
+            Some("second".to_owned())
+        );
+        drop(txn);
+
+        store.drop_substore(second).await.unwrap();
+        assert_eq!(store.substores().collect::<Vec<_>>(), [first]);
+        assert!(store.drop_substore(second).await.is_err());
+        assert!(!backend
+            .file_names()
+            .await
+            .unwrap()
+            .iter()
+            .any(|name| name.starts_with("/tmp/substore_lifecycle/second/")));
+
+        // a write to a substore that is gone fails as a whole
+        let mut txn = store.txn();
+        txn.set("key".to_owned(), "root").await.unwrap();
+        txn.set_scoped("key".to_owned(), "second".to_owned(), Some(second))
+            .await
+            .unwrap();
+        assert!(txn.commit().await.is_err());
+        assert_eq!(store.txn().get("key".to_owned()).await.unwrap(), None);
+    });
+}
+
+// A substore is compacted on its own, leaving the logs of the store alone
+#[test]
+fn substore_compaction() {
+    block_on(async {
+        let backend = MemoryBackend::new();
+        let test_path = PathBuf::from("/tmp/substore_compaction");
+        let component = Path::new("component");
+        let mut store = open(backend.clone(), &test_path).await;
+        store.add_substore(component).await.unwrap();
+        let mut txn = store.txn();
+        txn.set("root".to_owned(), "root").await.unwrap();
+        txn.commit().await.unwrap();
+
+        for iter in 0..128 {
+            for key_id in 0..128 {
+                let mut txn = store.txn();
+                txn.set_scoped(format!("key{}", key_id), iter.to_string(), Some(component))
+                    .await
+                    .unwrap();
+                txn.commit().await.unwrap();
+            }
+        }
+        drop(store);
+
+        let mut store = open(backend, &test_path).await;
+        store.add_substore(component).await.unwrap();
+        let mut txn = store.txn();
+        assert_eq!(
+            txn.get("root".to_owned()).await.unwrap(),
+            Some("\"root\"".to_owned())
+        );
+        for key_id in 0..128 {
+            assert_eq!(
+                txn.get_scoped(format!("key{}", key_id), Some(component))
+                    .await
+                    .unwrap(),
+                Some("127".to_owned())
+            );
+        }
+    });
+}
@@ -0,0 +1,87 @@
+//! Test suite for tuple keys, running natively on top of `MemoryBackend`.
+
+#![cfg(not(target_arch = "wasm32"))]
+
+mod common;
+
+use allotize_db::{KvsError, MemoryBackend, Segment, Tuple};
+use common::open;
+use futures::executor::block_on;
+
+// Tuple keys sort segment by segment, by the value of each segment,
+// and can be scanned by any prefix
+#[test]
+fn tuple_keys() {
+    block_on(async {
+        let backend = MemoryBackend::new();
+        let mut store = open(backend, "/tmp/tuple_keys").await;
+        let tuples = vec![
+            Tuple::new().with(b"\x01".to_vec()),
+            Tuple::new().with(b"\x01\x00".to_vec()),
+            Tuple::new().with("event"),
+            Tuple::new().with("event").with(-3),
+            Tuple::new().with("event").with(2),
+            Tuple::new().with("event").with(10).with("a"),
+            Tuple::new().with("event").with(10).with("a\0b"),
+            Tuple::new().with("event").with(10).with("b"),
+            Tuple::new()
+                .with("event")
+                .with(Segment::Timestamp(1_600_000_000_000)),
+            Tuple::new().with("event\0"),
+            Tuple::new().with("events").with(1),
+            Tuple::new().with(i64::MIN),
+            Tuple::new().with(i64::MAX),
+        ];
+        let mut sorted = tuples.clone();
+        sorted.sort();
+        assert_eq!(sorted, tuples);
+
+        let mut txn = store.txn();
+        for (i, tuple) in tuples.iter().enumerate().rev() {
+            txn.set(tuple.pack(), &i).await.unwrap();
+        }
+        txn.commit().await.unwrap();
+        let keys: Vec<Tuple> = store
+            .get_all()
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|(key, _)| Tuple::unpack(&key).unwrap())
+            .collect();
+        assert_eq!(keys, tuples);
+
+        // the prefix covers the tuple itself, but not `("event\0",)` or `("events",)`
+        let (start, end) = Tuple::new().with("event").range();
+        let mut txn = store.txn();
+        let values: Vec<_> = txn
+            .get_range(start, end)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|(_, value)| value)
+            .collect();
+        assert_eq!(values, ["2", "3", "4", "5", "6", "7", "8"]);
+        let prefix = Tuple::new().with("event").with(10);
+        let page = txn.scan(prefix.scan().reverse(true)).await.unwrap();
+        let keys: Vec<_> = page
+            .items
+            .iter()
+            .map(|(key, _)| Tuple::unpack(key).unwrap())
+            .collect();
+        assert!(keys.iter().all(|key| key.starts_with(&prefix)));
+        assert_eq!(keys.first(), tuples.get(7));
+        assert_eq!(keys.len(), 3);
+        txn.rollback();
+
+        assert!(matches!(
+            Tuple::unpack("user/bob"),
+            Err(KvsError::InvalidTuple { .. })
+        ));
+        assert!(Tuple::unpack("\u{2}unterminated").is_err());
+        assert!(Tuple::unpack("\u{3}+000000000000001").is_err());
+        assert_eq!(
+            Tuple::new().with("user").with(42).to_string(),
+            r#"("user", 42)"#
+        );
+    });
+}
@@ -0,0 +1,209 @@
+//! Test suite for transactions, running natively on top of `MemoryBackend`.
+
+#![cfg(not(target_arch = "wasm32"))]
+
+mod common;
+
+use allotize_db::{ChangeKind, KvsError, MemoryBackend, Scan, Watch};
+use common::open;
+use futures::executor::block_on;
+use std::ops::Bound;
+use std::path::PathBuf;
+
+// Should overwrite existent value
+#[test]
+fn overwrite_value() {
+    block_on(async {
+        let mut store = open(MemoryBackend::new(), "/tmp/1").await;
+
+        let mut txn = store.txn();
+        txn.set("key1".to_owned(), "value1").await.unwrap();
+        txn.commit().await.unwrap();
+        assert_eq!(
+            store.txn().get("key1".to_owned()).await.unwrap(),
+            Some("\"value1\"".to_owned())
+        );
+
+        let mut txn = store.txn();
+        txn.set("key1".to_owned(), "value2").await.unwrap();
+        txn.commit().await.unwrap();
+        assert_eq!(
+            store.txn().get("key1".to_owned()).await.unwrap(),
+            Some("\"value2\"".to_owned())
+        );
+    });
+}
+
+#[test]
+fn remove_non_existent_key() {
+    block_on(async {
+        let mut store = open(MemoryBackend::new(), "/tmp/3").await;
+        assert!(store.txn().remove("keybad".to_owned()).await.is_err());
+    });
+}
+
+// Conditional writes only happen if the key holds the expected value
+#[test]
+fn conditional_writes() {
+    block_on(async {
+        let mut store = open(MemoryBackend::new(), "/tmp/conditional").await;
+        let mut txn = store.txn();
+        txn.set_if_absent("counter".to_owned(), &1).await.unwrap();
+        match txn.set_if_absent("counter".to_owned(), &2).await {
+            Err(KvsError::PreconditionFailed { key, actual }) => {
+                assert_eq!(key, "counter");
+                assert_eq!(actual, Some("1".to_owned()));
+            }
+            _ => panic!("the key was not absent"),
+        }
+        txn.commit().await.unwrap();
+
+        let mut txn = store.txn();
+        txn.compare_and_swap("counter".to_owned(), &1, &2)
+            .await
+            .unwrap();
+        // the buffered write is compared against
+        assert!(matches!(
+            txn.compare_and_swap("counter".to_owned(), &1, &3).await,
+            Err(KvsError::PreconditionFailed { .. })
+        ));
+        txn.commit().await.unwrap();
+
+        let mut txn = store.txn();
+        assert!(matches!(
+            txn.compare_and_swap("missing".to_owned(), &1, &2).await,
+            Err(KvsError::PreconditionFailed { actual: None, .. })
+        ));
+        assert!(matches!(
+            txn.remove_if_equals("counter".to_owned(), &1).await,
+            Err(KvsError::PreconditionFailed { .. })
+        ));
+        assert_eq!(
+            txn.get("counter".to_owned()).await.unwrap(),
+            Some("2".to_owned())
+        );
+        txn.remove_if_equals("counter".to_owned(), &2)
+            .await
+            .unwrap();
+        txn.commit().await.unwrap();
+
+        let mut txn = store.txn();
+        assert_eq!(txn.get("counter".to_owned()).await.unwrap(), None);
+        txn.set_if_absent("counter".to_owned(), &3).await.unwrap();
+        txn.commit().await.unwrap();
+    });
+}
+
+// A transaction should see its own writes before they are committed
+#[test]
+fn read_your_writes() {
+    block_on(async {
+        let mut store = open(MemoryBackend::new(), "/tmp/4").await;
+        let mut txn = store.txn();
+        txn.set("key1".to_owned(), "value1").await.unwrap();
+        txn.set("key2".to_owned(), "value2").await.unwrap();
+        txn.remove("key2".to_owned()).await.unwrap();
+
+        assert_eq!(
+            txn.get("key1".to_owned()).await.unwrap(),
+            Some("\"value1\"".to_owned())
+        );
+        assert_eq!(txn.get("key2".to_owned()).await.unwrap(), None);
+        assert!(txn.remove("key2".to_owned()).await.is_err());
+        txn.commit().await.unwrap();
+
+        assert_eq!(
+            store.txn().get("key1".to_owned()).await.unwrap(),
+            Some("\"value1\"".to_owned())
+        );
+    });
+}
+
+// Writes of a transaction that is rolled back, or dropped, are discarded
+#[test]
+fn rollback() {
+    block_on(async {
+        let mut store = open(MemoryBackend::new(), "/tmp/5").await;
+        let mut txn = store.txn();
+        txn.set("key1".to_owned(), "value1").await.unwrap();
+        txn.rollback();
+
+        let mut txn = store.txn();
+        txn.set("key2".to_owned(), "value2").await.unwrap();
+        drop(txn);
+
+        assert_eq!(store.txn().get("key1".to_owned()).await.unwrap(), None);
+        assert_eq!(store.txn().get("key2".to_owned()).await.unwrap(), None);
+    });
+}
+
+// A range or a prefix of keys is removed with a single record,
+// which survives a reopen and a compaction
+#[test]
+fn remove_range() {
+    block_on(async {
+        let backend = MemoryBackend::new();
+        let test_path = PathBuf::from("/tmp/remove_range");
+        let mut store = open(backend.clone(), &test_path).await;
+        let mut txn = store.txn();
+        for key in &["user/alice/a", "user/alice/b", "user/bob/a", "user/carol/a"] {
+            txn.set((*key).to_owned(), "value").await.unwrap();
+        }
+        txn.set("other".to_owned(), "value").await.unwrap();
+        txn.commit().await.unwrap();
+        let mut alice = store.watch(Watch::prefix("user/alice/")).unwrap();
+        let mut other = store.watch(Watch::key("other")).unwrap();
+
+        let mut txn = store.txn();
+        txn.set("user/alice/new".to_owned(), "value").await.unwrap();
+        txn.remove_prefix("user/alice/");
+        txn.set("user/alice/c".to_owned(), "kept").await.unwrap();
+        // the removal is seen by the reads of the transaction
+        assert_eq!(txn.get("user/alice/a".to_owned()).await.unwrap(), None);
+        let page = txn.scan(Scan::prefix("user/alice/")).await.unwrap();
+        assert_eq!(
+            page.items,
+            [("user/alice/c".to_owned(), "\"kept\"".to_owned())]
+        );
+        // ranges that hold no key remove nothing
+        txn.remove_range(
+            Bound::Included("z".to_owned()),
+            Bound::Excluded("a".to_owned()),
+        );
+        txn.commit().await.unwrap();
+
+        let removed = alice.try_next().unwrap();
+        assert_eq!(
+            removed.kind,
+            ChangeKind::RemoveRange {
+                start: Bound::Included("user/alice/".to_owned()),
+                end: Bound::Excluded("user/alice0".to_owned()),
+            }
+        );
+        assert_eq!(alice.try_next().unwrap().key, "user/alice/c");
+        assert!(alice.try_next().is_none());
+        assert!(other.try_next().is_none());
+
+        let mut txn = store.txn();
+        txn.remove_range(
+            Bound::Excluded("user/alice/c".to_owned()),
+            Bound::Included("user/bob/a".to_owned()),
+        );
+        txn.commit().await.unwrap();
+        let keys = |items: Vec<(String, String)>| -> Vec<String> {
+            items.into_iter().map(|(key, _)| key).collect()
+        };
+        let expected = ["other", "user/alice/c", "user/carol/a"];
+        assert_eq!(keys(store.get_all().await.unwrap()), expected);
+        drop(store);
+
+        let mut store = open(backend.clone(), &test_path).await;
+        assert_eq!(keys(store.get_all().await.unwrap()), expected);
+        store.compact().await.unwrap();
+        assert_eq!(store.stats().unwrap().total().stale_bytes, 0);
+        drop(store);
+
+        let mut store = open(backend, &test_path).await;
+        assert_eq!(keys(store.get_all().await.unwrap()), expected);
+    });
+}
@@ -0,0 +1,127 @@
+//! Test suite for verifying and repairing a store, running natively on top of `MemoryBackend`.
+
+#![cfg(not(target_arch = "wasm32"))]
+
+mod common;
+
+use allotize_db::{Backend, KvStore, MemoryBackend, Problem, Watch};
+use common::{open, overwrite};
+use futures::executor::block_on;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+// Damaged records and orphaned files are found and repaired in an open store
+#[test]
+fn verify_and_repair() {
+    block_on(async {
+        let mut backend = MemoryBackend::new();
+        let test_path = PathBuf::from("/tmp/verify");
+        let mut store = open(backend.clone(), &test_path).await;
+        let component = Path::new("component1");
+        store.add_substore(component).await.unwrap();
+        for i in 0..10 {
+            let mut txn = store.txn();
+            txn.set(format!("key{}", i), &format!("value{}", i))
+                .await
+                .unwrap();
+            txn.commit().await.unwrap();
+        }
+        let mut txn = store.txn();
+        txn.set_scoped("draft".to_owned(), "text".to_owned(), Some(component))
+            .await
+            .unwrap();
+        txn.commit().await.unwrap();
+        assert!(store.verify().await.unwrap().is_ok());
+
+        overwrite(&mut backend, &test_path.join("1"), b"value5", b"valueX").await;
+        backend
+            .open_file(&test_path.join("99.hint"))
+            .await
+            .unwrap()
+            .write_all(b"hint")
+            .unwrap();
+
+        let report = store.verify().await.unwrap();
+        assert!(report.problems.iter().any(|problem| matches!(
+            problem,
+            Problem::BadEntry { substore: None, key, .. } if key == "key5"
+        )));
+        assert!(report.problems.iter().any(|problem| matches!(
+            problem,
+            Problem::UnreadableRecord { path, .. } if path == &test_path.join("1")
+        )));
+        assert!(report.problems.contains(&Problem::OrphanedFile {
+            path: test_path.join("99.hint")
+        }));
+
+        let repaired = store.repair().await.unwrap();
+        assert_eq!(repaired.lost, vec![(None, "key5".to_owned())]);
+        assert!(repaired.reverted.is_empty());
+        assert_eq!(repaired.unreadable_records, 1);
+        assert_eq!(repaired.removed_files, vec![test_path.join("99.hint")]);
+        assert!(store.verify().await.unwrap().is_ok());
+        drop(store);
+
+        let mut store = open(backend, &test_path).await;
+        store.add_substore(component).await.unwrap();
+        let mut txn = store.txn();
+        assert_eq!(txn.get("key5".to_owned()).await.unwrap(), None);
+        assert_eq!(
+            txn.get("key6".to_owned()).await.unwrap(),
+            Some("\"value6\"".to_owned())
+        );
+        assert_eq!(
+            txn.get_scoped("draft".to_owned(), Some(component))
+                .await
+                .unwrap(),
+            Some("text".to_owned())
+        );
+        txn.rollback();
+    });
+}
+
+// A store that can not be opened is opened by repairing it
+#[test]
+fn open_repaired() {
+    block_on(async {
+        let mut backend = MemoryBackend::new();
+        let test_path = PathBuf::from("/tmp/open_repaired");
+        let mut store = open(backend.clone(), &test_path).await;
+        for i in 0..3 {
+            let mut txn = store.txn();
+            txn.set(format!("key{}", i), &format!("value{}", i))
+                .await
+                .unwrap();
+            txn.commit().await.unwrap();
+        }
+        drop(store);
+
+        overwrite(&mut backend, &test_path.join("1"), b"value1", b"valueX").await;
+        assert!(KvStore::open_with_backend(backend.clone(), &test_path)
+            .await
+            .is_err());
+
+        let (mut store, report) = KvStore::open_repaired(backend.clone(), &test_path)
+            .await
+            .unwrap();
+        assert_eq!(report.unreadable_records, 1);
+        assert!(store.verify().await.unwrap().is_ok());
+        let mut txn = store.txn();
+        assert_eq!(txn.get("key1".to_owned()).await.unwrap(), None);
+        assert_eq!(
+            txn.get("key2".to_owned()).await.unwrap(),
+            Some("\"value2\"".to_owned())
+        );
+        txn.rollback();
+
+        // changes are never numbered twice
+        let mut watcher = store.watch(Watch::all()).unwrap();
+        let mut txn = store.txn();
+        txn.set("key3".to_owned(), "value3").await.unwrap();
+        txn.commit().await.unwrap();
+        assert_eq!(watcher.try_next().unwrap().seq, 4);
+        drop(store);
+
+        open(backend, &test_path).await;
+    });
+}
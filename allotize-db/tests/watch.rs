@@ -0,0 +1,105 @@
+//! Test suite for watchers, running natively on top of `MemoryBackend`.
+
+#![cfg(not(target_arch = "wasm32"))]
+
+mod common;
+
+use allotize_db::{Change, ChangeKind, KvsError, MemoryBackend, Watch};
+use common::{numbered, open};
+use futures::executor::block_on;
+
+// Watchers receive the changes of their range, numbered in order
+#[test]
+fn watch() {
+    block_on(async {
+        let mut store = open(MemoryBackend::new(), "/tmp/watch").await;
+        let mut users = store.watch(Watch::prefix("user/")).unwrap();
+        let mut alice = store.watch(Watch::key("user/alice")).unwrap();
+
+        let mut txn = store.txn();
+        txn.set("user/alice".to_owned(), &1).await.unwrap();
+        txn.set("other".to_owned(), &2).await.unwrap();
+        txn.set("user/bob".to_owned(), &3).await.unwrap();
+        txn.commit().await.unwrap();
+        let mut txn = store.txn();
+        txn.remove("user/alice".to_owned()).await.unwrap();
+        txn.commit().await.unwrap();
+
+        // the commands of a transaction are ordered by key
+        let change = |seq, key: &str, kind| Change {
+            seq,
+            key: key.to_owned(),
+            kind,
+        };
+        assert_eq!(
+            users.next().await,
+            Some(change(2, "user/alice", ChangeKind::Set("1".to_owned())))
+        );
+        assert_eq!(
+            users.next().await,
+            Some(change(3, "user/bob", ChangeKind::Set("3".to_owned())))
+        );
+        assert_eq!(
+            users.next().await,
+            Some(change(4, "user/alice", ChangeKind::Remove))
+        );
+        assert_eq!(users.try_next(), None);
+
+        assert_eq!(alice.try_next().map(|change| change.seq), Some(2));
+        assert_eq!(alice.try_next().map(|change| change.seq), Some(4));
+        assert_eq!(alice.try_next(), None);
+
+        drop(store);
+        assert_eq!(alice.next().await, None);
+    });
+}
+
+// A watcher resumes from a sequence number after a restart,
+// as long as the changes after it were not compacted away
+#[test]
+fn resume_watch() {
+    block_on(async {
+        let backend = MemoryBackend::new();
+        {
+            let mut store = open(backend.clone(), "/tmp/resume_watch").await;
+            numbered(&mut store).await;
+        }
+
+        let mut store = open(backend.clone(), "/tmp/resume_watch").await;
+        let mut watcher = store.watch(Watch::all().resume(7)).unwrap();
+        let keys: Vec<_> = std::iter::from_fn(|| watcher.try_next())
+            .map(|change| (change.seq, change.key))
+            .collect();
+        assert_eq!(
+            keys,
+            [
+                (8, "key7".to_owned()),
+                (9, "key8".to_owned()),
+                (10, "key9".to_owned())
+            ]
+        );
+
+        store.compact().await.unwrap();
+        let mut txn = store.txn();
+        txn.set("key10".to_owned(), &10).await.unwrap();
+        txn.commit().await.unwrap();
+        drop(store);
+
+        let mut store = open(backend, "/tmp/resume_watch").await;
+        match store.watch(Watch::all().resume(7)) {
+            Err(KvsError::ChangesUnavailable { seq, oldest }) => {
+                assert_eq!((seq, oldest), (7, 10))
+            }
+            _ => panic!("compacted changes were resumed from"),
+        }
+        assert!(store.watch(Watch::all().resume(12)).is_err());
+
+        // the numbering goes on after the compaction
+        let mut watcher = store.watch(Watch::all().resume(10)).unwrap();
+        let mut txn = store.txn();
+        txn.set("key11".to_owned(), &11).await.unwrap();
+        txn.commit().await.unwrap();
+        assert_eq!(watcher.try_next().map(|change| change.seq), Some(11));
+        assert_eq!(watcher.try_next().map(|change| change.seq), Some(12));
+    });
+}